@@ -0,0 +1,65 @@
+//! Compiles src/cutil.c, the variadic printf entry points, and makes the shared library
+//! export them. rustc only exports the Rust functions marked `#[no_mangle]`, so the C symbols
+//! are listed for the linker separately. With the `replica` feature, src/replica.c is compiled
+//! too, around the SQLite amalgamation that libsqlite3-sys bundles, for embedded replicas. The
+//! engine's functions are made static there, so none of them is exported and they cannot clash
+//! with this library's own `sqlite3_*` functions. libsqlite3-sys is only a source of the
+//! amalgamation: no Rust code uses it, so its own build of the engine is never linked in.
+
+use std::{env, fs, path::PathBuf};
+
+const C_EXPORTS: [&str; 4] = [
+    "sqlite3_mprintf",
+    "sqlite3_vmprintf",
+    "sqlite3_snprintf",
+    "sqlite3_vsnprintf",
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/cutil.c");
+    cc::Build::new().file("src/cutil.c").compile("cutil");
+
+    if env::var_os("CARGO_FEATURE_REPLICA").is_some() {
+        // Set by the build script of libsqlite3-sys, the directory holding sqlite3.c
+        let include = env::var("DEP_SQLITE3_INCLUDE").expect("libsqlite3-sys reports its sources");
+        println!("cargo:rerun-if-changed=src/replica.c");
+        // The amalgamation's own warnings are not ours to fix
+        cc::Build::new()
+            .file("src/replica.c")
+            .include(include)
+            .warnings(false)
+            .flag_if_supported("-Wno-unused-function")
+            .compile("replica");
+    }
+
+    let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default();
+    if os == "macos" || os == "ios" {
+        for symbol in C_EXPORTS {
+            println!(
+                "cargo:rustc-link-arg-cdylib=-Wl,-exported_symbol,_{}",
+                symbol
+            );
+        }
+    } else if os == "windows" && target_env == "msvc" {
+        for symbol in C_EXPORTS {
+            println!("cargo:rustc-link-arg-cdylib=/EXPORT:{}", symbol);
+        }
+    } else if os != "windows" {
+        // ELF: pulled in from the archive, then made global by a version script of their own,
+        // which the linker merges with the one rustc passes
+        let script = PathBuf::from(env::var("OUT_DIR").unwrap()).join("cutil.map");
+        fs::write(
+            &script,
+            format!("{{ global: {}; }};\n", C_EXPORTS.join("; ")),
+        )
+        .unwrap();
+        for symbol in C_EXPORTS {
+            println!("cargo:rustc-link-arg-cdylib=-Wl,--undefined={}", symbol);
+        }
+        println!(
+            "cargo:rustc-link-arg-cdylib=-Wl,--version-script={}",
+            script.display()
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Best-effort look at SQL text without a full parser: which tables a statement touches.
+//! Names are lowercased with quoting and schema prefixes stripped.
+
+use regex::Regex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementEffect {
+    Read(Vec<String>),  // SELECT over these tables, EXPLAIN and VALUES over none
+    Write(Vec<String>), // INSERT, UPDATE, DELETE or REPLACE on these tables
+    Unknown,            // DDL, PRAGMA and anything else not understood
+}
+
+pub fn analyze(sql: &str) -> StatementEffect {
+    let sql = strip_comments(sql);
+    let trimmed = sql.trim_start();
+    let keyword = trimmed
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+
+    match keyword.as_str() {
+        "SELECT" => StatementEffect::Read(read_tables(&sql)),
+        "WITH" => {
+            // A CTE may front a write, which the table scan below cannot attribute
+            let writes = Regex::new(r"(?i)\b(?:INSERT|UPDATE|DELETE|REPLACE)\b").unwrap();
+            if writes.is_match(&sql) {
+                StatementEffect::Unknown
+            } else {
+                StatementEffect::Read(read_tables(&sql))
+            }
+        }
+        // Neither touches stored data, so there is nothing to cache or evict
+        "EXPLAIN" | "VALUES" => StatementEffect::Read(vec![]),
+        "INSERT" | "REPLACE" | "UPDATE" | "DELETE" => match written_table(&sql) {
+            Some(table) => StatementEffect::Write(vec![table]),
+            None => StatementEffect::Unknown,
+        },
+        _ => StatementEffect::Unknown,
+    }
+}
+
+/// Tables a statement of any kind reads through FROM and JOIN, subqueries included.
+pub fn tables_read(sql: &str) -> Vec<String> {
+    read_tables(&strip_comments(sql))
+}
+
+/// Table an INSERT, REPLACE, UPDATE or DELETE writes to.
+pub fn table_written(sql: &str) -> Option<String> {
+    written_table(&strip_comments(sql))
+}
+
+fn read_tables(sql: &str) -> Vec<String> {
+    let re = Regex::new(
+        r#"(?ix)
+        \b(?:FROM|JOIN)\s+
+        ((?:[\w$]+|"[^"]+"|`[^`]+`|\[[^\]]+\])(?:\.(?:[\w$]+|"[^"]+"|`[^`]+`|\[[^\]]+\]))?)
+        ((?:\s*(?:AS\s+)?\w*\s*,\s*(?:[\w$]+|"[^"]+"|`[^`]+`|\[[^\]]+\]))*)
+    "#,
+    )
+    .unwrap();
+    let extra = Regex::new(r#",\s*([\w$]+|"[^"]+"|`[^`]+`|\[[^\]]+\])"#).unwrap();
+
+    let mut tables = Vec::new();
+    for captures in re.captures_iter(sql) {
+        let mut names = vec![captures[1].to_string()];
+        if let Some(rest) = captures.get(2) {
+            names.extend(extra.captures_iter(rest.as_str()).map(|c| c[1].to_string()));
+        }
+
+        for name in names {
+            let name = normalize_name(&name);
+            // Sub-selects show up as "(" which never matches, keywords can though
+            if !name.is_empty() && name != "select" && !tables.contains(&name) {
+                tables.push(name);
+            }
+        }
+    }
+
+    tables
+}
+
+fn written_table(sql: &str) -> Option<String> {
+    let re = Regex::new(
+        r#"(?ix)
+        ^\s*(?:
+            (?:INSERT|REPLACE)(?:\s+OR\s+\w+)?\s+INTO
+          | UPDATE(?:\s+OR\s+\w+)?
+          | DELETE\s+FROM
+        )\s+
+        ((?:[\w$]+|"[^"]+"|`[^`]+`|\[[^\]]+\])(?:\.(?:[\w$]+|"[^"]+"|`[^`]+`|\[[^\]]+\]))?)
+    "#,
+    )
+    .unwrap();
+
+    re.captures(sql).map(|c| normalize_name(&c[1]))
+}
+
+fn normalize_name(name: &str) -> String {
+    let name = name.rsplit('.').next().unwrap_or(name);
+    name.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'))
+        .to_ascii_lowercase()
+}
+
+fn strip_comments(sql: &str) -> String {
+    let re = Regex::new(r"(?s)--[^\n]*|/\*.*?\*/").unwrap();
+    re.replace_all(sql, " ").into_owned()
+}
@@ -0,0 +1,183 @@
+//! ATTACH across Turso databases. Every attached schema gets a connection of its own, opened
+//! through the same auth strategy as the main database. A statement naming an attached schema
+//! is sent to that database with the schema prefix dropped, so no statement spans two of them.
+
+use std::{ffi::c_int, ops::Range, sync::Arc};
+
+use crate::{
+    auth,
+    config::ConnectionOptions,
+    protocol::{RemoteSqliteResponse, StatementArgs},
+    sql::tokenizer::{unquote, Token, Tokenizer},
+    sqlite::{SQLite3, SqliteError, SQLITE_CANTOPEN, SQLITE_ERROR, SQLITE_OK},
+    transport::DatabaseConnection,
+};
+
+pub type AttachedDatabase = Arc<tokio::sync::Mutex<DatabaseConnection>>;
+
+pub async fn attach_on_db(
+    db: *mut SQLite3,
+    database: &str,
+    schema: &str,
+) -> Result<c_int, SqliteError> {
+    let db = unsafe { &*db };
+
+    if db.has_began_transaction() {
+        return Err(SqliteError::new(
+            "cannot ATTACH database within transaction",
+            Some(SQLITE_ERROR),
+        ));
+    }
+    if matches!(schema, "main" | "temp") || db.attached.lock().unwrap().contains_key(schema) {
+        return Err(SqliteError::new(
+            format!("database {} is already in use", schema),
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    // Query parameters on the attached name override the main connection's options
+    let (db_name, options) = ConnectionOptions::parse_with(database, db.options.clone())?;
+    let connection = DatabaseConnection::open(&db_name, auth::strategy_for(options.auth), options)
+        .await
+        .map_err(|err| {
+            SqliteError::new(
+                format!("unable to open database {}: {}", db_name, err),
+                Some(SQLITE_CANTOPEN),
+            )
+        })?;
+
+    tracing::debug!(schema, database = %db_name, "Attached database");
+    db.attached.lock().unwrap().insert(
+        schema.to_string(),
+        Arc::new(tokio::sync::Mutex::new(connection)),
+    );
+
+    Ok(SQLITE_OK)
+}
+
+pub async fn detach_on_db(db: *mut SQLite3, schema: &str) -> Result<c_int, SqliteError> {
+    let db = unsafe { &*db };
+
+    if db.has_began_transaction() {
+        return Err(SqliteError::new(
+            "cannot DETACH database within transaction",
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    let database = db.attached.lock().unwrap().remove(schema);
+    let Some(database) = database else {
+        return Err(SqliteError::new(
+            format!("no such database: {}", schema),
+            Some(SQLITE_ERROR),
+        ));
+    };
+    database.lock().await.close().await;
+
+    Ok(SQLITE_OK)
+}
+
+/// Closes every attached database, done when the main connection closes.
+pub async fn detach_all(db: &SQLite3) {
+    let attached: Vec<_> = db.attached.lock().unwrap().drain().collect();
+    for (_, database) in attached {
+        database.lock().await.close().await;
+    }
+}
+
+/// Readies every attached database for sqlite3_shutdown. They stay attached and reconnect
+/// when next used.
+pub async fn shut_down_all(db: &SQLite3) {
+    let attached: Vec<_> = db.attached.lock().unwrap().values().cloned().collect();
+    for database in attached {
+        if let Err(err) = database.lock().await.shut_down().await {
+            tracing::warn!(error = %err, "Failed to shut down attached database");
+        }
+    }
+}
+
+/// The attached database a statement is meant for, together with the statement as that
+/// database must see it. `None` leaves the statement to the main database.
+pub fn route(db: &SQLite3, sql: &str) -> Result<Option<(AttachedDatabase, String)>, SqliteError> {
+    let attached = db.attached.lock().unwrap();
+    if attached.is_empty() {
+        return Ok(None);
+    }
+
+    let mut target: Option<String> = None;
+    let mut prefixes: Vec<Range<usize>> = Vec::new();
+    let mut previous: Option<(usize, String)> = None; // Last identifier and where it started
+
+    let mut tokenizer = Tokenizer::new(sql);
+    loop {
+        let start = tokenizer.offset();
+        let Some(token) = tokenizer.next() else {
+            break;
+        };
+
+        match token {
+            Token::Whitespace | Token::Comment => continue,
+            Token::Identifier(word) => {
+                previous = Some((start, unquote(word)));
+                continue;
+            }
+            Token::Punct(".") => {
+                if let Some((begin, name)) = previous.take() {
+                    if attached.contains_key(&name) {
+                        if target.as_ref().is_some_and(|target| *target != name) {
+                            return Err(SqliteError::new(
+                                "Statements spanning several attached databases are not supported",
+                                Some(SQLITE_ERROR),
+                            ));
+                        }
+                        prefixes.push(begin..tokenizer.offset());
+                        target = Some(name);
+                    }
+                }
+            }
+            _ => (),
+        }
+        previous = None;
+    }
+
+    let Some(target) = target else {
+        return Ok(None);
+    };
+
+    let mut rewritten = String::with_capacity(sql.len());
+    let mut copied = 0;
+    for prefix in prefixes {
+        rewritten.push_str(&sql[copied..prefix.start]);
+        copied = prefix.end;
+    }
+    rewritten.push_str(&sql[copied..]);
+
+    Ok(Some((attached[&target].clone(), rewritten)))
+}
+
+/// Runs a routed statement on its attached database. Attached databases only run in
+/// autocommit mode, the main connection's transaction does not extend to them.
+pub async fn execute_on_attached(
+    db: &SQLite3,
+    database: &AttachedDatabase,
+    sql: &str,
+    params: StatementArgs,
+) -> Result<RemoteSqliteResponse, SqliteError> {
+    if db.has_began_transaction() {
+        return Err(SqliteError::new(
+            "Attached databases cannot be used inside a transaction",
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    let mut connection = database.lock().await;
+    if connection.is_inherited() {
+        connection.after_fork()?;
+    }
+    let mut request = connection.get_autocommit_request(sql, &params);
+    let mut response = connection.send(&mut request).await?;
+
+    // Whatever baton came back belongs to the attached database, never to the main one
+    response.baton = None;
+    Ok(response)
+}
@@ -0,0 +1,396 @@
+//! `sqlite3_set_authorizer` run on the client. Statements are checked when prepared, against
+//! the actions their text shows: tables read and written, DDL, PRAGMAs, transactions. Actions
+//! only the server's planner would see, such as individual column reads, are not reported.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    ptr,
+};
+
+use crate::{
+    analyzer::{table_written, tables_read},
+    sql::{
+        select::{is_keyword, spanned_tokens, Spanned},
+        tokenizer::{classify, StatementClass, Token},
+    },
+    sqlite::{SQLite3, SqliteError, SQLITE_AUTH, SQLITE_ERROR},
+};
+
+pub type AuthorizerCallback = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    action: c_int,
+    arg1: *const c_char,
+    arg2: *const c_char,
+    database: *const c_char,
+    trigger_or_view: *const c_char,
+) -> c_int;
+
+pub const SQLITE_DENY: c_int = 1;
+pub const SQLITE_IGNORE: c_int = 2;
+
+const SQLITE_CREATE_INDEX: c_int = 1;
+const SQLITE_CREATE_TABLE: c_int = 2;
+const SQLITE_CREATE_TEMP_INDEX: c_int = 3;
+const SQLITE_CREATE_TEMP_TABLE: c_int = 4;
+const SQLITE_CREATE_TEMP_TRIGGER: c_int = 5;
+const SQLITE_CREATE_TEMP_VIEW: c_int = 6;
+const SQLITE_CREATE_TRIGGER: c_int = 7;
+const SQLITE_CREATE_VIEW: c_int = 8;
+const SQLITE_DELETE: c_int = 9;
+const SQLITE_DROP_INDEX: c_int = 10;
+const SQLITE_DROP_TABLE: c_int = 11;
+const SQLITE_DROP_TRIGGER: c_int = 16;
+const SQLITE_DROP_VIEW: c_int = 17;
+const SQLITE_INSERT: c_int = 18;
+const SQLITE_PRAGMA: c_int = 19;
+const SQLITE_READ: c_int = 20;
+const SQLITE_SELECT: c_int = 21;
+const SQLITE_TRANSACTION: c_int = 22;
+const SQLITE_UPDATE: c_int = 23;
+const SQLITE_ATTACH: c_int = 24;
+const SQLITE_DETACH: c_int = 25;
+const SQLITE_ALTER_TABLE: c_int = 26;
+const SQLITE_REINDEX: c_int = 27;
+const SQLITE_ANALYZE: c_int = 28;
+const SQLITE_CREATE_VTABLE: c_int = 29;
+const SQLITE_SAVEPOINT: c_int = 32;
+
+pub struct Authorizer {
+    callback: AuthorizerCallback,
+    user_data: *mut c_void,
+}
+
+impl Authorizer {
+    pub fn new(callback: AuthorizerCallback, user_data: *mut c_void) -> Self {
+        Self {
+            callback,
+            user_data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authorization {
+    Allowed,
+    Ignored, // SQLITE_IGNORE: the statement is prepared but never sent
+}
+
+/// Runs the connection's authorizer over every action of `sql`. A denied action fails the
+/// statement with SQLITE_AUTH, as SQLite does when preparing it.
+pub fn authorize(db: &SQLite3, sql: &str) -> Result<Authorization, SqliteError> {
+    let (callback, user_data) = match &*db.authorizer.lock().unwrap() {
+        Some(authorizer) => (authorizer.callback, authorizer.user_data),
+        None => return Ok(Authorization::Allowed),
+    };
+
+    let mut authorization = Authorization::Allowed;
+    for action in actions(sql) {
+        let arg1 = action.arg1.and_then(|s| CString::new(s).ok());
+        let arg2 = action.arg2.and_then(|s| CString::new(s).ok());
+        let database = action.database.and_then(|s| CString::new(s).ok());
+        let as_ptr = |s: &Option<CString>| s.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+
+        let result = unsafe {
+            callback(
+                user_data,
+                action.code,
+                as_ptr(&arg1),
+                as_ptr(&arg2),
+                as_ptr(&database),
+                ptr::null(),
+            )
+        };
+        match result {
+            0 => (),
+            SQLITE_DENY => {
+                return Err(SqliteError::new("not authorized", Some(SQLITE_AUTH)));
+            }
+            SQLITE_IGNORE => authorization = Authorization::Ignored,
+            _ => {
+                return Err(SqliteError::new(
+                    "authorizer malfunction",
+                    Some(SQLITE_ERROR),
+                ))
+            }
+        }
+    }
+
+    Ok(authorization)
+}
+
+struct Action {
+    code: c_int,
+    arg1: Option<String>,
+    arg2: Option<String>,
+    database: Option<String>,
+}
+
+impl Action {
+    fn new(code: c_int, arg1: Option<String>, arg2: Option<String>) -> Self {
+        Self {
+            code,
+            arg1,
+            arg2,
+            database: None,
+        }
+    }
+
+    // Actions on schema objects also name the database they live in
+    fn on(mut self, database: Option<String>) -> Self {
+        self.database = Some(database.unwrap_or_else(|| "main".to_string()));
+        self
+    }
+}
+
+// Name as written with its quotes removed, and the schema it was qualified with
+fn qualified_name(tokens: &[Spanned], at: usize) -> Option<(Option<String>, String)> {
+    let name = |i: usize| match tokens.get(i).map(|t| &t.token) {
+        Some(Token::Identifier(word)) => Some(
+            word.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'))
+                .to_string(),
+        ),
+        _ => None,
+    };
+
+    let first = name(at)?;
+    match tokens.get(at + 1).map(|t| &t.token) {
+        Some(Token::Punct(".")) => Some((Some(first), name(at + 2)?)),
+        _ => Some((None, first)),
+    }
+}
+
+fn actions(sql: &str) -> Vec<Action> {
+    let text = |value: &str| Some(value.to_string());
+
+    match classify(sql) {
+        StatementClass::Begin => vec![Action::new(SQLITE_TRANSACTION, text("BEGIN"), None)],
+        StatementClass::Commit => vec![Action::new(SQLITE_TRANSACTION, text("COMMIT"), None)],
+        StatementClass::Rollback => {
+            vec![Action::new(SQLITE_TRANSACTION, text("ROLLBACK"), None)]
+        }
+        StatementClass::Savepoint(name) => {
+            vec![Action::new(SQLITE_SAVEPOINT, text("BEGIN"), Some(name))]
+        }
+        StatementClass::Release(name) => {
+            vec![Action::new(SQLITE_SAVEPOINT, text("RELEASE"), Some(name))]
+        }
+        StatementClass::RollbackTo(name) => {
+            vec![Action::new(SQLITE_SAVEPOINT, text("ROLLBACK"), Some(name))]
+        }
+        StatementClass::Attach { database, .. } => {
+            vec![Action::new(SQLITE_ATTACH, Some(database), None)]
+        }
+        StatementClass::Detach(schema) => vec![Action::new(SQLITE_DETACH, Some(schema), None)],
+        StatementClass::Pragma { .. } => pragma_actions(&spanned_tokens(sql), sql),
+        StatementClass::CreateTemp { .. } | StatementClass::Drop(_) | StatementClass::Other => {
+            statement_actions(&spanned_tokens(sql), sql)
+        }
+    }
+}
+
+// `PRAGMA [schema.]name [= value | (value)]`
+fn pragma_actions(tokens: &[Spanned], sql: &str) -> Vec<Action> {
+    let Some((schema, name)) = qualified_name(tokens, 1) else {
+        return vec![];
+    };
+    let value_at = if schema.is_some() { 4 } else { 2 };
+    let value = match tokens.get(value_at).map(|t| &t.token) {
+        Some(Token::Punct("=")) | Some(Token::Punct("(")) => tokens.get(value_at + 1).map(|t| {
+            sql[t.start..t.end]
+                .trim_matches(|c| matches!(c, '\'' | '"'))
+                .to_string()
+        }),
+        _ => None,
+    };
+
+    let mut action = Action::new(SQLITE_PRAGMA, Some(name), value);
+    action.database = schema;
+    vec![action]
+}
+
+fn statement_actions(tokens: &[Spanned], sql: &str) -> Vec<Action> {
+    let keyword = |i: usize| match tokens.get(i).map(|t| &t.token) {
+        Some(Token::Identifier(word)) => word.to_ascii_uppercase(),
+        _ => String::new(),
+    };
+    let reads = || {
+        tables_read(sql)
+            .into_iter()
+            .map(|table| Action::new(SQLITE_READ, Some(table), Some(String::new())).on(None))
+    };
+
+    match keyword(0).as_str() {
+        "SELECT" | "VALUES" => std::iter::once(Action::new(SQLITE_SELECT, None, None))
+            .chain(reads())
+            .collect(),
+        "WITH" => {
+            // The statement the CTEs front decides what is written
+            let mut depth = 0;
+            let main = tokens.iter().skip(1).find(|t| {
+                match t.token {
+                    Token::Punct("(") => depth += 1,
+                    Token::Punct(")") => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+                    && ["INSERT", "REPLACE", "UPDATE", "DELETE"]
+                        .iter()
+                        .any(|k| is_keyword(&t.token, k))
+            });
+            let mut actions = vec![Action::new(SQLITE_SELECT, None, None)];
+            if let Some(main) = main {
+                let statement = &sql[main.start..];
+                actions.extend(write_actions(&spanned_tokens(statement), statement));
+            }
+            actions.extend(reads());
+            actions
+        }
+        "INSERT" | "REPLACE" | "UPDATE" | "DELETE" => {
+            let mut actions = write_actions(tokens, sql);
+            actions.extend(reads());
+            actions
+        }
+        "CREATE" => create_actions(tokens),
+        "DROP" => {
+            let code = match keyword(1).as_str() {
+                "TABLE" => SQLITE_DROP_TABLE,
+                "INDEX" => SQLITE_DROP_INDEX,
+                "VIEW" => SQLITE_DROP_VIEW,
+                "TRIGGER" => SQLITE_DROP_TRIGGER,
+                _ => return vec![],
+            };
+            let at = if keyword(2) == "IF" { 4 } else { 2 };
+            match qualified_name(tokens, at) {
+                Some((schema, name)) => vec![Action::new(code, Some(name), None).on(schema)],
+                None => vec![],
+            }
+        }
+        "ALTER" => match qualified_name(tokens, 2) {
+            Some((schema, table)) => {
+                let database = schema.unwrap_or_else(|| "main".to_string());
+                vec![
+                    Action::new(SQLITE_ALTER_TABLE, Some(database.clone()), Some(table))
+                        .on(Some(database)),
+                ]
+            }
+            None => vec![],
+        },
+        "REINDEX" => {
+            let name = qualified_name(tokens, 1).map(|(_, name)| name);
+            vec![Action::new(SQLITE_REINDEX, name, None).on(None)]
+        }
+        "ANALYZE" => {
+            let name = qualified_name(tokens, 1).map(|(_, name)| name);
+            vec![Action::new(SQLITE_ANALYZE, name, None).on(None)]
+        }
+        _ => vec![],
+    }
+}
+
+// INSERT, REPLACE, DELETE or UPDATE, the last once per assigned column
+fn write_actions(tokens: &[Spanned], sql: &str) -> Vec<Action> {
+    let Some(table) = table_written(sql) else {
+        return vec![];
+    };
+    let first = &tokens[0].token;
+
+    if is_keyword(first, "DELETE") {
+        return vec![Action::new(SQLITE_DELETE, Some(table), None).on(None)];
+    }
+    if !is_keyword(first, "UPDATE") {
+        return vec![Action::new(SQLITE_INSERT, Some(table), None).on(None)];
+    }
+
+    let mut columns = Vec::new();
+    let mut depth = 0;
+    let set = tokens.iter().position(|t| is_keyword(&t.token, "SET"));
+    for (i, spanned) in tokens
+        .iter()
+        .enumerate()
+        .skip(set.map_or(tokens.len(), |s| s + 1))
+    {
+        match spanned.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth -= 1,
+            Token::Identifier(_) if depth == 0 => {
+                if ["FROM", "WHERE", "RETURNING", "ORDER", "LIMIT"]
+                    .iter()
+                    .any(|k| is_keyword(&spanned.token, k))
+                {
+                    break;
+                }
+                if matches!(tokens.get(i + 1).map(|t| &t.token), Some(Token::Punct("="))) {
+                    columns.extend(qualified_name(tokens, i).map(|(_, column)| column));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    columns
+        .into_iter()
+        .map(|column| Action::new(SQLITE_UPDATE, Some(table.clone()), Some(column)).on(None))
+        .collect()
+}
+
+// CREATE [TEMP] [UNIQUE] TABLE | INDEX | VIEW | TRIGGER | VIRTUAL TABLE [IF NOT EXISTS] name
+fn create_actions(tokens: &[Spanned]) -> Vec<Action> {
+    let mut at = 1;
+    let temp = tokens
+        .get(at)
+        .is_some_and(|t| is_keyword(&t.token, "TEMP") || is_keyword(&t.token, "TEMPORARY"));
+    if temp {
+        at += 1;
+    }
+    if tokens
+        .get(at)
+        .is_some_and(|t| is_keyword(&t.token, "UNIQUE"))
+    {
+        at += 1;
+    }
+    let Some(Spanned {
+        token: Token::Identifier(kind),
+        ..
+    }) = tokens.get(at)
+    else {
+        return vec![];
+    };
+    let kind = kind.to_ascii_uppercase();
+    at += if kind == "VIRTUAL" { 2 } else { 1 };
+    if tokens.get(at).is_some_and(|t| is_keyword(&t.token, "IF")) {
+        at += 3;
+    }
+
+    let Some((schema, name)) = qualified_name(tokens, at) else {
+        return vec![];
+    };
+    // The table an index or trigger is created on follows the first ON
+    let target = || {
+        let on = tokens.iter().position(|t| is_keyword(&t.token, "ON"))?;
+        qualified_name(tokens, on + 1).map(|(_, table)| table)
+    };
+
+    let (code, arg2) = match (kind.as_str(), temp) {
+        ("TABLE", false) => (SQLITE_CREATE_TABLE, None),
+        ("TABLE", true) => (SQLITE_CREATE_TEMP_TABLE, None),
+        ("INDEX", false) => (SQLITE_CREATE_INDEX, target()),
+        ("INDEX", true) => (SQLITE_CREATE_TEMP_INDEX, target()),
+        ("VIEW", false) => (SQLITE_CREATE_VIEW, None),
+        ("VIEW", true) => (SQLITE_CREATE_TEMP_VIEW, None),
+        ("TRIGGER", false) => (SQLITE_CREATE_TRIGGER, target()),
+        ("TRIGGER", true) => (SQLITE_CREATE_TEMP_TRIGGER, target()),
+        ("VIRTUAL", _) => {
+            let using = tokens.iter().position(|t| is_keyword(&t.token, "USING"));
+            let module = using.and_then(|i| qualified_name(tokens, i + 1).map(|(_, m)| m));
+            (SQLITE_CREATE_VTABLE, module)
+        }
+        _ => return vec![],
+    };
+
+    let schema = if temp {
+        Some("temp".to_string())
+    } else {
+        schema
+    };
+    vec![Action::new(code, Some(name), arg2).on(schema)]
+}
@@ -0,0 +1,285 @@
+//! Online backup between two connections of this library. The source is read with plain
+//! queries, its schema from `sqlite_master` and then every table in batches of rows, and
+//! replayed into the destination. A "page" is one unit of that work, so `backup_remaining`
+//! and `backup_pagecount` report progress the way SQLite's do.
+
+use std::{collections::VecDeque, ffi::c_int};
+
+use crate::{
+    sqlite::{
+        begin_tnx_on_db, commit_tnx_on_db, execute_stmt, rollback_tnx_on_db, SQLite3,
+        SQLite3PreparedStmt, SqliteError, Value, SQLITE_DONE, SQLITE_ERROR, SQLITE_OK,
+    },
+    worker::Worker,
+};
+
+/// Rows copied per page.
+const ROWS_PER_PAGE: i64 = 100;
+
+enum Page {
+    // Replaces the destination's tables
+    Schema {
+        drops: Vec<String>,
+        creates: Vec<String>,
+    },
+    Rows {
+        table: String,
+        offset: i64,
+        ordered: bool,
+    },
+    // Indexes, views and triggers, once the rows are in
+    Finish {
+        creates: Vec<String>,
+    },
+}
+
+pub struct Backup {
+    source: *mut SQLite3,
+    dest: *mut SQLite3,
+    pages: Option<VecDeque<Page>>, // Planned by the first step
+    pagecount: c_int,
+    error: Option<SqliteError>,
+}
+
+impl Backup {
+    pub fn new(dest: *mut SQLite3, source: *mut SQLite3) -> Self {
+        Self {
+            source,
+            dest,
+            pages: None,
+            pagecount: 0,
+            error: None,
+        }
+    }
+
+    /// Steps run on the destination's connection thread.
+    pub fn worker(&self) -> &Worker {
+        unsafe { &(*self.dest).worker }
+    }
+
+    pub fn remaining(&self) -> c_int {
+        self.pages.as_ref().map_or(0, |pages| pages.len() as c_int)
+    }
+
+    pub fn pagecount(&self) -> c_int {
+        self.pagecount
+    }
+
+    /// Error a failed step left behind, reported again by `sqlite3_backup_finish`.
+    pub fn error(&self) -> Option<&SqliteError> {
+        self.error.as_ref()
+    }
+
+    /// Copies up to `pages` pages, all of them when negative.
+    pub async fn step(&mut self, pages: c_int) -> Result<c_int, SqliteError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+
+        let result = self.copy(pages).await;
+        if let Err(error) = &result {
+            self.error = Some(error.clone());
+        }
+        result
+    }
+
+    async fn copy(&mut self, pages: c_int) -> Result<c_int, SqliteError> {
+        if self.pages.is_none() {
+            let planned = plan(self.source, self.dest).await?;
+            self.pagecount = planned.len() as c_int;
+            self.pages = Some(planned);
+        }
+
+        if unsafe { &*self.dest }.has_began_transaction() {
+            return Err(SqliteError::new(
+                "destination database is in use",
+                Some(SQLITE_ERROR),
+            ));
+        }
+
+        let mut copied = 0;
+        while pages < 0 || copied < pages {
+            let Some(page) = self.pages.as_mut().and_then(|pages| pages.pop_front()) else {
+                break;
+            };
+
+            // Each page lands in the destination whole or not at all
+            begin_tnx_on_db(self.dest, "BEGIN").await?;
+            match copy_page(self.source, self.dest, &page).await {
+                Ok(()) => {
+                    commit_tnx_on_db(self.dest, "COMMIT").await?;
+                }
+                Err(error) => {
+                    let _ = rollback_tnx_on_db(self.dest, "ROLLBACK").await;
+                    if let Some(pages) = self.pages.as_mut() {
+                        pages.push_front(page);
+                    }
+                    return Err(error);
+                }
+            }
+            copied += 1;
+        }
+
+        Ok(if self.remaining() == 0 {
+            SQLITE_DONE
+        } else {
+            SQLITE_OK
+        })
+    }
+}
+
+/// Runs `sql` on `db` through the usual statement path and returns the finished statement.
+pub async fn query(
+    db: *mut SQLite3,
+    sql: &str,
+    params: Vec<Value>,
+) -> Result<SQLite3PreparedStmt, SqliteError> {
+    let mut stmt = SQLite3PreparedStmt::new(db, sql);
+    stmt.params = (1..).zip(params).collect();
+    execute_stmt(&mut stmt).await?;
+    Ok(stmt)
+}
+
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+pub fn text(value: &Value) -> String {
+    match value {
+        Value::Text(s) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Statements dropping every table, view and trigger of `db`, tables last.
+pub async fn drop_statements(db: *mut SQLite3) -> Result<Vec<String>, SqliteError> {
+    let existing = query(
+        db,
+        "SELECT type, name FROM sqlite_master \
+         WHERE type IN ('table', 'view', 'trigger') AND name NOT LIKE 'sqlite_%'",
+        vec![],
+    )
+    .await?;
+
+    // Triggers and views first, tables take their indexes with them
+    let mut drops: Vec<String> = existing
+        .result_rows
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|row| (text(&row[0]), text(&row[1])))
+        .map(|(kind, name)| format!("DROP {} IF EXISTS {}", kind.to_uppercase(), quote(&name)))
+        .collect();
+    drops.sort_by_key(|drop| drop.starts_with("DROP TABLE"));
+
+    Ok(drops)
+}
+
+async fn plan(source: *mut SQLite3, dest: *mut SQLite3) -> Result<VecDeque<Page>, SqliteError> {
+    let objects = query(
+        source,
+        "SELECT type, name, sql FROM sqlite_master \
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' \
+         ORDER BY CASE type WHEN 'table' THEN 0 ELSE 1 END, rowid",
+        vec![],
+    )
+    .await?;
+    let drops = drop_statements(dest).await?;
+
+    let mut tables = Vec::new();
+    let mut creates = Vec::new();
+    let mut finish = Vec::new();
+    for row in objects.result_rows.lock().unwrap().iter() {
+        let (kind, name, sql) = (text(&row[0]), text(&row[1]), text(&row[2]));
+        if kind == "table" {
+            // Virtual tables keep their rows wherever their module does
+            if !sql.to_uppercase().starts_with("CREATE VIRTUAL") {
+                let ordered = !sql.to_uppercase().contains("WITHOUT ROWID");
+                tables.push((name, ordered));
+            }
+            creates.push(sql);
+        } else {
+            finish.push(sql);
+        }
+    }
+
+    let mut pages = VecDeque::from([Page::Schema { drops, creates }]);
+    for (table, ordered) in tables {
+        let count = query(
+            source,
+            &format!("SELECT count(*) FROM {}", quote(&table)),
+            vec![],
+        )
+        .await?;
+        let rows = match count.result_rows.lock().unwrap().first().map(|row| &row[0]) {
+            Some(Value::Integer(rows)) => *rows,
+            _ => 0,
+        };
+
+        let mut offset = 0;
+        loop {
+            pages.push_back(Page::Rows {
+                table: table.clone(),
+                offset,
+                ordered,
+            });
+            offset += ROWS_PER_PAGE;
+            if offset >= rows {
+                break;
+            }
+        }
+    }
+    pages.push_back(Page::Finish { creates: finish });
+
+    Ok(pages)
+}
+
+async fn copy_page(
+    source: *mut SQLite3,
+    dest: *mut SQLite3,
+    page: &Page,
+) -> Result<(), SqliteError> {
+    match page {
+        Page::Schema { drops, creates } => {
+            for sql in drops.iter().chain(creates) {
+                query(dest, sql, vec![]).await?;
+            }
+        }
+        Page::Finish { creates } => {
+            for sql in creates {
+                query(dest, sql, vec![]).await?;
+            }
+        }
+        Page::Rows {
+            table,
+            offset,
+            ordered,
+        } => {
+            // WITHOUT ROWID tables are scanned in primary key order already
+            let order = if *ordered { " ORDER BY rowid" } else { "" };
+            let batch = query(
+                source,
+                &format!("SELECT * FROM {}{} LIMIT ? OFFSET ?", quote(table), order),
+                vec![Value::Integer(ROWS_PER_PAGE), Value::Integer(*offset)],
+            )
+            .await?;
+
+            let rows = std::mem::take(&mut *batch.result_rows.lock().unwrap());
+            if rows.is_empty() {
+                return Ok(());
+            }
+
+            let columns: Vec<String> = batch.column_names.iter().map(|c| quote(c)).collect();
+            let placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+            let sql = format!(
+                "INSERT INTO {} ({}) VALUES {}",
+                quote(table),
+                columns.join(", "),
+                vec![placeholders; rows.len()].join(", ")
+            );
+            query(dest, &sql, rows.into_iter().flatten().collect()).await?;
+        }
+    }
+
+    Ok(())
+}
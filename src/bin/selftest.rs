@@ -0,0 +1,45 @@
+//! Prints the `sqlite3_turso_selftest` report for the database named on the command line,
+//! e.g. `turso-selftest 'my-db.turso.io?transport=ws'`, and exits with 1 when a check failed.
+
+use std::{
+    ffi::{CStr, CString},
+    process::ExitCode,
+};
+
+use sqlite3::{sqlite3_errmsg, sqlite3_turso_free_string, sqlite3_turso_selftest};
+
+fn main() -> ExitCode {
+    let Some(filename) = std::env::args().nth(1) else {
+        eprintln!("usage: turso-selftest <database>");
+        return ExitCode::from(2);
+    };
+    let Ok(filename) = CString::new(filename) else {
+        eprintln!("turso-selftest: the database name contains a NUL byte");
+        return ExitCode::from(2);
+    };
+
+    let report = unsafe { sqlite3_turso_selftest(filename.as_ptr()) };
+    if report.is_null() {
+        let message = unsafe { sqlite3_errmsg(std::ptr::null_mut()) };
+        let message = match message.is_null() {
+            true => "invalid database name".into(),
+            false => unsafe { CStr::from_ptr(message) }.to_string_lossy(),
+        };
+        eprintln!("turso-selftest: {}", message);
+        return ExitCode::from(2);
+    }
+
+    let json = unsafe { CStr::from_ptr(report) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { sqlite3_turso_free_string(report) };
+
+    let ok =
+        serde_json::from_str::<serde_json::Value>(&json).is_ok_and(|report| report["ok"] == true);
+    println!("{}", json);
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,204 @@
+//! Incremental blob I/O over SQL. A handle remembers the row and column it was opened on;
+//! reads fetch `hex(substr(...))` windows of the value, cached per handle, and writes patch
+//! the value in place with an UPDATE, keeping its size as `sqlite3_blob_write` requires.
+
+use std::fmt::Write;
+
+use crate::{
+    backup::{query, quote, text},
+    sqlite::{SQLite3, SqliteError, Value, SQLITE_ABORT, SQLITE_ERROR, SQLITE_READONLY},
+    worker::Worker,
+};
+
+/// Bytes fetched per read round trip, so small sequential reads hit the cache.
+const READ_CHUNK: usize = 64 * 1024;
+
+pub struct BlobHandle {
+    db: *mut SQLite3,
+    table: String,  // Quoted, schema-qualified when not main
+    column: String, // Quoted
+    rowid: i64,
+    size: usize,
+    writable: bool,
+    cache: Option<(usize, Vec<u8>)>, // Offset and bytes of the last window read
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            let _ = write!(out, "{:02X}", b);
+            out
+        })
+}
+
+fn hex_decode(hex: &str) -> Result<Vec<u8>, SqliteError> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| SqliteError::new("invalid blob data from server", None))
+        })
+        .collect()
+}
+
+impl BlobHandle {
+    pub async fn open(
+        db: *mut SQLite3,
+        schema: &str,
+        table: &str,
+        column: &str,
+        rowid: i64,
+        writable: bool,
+    ) -> Result<Self, SqliteError> {
+        // Attached schemas are routed by their prefix like any other statement
+        let table = if schema.eq_ignore_ascii_case("main") {
+            quote(table)
+        } else {
+            format!("{}.{}", quote(schema), quote(table))
+        };
+
+        let mut handle = Self {
+            db,
+            table,
+            column: quote(column),
+            rowid,
+            size: 0,
+            writable,
+            cache: None,
+        };
+        handle.reopen(rowid).await?;
+        Ok(handle)
+    }
+
+    /// Points the handle at another row of the same table and column.
+    pub async fn reopen(&mut self, rowid: i64) -> Result<(), SqliteError> {
+        let sql = format!(
+            "SELECT typeof({0}), length(CAST({0} AS BLOB)) FROM {1} WHERE rowid = ?",
+            self.column, self.table
+        );
+        let result = query(self.db, &sql, vec![Value::Integer(rowid)]).await?;
+        let rows = result.result_rows.lock().unwrap();
+        let Some(row) = rows.first() else {
+            return Err(SqliteError::new(
+                format!("no such rowid: {}", rowid),
+                Some(SQLITE_ERROR),
+            ));
+        };
+
+        let kind = text(&row[0]);
+        if kind != "blob" && kind != "text" {
+            return Err(SqliteError::new(
+                format!("cannot open value of type {}", kind),
+                Some(SQLITE_ERROR),
+            ));
+        }
+
+        self.rowid = rowid;
+        self.size = match row[1] {
+            Value::Integer(size) => size as usize,
+            _ => 0,
+        };
+        self.cache = None;
+        Ok(())
+    }
+
+    pub fn worker(&self) -> &Worker {
+        unsafe { &(*self.db).worker }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn check_range(&self, len: usize, offset: usize) -> Result<(), SqliteError> {
+        if offset.checked_add(len).is_none_or(|end| end > self.size) {
+            return Err(SqliteError::new(
+                "blob offset and length out of range",
+                Some(SQLITE_ERROR),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn read(&mut self, out: &mut [u8], offset: usize) -> Result<(), SqliteError> {
+        self.check_range(out.len(), offset)?;
+
+        let cached = self.cache.as_ref().is_some_and(|(start, bytes)| {
+            offset >= *start && offset + out.len() <= start + bytes.len()
+        });
+        if !cached {
+            let len = out.len().max(READ_CHUNK).min(self.size - offset);
+            let sql = format!(
+                "SELECT hex(substr(CAST({} AS BLOB), ?, ?)) FROM {} WHERE rowid = ?",
+                self.column, self.table
+            );
+            let params = vec![
+                Value::Integer(offset as i64 + 1),
+                Value::Integer(len as i64),
+                Value::Integer(self.rowid),
+            ];
+            let result = query(self.db, &sql, params).await?;
+            let hex = match result.result_rows.lock().unwrap().first() {
+                Some(row) => text(&row[0]),
+                None => return Err(expired()),
+            };
+
+            let bytes = hex_decode(&hex)?;
+            if bytes.len() < out.len() {
+                return Err(expired());
+            }
+            self.cache = Some((offset, bytes));
+        }
+
+        let (start, bytes) = self.cache.as_ref().unwrap();
+        out.copy_from_slice(&bytes[offset - start..offset - start + out.len()]);
+        Ok(())
+    }
+
+    pub async fn write(&mut self, data: &[u8], offset: usize) -> Result<(), SqliteError> {
+        if !self.writable {
+            return Err(SqliteError::new(
+                "attempt to write a readonly database",
+                Some(SQLITE_READONLY),
+            ));
+        }
+        self.check_range(data.len(), offset)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // Bytes around the patch are kept as they are, and the value stays a BLOB
+        let sql = format!(
+            "UPDATE {1} SET {0} = CAST(substr(CAST({0} AS BLOB), 1, ?) || X'{2}' || \
+             substr(CAST({0} AS BLOB), ?) AS BLOB) WHERE rowid = ?",
+            self.column,
+            self.table,
+            hex_encode(data)
+        );
+        let params = vec![
+            Value::Integer(offset as i64),
+            Value::Integer((offset + data.len()) as i64 + 1),
+            Value::Integer(self.rowid),
+        ];
+        query(self.db, &sql, params).await?;
+
+        if let Some((start, bytes)) = self.cache.as_mut() {
+            let from = offset.max(*start);
+            let to = (offset + data.len()).min(*start + bytes.len());
+            if from < to {
+                bytes[from - *start..to - *start]
+                    .copy_from_slice(&data[from - offset..to - offset]);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn expired() -> SqliteError {
+    SqliteError::new(
+        "the row behind the blob handle has changed",
+        Some(SQLITE_ABORT),
+    )
+}
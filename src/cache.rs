@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, CString},
+    mem::size_of,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    protocol::RemoteCol,
+    session::{SessionChange, SESSION_PRAGMAS},
+    sql::tokenizer::{
+        classify, is_dml, is_insert, normalize, parameter_names, returns_rows, StatementClass,
+    },
+    status_table::{self, StatusQuery},
+    utils::parse_turso_pragma,
+};
+
+pub const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// How `sqlite3_step` dispatches a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    TursoPragma(String, Option<String>), // Handled locally, never sent to the server
+    Begin,
+    Commit,
+    Rollback,
+    Savepoint(String),
+    Release(String),
+    RollbackTo(String),
+    Attach { database: String, schema: String },
+    Detach(String),
+    Session(SessionChange), // Run remotely, then replayed on every new server stream
+    Status(StatusQuery),    // A query of turso_status, answered locally
+    Remote,
+}
+
+/// Everything derived from the SQL text alone, shared by every prepare of the same text.
+#[derive(Debug)]
+pub struct CachedStatement {
+    pub param_count: c_int,
+    pub param_names: Vec<Option<CString>>, // By index from 1, `None` for `?`
+    pub kind: StatementKind,
+    pub returns_rows: bool, // Produces a result set, RETURNING and CTEs included
+    pub inserts: bool,      // INSERT or REPLACE, the statements that move last_insert_rowid
+    pub wants_rows: bool,   // false for DML without RETURNING, sent with want_rows off
+    pub normalized: CString, // The text sqlite3_normalized_sql reports
+    column_names: Mutex<Option<(Vec<String>, u64)>>, // With the schema generation they are from
+    decltypes: Mutex<Vec<Option<CString>>>, // Declared types of those columns, where known
+}
+
+impl CachedStatement {
+    pub fn parse(sql: &str) -> Self {
+        let kind = match (parse_turso_pragma(sql), status_table::parse(sql)) {
+            (Some((name, value)), _) => StatementKind::TursoPragma(name, value),
+            (None, Some(query)) => StatementKind::Status(query),
+            (None, None) => match classify(sql) {
+                StatementClass::Begin => StatementKind::Begin,
+                StatementClass::Commit => StatementKind::Commit,
+                StatementClass::Rollback => StatementKind::Rollback,
+                StatementClass::Savepoint(name) => StatementKind::Savepoint(name),
+                StatementClass::Release(name) => StatementKind::Release(name),
+                StatementClass::RollbackTo(name) => StatementKind::RollbackTo(name),
+                StatementClass::Attach { database, schema } => {
+                    StatementKind::Attach { database, schema }
+                }
+                StatementClass::Detach(schema) => StatementKind::Detach(schema),
+                StatementClass::Pragma {
+                    name,
+                    assigns: true,
+                } if SESSION_PRAGMAS.contains(&name.as_str()) => {
+                    StatementKind::Session(SessionChange::Pragma(name))
+                }
+                StatementClass::CreateTemp { name, table } => {
+                    StatementKind::Session(SessionChange::CreateTemp { name, table })
+                }
+                StatementClass::Drop(name) => StatementKind::Session(SessionChange::Drop(name)),
+                StatementClass::Pragma { .. } | StatementClass::Other => StatementKind::Remote,
+            },
+        };
+
+        let returns_rows = returns_rows(sql);
+        let param_names: Vec<_> = parameter_names(sql)
+            .into_iter()
+            .map(|name| name.and_then(|name| CString::new(name).ok()))
+            .collect();
+        Self {
+            param_count: param_names.len() as c_int,
+            param_names,
+            kind,
+            returns_rows,
+            inserts: is_insert(sql),
+            wants_rows: returns_rows || !is_dml(sql),
+            normalized: CString::new(normalize(sql)).unwrap_or_default(),
+            column_names: Mutex::new(None),
+            decltypes: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn column_names(&self) -> Option<Vec<String>> {
+        let column_names = self.column_names.lock().unwrap();
+        column_names.as_ref().map(|(names, _)| names.clone())
+    }
+
+    /// The column names, unless the schema has changed since they were learned.
+    pub fn current_column_names(&self, generation: u64) -> Option<Vec<String>> {
+        let column_names = self.column_names.lock().unwrap();
+        column_names
+            .as_ref()
+            .filter(|(_, learned_at)| *learned_at == generation)
+            .map(|(names, _)| names.clone())
+    }
+
+    pub fn set_column_names(&self, names: &[String], generation: u64) {
+        let mut column_names = self.column_names.lock().unwrap();
+        if column_names.as_ref() != Some(&(names.to_vec(), generation)) {
+            *column_names = Some((names.to_vec(), generation));
+        }
+    }
+
+    /// The declared type of column `index`, valid until the columns are described again.
+    pub fn decltype(&self, index: usize) -> *const c_char {
+        let decltypes = self.decltypes.lock().unwrap();
+        match decltypes.get(index) {
+            Some(Some(decltype)) => decltype.as_ptr(),
+            _ => std::ptr::null(),
+        }
+    }
+
+    pub fn set_decltypes(&self, cols: &[RemoteCol]) {
+        let described: Vec<Option<CString>> = cols
+            .iter()
+            .map(|col| col.decltype.as_deref().and_then(|t| CString::new(t).ok()))
+            .collect();
+        // Pointers handed out for the same types stay valid
+        let mut decltypes = self.decltypes.lock().unwrap();
+        if *decltypes != described {
+            *decltypes = described;
+        }
+    }
+}
+
+/// Least recently used cache of parsed statements keyed by SQL text.
+pub struct StatementCache {
+    capacity: usize, // Zero disables caching
+    tick: u64,
+    entries: HashMap<String, (u64, Arc<CachedStatement>)>,
+}
+
+impl StatementCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get_or_parse(&mut self, sql: &str) -> Arc<CachedStatement> {
+        if self.capacity == 0 {
+            return Arc::new(CachedStatement::parse(sql));
+        }
+
+        self.tick += 1;
+        if let Some((last_used, statement)) = self.entries.get_mut(sql) {
+            *last_used = self.tick;
+            return statement.clone();
+        }
+
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(sql, _)| sql.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let statement = Arc::new(CachedStatement::parse(sql));
+        self.entries
+            .insert(sql.to_string(), (self.tick, statement.clone()));
+        statement
+    }
+
+    /// Estimated bytes held by the cached statements and their SQL text.
+    pub fn memory_used(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(sql, (_, statement))| {
+                let columns = statement.column_names().unwrap_or_default();
+                sql.capacity()
+                    + size_of::<CachedStatement>()
+                    + columns.iter().map(|c| c.capacity()).sum::<usize>()
+            })
+            .sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
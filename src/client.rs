@@ -0,0 +1,571 @@
+//! Async Rust API over the same transports as the C ABI, for Rust programs that want Turso
+//! with SQLite's semantics without going through `unsafe` FFI. Enabled by the `rust-api`
+//! feature.
+//!
+//! Databases are opened with the names and query parameters `sqlite3_open_v2` takes, and
+//! requests run on the caller's async runtime:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), sqlite3::client::Error> {
+//! use sqlite3::client::{Connection, Value};
+//!
+//! let db = Connection::open("my-db?transport=websocket").await?;
+//! db.execute("INSERT INTO users (name) VALUES (?)", &["alice".into()]).await?;
+//!
+//! let mut tx = db.transaction().await?;
+//! for row in tx.query("SELECT id, name FROM users", &[]).await? {
+//!     let (id, name): (i64, String) = (row.get(0)?, row.get(1)?);
+//!     let params = [name.to_uppercase().into(), Value::Integer(id)];
+//!     tx.execute("UPDATE users SET name = ? WHERE id = ?", &params).await?;
+//! }
+//! tx.commit().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::{
+    auth, coercion,
+    config::ConnectionOptions,
+    protocol::{param_to_json, QueryResult, RemoteRow},
+    sql::tokenizer,
+    sqlite::{SQLITE_MISMATCH, SQLITE_RANGE},
+    transport::{ActiveStrategy, DatabaseConnection},
+    utils::get_tokio,
+};
+
+pub use crate::sqlite::{SqliteError as Error, Value};
+// For transports of the application's own, picked with `transport=custom:<name>`
+pub use crate::{
+    protocol::{RemoteCol, RemoteSqliteResponse, StatementArgs},
+    transport::{
+        register_transport, LibsqlInterface, Pipeline, TransportFactory, TransportFuture,
+        TursoConfig,
+    },
+};
+
+/// A connection to a Turso database. Statements are sent one at a time; a `Transaction`
+/// holds the connection until it is committed or rolled back.
+pub struct Connection {
+    connection: Arc<AsyncMutex<DatabaseConnection>>,
+    last_insert_rowid: Arc<Mutex<Option<i64>>>,
+}
+
+impl Connection {
+    /// Opens a database by name or URL, with the query parameters `sqlite3_open_v2` accepts.
+    pub async fn open(filename: &str) -> Result<Self, Error> {
+        let (db_name, options) = ConnectionOptions::parse(filename)?;
+        let connection =
+            DatabaseConnection::open(&db_name, auth::strategy_for(options.auth), options).await?;
+
+        Ok(Self {
+            connection: Arc::new(AsyncMutex::new(connection)),
+            last_insert_rowid: Default::default(),
+        })
+    }
+
+    /// Runs a statement and returns the number of rows it changed.
+    pub async fn execute(&self, sql: &str, params: &[Value]) -> Result<u64, Error> {
+        Ok(self.run(sql, params).await?.rows_written.unwrap_or(0))
+    }
+
+    /// Runs a statement and returns its rows.
+    pub async fn query(&self, sql: &str, params: &[Value]) -> Result<Rows, Error> {
+        Ok(Rows::new(&self.run(sql, params).await?))
+    }
+
+    /// A statement to run any number of times. Nothing is sent until it runs.
+    pub fn prepare(&self, sql: &str) -> Statement<'_> {
+        Statement {
+            connection: self,
+            sql: sql.to_string(),
+        }
+    }
+
+    /// Begins a transaction with `BEGIN`.
+    pub async fn transaction(&self) -> Result<Transaction, Error> {
+        self.transaction_with("BEGIN").await
+    }
+
+    /// Begins a transaction with the given statement, e.g. `BEGIN IMMEDIATE`.
+    pub async fn transaction_with(&self, begin: &str) -> Result<Transaction, Error> {
+        let mut connection = self.connection.clone().lock_owned().await;
+        let baton = connection.get_transaction_baton(begin).await?;
+
+        Ok(Transaction {
+            connection: Some(connection),
+            baton: Some(baton),
+            last_insert_rowid: self.last_insert_rowid.clone(),
+        })
+    }
+
+    /// Row ID of the last row inserted through this connection, as `sqlite3_last_insert_rowid`.
+    pub fn last_insert_rowid(&self) -> Option<i64> {
+        *self.last_insert_rowid.lock().unwrap()
+    }
+
+    /// Cheapest authenticated round trip, to check the database is reachable.
+    pub async fn ping(&self, timeout: Duration) -> Result<(), Error> {
+        self.connection.lock().await.ping(timeout).await
+    }
+
+    /// Closes the WebSocket, if one is open. Dropping the connection leaves it to the server.
+    pub async fn close(self) {
+        self.connection.lock().await.close().await;
+    }
+
+    async fn run(&self, sql: &str, params: &[Value]) -> Result<QueryResult, Error> {
+        let mut connection = self.connection.lock().await;
+        connection.maybe_restore_websocket().await;
+        let (result, _) = execute(&mut connection, None, sql, params).await?;
+        observe_insert(&self.last_insert_rowid, sql, &result);
+        Ok(result)
+    }
+}
+
+/// A statement bound to its connection. It is sent as text every time it runs.
+pub struct Statement<'a> {
+    connection: &'a Connection,
+    sql: String,
+}
+
+impl Statement<'_> {
+    pub async fn execute(&self, params: &[Value]) -> Result<u64, Error> {
+        self.connection.execute(&self.sql, params).await
+    }
+
+    pub async fn query(&self, params: &[Value]) -> Result<Rows, Error> {
+        self.connection.query(&self.sql, params).await
+    }
+
+    /// Names of the result columns, asked of the server without running the statement.
+    pub async fn columns(&self) -> Result<Vec<String>, Error> {
+        let cols = self
+            .connection
+            .connection
+            .lock()
+            .await
+            .describe(&self.sql)
+            .await?;
+        Ok(cols.into_iter().map(|col| col.name).collect())
+    }
+}
+
+/// An open transaction, holding the connection until it ends. Dropped without `commit` or
+/// `rollback`, its stream is closed in the background, which rolls it back.
+pub struct Transaction {
+    connection: Option<OwnedMutexGuard<DatabaseConnection>>, // Taken once the transaction ends
+    baton: Option<String>, // Identifies the transaction's stream; refreshed by every response
+    last_insert_rowid: Arc<Mutex<Option<i64>>>,
+}
+
+impl Transaction {
+    pub async fn execute(&mut self, sql: &str, params: &[Value]) -> Result<u64, Error> {
+        Ok(self.run(sql, params).await?.rows_written.unwrap_or(0))
+    }
+
+    pub async fn query(&mut self, sql: &str, params: &[Value]) -> Result<Rows, Error> {
+        Ok(Rows::new(&self.run(sql, params).await?))
+    }
+
+    pub async fn commit(self) -> Result<(), Error> {
+        self.end("COMMIT").await
+    }
+
+    pub async fn rollback(self) -> Result<(), Error> {
+        self.end("ROLLBACK").await
+    }
+
+    async fn run(&mut self, sql: &str, params: &[Value]) -> Result<QueryResult, Error> {
+        let connection = self.connection.as_mut().expect("transaction already ended");
+        let (result, baton) = execute(connection, self.baton.as_ref(), sql, params).await?;
+        if baton.is_some() {
+            self.baton = baton;
+        }
+        observe_insert(&self.last_insert_rowid, sql, &result);
+        Ok(result)
+    }
+
+    async fn end(mut self, sql: &str) -> Result<(), Error> {
+        let result = self.run(sql, &[]).await;
+
+        // Like end_tnx_on_db, the stream is closed even when the statement failed
+        let (Some(mut connection), Some(baton)) = (self.connection.take(), self.baton.take())
+        else {
+            return result.map(|_| ());
+        };
+        if let Err(err) = connection.close_stream(&baton).await {
+            tracing::debug!(%baton, error = %err, "Failed to close stream");
+        }
+
+        result.map(|_| ())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let (Some(mut connection), Some(baton)) = (self.connection.take(), self.baton.take())
+        else {
+            return;
+        };
+
+        // The connection stays locked until the server has let go of the stream
+        get_tokio().spawn(async move {
+            if let Err(err) = connection.close_stream(&baton).await {
+                tracing::debug!(%baton, error = %err, "Failed to close stream");
+            }
+        });
+    }
+}
+
+/// Rows returned by a query, in order.
+pub struct Rows {
+    columns: Arc<[String]>,
+    rows: std::vec::IntoIter<Vec<Value>>,
+}
+
+impl Rows {
+    fn new(result: &QueryResult) -> Self {
+        Self {
+            columns: result.cols.iter().map(|col| col.name.clone()).collect(),
+            rows: result
+                .rows
+                .iter()
+                .map(|row| row.iter().map(RemoteRow::decode).collect())
+                .collect::<Vec<_>>()
+                .into_iter(),
+        }
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+impl Iterator for Rows {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        self.rows.next().map(|values| Row {
+            columns: self.columns.clone(),
+            values,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+pub struct Row {
+    columns: Arc<[String]>,
+    values: Vec<Value>,
+}
+
+impl Row {
+    /// The value of column `index`, read as `T` the way the `sqlite3_column_*` accessors
+    /// read it: text holding a number reads as that number, NULL as 0.
+    pub fn get<T: FromValue>(&self, index: usize) -> Result<T, Error> {
+        let value = self.values.get(index).ok_or_else(|| {
+            Error::new(
+                format!("column index {} out of range", index),
+                Some(SQLITE_RANGE),
+            )
+        })?;
+        T::from_value(value)
+    }
+
+    /// The value of the column named `name`, compared case-insensitively as SQLite does.
+    pub fn get_by_name<T: FromValue>(&self, name: &str) -> Result<T, Error> {
+        let index = self
+            .columns
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::new(format!("no such column: {}", name), Some(SQLITE_RANGE)))?;
+        self.get(index)
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+/// Types a column value can be read as.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, Error>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(coercion::to_int64(value))
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(coercion::to_double(value))
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(coercion::to_int64(value) != 0)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        coercion::to_text(value).ok_or_else(null_value)
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::Blob(bytes) => Ok(bytes.clone()),
+            Value::Null => Err(null_value()),
+            other => Ok(coercion::to_text(other).unwrap_or_default().into_bytes()),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+fn null_value() -> Error {
+    Error::new("NULL read as a non-optional value", Some(SQLITE_MISMATCH))
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Integer(value.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Integer(value.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Real(value)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Blob(value)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Value::Blob(value.to_vec())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+// One statement on the connection, inside the transaction identified by `baton` if any.
+// Returns the result with the baton for the transaction's next statement.
+async fn execute(
+    connection: &mut DatabaseConnection,
+    baton: Option<&String>,
+    sql: &str,
+    params: &[Value],
+) -> Result<(QueryResult, Option<String>), Error> {
+    let params = params
+        .iter()
+        .zip(1..)
+        .map(|(value, index)| param_to_json(index, value))
+        .collect::<Result<Vec<_>, _>>()?
+        .into();
+
+    let mut request = connection.build_request(sql, &params, baton, baton.is_some());
+    let response = match connection.send(&mut request).await {
+        Ok(response) => response,
+        Err(err) => {
+            if connection.strategy == ActiveStrategy::Websocket {
+                connection.on_websocket_error().await;
+            }
+            return Err(err);
+        }
+    };
+
+    let result = response.first_result()?.clone();
+    connection.metrics.record_rows(
+        result.rows_read.unwrap_or(0),
+        result.rows_written.unwrap_or(0),
+    );
+    if let Some(ms) = result.query_duration_ms {
+        connection.metrics.record_server_time(ms);
+    }
+
+    Ok((result, response.baton))
+}
+
+// Same rule as the C API: only an INSERT that wrote a row moves last_insert_rowid
+fn observe_insert(last_insert_rowid: &Mutex<Option<i64>>, sql: &str, result: &QueryResult) {
+    if !tokenizer::is_insert(sql) || result.rows_written == Some(0) {
+        return;
+    }
+    let rowid = result
+        .last_insert_rowid
+        .as_deref()
+        .and_then(|r| r.parse().ok());
+    if let Some(rowid) = rowid {
+        *last_insert_rowid.lock().unwrap() = Some(rowid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::use_echo_server;
+
+    // On a runtime of the caller's own, as a Rust program would use the API
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn queries_and_reads_rows() {
+        use_echo_server();
+        block_on(async {
+            let db = Connection::open("echo.db?auth=none&transport=http")
+                .await
+                .unwrap();
+
+            let rows: Vec<Row> = db
+                .query("SELECT ?", &["42".into()])
+                .await
+                .unwrap()
+                .collect();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].columns(), ["n"]);
+            assert_eq!(rows[0].get::<String>(0).unwrap(), "42");
+            assert_eq!(rows[0].get::<i64>(0).unwrap(), 42);
+            assert_eq!(rows[0].get_by_name::<f64>("N").unwrap(), 42.0);
+            assert_eq!(rows[0].get::<i64>(1).unwrap_err().code, SQLITE_RANGE);
+
+            let row = db
+                .prepare("SELECT ?")
+                .query(&[Value::Null])
+                .await
+                .unwrap()
+                .next();
+            let row = row.unwrap();
+            assert_eq!(row.get::<Option<i64>>(0).unwrap(), None);
+            assert_eq!(row.get::<String>(0).unwrap_err().code, SQLITE_MISMATCH);
+
+            assert_eq!(
+                db.execute("INSERT INTO t VALUES (?)", &[7.into()])
+                    .await
+                    .unwrap(),
+                1
+            );
+            assert_eq!(db.last_insert_rowid(), Some(7));
+
+            db.close().await;
+        });
+    }
+
+    // Answers every statement with the database URL it was created for
+    struct Canned(String);
+
+    impl Pipeline for Canned {
+        fn pipeline<'a>(
+            &'a self,
+            _request: &'a serde_json::Value,
+        ) -> TransportFuture<'a, serde_json::Value> {
+            let result = serde_json::json!({
+                "cols": [{"name": "url"}],
+                "rows": [[{"type": "text", "value": self.0}]],
+                "affected_row_count": 0,
+            });
+            Box::pin(async move {
+                Ok(serde_json::json!({
+                    "baton": null,
+                    "results": [{"type": "ok", "response": {"type": "execute", "result": result}}],
+                }))
+            })
+        }
+
+        fn session(&self) -> &[String] {
+            &[]
+        }
+    }
+
+    #[test]
+    fn registered_transports_carry_requests() {
+        use_echo_server();
+        register_transport("canned", |config| {
+            Ok(Box::new(Canned(config.db_url.clone())))
+        });
+        block_on(async {
+            let err = Connection::open("echo.db?auth=none&transport=custom:missing")
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.code, crate::sqlite::SQLITE_CANTOPEN);
+
+            let db = Connection::open("echo.db?auth=none&transport=custom:canned")
+                .await
+                .unwrap();
+            let rows: Vec<Row> = db.query("SELECT 1", &[]).await.unwrap().collect();
+            let url = std::env::var("TURSO_DB_URL").unwrap();
+            assert_eq!(rows[0].get::<String>(0).unwrap(), url);
+            db.close().await;
+        });
+    }
+}
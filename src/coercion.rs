@@ -0,0 +1,258 @@
+//! SQLite's conversions for reading a value as another type, shared by the
+//! `sqlite3_column_*` and `sqlite3_value_*` accessors. They follow the table at
+//! <https://www.sqlite.org/c3ref/column_blob.html>: text is read up to its numeric prefix,
+//! reals are truncated and saturated, and NULL reads as 0 or as a NULL pointer.
+
+use std::ffi::c_char;
+
+use crate::sqlite::Value;
+
+pub fn to_int64(value: &Value) -> i64 {
+    match value {
+        Value::Integer(i) => *i,
+        // Rust's cast saturates and maps NaN to 0, as SQLite does
+        Value::Real(f) => *f as i64,
+        Value::Text(s) => text_to_int64(s),
+        Value::Blob(b) => text_to_int64(&blob_text(b)),
+        Value::Null => 0,
+    }
+}
+
+pub fn to_double(value: &Value) -> f64 {
+    match value {
+        Value::Integer(i) => *i as f64,
+        Value::Real(f) => *f,
+        Value::Text(s) => text_to_double(s),
+        Value::Blob(b) => text_to_double(&blob_text(b)),
+        Value::Null => 0.0,
+    }
+}
+
+/// Text form of a value, `None` for NULL.
+pub fn to_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Real(f) => Some(real_to_text(*f)),
+        Value::Text(s) => Some(s.clone()),
+        Value::Blob(b) => Some(blob_text(b)),
+        Value::Null => None,
+    }
+}
+
+/// Text form for C callers: every byte of the text, NULs inside it included, followed by a
+/// terminating NUL. Its length, not where the first NUL falls, is what `sqlite3_column_bytes`
+/// and `sqlite3_value_bytes` report.
+#[derive(Debug)]
+pub struct CText(Vec<u8>);
+
+impl CText {
+    /// `None` for NULL.
+    pub fn new(value: &Value) -> Option<Self> {
+        let mut bytes = to_text(value)?.into_bytes();
+        bytes.push(0);
+        Some(Self(bytes))
+    }
+
+    pub fn as_ptr(&self) -> *const c_char {
+        self.0.as_ptr().cast()
+    }
+
+    /// Bytes of the text, the terminator left out.
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+}
+
+/// Size in bytes of the value as `sqlite3_column_bytes` reports it: numbers count the
+/// length of their text form.
+pub fn byte_len(value: &Value) -> usize {
+    match value {
+        Value::Text(s) => s.len(),
+        Value::Blob(b) => b.len(),
+        Value::Null => 0,
+        number => to_text(number).map_or(0, |text| text.len()),
+    }
+}
+
+/// Text form of a BLOB as SQLite reads it, its bytes taken as UTF-8 up to the first NUL.
+pub fn blob_text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// Leading integer of the text, ignoring what follows it: '42abc' and '3.9' read as 42 and 3.
+// Out of range values saturate.
+fn text_to_int64(text: &str) -> i64 {
+    let text = text.trim_start();
+    let (negative, digits) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+
+    let mut value: i128 = 0;
+    for digit in digits.bytes().take_while(u8::is_ascii_digit) {
+        value = (value * 10 + (digit - b'0') as i128).min(i64::MAX as i128 + 1);
+    }
+    if negative {
+        value = -value;
+    }
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+// Longest prefix of the text that reads as a real, 0.0 when there is none
+fn text_to_double(text: &str) -> f64 {
+    let text = text.trim_start();
+    let bytes = text.as_bytes();
+    let digits_from = |start: usize| {
+        start
+            + bytes[start.min(bytes.len())..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count()
+    };
+
+    let mut end = match bytes.first() {
+        Some(b'-' | b'+') => 1,
+        _ => 0,
+    };
+    let integer_end = digits_from(end);
+    let mut mantissa_digits = integer_end - end;
+    end = integer_end;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_end = digits_from(end + 1);
+        mantissa_digits += fraction_end - end - 1;
+        end = fraction_end;
+    }
+    if mantissa_digits == 0 {
+        return 0.0;
+    }
+
+    // An exponent only counts once it has digits: '1e' reads as 1
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(end + 1), Some(b'-' | b'+')));
+        let exponent_end = digits_from(end + 1 + sign);
+        if exponent_end > end + 1 + sign {
+            end = exponent_end;
+        }
+    }
+
+    text[..end].parse().unwrap_or(0.0)
+}
+
+// printf("%!.15g"): 15 significant digits, scientific notation outside 1e-4..1e15, and a
+// ".0" on anything that would otherwise read as an integer
+fn real_to_text(f: f64) -> String {
+    if f.is_infinite() {
+        return if f < 0.0 { "-Inf" } else { "Inf" }.to_string();
+    }
+    if f.is_nan() {
+        return "NaN".to_string();
+    }
+
+    let scientific = format!("{:.14e}", f);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+
+    let with_point = |digits: &str| {
+        let digits = if digits.contains('.') {
+            digits.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            digits
+        };
+        if digits.contains('.') {
+            digits.to_string()
+        } else {
+            format!("{}.0", digits)
+        }
+    };
+
+    if !(-4..15).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", with_point(mantissa), sign, exponent.abs());
+    }
+
+    let decimals = (14 - exponent) as usize;
+    with_point(&format!("{:.*}", decimals, f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_reads_as_its_numeric_prefix() {
+        let int = |s: &str| to_int64(&Value::Text(s.to_string()));
+        let real = |s: &str| to_double(&Value::Text(s.to_string()));
+
+        assert_eq!(int("42"), 42);
+        assert_eq!(int(" 42 "), 42);
+        assert_eq!(int("42abc"), 42);
+        assert_eq!(int("3.9"), 3);
+        assert_eq!(int("-3.9"), -3);
+        assert_eq!(int("1e3"), 1);
+        assert_eq!(int("+7"), 7);
+        assert_eq!(int("0x10"), 0);
+        assert_eq!(int("abc"), 0);
+        assert_eq!(int("99999999999999999999"), i64::MAX);
+        assert_eq!(int("-99999999999999999999"), i64::MIN);
+        assert_eq!(int("-9223372036854775808"), i64::MIN);
+        assert_eq!(to_int64(&Value::Blob(b"42".to_vec())), 42);
+
+        assert_eq!(real("1e3"), 1000.0);
+        assert_eq!(real(" -2.5e1xyz"), -25.0);
+        assert_eq!(real(".5"), 0.5);
+        assert_eq!(real("5."), 5.0);
+        assert_eq!(real("1e"), 1.0);
+        assert_eq!(real("abc"), 0.0);
+    }
+
+    #[test]
+    fn text_keeps_every_byte_for_c_callers() {
+        let adversarial = [
+            "",
+            "\0",
+            "\0\0\0",
+            "a\0b",
+            "\0leading",
+            "trailing\0",
+            "é\0ü\0\u{10FFFF}",
+            "\u{FFFD}\0\u{1}\u{7F}",
+        ];
+        for text in adversarial {
+            let c_text = CText::new(&Value::Text(text.to_string())).unwrap();
+            assert_eq!(c_text.len(), text.len());
+            let bytes: &[u8] =
+                unsafe { std::slice::from_raw_parts(c_text.as_ptr().cast(), c_text.len() + 1) };
+            assert_eq!(&bytes[..text.len()], text.as_bytes());
+            assert_eq!(bytes[text.len()], 0);
+        }
+
+        assert!(CText::new(&Value::Null).is_none());
+        assert_eq!(CText::new(&Value::Integer(-12)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn reals_read_as_sqlite_formats_them() {
+        let text = |f: f64| to_text(&Value::Real(f)).unwrap();
+
+        assert_eq!(text(0.1), "0.1");
+        assert_eq!(text(1.0), "1.0");
+        assert_eq!(text(100.0), "100.0");
+        assert_eq!(text(0.1 + 0.2), "0.3");
+        assert_eq!(text(1.0 / 3.0), "0.333333333333333");
+        assert_eq!(text(1e14), "100000000000000.0");
+        assert_eq!(text(1e15), "1.0e+15");
+        assert_eq!(text(1234567890123456.0), "1.23456789012346e+15");
+        assert_eq!(text(0.0001), "0.0001");
+        assert_eq!(text(0.00001), "1.0e-05");
+        assert_eq!(text(1.5e-7), "1.5e-07");
+        assert_eq!(text(5e-324), "4.94065645841247e-324");
+        assert_eq!(text(f64::MAX), "1.79769313486232e+308");
+        assert_eq!(text(0.0), "0.0");
+
+        assert_eq!(to_int64(&Value::Real(1e300)), i64::MAX);
+        assert_eq!(to_text(&Value::Null), None);
+        assert_eq!(byte_len(&Value::Integer(-12)), 3);
+    }
+}
@@ -0,0 +1,464 @@
+//! Collations registered with `sqlite3_create_collation`. The server cannot call them, so a
+//! SELECT ordering by one is sent without its ORDER BY and LIMIT, and the fetched rows are
+//! sorted and cut here. A registered collation used anywhere else is refused.
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    ffi::{c_int, c_void},
+    sync::Arc,
+};
+
+use crate::{
+    functions::DestroyCallback,
+    sql::{
+        select::{
+            clause_end, is_keyword, projection, spanned_tokens, split_commas, Projection, Spanned,
+        },
+        tokenizer::{unquote, Token},
+    },
+    sqlite::{SQLite3, SQLite3PreparedStmt, SqliteError, Value, SQLITE_ERROR},
+};
+
+pub type CompareCallback = unsafe extern "C" fn(
+    arg: *mut c_void,
+    len_a: c_int,
+    a: *const c_void,
+    len_b: c_int,
+    b: *const c_void,
+) -> c_int;
+
+pub struct Collation {
+    arg: *mut c_void,
+    compare: CompareCallback,
+    destroy: Option<DestroyCallback>,
+}
+
+impl Collation {
+    pub fn new(
+        arg: *mut c_void,
+        compare: CompareCallback,
+        destroy: Option<DestroyCallback>,
+    ) -> Self {
+        Self {
+            arg,
+            compare,
+            destroy,
+        }
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let result = unsafe {
+            (self.compare)(
+                self.arg,
+                a.len() as c_int,
+                a.as_ptr() as *const c_void,
+                b.len() as c_int,
+                b.as_ptr() as *const c_void,
+            )
+        };
+        result.cmp(&0)
+    }
+}
+
+// As in SQLite, the collation runs on whichever thread steps the statement
+unsafe impl Send for Collation {}
+unsafe impl Sync for Collation {}
+
+impl Drop for Collation {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.arg) };
+        }
+    }
+}
+
+/// Registered collations keyed by lowercased name.
+pub type CollationRegistry = HashMap<String, Arc<Collation>>;
+
+enum Comparator {
+    Binary,
+    NoCase,
+    RTrim,
+    Registered(Arc<Collation>),
+}
+
+impl Comparator {
+    fn compare_text(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Comparator::Binary => a.cmp(b),
+            Comparator::NoCase => a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()),
+            Comparator::RTrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
+            Comparator::Registered(collation) => collation.compare(a, b),
+        }
+    }
+
+    // SQLite's ordering: NULL, then numbers, then text, then BLOBs by their bytes, with the
+    // collation applied to text only
+    fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        let rank = |value: &Value| match value {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+            Value::Blob(_) => 3,
+        };
+
+        match (a, b) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Integer(a), Value::Real(b)) => (*a as f64).total_cmp(b),
+            (Value::Real(a), Value::Integer(b)) => a.total_cmp(&(*b as f64)),
+            (Value::Real(a), Value::Real(b)) => a.total_cmp(b),
+            (Value::Text(a), Value::Text(b)) => self.compare_text(a, b),
+            (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+            _ => rank(a).cmp(&rank(b)),
+        }
+    }
+}
+
+enum Source {
+    Column(usize), // A result column of the statement
+    Hidden(usize), // Fetched only to sort by, dropped afterwards
+}
+
+struct SortKey {
+    source: Source,
+    comparator: Comparator,
+    descending: bool,
+    nulls_first: Option<bool>, // NULLS FIRST / NULLS LAST, otherwise NULLs sort lowest
+}
+
+/// A SELECT sent without its ORDER BY and LIMIT, both applied to the fetched rows instead.
+pub struct SortedQuery {
+    pub sql: String,
+    keys: Vec<SortKey>,
+    hidden: usize,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl SortedQuery {
+    pub fn apply(&self, stmt: &mut SQLite3PreparedStmt) -> Result<(), SqliteError> {
+        let visible = stmt.column_names.len().saturating_sub(self.hidden);
+        let mut rows = stmt.result_rows.lock().unwrap();
+
+        rows.sort_by(|a, b| self.compare(a, b, visible));
+        let sorted = std::mem::take(&mut *rows);
+        *rows = sorted
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|mut row| {
+                row.truncate(visible);
+                row
+            })
+            .collect();
+        drop(rows);
+
+        stmt.column_names.truncate(visible);
+        stmt.remember_column_names();
+        Ok(())
+    }
+
+    /// Column names of the original statement, given those of the rewritten one.
+    pub fn column_names(&self, fetched: &[String]) -> Vec<String> {
+        fetched[..fetched.len().saturating_sub(self.hidden)].to_vec()
+    }
+
+    fn compare(&self, a: &[Value], b: &[Value], visible: usize) -> Ordering {
+        for key in &self.keys {
+            let index = match key.source {
+                Source::Column(i) => i,
+                Source::Hidden(i) => visible + i,
+            };
+            let (a, b) = match (a.get(index), b.get(index)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => continue,
+            };
+
+            let ordering = match (a, b, key.nulls_first) {
+                (Value::Null, Value::Null, _) => Ordering::Equal,
+                (Value::Null, _, Some(first)) => first_or_last(first),
+                (_, Value::Null, Some(first)) => first_or_last(first).reverse(),
+                _ if key.descending => key.comparator.compare(a, b).reverse(),
+                _ => key.comparator.compare(a, b),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+}
+
+fn first_or_last(first: bool) -> Ordering {
+    if first {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+fn unsupported(name: &str) -> SqliteError {
+    SqliteError::new(
+        format!(
+            "collation {} is registered on the client and can only be used in the ORDER BY of a SELECT",
+            name
+        ),
+        Some(SQLITE_ERROR),
+    )
+}
+
+fn unsupported_limit() -> SqliteError {
+    SqliteError::new(
+        "LIMIT and OFFSET must be plain numbers when ordering by a client-side collation",
+        Some(SQLITE_ERROR),
+    )
+}
+
+/// Plans the client-side ordering of `sql`. `None` when it uses no registered collation and
+/// can go to the server untouched.
+pub fn plan(db: &SQLite3, sql: &str) -> Result<Option<SortedQuery>, SqliteError> {
+    let registry = db.collations.lock().unwrap();
+    if registry.is_empty() {
+        return Ok(None);
+    }
+
+    rewrite(&registry, sql)
+}
+
+fn rewrite(registry: &CollationRegistry, sql: &str) -> Result<Option<SortedQuery>, SqliteError> {
+    let tokens = spanned_tokens(sql);
+    let registered = |i: usize| match tokens.get(i + 1).map(|t| &t.token) {
+        Some(Token::Identifier(name)) if is_keyword(&tokens[i].token, "COLLATE") => {
+            let name = unquote(name);
+            registry.contains_key(&name).then_some(name)
+        }
+        _ => None,
+    };
+    let uses: Vec<usize> = (0..tokens.len())
+        .filter(|&i| registered(i).is_some())
+        .collect();
+    let Some(&first_use) = uses.first() else {
+        return Ok(None);
+    };
+    let first_name = registered(first_use).unwrap_or_default();
+
+    let Some(Projection {
+        begin,
+        end,
+        distinct: false,
+    }) = projection(&tokens)
+    else {
+        return Err(unsupported(&first_name));
+    };
+
+    // Only the ORDER BY of a simple SELECT can be moved to the client
+    let mut order = None;
+    let mut position = end;
+    while position < tokens.len() {
+        let token = &tokens[position].token;
+        if ["UNION", "INTERSECT", "EXCEPT"]
+            .iter()
+            .any(|k| is_keyword(token, k))
+        {
+            return Err(unsupported(&first_name));
+        }
+        if is_keyword(token, "ORDER") {
+            order = Some(position);
+            break;
+        }
+        position = clause_end(&tokens, position + 1, &[]);
+    }
+    let Some(order) = order else {
+        return Err(unsupported(&first_name));
+    };
+
+    let terms_begin = order + 2;
+    let terms_end = clause_end(&tokens, terms_begin, &[]);
+    if uses.iter().any(|&i| i < terms_begin || i >= terms_end) {
+        return Err(unsupported(&first_name));
+    }
+
+    let (offset, limit, stop) = parse_limit(&tokens, terms_end)?;
+    let columns = split_commas(&tokens, begin, end);
+    let text = |from: usize, to: usize| &sql[tokens[from].start..tokens[to - 1].end];
+
+    let mut keys = Vec::new();
+    let mut hidden = Vec::new();
+    for (start, mut stop) in split_commas(&tokens, terms_begin, terms_end) {
+        let mut nulls_first = None;
+        if stop - start > 2 && is_keyword(&tokens[stop - 2].token, "NULLS") {
+            nulls_first = Some(is_keyword(&tokens[stop - 1].token, "FIRST"));
+            stop -= 2;
+        }
+        let mut descending = false;
+        if stop - start > 1 {
+            let last = &tokens[stop - 1].token;
+            descending = is_keyword(last, "DESC");
+            if descending || is_keyword(last, "ASC") {
+                stop -= 1;
+            }
+        }
+        let mut comparator = Comparator::Binary;
+        if stop - start > 2 && is_keyword(&tokens[stop - 2].token, "COLLATE") {
+            let Token::Identifier(name) = tokens[stop - 1].token else {
+                return Err(unsupported(&first_name));
+            };
+            let name = unquote(name);
+            comparator = match (registry.get(&name), name.as_str()) {
+                (Some(collation), _) => Comparator::Registered(collation.clone()),
+                (None, "binary") => Comparator::Binary,
+                (None, "nocase") => Comparator::NoCase,
+                (None, "rtrim") => Comparator::RTrim,
+                _ => {
+                    return Err(SqliteError::new(
+                        format!("no such collation sequence: {}", name),
+                        Some(SQLITE_ERROR),
+                    ))
+                }
+            };
+            stop -= 2;
+        }
+
+        if tokens[start..stop]
+            .iter()
+            .any(|t| matches!(t.token, Token::Parameter(_)))
+        {
+            return Err(SqliteError::new(
+                "ORDER BY terms cannot bind parameters when ordering by a client-side collation",
+                Some(SQLITE_ERROR),
+            ));
+        }
+
+        let source = match result_column(&tokens, &columns, start, stop, sql) {
+            Some(index) => Source::Column(index),
+            None => {
+                hidden.push(text(start, stop).to_string());
+                Source::Hidden(hidden.len() - 1)
+            }
+        };
+        keys.push(SortKey {
+            source,
+            comparator,
+            descending,
+            nulls_first,
+        });
+    }
+
+    // Sort columns are fetched after the result columns, ORDER BY and LIMIT are left out
+    let mut rewritten = sql[..tokens[end - 1].end].to_string();
+    for expression in &hidden {
+        rewritten.push_str(", ");
+        rewritten.push_str(expression);
+    }
+    rewritten.push_str(&sql[tokens[end - 1].end..tokens[order].start]);
+    if let Some(token) = tokens.get(stop) {
+        rewritten.push_str(&sql[token.start..]);
+    }
+
+    Ok(Some(SortedQuery {
+        sql: rewritten.trim_end().to_string(),
+        keys,
+        hidden: hidden.len(),
+        offset,
+        limit,
+    }))
+}
+
+// `LIMIT n [OFFSET m]` or `LIMIT m, n` with literal numbers, and the index just past it
+fn parse_limit(
+    tokens: &[Spanned],
+    at: usize,
+) -> Result<(usize, Option<usize>, usize), SqliteError> {
+    if !tokens
+        .get(at)
+        .is_some_and(|t| is_keyword(&t.token, "LIMIT"))
+    {
+        return Ok((0, None, at));
+    }
+
+    let number = |i: usize| match tokens.get(i).map(|t| &t.token) {
+        Some(Token::Number(n)) => n.parse::<i64>().map_err(|_| unsupported_limit()),
+        _ => Err(unsupported_limit()),
+    };
+    // A negative limit means no limit, a negative offset none at all
+    let limit_of = |n: i64| usize::try_from(n).ok();
+    let offset_of = |n: i64| usize::try_from(n).unwrap_or(0);
+
+    let first = number(at + 1)?;
+    match tokens.get(at + 2).map(|t| &t.token) {
+        Some(Token::Punct(",")) => Ok((offset_of(first), limit_of(number(at + 3)?), at + 4)),
+        Some(token) if is_keyword(token, "OFFSET") => {
+            Ok((offset_of(number(at + 3)?), limit_of(first), at + 4))
+        }
+        _ => Ok((0, limit_of(first), at + 2)),
+    }
+}
+
+// Result column an ORDER BY term refers to: a column number, an alias or the same expression.
+// Columns behind a `*` have no known position, so only those before one are considered.
+fn result_column(
+    tokens: &[Spanned],
+    columns: &[(usize, usize)],
+    start: usize,
+    stop: usize,
+    sql: &str,
+) -> Option<usize> {
+    let normalize = |from: usize, to: usize| {
+        tokens[from..to]
+            .iter()
+            .map(|t| sql[t.start..t.end].to_ascii_lowercase())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    if let [Spanned {
+        token: Token::Number(n),
+        ..
+    }] = &tokens[start..stop]
+    {
+        return n.parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+    }
+
+    let term = normalize(start, stop);
+    for (index, &(from, to)) in columns.iter().enumerate() {
+        if matches!(tokens[to - 1].token, Token::Punct("*")) {
+            break;
+        }
+
+        // `expr AS alias` or `expr alias`
+        let (alias, expression_end) = match &tokens[from..to] {
+            [_, .., keyword, alias] if is_keyword(&keyword.token, "AS") => (Some(alias), to - 2),
+            [_, .., before, alias]
+                if matches!(alias.token, Token::Identifier(_))
+                    && matches!(
+                        before.token,
+                        Token::Identifier(_)
+                            | Token::Number(_)
+                            | Token::Literal(_)
+                            | Token::Punct(")")
+                    ) =>
+            {
+                (Some(alias), to - 1)
+            }
+            _ => (None, to),
+        };
+        let matches_alias = alias.is_some_and(|alias| match (&alias.token, &tokens[start..stop]) {
+            (
+                Token::Identifier(alias),
+                [Spanned {
+                    token: Token::Identifier(term),
+                    ..
+                }],
+            ) => unquote(alias) == unquote(term),
+            _ => false,
+        });
+
+        if matches_alias || normalize(from, expression_end) == term {
+            return Some(index);
+        }
+    }
+
+    None
+}
@@ -0,0 +1,165 @@
+//! What `sqlite3_compileoption_used` and `sqlite3_compileoption_get` report. Statements run on
+//! the server, so the SQL features it was built with (FTS5, JSON, math functions, ...) come
+//! from its `PRAGMA compile_options`, asked once through an open connection. Options about the
+//! C API describe the shim rather than the server and are always the shim's own.
+
+use std::{
+    ffi::{CStr, CString},
+    sync::OnceLock,
+};
+
+use crate::{
+    protocol::StatementArgs,
+    sqlite::{self, SqliteError, Value},
+};
+
+/// What the shim itself implements.
+const SHIM_OPTIONS: [&CStr; 2] = [c"ENABLE_COLUMN_METADATA", c"THREADSAFE=1"];
+
+// Server options that only concern its own C API, dropped from what the shim reports
+const C_API_OPTIONS: [&str; 11] = [
+    "ENABLE_API_ARMOR",
+    "ENABLE_COLUMN_METADATA",
+    "ENABLE_NORMALIZE",
+    "ENABLE_PREUPDATE_HOOK",
+    "ENABLE_SESSION",
+    "ENABLE_SNAPSHOT",
+    "ENABLE_STMT_SCANSTATUS",
+    "ENABLE_UNLOCK_NOTIFY",
+    "OMIT_DESERIALIZE",
+    "OMIT_LOAD_EXTENSION",
+    "THREADSAFE",
+];
+
+// The shim's options and the server's, once a connection could ask for them
+static OPTIONS: OnceLock<Vec<CString>> = OnceLock::new();
+
+/// Every option, sorted like SQLite's own list.
+pub fn options() -> Vec<&'static CStr> {
+    let options = OPTIONS.get().or_else(|| {
+        let server = server_options()?;
+        Some(OPTIONS.get_or_init(|| merge(server)))
+    });
+    match options {
+        Some(options) => options.iter().map(CString::as_c_str).collect(),
+        None => SHIM_OPTIONS.to_vec(),
+    }
+}
+
+/// Whether option `name` is set. As in SQLite, the `SQLITE_` prefix is optional, case is
+/// ignored and a name without a value, e.g. `THREADSAFE`, matches any value.
+pub fn is_used(name: &str) -> bool {
+    let name = strip_prefix_ignore_case(name, "SQLITE_");
+    options()
+        .iter()
+        .any(|option| matches_option(option.to_bytes(), name.as_bytes()))
+}
+
+fn strip_prefix_ignore_case<'a>(name: &'a str, prefix: &str) -> &'a str {
+    match name.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => &name[prefix.len()..],
+        _ => name,
+    }
+}
+
+fn matches_option(option: &[u8], name: &[u8]) -> bool {
+    let Some(head) = option.get(..name.len()) else {
+        return false;
+    };
+    let next = option.get(name.len()).copied().unwrap_or(0);
+    head.eq_ignore_ascii_case(name) && !(next.is_ascii_alphanumeric() || next == b'_')
+}
+
+fn merge(server: Vec<String>) -> Vec<CString> {
+    let server = server.into_iter().filter(|option| {
+        let name = option.split('=').next().unwrap_or_default();
+        !C_API_OPTIONS.contains(&name)
+    });
+
+    let mut options: Vec<CString> = SHIM_OPTIONS.iter().map(|&option| option.into()).collect();
+    options.extend(server.filter_map(|option| CString::new(option).ok()));
+    options.sort();
+    options
+}
+
+// None when no connection can ask right now, to try again on the next call
+fn server_options() -> Option<Vec<String>> {
+    sqlite::with_open_connection(|db| {
+        if db.has_began_transaction() {
+            return None;
+        }
+
+        db.worker.run(async {
+            // Never waits: the caller may be inside a callback of the statement holding it
+            let mut connection = db.connection.try_lock().ok()?;
+            if connection.is_inherited() {
+                return None;
+            }
+
+            let mut request = connection
+                .get_autocommit_request("PRAGMA compile_options", &StatementArgs::default());
+            let response = match connection.send(&mut request).await {
+                Ok(response) => response,
+                Err(err) => return Some(unavailable(err)),
+            };
+            let rows = match response.first_result() {
+                Ok(result) => &result.rows,
+                Err(err) => return Some(unavailable(err)),
+            };
+
+            let options = rows
+                .iter()
+                .filter_map(|row| match row.first()?.decode() {
+                    Value::Text(option) => Some(option),
+                    _ => None,
+                })
+                .collect();
+            Some(options)
+        })
+    })
+    .flatten()
+}
+
+// Not asked again, the shim's options are all there is to report
+fn unavailable(err: SqliteError) -> Vec<String> {
+    tracing::warn!(error = %err, "Failed to read the server's compile options");
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_match_up_to_the_value() {
+        assert!(matches_option(b"THREADSAFE=1", b"THREADSAFE"));
+        assert!(matches_option(b"THREADSAFE=1", b"threadsafe=1"));
+        assert!(!matches_option(b"THREADSAFE=1", b"THREADSAFE=0"));
+        assert!(!matches_option(b"ENABLE_FTS5", b"ENABLE_FTS"));
+        assert!(!matches_option(b"ENABLE_FTS5", b"ENABLE_FTS5_EXTRA"));
+        assert_eq!(
+            strip_prefix_ignore_case("sqlite_ENABLE_FTS5", "SQLITE_"),
+            "ENABLE_FTS5"
+        );
+    }
+
+    #[test]
+    fn server_options_about_the_c_api_are_dropped() {
+        let server = [
+            "ENABLE_FTS5",
+            "THREADSAFE=0",
+            "ENABLE_PREUPDATE_HOOK",
+            "ENABLE_MATH_FUNCTIONS",
+        ];
+        let merged = merge(server.map(String::from).to_vec());
+        assert_eq!(
+            merged,
+            [
+                c"ENABLE_COLUMN_METADATA",
+                c"ENABLE_FTS5",
+                c"ENABLE_MATH_FUNCTIONS",
+                c"THREADSAFE=1"
+            ]
+        );
+    }
+}
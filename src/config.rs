@@ -0,0 +1,408 @@
+use std::{path::PathBuf, sync::Mutex, time::Duration};
+
+use crate::{
+    cache::DEFAULT_STATEMENT_CACHE_CAPACITY,
+    slow_query::SLOW_MS_ENV,
+    sqlite::{SqliteError, SQLITE_CANTOPEN},
+    table::DEFAULT_TABLE_LIMIT,
+};
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+pub const TIMEOUT_ENV_VAR: &str = "TURSO_TIMEOUT_MS";
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+pub const DEFAULT_DNS_TTL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    Off, // Send request bodies uncompressed
+    Gzip,   // gzip request bodies above the threshold
+    Brotli, // brotli request bodies above the threshold
+}
+
+impl Compression {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" | "none" | "0" | "false" => Some(Compression::Off),
+            "gzip" | "on" | "1" | "true" => Some(Compression::Gzip),
+            "br" | "brotli" => Some(Compression::Brotli),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub enabled: bool,              // false switches to plain http:// and ws://
+    pub ca_file: Option<PathBuf>,   // Extra PEM root certificate(s) to trust
+    pub cert_file: Option<PathBuf>, // PEM client certificate
+    pub key_file: Option<PathBuf>,  // PKCS#8 PEM key for the client certificate
+    pub accept_invalid_certs: bool, // Development only: skip certificate verification
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            accept_invalid_certs: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportPolicy {
+    #[default]
+    Auto, // WebSocket first, HTTP fallback with periodic WebSocket retries
+    Http,      // Never open a WebSocket
+    Websocket, // WebSocket only, fail instead of falling back
+    Mock,      // Replay exchanges from the fixture file, never touching the network
+    Record,    // HTTP, with every exchange written to the fixture file
+    Custom,    // A transport the application registered with register_transport
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthMode {
+    #[default]
+    Auto, // Globe when GLOBE=1, otherwise TURSO_DB_URL + TURSO_DB_TOKEN
+    None, // TURSO_DB_URL only, for self-hosted sqld without a token
+}
+
+/// Connection options supplied as URI query parameters on the database filename,
+/// e.g. `file:my-db.db?compress=gzip`.
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub compress: Compression,
+    pub timeout: Option<Duration>,
+    pub proxy: Option<String>, // Overrides the HTTP(S)_PROXY / ALL_PROXY environment variables
+    pub tls: TlsOptions,
+    pub auth: AuthMode,
+    pub transport: TransportPolicy,
+    pub fixture: Option<PathBuf>, // Exchanges file of the mock and record transports
+    pub custom_transport: Option<String>, // Registered name of the custom transport
+    pub ping_interval: Duration,  // WebSocket keepalive ping period, zero disables pings
+    pub idle_timeout: Duration,   // WebSocket is dropped after this long without traffic
+    pub max_message: usize,       // Largest WebSocket message read, zero for no limit
+    pub share_socket: bool,       // One WebSocket per endpoint, shared with other connections
+    pub failover: Vec<String>,    // Hosts tried in turn when the database's own is unreachable
+    pub dns_ttl: Duration,        // How long resolved addresses are reused, zero disables caching
+    pub statement_cache: usize,   // Prepared statement cache capacity, zero disables it
+    pub replica: Option<PathBuf>, // Local file of the embedded replica, see replica.rs
+    pub sync_interval: Duration,  // Replica reads between syncs, zero for until a write
+    pub async_step: bool,         // sqlite3_step answers SQLITE_BUSY while a request is in flight
+    pub batch_scripts: bool,      // Prepared statements of a script are sent with the first step
+    pub txn_replay: bool, // Transactions are journaled and replayed after losing their stream
+    pub session_replay: bool, // Temp objects and session pragmas are replayed on new streams
+    pub schema_cache: bool, // sqlite_master results are kept while the schema is unchanged
+    pub slow_ms: Option<Duration>, // Round trips above this are logged, zero logs none
+    pub table_limit: usize, // Most bytes sqlite3_get_table returns, zero for no limit
+    pub max_rows: u64,    // Most rows a query returns, zero for no limit
+    pub warmup: bool,     // The schema cache is filled in the background after opening
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            compress: Compression::default(),
+            timeout: None,
+            proxy: None,
+            tls: TlsOptions::default(),
+            auth: AuthMode::default(),
+            transport: TransportPolicy::default(),
+            fixture: None,
+            custom_transport: None,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_message: DEFAULT_MAX_MESSAGE,
+            share_socket: true,
+            failover: Vec::new(),
+            dns_ttl: DEFAULT_DNS_TTL,
+            statement_cache: DEFAULT_STATEMENT_CACHE_CAPACITY,
+            replica: None,
+            sync_interval: Duration::ZERO,
+            async_step: false,
+            batch_scripts: false,
+            txn_replay: false,
+            session_replay: true,
+            schema_cache: true,
+            slow_ms: None,
+            table_limit: DEFAULT_TABLE_LIMIT,
+            max_rows: 0,
+            warmup: false,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// Splits the filename into the database name and its options.
+    /// Unknown parameters are ignored, as SQLite does for URI filenames. Parameters missing
+    /// from the filename take the process defaults set with `sqlite3_turso_config`.
+    pub fn parse(filename: &str) -> Result<(String, Self), SqliteError> {
+        Self::parse_with(filename, process_defaults())
+    }
+
+    /// Like `parse`, with parameters missing from the filename taken from `base` rather than
+    /// the defaults. Used for attached databases, which inherit the main connection's options.
+    pub fn parse_with(filename: &str, base: Self) -> Result<(String, Self), SqliteError> {
+        let filename = filename.strip_prefix("file:").unwrap_or(filename);
+        let (db_name, query) = match filename.split_once('?') {
+            Some((db_name, query)) => (db_name, query),
+            None => (filename, ""),
+        };
+
+        let mut options = base;
+
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            options.set(key, value)?;
+        }
+
+        if options.tls.cert_file.is_some() != options.tls.key_file.is_some() {
+            return Err(SqliteError::new(
+                "URI parameters 'tls_cert' and 'tls_key' must be provided together",
+                Some(SQLITE_CANTOPEN),
+            ));
+        }
+
+        Ok((db_name.to_string(), options))
+    }
+
+    /// Applies one option as its URI parameter spells it. Returns false for an unknown key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, SqliteError> {
+        match key {
+            "compress" => {
+                self.compress = Compression::parse(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected off, gzip or brotli"))?;
+            }
+            "timeout" => {
+                self.timeout = Some(parse_timeout_ms(value).ok_or_else(|| {
+                    invalid_param(key, value, "expected a positive number of milliseconds")
+                })?);
+            }
+            "proxy" => {
+                if value.is_empty() {
+                    return Err(invalid_param(key, value, "expected a proxy URL"));
+                }
+                self.proxy = Some(value.to_string());
+            }
+            "tls" => {
+                self.tls.enabled = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "auth" => {
+                self.auth = match value {
+                    "auto" => AuthMode::Auto,
+                    "none" => AuthMode::None,
+                    _ => return Err(invalid_param(key, value, "expected auto or none")),
+                };
+            }
+            "transport" => {
+                self.custom_transport = None;
+                (self.transport, self.fixture) = match value.split_once(':') {
+                    Some(("mock", path)) => (TransportPolicy::Mock, Some(parse_path(key, path)?)),
+                    Some(("record", path)) => {
+                        (TransportPolicy::Record, Some(parse_path(key, path)?))
+                    }
+                    Some(("custom", name)) if !name.is_empty() => {
+                        self.custom_transport = Some(name.to_string());
+                        (TransportPolicy::Custom, None)
+                    }
+                    _ => match value {
+                        "auto" => (TransportPolicy::Auto, None),
+                        "http" => (TransportPolicy::Http, None),
+                        "ws" | "websocket" => (TransportPolicy::Websocket, None),
+                        _ => {
+                            let expected = "expected auto, http, ws, mock:<path>, record:<path> \
+                                            or custom:<name>";
+                            return Err(invalid_param(key, value, expected));
+                        }
+                    },
+                };
+            }
+            "ping_interval" => {
+                self.ping_interval = parse_duration_ms(value).ok_or_else(|| {
+                    invalid_param(key, value, "expected a number of milliseconds")
+                })?;
+            }
+            "idle_timeout" => {
+                self.idle_timeout = parse_timeout_ms(value).ok_or_else(|| {
+                    invalid_param(key, value, "expected a positive number of milliseconds")
+                })?;
+            }
+            "max_message" => {
+                self.max_message = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_param(key, value, "expected a number of bytes"))?;
+            }
+            "share_socket" => {
+                self.share_socket = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "failover" => {
+                self.failover = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            "dns_ttl" => {
+                self.dns_ttl = parse_duration_ms(value).ok_or_else(|| {
+                    invalid_param(key, value, "expected a number of milliseconds")
+                })?;
+            }
+            "statement_cache" => {
+                self.statement_cache = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_param(key, value, "expected a number of statements"))?;
+            }
+            "replica" if cfg!(feature = "replica") => self.replica = Some(parse_path(key, value)?),
+            "replica" => {
+                return Err(invalid_param(
+                    key,
+                    value,
+                    "built without the replica feature",
+                ));
+            }
+            "sync_interval" => {
+                self.sync_interval = parse_duration_ms(value).ok_or_else(|| {
+                    invalid_param(key, value, "expected a number of milliseconds")
+                })?;
+            }
+            "async_step" => {
+                self.async_step = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "batch_scripts" => {
+                self.batch_scripts = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "txn_replay" => {
+                self.txn_replay = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "session_replay" => {
+                self.session_replay = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "schema_cache" => {
+                self.schema_cache = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "warmup" => {
+                self.warmup = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            "slow_ms" => {
+                self.slow_ms = Some(parse_duration_ms(value).ok_or_else(|| {
+                    invalid_param(key, value, "expected a number of milliseconds")
+                })?);
+            }
+            "table_limit" => {
+                self.table_limit = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_param(key, value, "expected a number of bytes"))?;
+            }
+            "max_rows" => {
+                self.max_rows = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_param(key, value, "expected a number of rows"))?;
+            }
+            "tls_ca" => self.tls.ca_file = Some(parse_path(key, value)?),
+            "tls_cert" => self.tls.cert_file = Some(parse_path(key, value)?),
+            "tls_key" => self.tls.key_file = Some(parse_path(key, value)?),
+            "tls_insecure" => {
+                self.tls.accept_invalid_certs = parse_bool(value)
+                    .ok_or_else(|| invalid_param(key, value, "expected on or off"))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Round trips slower than this are logged: the `slow_ms` option wins over the
+    /// `TURSO_SLOW_MS` environment variable, and zero or neither turns the log off.
+    pub fn slow_query_threshold(&self) -> Option<Duration> {
+        self.slow_ms
+            .or_else(|| {
+                std::env::var(SLOW_MS_ENV)
+                    .ok()
+                    .and_then(|v| parse_duration_ms(&v))
+            })
+            .filter(|threshold| !threshold.is_zero())
+    }
+
+    /// Timeout applied to every remote request: the `timeout` option, from the URI or the
+    /// process defaults, wins over the `TURSO_TIMEOUT_MS` environment variable, which wins over the default.
+    pub fn request_timeout(&self) -> Duration {
+        self.timeout
+            .or_else(|| {
+                std::env::var(TIMEOUT_ENV_VAR)
+                    .ok()
+                    .and_then(|v| parse_timeout_ms(&v))
+            })
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+    }
+}
+
+/// Options every connection opened afterwards starts from, before its URI parameters.
+static PROCESS_DEFAULTS: Mutex<Option<ConnectionOptions>> = Mutex::new(None);
+
+fn process_defaults() -> ConnectionOptions {
+    PROCESS_DEFAULTS.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Changes one process default. Returns false for an unknown key, leaving them unchanged.
+pub fn set_process_default(key: &str, value: &str) -> Result<bool, SqliteError> {
+    let mut defaults = PROCESS_DEFAULTS.lock().unwrap();
+    let mut options = defaults.clone().unwrap_or_default();
+    let known = options.set(key, value)?;
+    if known {
+        *defaults = Some(options);
+    }
+    Ok(known)
+}
+
+fn parse_duration_ms(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_millis)
+}
+
+pub fn parse_timeout_ms(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(ms) if ms > 0 => Some(Duration::from_millis(ms)),
+        _ => None,
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "1" | "true" | "yes" => Some(true),
+        "off" | "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_path(key: &str, value: &str) -> Result<PathBuf, SqliteError> {
+    if value.is_empty() {
+        return Err(invalid_param(key, value, "expected a file path"));
+    }
+    Ok(PathBuf::from(value))
+}
+
+fn invalid_param(key: &str, value: &str, hint: &str) -> SqliteError {
+    SqliteError::new(
+        format!(
+            "Invalid value '{}' for URI parameter '{}': {}",
+            value, key, hint
+        ),
+        Some(SQLITE_CANTOPEN),
+    )
+}
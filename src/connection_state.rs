@@ -0,0 +1,153 @@
+//! Whether a connection can reach its database, for hosts that show it to their users. The
+//! transports report what they see, and the hook registered with
+//! `sqlite3_turso_on_state_change` hears of every change. They also note the region the
+//! server says answered, which `turso_status` reports.
+
+use std::{
+    ffi::{c_int, c_void},
+    sync::Mutex,
+};
+
+pub const SQLITE_TURSO_STATE_CONNECTED: c_int = 1;
+pub const SQLITE_TURSO_STATE_DEGRADED: c_int = 2;
+pub const SQLITE_TURSO_STATE_DISCONNECTED: c_int = 3;
+
+/// Response headers naming the region of the server that answered, the first one present
+/// wins.
+pub const REGION_HEADERS: [&str; 2] = ["x-turso-region", "fly-region"];
+
+pub type StateChangeHook = extern "C" fn(
+    user_data: *mut c_void, // User-provided data
+    state: c_int,           // One of the SQLITE_TURSO_STATE_* values
+);
+
+#[derive(Clone, Copy)]
+struct RegisteredHook {
+    callback: StateChangeHook,
+    user_data: *mut c_void,
+}
+
+// The hook is only ever invoked with the pointer the caller handed us
+unsafe impl Send for RegisteredHook {}
+
+#[derive(Default)]
+struct Observed {
+    degraded: bool,    // An `auto` connection fell back to HTTP
+    unreachable: bool, // The last request never got an answer from the server
+}
+
+impl Observed {
+    fn state(&self) -> c_int {
+        match (self.unreachable, self.degraded) {
+            (true, _) => SQLITE_TURSO_STATE_DISCONNECTED,
+            (false, true) => SQLITE_TURSO_STATE_DEGRADED,
+            (false, false) => SQLITE_TURSO_STATE_CONNECTED,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConnectionState {
+    observed: Mutex<Observed>,
+    hook: Mutex<Option<RegisteredHook>>,
+    region: Mutex<Option<String>>,
+}
+
+impl ConnectionState {
+    pub fn state(&self) -> c_int {
+        self.observed.lock().unwrap().state()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.state() {
+            SQLITE_TURSO_STATE_DISCONNECTED => "disconnected",
+            SQLITE_TURSO_STATE_DEGRADED => "degraded",
+            _ => "connected",
+        }
+    }
+
+    pub fn region(&self) -> Option<String> {
+        self.region.lock().unwrap().clone()
+    }
+
+    /// Keeps the region named by the first of `REGION_HEADERS` a response carries.
+    pub fn observe_region<'a>(&self, header: impl Fn(&str) -> Option<&'a str>) {
+        if let Some(region) = REGION_HEADERS.iter().find_map(|name| header(name)) {
+            *self.region.lock().unwrap() = Some(region.to_string());
+        }
+    }
+
+    /// Registers the hook, or removes it for `None`, and tells a new one the current state.
+    pub fn set_hook(&self, callback: Option<StateChangeHook>, user_data: *mut c_void) {
+        let hook = callback.map(|callback| RegisteredHook {
+            callback,
+            user_data,
+        });
+        *self.hook.lock().unwrap() = hook;
+        if let Some(hook) = hook {
+            (hook.callback)(hook.user_data, self.state());
+        }
+    }
+
+    /// Whether the server answered the last request, whatever the answer was.
+    pub fn set_reachable(&self, reachable: bool) {
+        self.update(|observed| observed.unreachable = !reachable);
+    }
+
+    /// Whether requests go over HTTP because the WebSocket could not be used.
+    pub fn set_degraded(&self, degraded: bool) {
+        self.update(|observed| observed.degraded = degraded);
+    }
+
+    // The hook runs without either lock held, so it may register another one
+    fn update(&self, change: impl FnOnce(&mut Observed)) {
+        let state = {
+            let mut observed = self.observed.lock().unwrap();
+            let before = observed.state();
+            change(&mut observed);
+            let after = observed.state();
+            if after == before {
+                return;
+            }
+            after
+        };
+
+        tracing::info!(state, "Connection state changed");
+        let hook = *self.hook.lock().unwrap();
+        if let Some(hook) = hook {
+            (hook.callback)(hook.user_data, state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn record(user_data: *mut c_void, state: c_int) {
+        unsafe { &mut *(user_data as *mut Vec<c_int>) }.push(state);
+    }
+
+    #[test]
+    fn hook_hears_each_change_once() {
+        let mut states: Vec<c_int> = vec![];
+        let connection = ConnectionState::default();
+        connection.set_hook(Some(record), &mut states as *mut _ as *mut c_void);
+
+        connection.set_degraded(true);
+        connection.set_reachable(false);
+        connection.set_reachable(false);
+        connection.set_degraded(false); // Still unreachable
+        connection.set_reachable(true);
+
+        assert_eq!(
+            states,
+            vec![
+                SQLITE_TURSO_STATE_CONNECTED,
+                SQLITE_TURSO_STATE_DEGRADED,
+                SQLITE_TURSO_STATE_DISCONNECTED,
+                SQLITE_TURSO_STATE_CONNECTED,
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+/*
+ * The variadic entry points of sqlite3_mprintf and sqlite3_snprintf, which stable Rust cannot
+ * define. Each hands its va_list to the formatter in cutil.rs, which takes the arguments one
+ * at a time through the accessors below as the format string asks for them.
+ */
+#include <stdarg.h>
+
+char *turso_vmprintf(const char *format, va_list *args);
+char *turso_vsnprintf(int size, char *buffer, const char *format, va_list *args);
+
+/* length: 0 for int, 1 for long, 2 for long long, as the l and ll modifiers ask */
+long long turso_va_int(va_list *args, int length) {
+    switch (length) {
+    case 1:
+        return va_arg(*args, long);
+    case 2:
+        return va_arg(*args, long long);
+    default:
+        return va_arg(*args, int);
+    }
+}
+
+unsigned long long turso_va_uint(va_list *args, int length) {
+    switch (length) {
+    case 1:
+        return va_arg(*args, unsigned long);
+    case 2:
+        return va_arg(*args, unsigned long long);
+    default:
+        return va_arg(*args, unsigned int);
+    }
+}
+
+double turso_va_double(va_list *args) { return va_arg(*args, double); }
+
+void *turso_va_pointer(va_list *args) { return va_arg(*args, void *); }
+
+char *sqlite3_vmprintf(const char *format, va_list args) {
+    va_list copy;
+    va_copy(copy, args);
+    char *result = turso_vmprintf(format, &copy);
+    va_end(copy);
+    return result;
+}
+
+char *sqlite3_mprintf(const char *format, ...) {
+    va_list args;
+    va_start(args, format);
+    char *result = turso_vmprintf(format, &args);
+    va_end(args);
+    return result;
+}
+
+char *sqlite3_vsnprintf(int size, char *buffer, const char *format, va_list args) {
+    va_list copy;
+    va_copy(copy, args);
+    char *result = turso_vsnprintf(size, buffer, format, &copy);
+    va_end(copy);
+    return result;
+}
+
+char *sqlite3_snprintf(int size, char *buffer, const char *format, ...) {
+    va_list args;
+    va_start(args, format);
+    char *result = turso_vsnprintf(size, buffer, format, &args);
+    va_end(args);
+    return result;
+}
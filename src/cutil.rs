@@ -0,0 +1,534 @@
+//! The string utilities SQLite exports for drivers: case-insensitive comparison and the
+//! `sqlite3_mprintf` family, including SQLite's own `%q`, `%Q` and `%w` conversions for
+//! quoting text into SQL. Stable Rust cannot define variadic functions, so the entry points
+//! taking `...` or a `va_list` are in cutil.c and call back into `format` here.
+
+use std::{
+    ffi::{c_int, c_void, CStr},
+    os::raw::c_char,
+};
+
+use crate::utils::{allocate, release};
+
+/// Compares ASCII case-insensitively, like `sqlite3_stricmp`, over at most `limit` bytes.
+/// A null string sorts before any other.
+pub unsafe fn compare(left: *const c_char, right: *const c_char, limit: Option<usize>) -> c_int {
+    match (left.is_null(), right.is_null()) {
+        (true, true) => return 0,
+        (true, false) => return -1,
+        (false, true) => return 1,
+        _ => (),
+    }
+
+    let limit = limit.unwrap_or(usize::MAX);
+    for index in 0..limit {
+        let a = (*left.add(index) as u8).to_ascii_lowercase();
+        let b = (*right.add(index) as u8).to_ascii_lowercase();
+        if a != b || a == 0 {
+            return a as c_int - b as c_int;
+        }
+    }
+    0
+}
+
+/// The integer types an argument was passed as, from the `l` and `ll` modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Length {
+    Int = 0,
+    Long = 1,
+    LongLong = 2,
+}
+
+/// Where `format` takes its arguments from, one conversion at a time.
+pub trait Varargs {
+    fn int(&mut self, length: Length) -> i64;
+    fn uint(&mut self, length: Length) -> u64;
+    fn double(&mut self) -> f64;
+    fn pointer(&mut self) -> *mut c_void;
+}
+
+extern "C" {
+    fn turso_va_int(args: *mut c_void, length: c_int) -> i64;
+    fn turso_va_uint(args: *mut c_void, length: c_int) -> u64;
+    fn turso_va_double(args: *mut c_void) -> f64;
+    fn turso_va_pointer(args: *mut c_void) -> *mut c_void;
+}
+
+/// A `va_list` of cutil.c, read through its accessors.
+pub struct VaList(pub *mut c_void);
+
+impl Varargs for VaList {
+    fn int(&mut self, length: Length) -> i64 {
+        unsafe { turso_va_int(self.0, length as c_int) }
+    }
+
+    fn uint(&mut self, length: Length) -> u64 {
+        unsafe { turso_va_uint(self.0, length as c_int) }
+    }
+
+    fn double(&mut self) -> f64 {
+        unsafe { turso_va_double(self.0) }
+    }
+
+    fn pointer(&mut self) -> *mut c_void {
+        unsafe { turso_va_pointer(self.0) }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,      // -
+    plus: bool,      // +
+    space: bool,     // ' '
+    alternate: bool, // #
+    zero: bool,      // 0
+    thousands: bool, // ,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Formats like SQLite's printf. Besides the C conversions, `%q` doubles single quotes in a
+/// string, `%Q` does too and wraps it in them, or writes NULL for a null pointer, `%w` doubles
+/// double quotes for identifiers and `%z` is `%s` of a string it then frees.
+pub unsafe fn format(format: &[u8], args: &mut impl Varargs) -> Vec<u8> {
+    let mut out = Vec::with_capacity(format.len());
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+
+        let mut spec = Spec::default();
+        while let Some(&flag) = format.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                b',' => spec.thousands = true,
+                b'!' => (),
+                _ => break,
+            }
+            i += 1;
+        }
+
+        if format.get(i) == Some(&b'*') {
+            i += 1;
+            let width = args.int(Length::Int);
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        } else {
+            spec.width = digits(format, &mut i);
+        }
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            spec.precision = if format.get(i) == Some(&b'*') {
+                i += 1;
+                usize::try_from(args.int(Length::Int)).ok()
+            } else {
+                Some(digits(format, &mut i))
+            };
+        }
+
+        let mut length = Length::Int;
+        if format.get(i) == Some(&b'l') {
+            i += 1;
+            length = Length::Long;
+            if format.get(i) == Some(&b'l') {
+                i += 1;
+                length = Length::LongLong;
+            }
+        }
+
+        let Some(&conversion) = format.get(i) else {
+            out.extend_from_slice(&format[start..]);
+            break;
+        };
+        i += 1;
+
+        let field = match conversion {
+            b'%' => b"%".to_vec(),
+            b'd' | b'i' => {
+                let value = args.int(length);
+                integer(value < 0, value.unsigned_abs(), conversion, &spec)
+            }
+            b'u' | b'x' | b'X' | b'o' => integer(false, args.uint(length), conversion, &spec),
+            b'p' => integer(false, args.pointer() as usize as u64, b'x', &spec),
+            b'c' => pad(vec![args.int(Length::Int) as u8], &spec),
+            b's' | b'z' => {
+                let text = args.pointer() as *mut c_char;
+                let mut field = string(text).to_vec();
+                if let Some(precision) = spec.precision {
+                    field.truncate(precision);
+                }
+                if conversion == b'z' {
+                    release(text as *mut u8);
+                }
+                pad(field, &spec)
+            }
+            b'q' | b'Q' | b'w' => quote(args.pointer() as *const c_char, conversion, &spec),
+            b'f' | b'e' | b'E' | b'g' | b'G' => float(args.double(), conversion, &spec),
+            b'n' => {
+                // Nothing is written through it, as in SQLite
+                args.pointer();
+                Vec::new()
+            }
+            _ => format[start..i].to_vec(),
+        };
+        out.extend_from_slice(&field);
+    }
+    out
+}
+
+/// `text` in memory from `sqlite3_malloc`, null-terminated, or null if that fails.
+pub fn to_c_string(text: &[u8]) -> *mut c_char {
+    let copy = allocate(text.len() + 1) as *mut c_char;
+    if !copy.is_null() {
+        unsafe {
+            std::ptr::copy_nonoverlapping(text.as_ptr() as *const c_char, copy, text.len());
+            *copy.add(text.len()) = 0;
+        }
+    }
+    copy
+}
+
+fn digits(format: &[u8], i: &mut usize) -> usize {
+    let mut value = 0usize;
+    while let Some(digit) = format.get(*i).filter(|byte| byte.is_ascii_digit()) {
+        value = value
+            .saturating_mul(10)
+            .saturating_add((digit - b'0') as usize);
+        *i += 1;
+    }
+    value
+}
+
+unsafe fn string<'a>(text: *const c_char) -> &'a [u8] {
+    if text.is_null() {
+        b""
+    } else {
+        CStr::from_ptr(text).to_bytes()
+    }
+}
+
+fn integer(negative: bool, magnitude: u64, conversion: u8, spec: &Spec) -> Vec<u8> {
+    let (mut digits, prefix) = match conversion {
+        b'x' => (format!("{:x}", magnitude), "0x"),
+        b'X' => (format!("{:X}", magnitude), "0X"),
+        b'o' => (format!("{:o}", magnitude), "0"),
+        _ => (magnitude.to_string(), ""),
+    };
+    if let Some(precision) = spec.precision {
+        digits.insert_str(0, &"0".repeat(precision.saturating_sub(digits.len())));
+    }
+    if spec.thousands && prefix.is_empty() {
+        let mut grouped = String::with_capacity(digits.len() * 4 / 3);
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && (digits.len() - index) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        digits = grouped;
+    }
+
+    let mut lead = sign(negative, spec).to_string();
+    if spec.alternate && magnitude != 0 {
+        lead.push_str(prefix);
+    }
+    // Zeros from the 0 flag only pad numbers without a precision
+    number(lead, digits, spec, spec.precision.is_none())
+}
+
+fn float(value: f64, conversion: u8, spec: &Spec) -> Vec<u8> {
+    // SQLite's own spelling of the values without digits
+    if value.is_nan() {
+        return pad(b"NaN".to_vec(), spec);
+    }
+    if value.is_infinite() {
+        let text = format!("{}Inf", sign(value < 0.0, spec));
+        return pad(text.into_bytes(), spec);
+    }
+
+    let precision = spec.precision.unwrap_or(6);
+    let magnitude = value.abs();
+    let mut digits = match conversion {
+        b'f' => format!("{:.*}", precision, magnitude),
+        b'e' | b'E' => exponent(magnitude, precision),
+        _ => general(magnitude, precision, spec.alternate),
+    };
+    if conversion.is_ascii_uppercase() {
+        digits.make_ascii_uppercase();
+    }
+    number(sign(value < 0.0, spec).to_string(), digits, spec, true)
+}
+
+// `{:e}` with C's exponent: a sign and at least two digits
+fn exponent(magnitude: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, magnitude);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+}
+
+// %g: %e when the exponent is below -4 or not below the precision, %f otherwise, without
+// trailing zeros unless # asked to keep them
+fn general(magnitude: f64, precision: usize, keep_zeros: bool) -> String {
+    let precision = precision.max(1);
+    let rounded = format!("{:.*e}", precision - 1, magnitude);
+    let exponent: i32 = rounded
+        .split_once('e')
+        .and_then(|(_, exponent)| exponent.parse().ok())
+        .unwrap_or(0);
+
+    let text = if exponent < -4 || exponent >= precision as i32 {
+        self::exponent(magnitude, precision - 1)
+    } else {
+        format!(
+            "{:.*}",
+            (precision as i32 - 1 - exponent) as usize,
+            magnitude
+        )
+    };
+    if keep_zeros {
+        return text;
+    }
+
+    let (mantissa, exponent) = match text.find('e') {
+        Some(at) => text.split_at(at),
+        None => (text.as_str(), ""),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    format!("{}{}", mantissa, exponent)
+}
+
+fn sign(negative: bool, spec: &Spec) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+// A number padded to the width, with zeros between its sign and digits if the 0 flag allows
+fn number(lead: String, digits: String, spec: &Spec, zero_fill: bool) -> Vec<u8> {
+    if spec.zero && zero_fill && !spec.left {
+        let fill = spec.width.saturating_sub(lead.len() + digits.len());
+        return format!("{}{}{}", lead, "0".repeat(fill), digits).into_bytes();
+    }
+    pad(format!("{}{}", lead, digits).into_bytes(), spec)
+}
+
+fn pad(mut field: Vec<u8>, spec: &Spec) -> Vec<u8> {
+    let fill = spec.width.saturating_sub(field.len());
+    if fill == 0 {
+        return field;
+    }
+    if spec.left {
+        field.resize(field.len() + fill, b' ');
+        field
+    } else {
+        let mut padded = vec![b' '; fill];
+        padded.append(&mut field);
+        padded
+    }
+}
+
+unsafe fn quote(text: *const c_char, conversion: u8, spec: &Spec) -> Vec<u8> {
+    if text.is_null() {
+        let null: &[u8] = if conversion == b'Q' {
+            b"NULL"
+        } else {
+            b"(NULL)"
+        };
+        return pad(null.to_vec(), spec);
+    }
+
+    let quote = if conversion == b'w' { b'"' } else { b'\'' };
+    let mut text = string(text);
+    if let Some(precision) = spec.precision {
+        text = &text[..precision.min(text.len())];
+    }
+
+    let mut field = Vec::with_capacity(text.len() + 2);
+    if conversion == b'Q' {
+        field.push(quote);
+    }
+    for &byte in text {
+        field.push(byte);
+        if byte == quote {
+            field.push(quote);
+        }
+    }
+    if conversion == b'Q' {
+        field.push(quote);
+    }
+    pad(field, spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Arg {
+        Int(i64),
+        Double(f64),
+        Text(&'static CStr),
+        Null,
+    }
+
+    impl Varargs for std::vec::IntoIter<Arg> {
+        fn int(&mut self, _: Length) -> i64 {
+            match self.next() {
+                Some(Arg::Int(value)) => value,
+                _ => panic!("expected an integer argument"),
+            }
+        }
+
+        fn uint(&mut self, _: Length) -> u64 {
+            self.int(Length::LongLong) as u64
+        }
+
+        fn double(&mut self) -> f64 {
+            match self.next() {
+                Some(Arg::Double(value)) => value,
+                _ => panic!("expected a double argument"),
+            }
+        }
+
+        fn pointer(&mut self) -> *mut c_void {
+            match self.next() {
+                Some(Arg::Text(text)) => text.as_ptr() as *mut c_void,
+                Some(Arg::Null) => std::ptr::null_mut(),
+                _ => panic!("expected a pointer argument"),
+            }
+        }
+    }
+
+    fn printf(spec: &str, args: Vec<Arg>) -> String {
+        let text = unsafe { format(spec.as_bytes(), &mut args.into_iter()) };
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn sql_conversions_quote_text_and_nulls() {
+        use Arg::*;
+        assert_eq!(
+            printf(
+                "INSERT INTO t VALUES('%q', %Q, %Q)",
+                vec![Text(c"it's"), Text(c"o'k"), Null]
+            ),
+            "INSERT INTO t VALUES('it''s', 'o''k', NULL)"
+        );
+        assert_eq!(
+            printf("SELECT * FROM \"%w\"", vec![Text(c"my \"t\"")]),
+            "SELECT * FROM \"my \"\"t\"\"\""
+        );
+        assert_eq!(
+            printf("%q|%.2s|%s", vec![Null, Text(c"abc"), Null]),
+            "(NULL)|ab|"
+        );
+    }
+
+    #[test]
+    fn numbers_follow_the_c_conversions() {
+        use Arg::*;
+        assert_eq!(
+            printf(
+                "%d %5d|%-5d|%05d",
+                vec![Int(-7), Int(42), Int(42), Int(-42)]
+            ),
+            "-7    42|42   |-0042"
+        );
+        assert_eq!(
+            printf(
+                "%x %#X %o %,d %+d",
+                vec![Int(255), Int(255), Int(8), Int(1234567), Int(3)]
+            ),
+            "ff 0XFF 10 1,234,567 +3"
+        );
+        assert_eq!(
+            printf("%*d|%.3d|%%|%c", vec![Int(4), Int(7), Int(7), Int(65)]),
+            "   7|007|%|A"
+        );
+        assert_eq!(
+            printf(
+                "%f %.2f %e",
+                vec![Double(1.5), Double(-2.345), Double(1234.5)]
+            ),
+            "1.500000 -2.35 1.234500e+03"
+        );
+        assert_eq!(
+            printf(
+                "%g %g %g %G",
+                vec![Double(100000.0), Double(1e-5), Double(0.5), Double(1e20)]
+            ),
+            "100000 1e-05 0.5 1E+20"
+        );
+        assert_eq!(
+            printf("%f %08.3f %y", vec![Double(f64::NAN), Double(-4.56789)]),
+            "NaN -004.568 %y"
+        );
+    }
+
+    extern "C" {
+        fn sqlite3_mprintf(format: *const c_char, ...) -> *mut c_char;
+        fn sqlite3_snprintf(
+            size: c_int,
+            buffer: *mut c_char,
+            format: *const c_char,
+            ...
+        ) -> *mut c_char;
+    }
+
+    #[test]
+    fn variadic_entry_points_read_each_argument_type() {
+        unsafe {
+            let text = sqlite3_mprintf(
+                c"%d %lld %.1f %Q %p".as_ptr(),
+                -3 as c_int,
+                1i64 << 40,
+                2.25f64,
+                c"x".as_ptr(),
+                std::ptr::null::<c_void>(),
+            );
+            assert_eq!(CStr::from_ptr(text), c"-3 1099511627776 2.2 'x' 0");
+            release(text as *mut u8);
+
+            let mut buffer = [1 as c_char; 8];
+            let result = sqlite3_snprintf(
+                8,
+                buffer.as_mut_ptr(),
+                c"%s!".as_ptr(),
+                c"truncated".as_ptr(),
+            );
+            assert_eq!(result, buffer.as_mut_ptr());
+            assert_eq!(CStr::from_ptr(result), c"truncat");
+        }
+    }
+
+    #[test]
+    fn case_insensitive_comparison_stops_at_the_limit() {
+        unsafe {
+            assert_eq!(compare(c"Hello".as_ptr(), c"hELLO".as_ptr(), None), 0);
+            assert!(compare(c"abc".as_ptr(), c"ABD".as_ptr(), None) < 0);
+            assert!(compare(c"ab".as_ptr(), c"abc".as_ptr(), None) < 0);
+            assert_eq!(compare(c"abcX".as_ptr(), c"ABCy".as_ptr(), Some(3)), 0);
+            assert!(compare(std::ptr::null(), c"".as_ptr(), None) < 0);
+        }
+    }
+}
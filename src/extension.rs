@@ -0,0 +1,88 @@
+//! `sqlite3_load_extension` against a server-side engine. Nothing can be loaded into the
+//! client, but the server comes with extensions of its own, so loading one succeeds when the
+//! server has it. Applications that load `fts5` to find out whether they may use it then get
+//! the answer that holds for the database they talk to.
+
+use std::path::Path;
+
+use crate::{
+    backup::query,
+    sqlite::{SQLite3, SqliteError, Value, SQLITE_ERROR},
+};
+
+// What shows an extension is on the server: a virtual table module or a function
+enum Probe {
+    Module(&'static str),
+    Function(&'static str),
+}
+
+// Extensions the server may provide, under the names applications load them by
+const KNOWN: [(&str, Probe); 8] = [
+    ("fts5", Probe::Module("fts5")),
+    ("fts4", Probe::Module("fts4")),
+    ("fts3", Probe::Module("fts3")),
+    ("rtree", Probe::Module("rtree")),
+    ("vector", Probe::Function("vector_distance_cos")),
+    ("crypto", Probe::Function("crypto_sha256")),
+    ("uuid", Probe::Function("uuid4")),
+    ("regexp", Probe::Function("regexp")),
+];
+
+/// The name of the extension in `file`, without its directory, `lib` prefix and suffix:
+/// `/usr/lib/libfts5.so` is `fts5`.
+pub fn name(file: &str) -> String {
+    let stem = Path::new(file)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(file);
+    let stem = match stem.rsplit_once('.') {
+        Some((stem, "so" | "dylib" | "dll")) => stem,
+        _ => stem,
+    };
+    stem.strip_prefix("lib")
+        .unwrap_or(stem)
+        .to_ascii_lowercase()
+}
+
+/// Succeeds when the server has the extension in `file`. An extension this does not know
+/// is looked for as a module or function of its own name.
+pub async fn probe(db: &SQLite3, file: &str) -> Result<(), SqliteError> {
+    let name = name(file);
+    let (sql, lookup) = match KNOWN.iter().find(|(known, _)| *known == name) {
+        Some((_, Probe::Module(module))) => {
+            ("SELECT 1 FROM pragma_module_list WHERE name = ?", *module)
+        }
+        Some((_, Probe::Function(function))) => (
+            "SELECT 1 FROM pragma_function_list WHERE name = ?",
+            *function,
+        ),
+        None => (
+            "SELECT 1 FROM pragma_module_list WHERE name = ?1 \
+             UNION ALL SELECT 1 FROM pragma_function_list WHERE name = ?1",
+            name.as_str(),
+        ),
+    };
+
+    let db = db as *const SQLite3 as *mut SQLite3;
+    let found = query(db, sql, vec![Value::Text(lookup.to_string())]).await?;
+    if found.result_rows.lock().unwrap().is_empty() {
+        return Err(SqliteError::new(
+            format!("Extension '{}' is not available on the server", name),
+            Some(SQLITE_ERROR),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_drop_path_prefix_and_suffix() {
+        assert_eq!(name("fts5"), "fts5");
+        assert_eq!(name("/usr/lib/libfts5.so"), "fts5");
+        assert_eq!(name("./ext/vector.dylib"), "vector");
+        assert_eq!(name("Regexp"), "regexp");
+    }
+}
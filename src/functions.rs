@@ -0,0 +1,436 @@
+//! Scalar functions registered with `sqlite3_create_function_v2`. The server has never heard
+//! of them, so a SELECT using one as a whole result column is rewritten to fetch the call's
+//! arguments instead, and the function runs over the fetched rows. Any other use is refused.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, c_uint, c_void},
+    sync::Arc,
+};
+
+use crate::{
+    coercion::CText,
+    sql::{
+        select::{
+            closing_paren, is_keyword, projection, spanned_tokens, split_commas, Projection,
+            Spanned,
+        },
+        tokenizer::Token,
+    },
+    sqlite::{SQLite3, SQLite3PreparedStmt, SqliteError, Value, SQLITE_ERROR},
+};
+
+pub type ScalarCallback =
+    unsafe extern "C" fn(context: *mut FunctionContext, argc: c_int, argv: *mut *mut FunctionArg);
+pub type DestroyCallback = unsafe extern "C" fn(app: *mut c_void);
+
+/// The subtype SQLite's JSON functions give the JSON text they return, `'J'`.
+pub const JSON_SUBTYPE: c_uint = b'J' as c_uint;
+
+// Functions of SQLite's JSON1 whose text result is always JSON
+const JSON_FUNCTIONS: [&str; 11] = [
+    "json",
+    "json_array",
+    "json_object",
+    "json_insert",
+    "json_replace",
+    "json_set",
+    "json_remove",
+    "json_patch",
+    "json_quote",
+    "json_group_array",
+    "json_group_object",
+];
+
+/// `sqlite3_context` handed to a scalar function while it runs.
+pub struct FunctionContext {
+    pub app: *mut c_void,
+    pub db: *mut SQLite3,
+    pub result: Value,
+    pub subtype: c_uint, // From sqlite3_result_subtype
+    pub error: Option<String>,
+}
+
+/// `sqlite3_value` for one argument. The text form is built up front so the pointer
+/// returned by `sqlite3_value_text` stays valid for the whole call.
+pub struct FunctionArg {
+    pub value: Value,
+    pub text: Option<CText>,
+    pub subtype: c_uint, // JSON_SUBTYPE for JSON text, as SQLite would have passed it
+}
+
+impl FunctionArg {
+    fn new(value: Value, json: JsonArg) -> Self {
+        let text = CText::new(&value);
+        let is_json = match (&value, json) {
+            (Value::Text(_), JsonArg::Always) => true,
+            (Value::Text(text), JsonArg::Container) => text.starts_with(['[', '{']),
+            _ => false,
+        };
+
+        Self {
+            value,
+            text,
+            subtype: if is_json { JSON_SUBTYPE } else { 0 },
+        }
+    }
+}
+
+/// Whether an argument the server computed is JSON text. Values carry no subtype over the
+/// wire, so it is told from the argument's expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JsonArg {
+    No,
+    Always,    // A JSON1 function building JSON, or the `->` operator
+    Container, // json_extract(), which returns JSON only for objects and arrays
+}
+
+impl JsonArg {
+    fn of(tokens: &[Spanned]) -> Self {
+        let arrow = tokens.windows(3).any(|window| {
+            matches!(
+                [&window[0].token, &window[1].token, &window[2].token],
+                [Token::Punct("-"), Token::Punct(">"), next] if *next != Token::Punct(">")
+            ) && window[0].end == window[1].start
+        });
+        let called = match tokens {
+            [Spanned {
+                token: Token::Identifier(name),
+                ..
+            }, Spanned {
+                token: Token::Punct("("),
+                ..
+            }, ..]
+                if closing_paren(tokens, 1) == Some(tokens.len() - 1) =>
+            {
+                Some(name.to_ascii_lowercase())
+            }
+            _ => None,
+        };
+
+        match called.as_deref() {
+            _ if arrow => Self::Always,
+            Some(name) if JSON_FUNCTIONS.contains(&name) => Self::Always,
+            Some("json_extract") => Self::Container,
+            _ => Self::No,
+        }
+    }
+}
+
+pub struct ScalarFunction {
+    app: *mut c_void,
+    callback: ScalarCallback,
+    destroy: Option<DestroyCallback>,
+}
+
+impl ScalarFunction {
+    pub fn new(
+        app: *mut c_void,
+        callback: ScalarCallback,
+        destroy: Option<DestroyCallback>,
+    ) -> Self {
+        Self {
+            app,
+            callback,
+            destroy,
+        }
+    }
+
+    fn call(
+        &self,
+        db: *mut SQLite3,
+        args: &[Value],
+        json: &[JsonArg],
+    ) -> Result<Value, SqliteError> {
+        let mut args: Vec<FunctionArg> = args
+            .iter()
+            .zip(json)
+            .map(|(value, &json)| FunctionArg::new(value.clone(), json))
+            .collect();
+        let mut argv: Vec<*mut FunctionArg> = args.iter_mut().map(|arg| arg as *mut _).collect();
+        let mut context = FunctionContext {
+            app: self.app,
+            db,
+            result: Value::Null,
+            subtype: 0,
+            error: None,
+        };
+
+        unsafe { (self.callback)(&mut context, argv.len() as c_int, argv.as_mut_ptr()) };
+
+        if let Some(message) = context.error {
+            return Err(SqliteError::new(message, Some(SQLITE_ERROR)));
+        }
+        // JSON text cannot hold a raw NUL, SQLite's json() refuses it too
+        match &context.result {
+            Value::Text(text) if context.subtype == JSON_SUBTYPE && text.contains('\0') => {
+                Err(SqliteError::new("malformed JSON", Some(SQLITE_ERROR)))
+            }
+            _ => Ok(context.result),
+        }
+    }
+}
+
+// As in SQLite, the function runs on whichever thread steps the statement
+unsafe impl Send for ScalarFunction {}
+unsafe impl Sync for ScalarFunction {}
+
+impl Drop for ScalarFunction {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.app) };
+        }
+    }
+}
+
+/// Registered functions keyed by lowercased name and argument count, -1 taking any count.
+pub type FunctionRegistry = HashMap<(String, c_int), Arc<ScalarFunction>>;
+
+fn lookup(registry: &FunctionRegistry, name: &str, argc: usize) -> Option<Arc<ScalarFunction>> {
+    registry
+        .get(&(name.to_string(), argc as c_int))
+        .or_else(|| registry.get(&(name.to_string(), -1)))
+        .cloned()
+}
+
+enum Output {
+    Column {
+        star: bool,
+    }, // Passed through, `*` and `t.*` standing for several columns
+    Call {
+        function: Arc<ScalarFunction>,
+        json: Vec<JsonArg>, // One per argument
+        name: String,
+    },
+}
+
+/// A SELECT with its client-side calls replaced by their arguments.
+pub struct EmulatedQuery {
+    pub sql: String,
+    outputs: Vec<Output>,
+}
+
+impl EmulatedQuery {
+    /// Replaces the fetched argument columns with the results of the calls.
+    pub fn apply(&self, stmt: &mut SQLite3PreparedStmt) -> Result<(), SqliteError> {
+        let widths = self.widths(stmt.column_names.len());
+        let mut rows = stmt.result_rows.lock().unwrap();
+
+        for row in rows.iter_mut() {
+            let mut values = Vec::with_capacity(self.outputs.len());
+            let mut cursor = 0;
+            for (output, &width) in self.outputs.iter().zip(&widths) {
+                let fetched = row.get(cursor..cursor + width).unwrap_or_default();
+                cursor += width;
+                match output {
+                    Output::Column { .. } => values.extend_from_slice(fetched),
+                    Output::Call { function, json, .. } => {
+                        values.push(function.call(stmt.db, fetched, json)?)
+                    }
+                }
+            }
+            *row = values;
+        }
+        drop(rows);
+
+        stmt.column_names = self.column_names(&stmt.column_names);
+        stmt.remember_column_names();
+        Ok(())
+    }
+
+    /// Column names of the original statement, given those of the rewritten one.
+    pub fn column_names(&self, fetched: &[String]) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut cursor = 0;
+        for (output, width) in self.outputs.iter().zip(self.widths(fetched.len())) {
+            match output {
+                Output::Column { .. } => {
+                    names.extend_from_slice(fetched.get(cursor..cursor + width).unwrap_or_default())
+                }
+                Output::Call { name, .. } => names.push(name.clone()),
+            }
+            cursor += width;
+        }
+        names
+    }
+
+    // Fetched columns behind each output; at most one star column takes whatever is left
+    fn widths(&self, fetched: usize) -> Vec<usize> {
+        let fixed: usize = self
+            .outputs
+            .iter()
+            .map(|output| match output {
+                Output::Column { star: true } => 0,
+                Output::Column { star: false } => 1,
+                Output::Call { json, .. } => json.len(),
+            })
+            .sum();
+
+        self.outputs
+            .iter()
+            .map(|output| match output {
+                Output::Column { star: true } => fetched.saturating_sub(fixed),
+                Output::Column { star: false } => 1,
+                Output::Call { json, .. } => json.len(),
+            })
+            .collect()
+    }
+}
+
+fn unsupported(name: &str) -> SqliteError {
+    SqliteError::new(
+        format!(
+            "function {}() is registered on the client and can only be used as a whole result column of a SELECT",
+            name
+        ),
+        Some(SQLITE_ERROR),
+    )
+}
+
+/// Plans the client-side evaluation of registered functions in `sql`. `None` when it calls
+/// none of them and can go to the server untouched.
+pub fn plan(db: &SQLite3, sql: &str) -> Result<Option<EmulatedQuery>, SqliteError> {
+    let registry = db.functions.lock().unwrap();
+    if registry.is_empty() {
+        return Ok(None);
+    }
+
+    rewrite(&registry, sql)
+}
+
+fn rewrite(registry: &FunctionRegistry, sql: &str) -> Result<Option<EmulatedQuery>, SqliteError> {
+    let tokens = spanned_tokens(sql);
+
+    let registered = |i: usize| match tokens[i].token {
+        Token::Identifier(word)
+            if matches!(tokens.get(i + 1).map(|t| &t.token), Some(Token::Punct("("))) =>
+        {
+            let name = word.to_ascii_lowercase();
+            registry.keys().any(|(n, _)| *n == name).then_some(name)
+        }
+        _ => None,
+    };
+    let calls: Vec<usize> = (0..tokens.len())
+        .filter(|&i| registered(i).is_some())
+        .collect();
+    let Some(&first_call) = calls.first() else {
+        return Ok(None);
+    };
+    let first_name = registered(first_call).unwrap_or_default();
+
+    let Some(Projection {
+        begin,
+        end,
+        distinct: false,
+    }) = projection(&tokens)
+    else {
+        return Err(unsupported(&first_name));
+    };
+
+    let text = |from: usize, to: usize| &sql[tokens[from].start..tokens[to - 1].end];
+    let mut outputs = Vec::new();
+    let mut columns = Vec::new();
+    let mut emulated = Vec::new(); // Calls handled here, any other is refused
+
+    for (start, stop) in split_commas(&tokens, begin, end) {
+        let call = registered(start).and_then(|name| {
+            let close = closing_paren(&tokens, start + 1)?;
+            let alias = match &tokens[close + 1..stop] {
+                [] => None,
+                [alias] => Some(alias),
+                [keyword, alias] if is_keyword(&keyword.token, "AS") => Some(alias),
+                _ => return None,
+            };
+            if alias.is_some_and(|alias| !matches!(alias.token, Token::Identifier(_))) {
+                return None;
+            }
+            Some((name, close, alias))
+        });
+
+        let Some((name, close, alias)) = call else {
+            let star = matches!(tokens[stop - 1].token, Token::Punct("*"));
+            outputs.push(Output::Column { star });
+            columns.push(text(start, stop).to_string());
+            continue;
+        };
+
+        let args = split_commas(&tokens, start + 2, close);
+        let function = lookup(registry, &name, args.len()).ok_or_else(|| {
+            SqliteError::new(
+                format!("wrong number of arguments to function {}()", name),
+                Some(SQLITE_ERROR),
+            )
+        })?;
+
+        emulated.push(start);
+        columns.extend(args.iter().map(|&(from, to)| text(from, to).to_string()));
+        outputs.push(Output::Call {
+            function,
+            json: args
+                .iter()
+                .map(|&(from, to)| JsonArg::of(&tokens[from..to]))
+                .collect(),
+            name: match alias {
+                Some(alias) => alias_name(&sql[alias.start..alias.end]),
+                None => text(start, close + 1).to_string(),
+            },
+        });
+    }
+
+    if let Some(&other) = calls.iter().find(|i| !emulated.contains(i)) {
+        return Err(unsupported(&registered(other).unwrap_or_default()));
+    }
+    let stars = outputs
+        .iter()
+        .filter(|o| matches!(o, Output::Column { star: true }))
+        .count();
+    if stars > 1 {
+        return Err(SqliteError::new(
+            "functions registered on the client cannot be combined with more than one * column",
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    // A projection left without columns still has to produce one row per source row
+    let projection = if columns.is_empty() {
+        "NULL".to_string()
+    } else {
+        columns.join(", ")
+    };
+    let head = &sql[..tokens[begin - 1].end];
+    let tail = match tokens.get(end) {
+        Some(token) => &sql[token.start..],
+        None => "",
+    };
+
+    Ok(Some(EmulatedQuery {
+        sql: format!("{} {} {}", head, projection, tail)
+            .trim_end()
+            .to_string(),
+        outputs,
+    }))
+}
+
+// Alias as SQLite reports it: quotes removed, case kept
+fn alias_name(alias: &str) -> String {
+    match alias.chars().next() {
+        Some(quote @ ('"' | '`')) => alias[1..alias.len().saturating_sub(1).max(1)]
+            .replace(&format!("{0}{0}", quote), &quote.to_string()),
+        Some('[') => alias[1..alias.len().saturating_sub(1).max(1)].to_string(),
+        _ => alias.to_string(),
+    }
+}
+
+/// Copies `len` bytes of `text` (up to the NUL when negative) into an owned string.
+pub unsafe fn text_arg(text: *const c_char, len: c_int) -> String {
+    if text.is_null() {
+        return String::new();
+    }
+    if len < 0 {
+        return std::ffi::CStr::from_ptr(text)
+            .to_string_lossy()
+            .into_owned();
+    }
+    let bytes = std::slice::from_raw_parts(text as *const u8, len as usize);
+    String::from_utf8_lossy(bytes).into_owned()
+}
@@ -0,0 +1,135 @@
+//! Scripts prepared one statement at a time. SQLite compiles only the first statement of the
+//! text it is given and points `pz_tail` at the rest, so drivers running a migration prepare,
+//! step and finalize each statement in turn. The statements of one script form a group. With
+//! `turso.batch_scripts` on, stepping one also sends the statements after it that the caller
+//! has already prepared and that need nothing from it, in the same request and on the same
+//! stream, and their results wait here for their own steps. Text the caller has not prepared
+//! never runs.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    protocol::RemoteSqliteResponse,
+    sql::tokenizer::{is_empty_statement, statement_end},
+    sqlite::{SQLite3, SqliteError},
+};
+
+#[derive(Debug)]
+pub struct StatementGroup {
+    script: String,
+    ends: Vec<usize>,                // Where each statement ends, its `;` included
+    sent: Mutex<usize>,              // Statements before this index have been sent
+    prepared: Mutex<HashSet<usize>>, // Statements the caller holds, not finalized yet
+    results: Mutex<HashMap<usize, Result<RemoteSqliteResponse, SqliteError>>>,
+}
+
+impl StatementGroup {
+    /// Splits `script` into its statements. `None` for a single statement, which has
+    /// nothing to run ahead of.
+    pub fn split(script: &str) -> Option<Arc<Self>> {
+        let mut ends = Vec::new();
+        let mut start = 0;
+        while start < script.len() {
+            start += statement_end(&script[start..]);
+            ends.push(start);
+        }
+        // Whitespace or comments after the last `;` are not a statement of their own
+        if let [.., last, tail] = ends[..] {
+            if is_empty_statement(&script[last..tail]) {
+                ends.pop();
+            }
+        }
+
+        (ends.len() > 1).then(|| {
+            Arc::new(Self {
+                script: script.to_string(),
+                ends,
+                sent: Mutex::new(0),
+                prepared: Mutex::new(HashSet::new()),
+                results: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn statement(&self, index: usize) -> &str {
+        &self.script[self.start(index)..self.ends[index]]
+    }
+
+    /// The script from the statement at `index` on, which is what `pz_tail` pointed the
+    /// caller at after the statement before it.
+    pub fn rest(&self, index: usize) -> &str {
+        &self.script[self.start(index)..]
+    }
+
+    fn start(&self, index: usize) -> usize {
+        index.checked_sub(1).map_or(0, |before| self.ends[before])
+    }
+
+    /// Records that the caller prepared the statement at `index`, so it may run ahead.
+    pub fn enter(&self, index: usize) {
+        self.prepared.lock().unwrap().insert(index);
+    }
+
+    /// Records that the statement at `index` was finalized.
+    pub fn leave(&self, index: usize) {
+        self.prepared.lock().unwrap().remove(&index);
+    }
+
+    pub fn is_prepared(&self, index: usize) -> bool {
+        self.prepared.lock().unwrap().contains(&index)
+    }
+
+    /// Claims the statements from `index` up to `end` for a request about to send them,
+    /// leaving out any already sent, so none runs ahead twice.
+    pub fn claim(&self, index: usize, end: usize) -> Range<usize> {
+        let mut sent = self.sent.lock().unwrap();
+        let start = index.max(*sent);
+        let range = start..end.max(start);
+        *sent = range.end;
+        range
+    }
+
+    /// Keeps what the statements starting at `index` returned until they are stepped.
+    /// Statements skipped after an earlier one failed have no result and run on their own.
+    pub fn store(
+        &self,
+        index: usize,
+        results: impl IntoIterator<Item = Option<Result<RemoteSqliteResponse, SqliteError>>>,
+    ) {
+        let mut stored = self.results.lock().unwrap();
+        for (offset, result) in results.into_iter().enumerate() {
+            if let Some(result) = result {
+                stored.insert(index + offset, result);
+            }
+        }
+    }
+
+    /// The result of the statement at `index` if it ran ahead of its step.
+    pub fn take(&self, index: usize) -> Option<Result<RemoteSqliteResponse, SqliteError>> {
+        self.results.lock().unwrap().remove(&index)
+    }
+}
+
+/// The group of a statement prepared through `pz_tail`: the script `db` last split when `sql`
+/// is the rest of it the caller was pointed at, else a new script `sql` starts. `None` when
+/// `sql` holds a single statement.
+pub fn join(db: &SQLite3, sql: &str) -> Option<(Arc<StatementGroup>, usize)> {
+    let mut script = db.script.lock().unwrap();
+    let member = match script.as_ref() {
+        Some((group, index)) if group.rest(*index) == sql => Some((group.clone(), *index)),
+        _ => StatementGroup::split(sql).map(|group| (group, 0)),
+    };
+
+    if let Some((group, index)) = &member {
+        *script = (index + 1 < group.len()).then(|| (group.clone(), index + 1));
+    }
+    member
+}
@@ -0,0 +1,691 @@
+//! The SQLite database file format, enough to write an image of rows fetched from the server
+//! and to read the rows of one back. Images are written with 4096 byte pages, no freelist and
+//! b-trees packed bottom-up, which SQLite opens like any file it wrote itself.
+
+use crate::sqlite::{SqliteError, Value, SQLITE_CORRUPT, SQLITE_ERROR, SQLITE_NOTADB};
+
+pub const PAGE_SIZE: usize = 4096;
+
+const HEADER_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const HEADER_SIZE: usize = 100;
+
+const INTERIOR_INDEX: u8 = 0x02;
+const INTERIOR_TABLE: u8 = 0x05;
+const LEAF_INDEX: u8 = 0x0a;
+const LEAF_TABLE: u8 = 0x0d;
+
+/// One row of `sqlite_schema`.
+pub struct SchemaEntry {
+    pub kind: String,
+    pub name: String,
+    pub table: String,
+    pub rootpage: u32,
+    pub sql: Option<String>,
+}
+
+fn corrupt() -> SqliteError {
+    SqliteError::new("database disk image is malformed", Some(SQLITE_CORRUPT))
+}
+
+fn put_varint(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0u8; 9];
+    if value > 0x00ff_ffff_ffff_ffff {
+        bytes[8] = value as u8;
+        let mut rest = value >> 8;
+        for byte in bytes[..8].iter_mut().rev() {
+            *byte = (rest & 0x7f) as u8 | 0x80;
+            rest >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return;
+    }
+
+    // Low bits first, then reversed; only the last byte written has its high bit clear
+    let mut len = 0;
+    let mut rest = value;
+    loop {
+        bytes[len] = (rest & 0x7f) as u8 | 0x80;
+        len += 1;
+        rest >>= 7;
+        if rest == 0 {
+            break;
+        }
+    }
+    bytes[0] &= 0x7f;
+    out.extend(bytes[..len].iter().rev());
+}
+
+fn get_varint(data: &[u8]) -> Result<(u64, usize), SqliteError> {
+    let mut value = 0u64;
+    for i in 0..8 {
+        let byte = *data.get(i).ok_or_else(corrupt)?;
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    let byte = *data.get(8).ok_or_else(corrupt)?;
+    Ok(((value << 8) | byte as u64, 9))
+}
+
+fn varint_len(value: u64) -> usize {
+    let mut out = Vec::with_capacity(9);
+    put_varint(&mut out, value);
+    out.len()
+}
+
+/// Serial type and big-endian width of an integer, zero and one costing no body bytes.
+fn integer_type(i: i64) -> (u64, usize) {
+    match i {
+        0 => (8, 0),
+        1 => (9, 0),
+        -0x80..=0x7f => (1, 1),
+        -0x8000..=0x7fff => (2, 2),
+        -0x80_0000..=0x7f_ffff => (3, 3),
+        -0x8000_0000..=0x7fff_ffff => (4, 4),
+        -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+        _ => (6, 8),
+    }
+}
+
+pub fn encode_record(values: &[Value]) -> Vec<u8> {
+    let mut types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        match value {
+            Value::Null => put_varint(&mut types, 0),
+            Value::Integer(i) => {
+                let (serial, width) = integer_type(*i);
+                put_varint(&mut types, serial);
+                body.extend_from_slice(&i.to_be_bytes()[8 - width..]);
+            }
+            Value::Real(f) => {
+                put_varint(&mut types, 7);
+                body.extend_from_slice(&f.to_bits().to_be_bytes());
+            }
+            Value::Text(s) => {
+                put_varint(&mut types, 13 + 2 * s.len() as u64);
+                body.extend_from_slice(s.as_bytes());
+            }
+            Value::Blob(b) => {
+                put_varint(&mut types, 12 + 2 * b.len() as u64);
+                body.extend_from_slice(b);
+            }
+        }
+    }
+
+    // The header size counts its own varint
+    let mut header_size = types.len() + 1;
+    while types.len() + varint_len(header_size as u64) != header_size {
+        header_size = types.len() + varint_len(header_size as u64);
+    }
+
+    let mut record = Vec::with_capacity(header_size + body.len());
+    put_varint(&mut record, header_size as u64);
+    record.extend(types);
+    record.extend(body);
+    record
+}
+
+pub fn decode_record(record: &[u8]) -> Result<Vec<Value>, SqliteError> {
+    let (header_size, mut cursor) = get_varint(record)?;
+    let header_size = header_size as usize;
+    if header_size > record.len() {
+        return Err(corrupt());
+    }
+
+    let mut body = header_size;
+    let mut values = Vec::new();
+    while cursor < header_size {
+        let (serial, len) = get_varint(&record[cursor..header_size])?;
+        cursor += len;
+
+        let width = match serial {
+            0 | 8 | 9 => 0,
+            1..=4 => serial as usize,
+            5 => 6,
+            6 | 7 => 8,
+            10 | 11 => return Err(corrupt()),
+            n => ((n - 12) / 2) as usize,
+        };
+        let bytes = record.get(body..body + width).ok_or_else(corrupt)?;
+        body += width;
+
+        values.push(match serial {
+            0 => Value::Null,
+            8 => Value::Integer(0),
+            9 => Value::Integer(1),
+            1..=6 => {
+                // Sign-extend the big-endian integer to 64 bits
+                let fill = if bytes[0] & 0x80 != 0 { 0xff } else { 0 };
+                let mut wide = [fill; 8];
+                wide[8 - width..].copy_from_slice(bytes);
+                Value::Integer(i64::from_be_bytes(wide))
+            }
+            7 => Value::Real(f64::from_bits(u64::from_be_bytes(
+                bytes.try_into().map_err(|_| corrupt())?,
+            ))),
+            n if n % 2 == 1 => Value::Text(String::from_utf8_lossy(bytes).into_owned()),
+            _ => Value::Blob(bytes.to_vec()),
+        });
+    }
+
+    Ok(values)
+}
+
+// Bytes of a payload kept on the b-tree page, the rest going to overflow pages
+fn local_size(payload: usize, usable: usize, max_local: usize) -> usize {
+    if payload <= max_local {
+        return payload;
+    }
+    let min_local = (usable - 12) * 32 / 255 - 23;
+    let spilled = min_local + (payload - min_local) % (usable - 4);
+    if spilled <= max_local {
+        spilled
+    } else {
+        min_local
+    }
+}
+
+fn table_max_local(usable: usize) -> usize {
+    usable - 35
+}
+
+fn index_max_local(usable: usize) -> usize {
+    (usable - 12) * 64 / 255 - 23
+}
+
+/// Builds an image page by page. Page 1 is held back for the schema, written last since it
+/// records the root page of every other b-tree.
+pub struct ImageWriter {
+    pages: Vec<Vec<u8>>,
+}
+
+impl Default for ImageWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageWriter {
+    pub fn new() -> Self {
+        Self {
+            pages: vec![vec![0; PAGE_SIZE]],
+        }
+    }
+
+    fn allocate(&mut self) -> u32 {
+        self.pages.push(vec![0; PAGE_SIZE]);
+        self.pages.len() as u32
+    }
+
+    // Local part of a payload followed, when it spills, by the first overflow page
+    fn spill(&mut self, payload: &[u8], max_local: usize) -> Vec<u8> {
+        let local = local_size(payload.len(), PAGE_SIZE, max_local);
+        let mut out = payload[..local].to_vec();
+        if local == payload.len() {
+            return out;
+        }
+
+        let chunks: Vec<&[u8]> = payload[local..].chunks(PAGE_SIZE - 4).collect();
+        let first = self.pages.len() as u32 + 1;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = if i + 1 < chunks.len() {
+                first + i as u32 + 1
+            } else {
+                0
+            };
+            let page = self.allocate() as usize - 1;
+            self.pages[page][..4].copy_from_slice(&next.to_be_bytes());
+            self.pages[page][4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        out.extend_from_slice(&first.to_be_bytes());
+        out
+    }
+
+    fn write_page(&mut self, number: u32, kind: u8, cells: &[Vec<u8>], right: Option<u32>) {
+        let page = &mut self.pages[number as usize - 1];
+        let offset = if number == 1 { HEADER_SIZE } else { 0 };
+        let header = if right.is_some() { 12 } else { 8 };
+
+        let mut content = PAGE_SIZE;
+        for (i, cell) in cells.iter().enumerate() {
+            content -= cell.len();
+            page[content..content + cell.len()].copy_from_slice(cell);
+            let pointer = offset + header + 2 * i;
+            page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+
+        page[offset] = kind;
+        page[offset + 3..offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        page[offset + 5..offset + 7].copy_from_slice(&(content as u16).to_be_bytes());
+        if let Some(right) = right {
+            page[offset + 8..offset + 12].copy_from_slice(&right.to_be_bytes());
+        }
+    }
+
+    // Room for cells and their pointers. A b-tree rooted on page 1 uses the smaller page 1
+    // capacity throughout, so whatever does not fit the root always splits into two pages.
+    fn capacity(on_first_page: bool, interior: bool) -> usize {
+        let header = if interior { 12 } else { 8 };
+        let offset = if on_first_page { HEADER_SIZE } else { 0 };
+        PAGE_SIZE - offset - header
+    }
+
+    fn place(&mut self, root: Option<u32>) -> u32 {
+        root.unwrap_or_else(|| self.allocate())
+    }
+
+    /// Writes a table b-tree of rows sorted by rowid and returns its root page.
+    pub fn table(&mut self, rows: Vec<(i64, Vec<Value>)>) -> u32 {
+        self.table_tree(rows, None)
+    }
+
+    fn table_tree(&mut self, rows: Vec<(i64, Vec<Value>)>, root: Option<u32>) -> u32 {
+        let on_first_page = root == Some(1);
+        let cells: Vec<(i64, Vec<u8>)> = rows
+            .into_iter()
+            .map(|(rowid, values)| {
+                let payload = encode_record(&values);
+                let mut cell = Vec::new();
+                put_varint(&mut cell, payload.len() as u64);
+                put_varint(&mut cell, rowid as u64);
+                cell.extend(self.spill(&payload, table_max_local(PAGE_SIZE)));
+                (rowid, cell)
+            })
+            .collect();
+
+        let capacity = Self::capacity(on_first_page, false);
+        if cells.iter().map(|(_, cell)| cell.len() + 2).sum::<usize>() <= capacity {
+            let root = self.place(root);
+            let cells: Vec<Vec<u8>> = cells.into_iter().map(|(_, cell)| cell).collect();
+            self.write_page(root, LEAF_TABLE, &cells, None);
+            return root;
+        }
+
+        // Children of the next level up, each with the largest rowid under it
+        let mut children: Vec<(u32, i64)> = Vec::new();
+        let mut page: Vec<Vec<u8>> = Vec::new();
+        let mut used = 0;
+        let mut last_rowid = 0;
+        for (rowid, cell) in cells {
+            if used + cell.len() + 2 > capacity {
+                let number = self.allocate();
+                self.write_page(number, LEAF_TABLE, &page, None);
+                children.push((number, last_rowid));
+                page.clear();
+                used = 0;
+            }
+            used += cell.len() + 2;
+            last_rowid = rowid;
+            page.push(cell);
+        }
+        let number = self.allocate();
+        self.write_page(number, LEAF_TABLE, &page, None);
+        children.push((number, last_rowid));
+
+        let capacity = Self::capacity(on_first_page, true);
+        loop {
+            let cells: Vec<Vec<u8>> = children
+                .iter()
+                .map(|&(child, key)| {
+                    let mut cell = child.to_be_bytes().to_vec();
+                    put_varint(&mut cell, key as u64);
+                    cell
+                })
+                .collect();
+
+            let (last, _) = children[children.len() - 1];
+            let size: usize = cells[..cells.len() - 1].iter().map(|c| c.len() + 2).sum();
+            if size <= capacity {
+                let root = self.place(root);
+                self.write_page(root, INTERIOR_TABLE, &cells[..cells.len() - 1], Some(last));
+                return root;
+            }
+
+            let mut parents = Vec::new();
+            for group in pack(&cells, capacity) {
+                let number = self.allocate();
+                let right = children[*group.end()];
+                self.write_page(
+                    number,
+                    INTERIOR_TABLE,
+                    &cells[*group.start()..*group.end()],
+                    Some(right.0),
+                );
+                parents.push((number, right.1));
+            }
+            children = parents;
+        }
+    }
+
+    /// Writes an index b-tree of records sorted the way the index orders them and returns
+    /// its root page.
+    pub fn index(&mut self, entries: Vec<Vec<Value>>) -> u32 {
+        let cells: Vec<Vec<u8>> = entries
+            .iter()
+            .map(|values| {
+                let payload = encode_record(values);
+                let mut cell = Vec::new();
+                put_varint(&mut cell, payload.len() as u64);
+                cell.extend(self.spill(&payload, index_max_local(PAGE_SIZE)));
+                cell
+            })
+            .collect();
+
+        let capacity = Self::capacity(false, false);
+        if cells.iter().map(|cell| cell.len() + 2).sum::<usize>() <= capacity {
+            let root = self.allocate();
+            self.write_page(root, LEAF_INDEX, &cells, None);
+            return root;
+        }
+
+        // Entries live in exactly one page; the one between two leaves moves up a level
+        let mut children: Vec<u32> = Vec::new();
+        let mut separators: Vec<Vec<u8>> = Vec::new();
+        let mut page: Vec<Vec<u8>> = Vec::new();
+        let mut used = 0;
+        let count = cells.len();
+        for (i, cell) in cells.into_iter().enumerate() {
+            if used + cell.len() + 2 > capacity {
+                // The last entry still needs a separator before it, so the full leaf gives up
+                // its own last entry. Cells are small enough that it always holds several.
+                let last = i + 1 == count;
+                let separator = if last { page.pop() } else { None };
+                let number = self.allocate();
+                self.write_page(number, LEAF_INDEX, &page, None);
+                children.push(number);
+                page.clear();
+                used = 0;
+                match separator {
+                    Some(separator) => separators.push(separator),
+                    None => {
+                        separators.push(cell);
+                        continue;
+                    }
+                }
+            }
+            used += cell.len() + 2;
+            page.push(cell);
+        }
+        let number = self.allocate();
+        self.write_page(number, LEAF_INDEX, &page, None);
+        children.push(number);
+
+        let capacity = Self::capacity(false, true);
+        loop {
+            let cells: Vec<Vec<u8>> = separators
+                .iter()
+                .zip(&children)
+                .map(|(separator, child)| {
+                    let mut cell = child.to_be_bytes().to_vec();
+                    cell.extend_from_slice(separator);
+                    cell
+                })
+                .collect();
+
+            let last = children[children.len() - 1];
+            if cells.iter().map(|c| c.len() + 2).sum::<usize>() <= capacity {
+                let root = self.allocate();
+                self.write_page(root, INTERIOR_INDEX, &cells, Some(last));
+                return root;
+            }
+
+            let mut parents = Vec::new();
+            let mut lifted = Vec::new();
+            // `pack` sees one cell per child but the last, which only becomes a right pointer
+            let mut padded = cells.clone();
+            padded.push(Vec::new());
+            for group in pack(&padded, capacity) {
+                let number = self.allocate();
+                let right = children[*group.end()];
+                self.write_page(
+                    number,
+                    INTERIOR_INDEX,
+                    &cells[*group.start()..*group.end()],
+                    Some(right),
+                );
+                parents.push(number);
+                if *group.end() < separators.len() {
+                    lifted.push(separators[*group.end()].clone());
+                }
+            }
+            children = parents;
+            separators = lifted;
+        }
+    }
+
+    /// Writes the schema b-tree on page 1 and the file header, returning the whole image.
+    pub fn finish(
+        mut self,
+        schema: Vec<SchemaEntry>,
+        user_version: i64,
+        application_id: i64,
+    ) -> Vec<u8> {
+        let rows = schema
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let values = vec![
+                    Value::Text(entry.kind),
+                    Value::Text(entry.name),
+                    Value::Text(entry.table),
+                    Value::Integer(entry.rootpage as i64),
+                    entry.sql.map_or(Value::Null, Value::Text),
+                ];
+                (i as i64 + 1, values)
+            })
+            .collect();
+        self.table_tree(rows, Some(1));
+
+        let page_count = self.pages.len() as u32;
+        let header = &mut self.pages[0][..HEADER_SIZE];
+        header[..16].copy_from_slice(HEADER_MAGIC);
+        header[16..18].copy_from_slice(&(PAGE_SIZE as u16).to_be_bytes());
+        header[18] = 1; // Legacy journal, not WAL
+        header[19] = 1;
+        header[21] = 64; // Payload fractions, fixed by the format
+        header[22] = 32;
+        header[23] = 32;
+        header[24..28].copy_from_slice(&1u32.to_be_bytes()); // File change counter
+        header[28..32].copy_from_slice(&page_count.to_be_bytes());
+        header[40..44].copy_from_slice(&1u32.to_be_bytes()); // Schema cookie
+        header[44..48].copy_from_slice(&4u32.to_be_bytes()); // Schema format
+        header[56..60].copy_from_slice(&1u32.to_be_bytes()); // UTF-8
+        header[60..64].copy_from_slice(&(user_version as u32).to_be_bytes());
+        header[68..72].copy_from_slice(&(application_id as u32).to_be_bytes());
+        header[92..96].copy_from_slice(&1u32.to_be_bytes()); // Valid for change counter 1
+        header[96..100].copy_from_slice(&(crate::sqlite3_libversion_number() as u32).to_be_bytes());
+
+        self.pages.concat()
+    }
+}
+
+/// Groups the children of an interior level into pages. `cells[i]` is the cell pointing at
+/// child `i`; each group is the range of children on one page, its last child becoming the
+/// right pointer. No page is left with a right pointer alone.
+fn pack(cells: &[Vec<u8>], capacity: usize) -> Vec<std::ops::RangeInclusive<usize>> {
+    let count = cells.len();
+    let mut groups = Vec::new();
+    let mut i = 0;
+    while i < count {
+        let start = i;
+        let mut used = 0;
+        while i + 1 < count && used + cells[i].len() + 2 <= capacity {
+            used += cells[i].len() + 2;
+            i += 1;
+        }
+        if i + 2 == count && i - start >= 2 {
+            i -= 1;
+        }
+        groups.push(start..=i);
+        i += 1;
+    }
+    groups
+}
+
+/// A database image read from memory.
+pub struct Image<'a> {
+    data: &'a [u8],
+    page_size: usize,
+    usable: usize,
+}
+
+impl<'a> Image<'a> {
+    pub fn open(data: &'a [u8]) -> Result<Self, SqliteError> {
+        if data.len() < HEADER_SIZE || &data[..16] != HEADER_MAGIC {
+            return Err(SqliteError::new(
+                "file is not a database",
+                Some(SQLITE_NOTADB),
+            ));
+        }
+
+        let page_size = match u16::from_be_bytes([data[16], data[17]]) {
+            1 => 65536,
+            size => size as usize,
+        };
+        if !page_size.is_power_of_two() || page_size < 512 || data.len() < page_size {
+            return Err(corrupt());
+        }
+        if u32::from_be_bytes([data[56], data[57], data[58], data[59]]) > 1 {
+            return Err(SqliteError::new(
+                "only UTF-8 database images can be deserialized",
+                Some(SQLITE_ERROR),
+            ));
+        }
+
+        let usable = page_size - data[20] as usize;
+        if usable < 480 {
+            return Err(corrupt());
+        }
+
+        Ok(Self {
+            data,
+            page_size,
+            usable,
+        })
+    }
+
+    pub fn user_version(&self) -> i64 {
+        i32::from_be_bytes(self.data[60..64].try_into().unwrap()) as i64
+    }
+
+    pub fn application_id(&self) -> i64 {
+        i32::from_be_bytes(self.data[68..72].try_into().unwrap()) as i64
+    }
+
+    fn page(&self, number: u32) -> Result<&'a [u8], SqliteError> {
+        let start = (number as usize)
+            .checked_sub(1)
+            .ok_or_else(corrupt)?
+            .checked_mul(self.page_size)
+            .ok_or_else(corrupt)?;
+        self.data
+            .get(start..start + self.page_size)
+            .ok_or_else(corrupt)
+    }
+
+    pub fn schema(&self) -> Result<Vec<SchemaEntry>, SqliteError> {
+        let text = |value: &Value| match value {
+            Value::Text(s) => s.clone(),
+            _ => String::new(),
+        };
+
+        self.table_rows(1)?
+            .into_iter()
+            .map(|(_, row)| match row.as_slice() {
+                [kind, name, table, rootpage, sql] => Ok(SchemaEntry {
+                    kind: text(kind),
+                    name: text(name),
+                    table: text(table),
+                    rootpage: match rootpage {
+                        Value::Integer(page) => *page as u32,
+                        _ => 0,
+                    },
+                    sql: match sql {
+                        Value::Text(sql) => Some(sql.clone()),
+                        _ => None,
+                    },
+                }),
+                _ => Err(corrupt()),
+            })
+            .collect()
+    }
+
+    /// Rows of the table b-tree rooted at `root`, in rowid order.
+    pub fn table_rows(&self, root: u32) -> Result<Vec<(i64, Vec<Value>)>, SqliteError> {
+        let mut rows = Vec::new();
+        let mut pending = vec![root];
+        let mut visited = 0;
+        let page_count = self.data.len() / self.page_size;
+
+        // Children are pushed in reverse so the leftmost is read first
+        while let Some(number) = pending.pop() {
+            visited += 1;
+            if visited > page_count {
+                return Err(corrupt());
+            }
+
+            let page = self.page(number)?;
+            let offset = if number == 1 { HEADER_SIZE } else { 0 };
+            let kind = page[offset];
+            let count = u16::from_be_bytes([page[offset + 3], page[offset + 4]]) as usize;
+            let header = match kind {
+                LEAF_TABLE => 8,
+                INTERIOR_TABLE => 12,
+                _ => return Err(corrupt()),
+            };
+
+            let cell = |i: usize| -> Result<&[u8], SqliteError> {
+                let pointer = offset + header + 2 * i;
+                let at = page.get(pointer..pointer + 2).ok_or_else(corrupt)?;
+                let at = u16::from_be_bytes([at[0], at[1]]) as usize;
+                page.get(at..).ok_or_else(corrupt)
+            };
+
+            if kind == INTERIOR_TABLE {
+                let right = u32::from_be_bytes(page[offset + 8..offset + 12].try_into().unwrap());
+                pending.push(right);
+                for i in (0..count).rev() {
+                    let cell = cell(i)?;
+                    let child = cell.get(..4).ok_or_else(corrupt)?;
+                    pending.push(u32::from_be_bytes(child.try_into().unwrap()));
+                }
+                continue;
+            }
+
+            for i in 0..count {
+                let cell = cell(i)?;
+                let (payload_len, first) = get_varint(cell)?;
+                let (rowid, second) = get_varint(&cell[first..])?;
+                let payload = self.payload(&cell[first + second..], payload_len as usize)?;
+                rows.push((rowid as i64, decode_record(&payload)?));
+            }
+        }
+
+        Ok(rows)
+    }
+
+    // Local part of a table leaf payload joined with its overflow chain
+    fn payload(&self, cell: &[u8], len: usize) -> Result<Vec<u8>, SqliteError> {
+        let local = local_size(len, self.usable, table_max_local(self.usable));
+        let mut payload = cell.get(..local).ok_or_else(corrupt)?.to_vec();
+        if local == len {
+            return Ok(payload);
+        }
+
+        let next = cell.get(local..local + 4).ok_or_else(corrupt)?;
+        let mut next = u32::from_be_bytes(next.try_into().unwrap());
+        while payload.len() < len {
+            if next == 0 {
+                return Err(corrupt());
+            }
+            let page = self.page(next)?;
+            let take = (len - payload.len()).min(self.usable - 4);
+            payload.extend_from_slice(&page[4..4 + take]);
+            next = u32::from_be_bytes(page[..4].try_into().unwrap());
+        }
+
+        Ok(payload)
+    }
+}
@@ -0,0 +1,109 @@
+//! The statement journal of `PRAGMA turso.txn_replay`. A transaction lives on one server
+//! stream, and a stream does not outlive the connection that opened it: when the socket drops
+//! or the server lets the stream expire, the work done in the transaction so far is rolled
+//! back. With the journal on, every statement that ran in the transaction is kept with its
+//! arguments, so the transaction can be run again from its first statement on a new stream
+//! and the statement that found the stream gone sent once more.
+
+use std::ffi::c_int;
+
+use crate::{
+    connection_state::{
+        SQLITE_TURSO_STATE_CONNECTED, SQLITE_TURSO_STATE_DEGRADED, SQLITE_TURSO_STATE_DISCONNECTED,
+    },
+    protocol::{RemoteSqliteResponse, StatementArgs},
+    sqlite::{end_tnx_on_db, send_once, SQLite3, SqliteError, SQLITE_ABORT},
+    utils::get_execution_result,
+};
+
+#[derive(Debug)]
+pub struct Journal {
+    begin: String,                            // The statement that opened the transaction
+    statements: Vec<(String, StatementArgs)>, // Run since, in order
+}
+
+impl Journal {
+    pub fn new(begin: &str) -> Self {
+        Self {
+            begin: begin.to_string(),
+            statements: Vec::new(),
+        }
+    }
+}
+
+/// Keeps a statement the open transaction ran. One the server refused changed nothing, so
+/// there is nothing of it to run again.
+pub fn record(
+    db: &SQLite3,
+    sql: &str,
+    params: &StatementArgs,
+    result: &Result<RemoteSqliteResponse, SqliteError>,
+) {
+    if let Some(journal) = db.journal.lock().unwrap().as_mut() {
+        if result.is_ok() {
+            journal.statements.push((sql.to_string(), params.clone()));
+        }
+    }
+}
+
+/// Whether `err` means the transaction's stream is gone, rather than that its statement
+/// failed, for a transaction being journaled. `before` is the connection's state from before
+/// the request: the server stopped answering, or an `auto` connection lost its WebSocket on
+/// the way.
+pub fn lost_stream(db: &SQLite3, before: c_int, err: &SqliteError) -> bool {
+    if db.journal.lock().unwrap().is_none() {
+        return false;
+    }
+
+    let after = db.state.state();
+    let message = err.message.to_ascii_lowercase();
+    after == SQLITE_TURSO_STATE_DISCONNECTED
+        || (before == SQLITE_TURSO_STATE_CONNECTED && after == SQLITE_TURSO_STATE_DEGRADED)
+        || (message.contains("stream") && message.contains("expired"))
+        || message.contains("invalid baton")
+}
+
+/// Runs the journaled transaction again on a new stream, which is then the transaction's.
+/// The journal stays as it was, so a later loss can be recovered from too.
+pub async fn replay(db: &SQLite3) -> Result<(), SqliteError> {
+    let Some((begin, statements)) = db
+        .journal
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|journal| (journal.begin.clone(), journal.statements.clone()))
+    else {
+        return Err(SqliteError::new("No transaction to replay", None));
+    };
+    tracing::warn!(
+        statements = statements.len(),
+        "Transaction stream lost, replaying the transaction"
+    );
+
+    db.transaction_baton.lock().unwrap().take();
+    let baton = db
+        .lock_connection()
+        .await?
+        .get_transaction_baton(&begin)
+        .await?;
+    db.transaction_baton.lock().unwrap().replace(baton);
+    for (sql, params) in statements {
+        let response = send_once(db, &sql, params, false, None).await?;
+        get_execution_result(db, &response)?;
+    }
+    Ok(())
+}
+
+/// Gives up on a transaction that could not be replayed: its stream is closed, which rolls
+/// back whatever the replay got through, and the connection is left outside a transaction
+/// like SQLite leaves one after rolling back on its own.
+pub async fn abandon(db: &SQLite3, err: SqliteError) -> SqliteError {
+    let _ = end_tnx_on_db(db).await;
+    SqliteError::new(
+        format!(
+            "Transaction lost with its stream and could not be replayed: {}",
+            err.message
+        ),
+        Some(SQLITE_ABORT),
+    )
+}
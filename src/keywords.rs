@@ -0,0 +1,183 @@
+//! SQLite's keyword table, behind `sqlite3_keyword_count`, `sqlite3_keyword_name` and
+//! `sqlite3_keyword_check`. Query builders quote identifiers that collide with a keyword. The
+//! table is SQLite 3.37's, the version reported when the server's is not known.
+
+use std::ffi::CStr;
+
+/// Every keyword, in alphabetical order.
+pub const KEYWORDS: [&CStr; 147] = [
+    c"ABORT",
+    c"ACTION",
+    c"ADD",
+    c"AFTER",
+    c"ALL",
+    c"ALTER",
+    c"ALWAYS",
+    c"ANALYZE",
+    c"AND",
+    c"AS",
+    c"ASC",
+    c"ATTACH",
+    c"AUTOINCREMENT",
+    c"BEFORE",
+    c"BEGIN",
+    c"BETWEEN",
+    c"BY",
+    c"CASCADE",
+    c"CASE",
+    c"CAST",
+    c"CHECK",
+    c"COLLATE",
+    c"COLUMN",
+    c"COMMIT",
+    c"CONFLICT",
+    c"CONSTRAINT",
+    c"CREATE",
+    c"CROSS",
+    c"CURRENT",
+    c"CURRENT_DATE",
+    c"CURRENT_TIME",
+    c"CURRENT_TIMESTAMP",
+    c"DATABASE",
+    c"DEFAULT",
+    c"DEFERRABLE",
+    c"DEFERRED",
+    c"DELETE",
+    c"DESC",
+    c"DETACH",
+    c"DISTINCT",
+    c"DO",
+    c"DROP",
+    c"EACH",
+    c"ELSE",
+    c"END",
+    c"ESCAPE",
+    c"EXCEPT",
+    c"EXCLUDE",
+    c"EXCLUSIVE",
+    c"EXISTS",
+    c"EXPLAIN",
+    c"FAIL",
+    c"FILTER",
+    c"FIRST",
+    c"FOLLOWING",
+    c"FOR",
+    c"FOREIGN",
+    c"FROM",
+    c"FULL",
+    c"GENERATED",
+    c"GLOB",
+    c"GROUP",
+    c"GROUPS",
+    c"HAVING",
+    c"IF",
+    c"IGNORE",
+    c"IMMEDIATE",
+    c"IN",
+    c"INDEX",
+    c"INDEXED",
+    c"INITIALLY",
+    c"INNER",
+    c"INSERT",
+    c"INSTEAD",
+    c"INTERSECT",
+    c"INTO",
+    c"IS",
+    c"ISNULL",
+    c"JOIN",
+    c"KEY",
+    c"LAST",
+    c"LEFT",
+    c"LIKE",
+    c"LIMIT",
+    c"MATCH",
+    c"MATERIALIZED",
+    c"NATURAL",
+    c"NO",
+    c"NOT",
+    c"NOTHING",
+    c"NOTNULL",
+    c"NULL",
+    c"NULLS",
+    c"OF",
+    c"OFFSET",
+    c"ON",
+    c"OR",
+    c"ORDER",
+    c"OTHERS",
+    c"OUTER",
+    c"OVER",
+    c"PARTITION",
+    c"PLAN",
+    c"PRAGMA",
+    c"PRECEDING",
+    c"PRIMARY",
+    c"QUERY",
+    c"RAISE",
+    c"RANGE",
+    c"RECURSIVE",
+    c"REFERENCES",
+    c"REGEXP",
+    c"REINDEX",
+    c"RELEASE",
+    c"RENAME",
+    c"REPLACE",
+    c"RESTRICT",
+    c"RETURNING",
+    c"RIGHT",
+    c"ROLLBACK",
+    c"ROW",
+    c"ROWS",
+    c"SAVEPOINT",
+    c"SELECT",
+    c"SET",
+    c"TABLE",
+    c"TEMP",
+    c"TEMPORARY",
+    c"THEN",
+    c"TIES",
+    c"TO",
+    c"TRANSACTION",
+    c"TRIGGER",
+    c"UNBOUNDED",
+    c"UNION",
+    c"UNIQUE",
+    c"UPDATE",
+    c"USING",
+    c"VACUUM",
+    c"VALUES",
+    c"VIEW",
+    c"VIRTUAL",
+    c"WHEN",
+    c"WHERE",
+    c"WINDOW",
+    c"WITH",
+    c"WITHOUT",
+];
+
+/// Whether `word` is a keyword, ignoring ASCII case as SQLite does.
+pub fn is_keyword(word: &[u8]) -> bool {
+    let word = word.to_ascii_uppercase();
+    KEYWORDS
+        .binary_search_by(|keyword| keyword.to_bytes().cmp(&word))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_are_sorted_for_binary_search() {
+        assert!(KEYWORDS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn keywords_match_in_any_case() {
+        assert!(is_keyword(b"select"));
+        assert!(is_keyword(b"Current_Timestamp"));
+        assert!(!is_keyword(b"users"));
+        assert!(!is_keyword(b"SELECTS"));
+        assert!(!is_keyword(b""));
+    }
+}
@@ -1,103 +1,273 @@
+#![allow(clippy::missing_safety_doc, clippy::not_unsafe_ptr_arg_deref)]
+
 use regex::Regex;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     ffi::{c_int, c_uint, c_void, CStr, CString},
     os::raw::c_char,
     slice,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use sqlite::{
-    push_error, reset_txn_on_db, ExecutionState, SQLite3, SQLite3ExecCallback, SQLite3PreparedStmt,
-    Value, SQLITE_BUSY, SQLITE_CANTOPEN, SQLITE_DONE, SQLITE_ERROR, SQLITE_FLOAT, SQLITE_INTEGER,
-    SQLITE_MISUSE, SQLITE_NULL, SQLITE_OK, SQLITE_RANGE, SQLITE_TEXT,
+    push_error, ExecutionState, SQLite3, SQLite3ExecCallback, SQLite3PreparedStmt, Value,
+    SQLITE_AUTH, SQLITE_BLOB, SQLITE_BUSY, SQLITE_CANTOPEN, SQLITE_CONSTRAINT, SQLITE_DONE,
+    SQLITE_ERROR, SQLITE_FLOAT, SQLITE_FULL, SQLITE_INTEGER, SQLITE_IOERR, SQLITE_MISUSE,
+    SQLITE_NOTFOUND, SQLITE_NULL, SQLITE_OK, SQLITE_OPEN_CREATE, SQLITE_OPEN_FULLMUTEX,
+    SQLITE_OPEN_READWRITE, SQLITE_PERM, SQLITE_PREPARE_PERSISTENT, SQLITE_RANGE, SQLITE_READONLY,
+    SQLITE_TEXT,
 };
 
 use crate::{
-    auth::{DbAuthStrategy, EnvVarStrategy, GlobeStrategy},
+    authorizer::{authorize, Authorization, Authorizer, AuthorizerCallback},
+    backup::Backup,
+    blob::BlobHandle,
+    cache::StatementCache,
+    collation::{Collation, CompareCallback},
+    config::ConnectionOptions,
+    functions::{DestroyCallback, FunctionArg, FunctionContext, ScalarCallback, ScalarFunction},
+    schema_cache::SchemaCache,
+    session::Session,
+    slow_query::SlowQueryLog,
+    sql::tokenizer::{classify, is_empty_statement, statement_end, StatementClass},
     sqlite::get_latest_error,
+    table::Table,
     utils::{
-        count_parameters, execute_async_task, get_tokio, is_aligned, sql_is_begin_transaction,
-        sql_is_commit, sql_is_pragma, sql_is_rollback,
+        allocate, allocation_size, execute_async_task, is_aligned, parse_turso_pragma, release,
+        result_code,
     },
+    worker::{Priority, Worker},
 };
 
+mod analyzer;
+mod attach;
 mod auth;
+mod authorizer;
+mod backup;
+mod blob;
+mod cache;
+#[cfg(feature = "rust-api")]
+pub mod client;
+mod coercion;
+mod collation;
+mod compile_options;
+mod config;
+mod connection_state;
+mod cutil;
+mod extension;
+mod functions;
+mod group;
+mod image;
+mod journal;
+mod keywords;
+mod logging;
+mod metrics;
+mod prewarm;
+mod protocol;
+#[cfg(feature = "replica")]
+mod replica;
+mod result_cache;
+mod schema_cache;
+mod serialize;
+mod session;
+mod slow_query;
+mod sql;
 mod sqlite;
+mod status;
+mod status_table;
+mod table;
 mod transport;
 mod utils;
+mod vector;
+mod version;
+mod worker;
+mod write_behind;
 
 #[no_mangle]
 pub extern "C" fn sqlite3_libversion_number() -> c_int {
-    3037000 // This represents SQLite version 3.37.0
+    version::number()
+}
+
+/// Always serialized: a connection may be shared between threads, its requests are sent one
+/// at a time.
+#[no_mangle]
+pub extern "C" fn sqlite3_threadsafe() -> c_int {
+    1
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_libversion() -> *const c_char {
-    let version = CString::new("3.37.0").unwrap();
-    version.into_raw()
+    version::version().as_ptr()
 }
 
 #[no_mangle]
 pub extern "C" fn sqlite3_sourceid() -> *const c_char {
-    let id = CString::new("2022-01-06 13:25:4 libsqlite3_turso").unwrap();
-    id.into_raw()
+    version::source_id().as_ptr()
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn sqlite3_initialize() -> c_int {
+    logging::init();
+    sqlite::initialize();
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_shutdown() -> c_int {
+    sqlite::shutdown();
     SQLITE_OK
 }
 
+/// Only the threading modes are supported. They take no arguments, so the variadic tail
+/// SQLite declares is never read.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_config(op: c_int) -> c_int {
+    match sqlite::configure(op) {
+        Ok(()) => SQLITE_OK,
+        Err(error) => push_error((error.message, error.code)),
+    }
+}
+
+/// Opens with the flags SQLite's own `sqlite3_open` uses, through the same option parsing and
+/// auth selection as `sqlite3_open_v2`.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_open(filename: *const c_char, db: *mut *mut SQLite3) -> c_int {
+    sqlite3_open_v2(
+        filename,
+        db,
+        SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+        std::ptr::null(),
+    )
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sqlite3_open_v2(
     filename: *const c_char,
     db: *mut *mut SQLite3,
-    _: c_int,
+    flags: c_int,
     _: *const c_char,
 ) -> c_int {
     if filename.is_null() || db.is_null() {
         return SQLITE_ERROR;
     }
 
-    let db_name = CStr::from_ptr(filename).to_str().unwrap();
-    if db_name.contains(":memory") {
+    sqlite3_initialize();
+
+    let filename = CStr::from_ptr(filename).to_str().unwrap();
+    if filename.contains(":memory") {
         return push_error((
             "In-memory databases are not supported".to_string(),
             SQLITE_CANTOPEN,
         ));
     }
 
-    // Check if running in Globe environment
-    let auth_strategy: Box<dyn DbAuthStrategy> = {
-        let is_globe_env = std::env::var("GLOBE")
-            .and_then(|v| Ok(v == "1"))
-            .unwrap_or(false);
-        if is_globe_env {
-            Box::new(GlobeStrategy)
-        } else {
-            Box::new(EnvVarStrategy)
+    let (db_name, options) = match ConnectionOptions::parse(filename) {
+        Ok(parsed) => parsed,
+        Err(error) => return push_error((error.to_string(), error.code)),
+    };
+
+    let statement_cache = StatementCache::new(options.statement_cache);
+    #[cfg(feature = "replica")]
+    let replica = options
+        .replica
+        .as_deref()
+        .map(|path| replica::Replica::new(path, options.sync_interval));
+    let worker = Worker::start();
+    let connection = worker.run(async {
+        // The server may have dropped a prewarmed connection while it waited
+        if let Some(mut connection) = prewarm::take(filename) {
+            let timeout = connection.timeout;
+            match connection.ping(timeout).await {
+                Ok(()) => return Ok(connection),
+                Err(err) => tracing::debug!(error = %err, "Discarding a prewarmed connection"),
+            }
         }
+        let mut connection = transport::DatabaseConnection::open(
+            &db_name,
+            auth::strategy_for(options.auth),
+            options.clone(),
+        )
+        .await?;
+        version::learn(&mut connection).await;
+        Ok::<_, sqlite::SqliteError>(connection)
+    });
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(error) => return push_error((error.to_string(), SQLITE_CANTOPEN)),
     };
-    let connection =
-        get_tokio().block_on(transport::DatabaseConnection::open(db_name, auth_strategy));
-    if let Some(error) = connection.as_ref().err() {
-        return push_error((error.to_string(), SQLITE_CANTOPEN));
-    }
 
     let mock_db = Box::into_raw(Box::new(SQLite3 {
-        connection: connection.unwrap(),
+        metrics: connection.metrics.clone(),
+        state: connection.state.clone(),
+        worker,
+        connection: tokio::sync::Mutex::new(connection),
+        async_step: AtomicBool::new(options.async_step),
+        batch_scripts: AtomicBool::new(options.batch_scripts),
+        txn_replay: AtomicBool::new(options.txn_replay),
+        load_extension: AtomicBool::new(false),
+        journal: Mutex::new(None),
+        deadline: Mutex::new(None),
+        session: Mutex::new(Session::new(options.session_replay)),
+        schema_cache: Mutex::new(SchemaCache::new(options.schema_cache)),
+        slow_queries: Mutex::new(SlowQueryLog::new(options.slow_query_threshold())),
+        table_limit: AtomicUsize::new(options.table_limit),
+        max_rows: AtomicU64::new(options.max_rows),
+        last_truncated: AtomicBool::new(false),
+        options,
+        attached: Mutex::new(HashMap::new()),
+        functions: Mutex::new(HashMap::new()),
+        collations: Mutex::new(HashMap::new()),
+        authorizer: Mutex::new(None),
         transaction_baton: Mutex::new(None),
         last_insert_rowid: Mutex::new(None),
         rows_written: Mutex::new(None),
+        replication_index: Mutex::new(None),
+        schema_generation: AtomicU64::new(0),
+        last_query_stats: Mutex::new(None),
+        statement_cache: Mutex::new(statement_cache),
+        result_cache: Default::default(),
+        write_behind: Mutex::new(None),
+        async_error_hook: Default::default(),
+        savepoints: Mutex::new(Vec::new()),
         transaction_has_began: Mutex::new(false),
+        transaction_owner: Mutex::new(None),
+        in_flight_steps: Default::default(),
+        priority: Default::default(),
+        warm_up: Mutex::new(None),
+        script: Mutex::new(None),
+        opened_at: Instant::now(),
+        serialized: flags & SQLITE_OPEN_FULLMUTEX != 0,
+        caller_managed: sqlite::is_caller_managed(flags),
         delete_hook: Mutex::new(None),
         insert_hook: Mutex::new(None),
         update_hook: Mutex::new(None),
+        #[cfg(feature = "replica")]
+        replica,
     }));
 
+    sqlite::register_connection(mock_db);
+
+    #[cfg(feature = "replica")]
+    if let Err(error) = (*mock_db).worker.run(replica::open(mock_db)) {
+        sqlite3_close_v2(mock_db);
+        return push_error((error.message, error.code));
+    }
+
     *db = mock_db;
 
+    if (*mock_db).options.warmup {
+        // Queued behind nothing yet, so it usually starts before the first statement, which
+        // is the only one that may wait for it
+        let warm_up = (*mock_db)
+            .worker
+            .submit(Priority::Low, sqlite::warm_up(mock_db));
+        *(*mock_db).warm_up.lock().unwrap() = Some(warm_up);
+    }
+
     SQLITE_OK
 }
 
@@ -123,41 +293,87 @@ pub unsafe extern "C" fn sqlite3_prepare_v3(
         return SQLITE_ERROR;
     }
 
-    if prep_flag != 0 {
+    // Flags from newer SQLite versions may mean something this one cannot honour
+    let known = SQLITE_PREPARE_PERSISTENT
+        | sqlite::SQLITE_PREPARE_NORMALIZE
+        | sqlite::SQLITE_PREPARE_NO_VTAB
+        | sqlite::SQLITE_PREPARE_DONT_LOG;
+    if prep_flag & !known != 0 {
         return push_error((
-            "Persisted prepared statements not supported yet.".to_string(),
+            format!("Unsupported prepare flags {:#x}", prep_flag),
             SQLITE_MISUSE,
         ));
     }
 
     let bytes = slice::from_raw_parts(_sql as *const u8, byte_len);
-    let sql = match std::str::from_utf8(bytes) {
-        Ok(s) => s.to_string(),
+    let text = match std::str::from_utf8(bytes) {
+        Ok(s) => s,
         Err(_) => {
-            eprintln!("sqlite3_prepare_v3: Failed to convert SQL statement to string");
+            if prep_flag & sqlite::SQLITE_PREPARE_DONT_LOG == 0 {
+                tracing::warn!("sqlite3_prepare_v3: Failed to convert SQL statement to string");
+            }
             return SQLITE_ERROR;
         }
     };
 
-    let param_count = count_parameters(&sql);
+    // Only the first statement is prepared, as in SQLite, and the caller pointed at the rest
+    let end = statement_end(text);
+    let sql = text[..end].to_string();
+    let group = match pz_tail.is_null() {
+        true => None,
+        false => {
+            *pz_tail = _sql.add(end);
+            group::join(&*_db, text)
+        }
+    };
+    if is_empty_statement(&sql) {
+        *pp_stmt = std::ptr::null_mut();
+        return SQLITE_OK;
+    }
+
+    let ignored = match authorize(&*_db, &sql) {
+        Ok(authorization) => authorization == Authorization::Ignored,
+        Err(error) => return push_error((error.message, error.code)),
+    };
 
-    // Mock unparsed portion of SQL
-    if !pz_tail.is_null() {
-        let parsed_sql = CString::new(sql.as_str()).unwrap();
-        unsafe { *pz_tail = _sql.add(parsed_sql.to_bytes().len()) };
+    let statement = (*_db).statement_cache.lock().unwrap().get_or_parse(&sql);
+    let persistent = prep_flag & SQLITE_PREPARE_PERSISTENT != 0;
+    if persistent {
+        let db = &*_db;
+        let retained = db.worker.run(async {
+            let mut connection = db.lock_connection().await?;
+            connection.retain_persistent_sql(&sql);
+            Ok::<_, sqlite::SqliteError>(())
+        });
+        if let Err(error) = retained {
+            return push_error((error.message, error.code));
+        }
     }
 
     // Allocate a mock prepared statement
     let stmt = Box::new(SQLite3PreparedStmt {
         db: _db,
-        sql: sql,
-        param_count,
+        sql,
+        param_count: statement.param_count,
         params: HashMap::new(), // Initialize an empty map for parameters
         execution_state: Mutex::new(ExecutionState::Prepared), // Start in the "Prepared" state
         result_rows: Mutex::new(vec![]), // Initialize an empty result set
         current_row: Mutex::new(None), // No current row initially
-        column_names: Vec::new(),
+        row_text: Mutex::new(HashMap::new()),
+        row_vectors: Mutex::new(HashMap::new()),
+        column_names: statement
+            .current_column_names((*_db).schema_generation())
+            .unwrap_or_default(),
+        statement,
+        persistent,
+        priority: *(*_db).priority.lock().unwrap(),
+        ignored,
+        counters: Default::default(),
+        group,
     });
+    if let Some((group, index)) = &stmt.group {
+        group.enter(*index);
+    }
     *pp_stmt = Box::into_raw(stmt);
 
     SQLITE_OK
@@ -172,18 +388,72 @@ pub extern "C" fn sqlite3_bind_parameter_count(stmt: *mut SQLite3PreparedStmt) -
     stmt.param_count
 }
 
+#[no_mangle]
+pub extern "C" fn sqlite3_bind_parameter_name(
+    stmt: *mut SQLite3PreparedStmt,
+    index: c_int,
+) -> *const c_char {
+    if stmt.is_null() || index <= 0 {
+        return std::ptr::null();
+    }
+    let stmt = unsafe { &*stmt };
+    // The name lives in the parse shared through the statement cache, as long as the statement
+    stmt.statement
+        .param_names
+        .get(index as usize - 1)
+        .and_then(Option::as_ref)
+        .map_or(std::ptr::null(), |name| name.as_ptr())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_bind_parameter_index(
+    stmt: *mut SQLite3PreparedStmt,
+    name: *const c_char,
+) -> c_int {
+    if stmt.is_null() || name.is_null() {
+        return 0;
+    }
+    let stmt = &*stmt;
+    let name = CStr::from_ptr(name);
+    stmt.statement
+        .param_names
+        .iter()
+        .position(|n| n.as_deref() == Some(name))
+        .map_or(0, |i| i as c_int + 1)
+}
+
 #[no_mangle]
 pub extern "C" fn sqlite3_finalize(stmt: *mut SQLite3PreparedStmt) -> c_int {
     if stmt.is_null() {
         return SQLITE_ERROR;
     }
 
-    unsafe {
-        let _ = Box::from_raw(stmt);
+    // A step still queued is dropped, one already sent holds the statement until it has
+    // finished
+    let settled = is_aligned(unsafe { (*stmt).db })
+        .then(|| unsafe { (*(*stmt).db).cancel_step(stmt) })
+        .flatten();
+
+    let stmt = unsafe { Box::from_raw(stmt) };
+    if let Some((group, index)) = &stmt.group {
+        group.leave(*index);
+    }
+    if stmt.persistent && is_aligned(stmt.db) {
+        let db = unsafe { &*stmt.db };
+        db.worker.run(async {
+            let mut connection = db.connection.lock().await;
+            if db.adopt_after_fork(&mut connection).is_err() {
+                return;
+            }
+            connection.release_persistent_sql(&stmt.sql).await
+        });
     }
 
-    // Return success code
-    SQLITE_OK
+    // Like SQLite, report a failure of the last step
+    match settled {
+        Some(Err(err)) => result_code::<c_int>(Err(err)),
+        _ => SQLITE_OK,
+    }
 }
 
 #[no_mangle]
@@ -208,7 +478,7 @@ pub unsafe extern "C" fn sqlite3_bind_text(
     let text = match std::str::from_utf8(bytes) {
         Ok(s) => s.to_string(),
         Err(_) => {
-            eprintln!("sqlite3_bind_text: invalid UTF-8 at index {}", index);
+            tracing::warn!("sqlite3_bind_text: invalid UTF-8 at index {}", index);
             return SQLITE_MISUSE;
         }
     };
@@ -273,12 +543,57 @@ pub extern "C" fn sqlite3_bind_null(stmt_ptr: *mut SQLite3PreparedStmt, index: c
     SQLITE_OK
 }
 
+/// Binds the `dims` floats at `vector` as the BLOB an `F32_BLOB` column stores, ready for
+/// vector functions such as `vector_distance_cos` without a `vector('[...]')` literal.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_bind_vector_f32(
+    stmt_ptr: *mut SQLite3PreparedStmt,
+    index: c_int,
+    vector: *const f32,
+    dims: c_int,
+) -> c_int {
+    if !is_aligned(stmt_ptr) || !is_aligned(vector) || dims <= 0 {
+        return SQLITE_MISUSE;
+    }
+
+    let stmt = &mut *stmt_ptr;
+    if index <= 0 || index > stmt.param_count {
+        return SQLITE_RANGE;
+    }
+
+    let vector = slice::from_raw_parts(vector, dims as usize);
+    stmt.params
+        .insert(index, Value::Blob(vector::to_blob(vector)));
+    SQLITE_OK
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sqlite3_step(stmt_ptr: *mut SQLite3PreparedStmt) -> c_int {
     if stmt_ptr.is_null() {
         return SQLITE_MISUSE;
     }
 
+    // In non-blocking mode a step sent to the server answers SQLITE_BUSY until the response
+    // is in, and the step after that reports how the execution went
+    let db = &*(*stmt_ptr).db;
+    let pending = db
+        .in_flight_steps
+        .lock()
+        .unwrap()
+        .remove(&(stmt_ptr as usize));
+    if let Some(pending) = pending {
+        return match pending.try_take() {
+            Ok(result) => result_code(result),
+            Err(pending) => {
+                db.in_flight_steps
+                    .lock()
+                    .unwrap()
+                    .insert(stmt_ptr as usize, pending);
+                SQLITE_BUSY
+            }
+        };
+    }
+
     let stmt = &mut *stmt_ptr;
 
     let mut exec_state = match stmt.execution_state.lock() {
@@ -286,42 +601,37 @@ pub unsafe extern "C" fn sqlite3_step(stmt_ptr: *mut SQLite3PreparedStmt) -> c_i
         Err(_) => return SQLITE_ERROR,
     };
 
-    match *exec_state {
-        ExecutionState::Prepared | ExecutionState::Row => {
-            if *exec_state == ExecutionState::Prepared {
-                *exec_state = ExecutionState::Executing;
-            }
+    // Only a fresh statement goes to the server. Whether it yields rows is decided by what
+    // came back, so INSERT ... RETURNING steps through its rows just like a SELECT.
+    let needs_execution = match *exec_state {
+        ExecutionState::Prepared => {
+            *exec_state = ExecutionState::Executing;
+            true
         }
+        ExecutionState::Row => false,
         ExecutionState::Done => return SQLITE_DONE,
         _ => return SQLITE_MISUSE,
-    }
+    };
     drop(exec_state);
 
-    let needs_execution = stmt.result_rows.lock().unwrap().is_empty();
-    if needs_execution {
-        let sql = stmt.sql.to_uppercase();
-        let sql_result_code = {
-            if sql_is_begin_transaction(&sql) {
-                execute_async_task(sqlite::begin_tnx_on_db(stmt.db, &sql))
-            } else if sql_is_commit(&sql) {
-                execute_async_task(sqlite::commit_tnx_on_db(stmt.db, &sql))
-            } else {
-                execute_async_task(sqlite::execute_stmt(stmt))
-            }
-        };
-
-        if sql_result_code != SQLITE_OK {
-            return sql_result_code;
-        }
-    }
-
-    let sql_result_code = sqlite::iterate_rows(stmt);
-    if let Err(error) = sql_result_code {
-        push_error((error.to_string(), SQLITE_ERROR));
-        return SQLITE_ERROR;
+    if needs_execution && db.async_step.load(Ordering::Relaxed) {
+        // SAFETY: reset, finalize and close settle the pending step before the statement
+        // changes or goes away
+        let pending = db
+            .worker
+            .submit(stmt.priority, sqlite::step_stmt(stmt, true));
+        db.in_flight_steps
+            .lock()
+            .unwrap()
+            .insert(stmt_ptr as usize, pending);
+        return SQLITE_BUSY;
     }
 
-    sql_result_code.unwrap()
+    let priority = stmt.priority;
+    result_code(
+        db.worker
+            .run_at(priority, sqlite::step_stmt(stmt, needs_execution)),
+    )
 }
 
 #[no_mangle]
@@ -331,6 +641,8 @@ pub extern "C" fn sqlite3_column_count(stmt: *mut SQLite3PreparedStmt) -> i32 {
     }
 
     let stmt = unsafe { &mut *stmt };
+    let db = unsafe { &*stmt.db };
+    db.worker.run(sqlite::describe_stmt(stmt));
 
     stmt.column_names.len() as i32
 }
@@ -341,7 +653,7 @@ pub unsafe extern "C" fn sqlite3_last_insert_rowid(_db: *mut SQLite3) -> i64 {
         return 0;
     }
 
-    let db = &mut *_db;
+    let db = &*_db;
 
     if let Ok(last_insert_rowid) = db.last_insert_rowid.lock() {
         if let Some(row_id) = *last_insert_rowid {
@@ -353,457 +665,3881 @@ pub unsafe extern "C" fn sqlite3_last_insert_rowid(_db: *mut SQLite3) -> i64 {
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_reset(stmt: *mut SQLite3PreparedStmt) -> c_int {
-    if stmt.is_null() {
-        return SQLITE_ERROR;
-    }
-
-    // Safely convert the raw pointer to a mutable reference
-    let stmt = unsafe { &mut *stmt };
-
-    // Reset execution state to Prepared
-    if let Ok(mut exec_state) = stmt.execution_state.lock() {
-        *exec_state = ExecutionState::Prepared; // Reset to initial state
+pub unsafe extern "C" fn sqlite3_set_last_insert_rowid(db: *mut SQLite3, rowid: i64) {
+    if !is_aligned(db) {
+        return;
     }
 
-    // Clear parameters
-    stmt.params.clear();
+    *(*db).last_insert_rowid.lock().unwrap() = Some(rowid);
+}
 
-    // Clear result
-    if let Ok(mut result_rows) = stmt.result_rows.lock() {
-        result_rows.clear(); // Remove all previously bound parameters
+/// Highest replication index seen in the results of `db`'s statements. Only HTTP requests
+/// send it back, so only reads over HTTP are sure to see the connection's own writes; over
+/// the WebSocket the index is tracked but a replica may still answer from before it.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_replication_index(db: *mut SQLite3) -> i64 {
+    if !is_aligned(db) {
+        return 0;
     }
 
-    stmt.column_names.clear();
+    let db = &*db;
 
-    SQLITE_OK
+    // 0 means no write has been observed on this connection yet
+    db.replication_index().map_or(0, |index| index as i64)
 }
 
+/// Rows the server read for `db` since it was opened, or across every connection when `db`
+/// is NULL. Turso bills by this figure.
 #[no_mangle]
-pub unsafe extern "C" fn sqlite3_close_v2(db: *mut SQLite3) -> c_int {
+pub unsafe extern "C" fn sqlite3_turso_rows_read(db: *mut SQLite3) -> i64 {
+    if db.is_null() {
+        return metrics::GLOBAL_METRICS.rows_read() as i64;
+    }
     if !is_aligned(db) {
-        return SQLITE_OK;
+        return 0;
     }
 
-    let db = unsafe { &mut *db };
+    (*db).metrics.rows_read() as i64
+}
 
-    reset_txn_on_db(db);
+/// Rows the server wrote for `db` since it was opened, or across every connection when `db`
+/// is NULL, queued writes included.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_rows_written(db: *mut SQLite3) -> i64 {
+    if db.is_null() {
+        return metrics::GLOBAL_METRICS.rows_written() as i64;
+    }
+    if !is_aligned(db) {
+        return 0;
+    }
 
-    drop(Box::from_raw(db));
+    (*db).metrics.rows_written() as i64
+}
 
+#[no_mangle]
+pub extern "C" fn sqlite3_turso_log_hook(
+    callback: Option<logging::LogHook>,
+    user_data: *mut c_void,
+) -> c_int {
+    logging::set_log_hook(callback, user_data);
+    logging::init();
     SQLITE_OK
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn sqlite3_extended_errcode(_: *mut SQLite3) -> c_int {
-    let error_stack = get_latest_error();
-    if let Some((_, code)) = error_stack {
-        return code;
+pub unsafe extern "C" fn sqlite3_turso_ping(db: *mut SQLite3, timeout_ms: c_int) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_MISUSE;
     }
 
-    SQLITE_OK
+    let db = &*db;
+
+    match db.worker.run(async {
+        let mut connection = db.lock_connection().await?;
+        // A non-positive timeout falls back to the connection's request timeout
+        let timeout = match timeout_ms {
+            ms if ms > 0 => Duration::from_millis(ms as u64),
+            _ => connection.timeout,
+        };
+        connection.ping(timeout).await
+    }) {
+        Ok(_) => SQLITE_OK,
+        Err(error) => push_error((error.to_string(), SQLITE_IOERR)),
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn sqlite3_changes(db: *mut SQLite3) -> c_int {
+pub unsafe extern "C" fn sqlite3_turso_transport(db: *mut SQLite3) -> *const c_char {
     if !is_aligned(db) {
-        return SQLITE_OK;
+        return std::ptr::null();
     }
-    let db = &mut *db;
 
-    if let Ok(rows_written) = db.rows_written.lock() {
-        if rows_written.is_some() {
-            return rows_written.unwrap() as c_int;
+    let db = &*db;
+
+    match db.worker.run(db.lock_connection()) {
+        Ok(connection) => connection.strategy.name().as_ptr(),
+        Err(error) => {
+            push_error((error.message, error.code));
+            std::ptr::null()
         }
     }
-
-    0
 }
 
+/// Query counters as JSON for `db`, or process-wide totals when `db` is NULL. The string
+/// must be released with `sqlite3_turso_free_string`.
 #[no_mangle]
-pub unsafe extern "C" fn sqlite3_errmsg(_: *mut SQLite3) -> *const c_char {
-    if let Some(error_entry) = sqlite::get_latest_error() {
-        if let Ok(c_string) = CString::new(error_entry.0) {
-            return c_string.into_raw();
-        }
+pub unsafe extern "C" fn sqlite3_turso_metrics_json(db: *mut SQLite3) -> *mut c_char {
+    let metrics = if db.is_null() {
+        let mut metrics = metrics::GLOBAL_METRICS.to_json();
+        metrics["runtime"] = metrics::runtime_json().unwrap_or_default();
+        metrics
+    } else if is_aligned(db) {
+        (*db).metrics.to_json()
+    } else {
+        return std::ptr::null_mut();
+    };
+
+    match CString::new(metrics.to_string()) {
+        Ok(c_string) => c_string.into_raw(),
+        Err(_) => std::ptr::null_mut(),
     }
-    std::ptr::null()
 }
 
+/// Same as `sqlite3_turso_metrics_json` in the Prometheus text exposition format.
+#[cfg(feature = "prometheus")]
 #[no_mangle]
-pub extern "C" fn sqlite3_column_type(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> i32 {
-    if stmt.is_null() {
-        return SQLITE_NULL;
-    }
-
-    let stmt = unsafe { &mut *stmt };
-    let result_rows = stmt.result_rows.lock().unwrap();
-    let current_row = stmt.current_row.lock().unwrap();
+pub unsafe extern "C" fn sqlite3_turso_metrics_prometheus(db: *mut SQLite3) -> *mut c_char {
+    let text = if db.is_null() {
+        metrics::GLOBAL_METRICS.to_prometheus("global") + &metrics::runtime_prometheus()
+    } else if is_aligned(db) {
+        (*db).metrics.to_prometheus("connection")
+    } else {
+        return std::ptr::null_mut();
+    };
 
-    // Check if there's a current row
-    if let Some(row_index) = *current_row {
-        // Get the row at the current index
-        if let Some(row) = result_rows.get(row_index) {
-            // Get the value at the specified column index
-            if let Some(value) = row.get(col_index as usize) {
-                // Match the value type to determine SQLite type
-                return match value {
-                    Value::Integer(_) => SQLITE_INTEGER,
-                    Value::Real(_) => SQLITE_FLOAT,
-                    Value::Text(_) => SQLITE_TEXT,
-                    Value::Null => SQLITE_NULL,
-                };
-            }
-        }
+    match CString::new(text) {
+        Ok(c_string) => c_string.into_raw(),
+        Err(_) => std::ptr::null_mut(),
     }
-
-    SQLITE_NULL // Invalid column or no current row
 }
 
+/// Checks that the database `filename` names can be reached, the way `sqlite3_open_v2` would
+/// reach it, and returns a JSON report of each step with its latency. NULL when `filename`
+/// cannot be parsed. The string must be released with `sqlite3_turso_free_string`.
 #[no_mangle]
-pub extern "C" fn sqlite3_column_bytes(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> i32 {
-    if stmt.is_null() {
-        return 0;
+pub unsafe extern "C" fn sqlite3_turso_selftest(filename: *const c_char) -> *mut c_char {
+    if filename.is_null() {
+        return std::ptr::null_mut();
     }
+    sqlite3_initialize();
 
-    let stmt = unsafe { &mut *stmt };
-    let result_rows = stmt.result_rows.lock().unwrap();
-    let current_row = stmt.current_row.lock().unwrap();
-
-    // Check if there's a current row
-    if let Some(row_index) = *current_row {
-        // Get the row at the current index
-        if let Some(row) = result_rows.get(row_index) {
-            // Get the value at the specified column index
-            if let Some(value) = row.get(col_index as usize) {
-                // Calculate the byte length based on the value type
-                return match value {
-                    Value::Text(s) => s.len() as i32, // Length of the string in bytes
-                    Value::Integer(_) => std::mem::size_of::<i64>() as i32, // Size of an integer
-                    Value::Real(_) => std::mem::size_of::<f64>() as i32, // Size of a float
-                    Value::Null => 0,                 // Null has no byte size
-                };
-            }
+    let Ok(filename) = CStr::from_ptr(filename).to_str() else {
+        return std::ptr::null_mut();
+    };
+    let (db_name, options) = match ConnectionOptions::parse(filename) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            push_error((error.to_string(), error.code));
+            return std::ptr::null_mut();
         }
-    }
+    };
 
-    0 // Invalid column or no current row
+    let report = Worker::start().run(transport::self_test(
+        &db_name,
+        auth::strategy_for(options.auth),
+        options,
+    ));
+    match serde_json::to_string(&report).map(CString::new) {
+        Ok(Ok(c_string)) => c_string.into_raw(),
+        _ => std::ptr::null_mut(),
+    }
 }
 
+/// Opens `count` connections to the database `filename` names, the way `sqlite3_open_v2`
+/// would, and keeps them until `sqlite3_open_v2` is called with the same filename.
+/// Credentials are resolved once and the connections opened in parallel, each pinged before
+/// it is kept. Returns `SQLITE_CANTOPEN` if any of them could not be opened; the others are
+/// kept all the same.
 #[no_mangle]
-pub extern "C" fn sqlite3_column_text(
-    stmt: *mut SQLite3PreparedStmt,
-    col_index: i32,
-) -> *const c_char {
-    if stmt.is_null() {
-        return std::ptr::null();
+pub unsafe extern "C" fn sqlite3_turso_prewarm(filename: *const c_char, count: c_int) -> c_int {
+    if filename.is_null() || count < 0 {
+        return SQLITE_MISUSE;
     }
+    sqlite3_initialize();
 
-    let stmt = unsafe { &mut *stmt };
-    let result_rows = stmt.result_rows.lock().unwrap();
-    let current_row = stmt.current_row.lock().unwrap();
-
-    // Check if there's a current row
-    if let Some(row_index) = *current_row {
-        if let Some(value) = result_rows
-            .get(row_index)
-            .and_then(|row| row.get(col_index as usize))
-        {
-            // Convert the value to a CString based on its type
-            let text_representation = match value {
-                Value::Text(s) => s.clone(),        // Use the text directly
-                Value::Integer(i) => i.to_string(), // Convert integer to string
-                Value::Real(f) => f.to_string(),    // Convert float to string
-                Value::Null => "NULL".to_string(),  // Represent NULL as "NULL"
-            };
+    let Ok(filename) = CStr::from_ptr(filename).to_str() else {
+        return SQLITE_MISUSE;
+    };
+    if filename.contains(":memory") {
+        return push_error((
+            "In-memory databases are not supported".to_string(),
+            SQLITE_CANTOPEN,
+        ));
+    }
+    let (db_name, options) = match ConnectionOptions::parse(filename) {
+        Ok(parsed) => parsed,
+        Err(error) => return push_error((error.to_string(), error.code)),
+    };
 
-            // Convert the string into a CString and return a raw pointer
-            return CString::new(text_representation).unwrap().into_raw();
-        }
+    match Worker::start().run(prewarm::fill(filename, &db_name, &options, count as usize)) {
+        Ok(()) => SQLITE_OK,
+        Err(error) => push_error((error.to_string(), SQLITE_CANTOPEN)),
     }
+}
 
-    std::ptr::null() // Invalid column or no current row
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_free_string(ptr: *mut c_char) {
+    if !ptr.is_null() {
+        drop(CString::from_raw(ptr));
+    }
 }
 
+/// Blocks until every write queued by `PRAGMA turso.async_writes` has been sent. Returns
+/// `SQLITE_ERROR` when any of them failed since the previous flush.
 #[no_mangle]
-pub extern "C" fn sqlite3_column_double(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> f64 {
-    if stmt.is_null() {
-        return 0.0;
+pub unsafe extern "C" fn sqlite3_turso_flush(db: *mut SQLite3) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_MISUSE;
     }
 
-    let stmt = unsafe { &mut *stmt };
-    let result_rows = stmt.result_rows.lock().unwrap();
-    let current_row = stmt.current_row.lock().unwrap();
+    let write_behind = (*db).write_behind.lock().unwrap().clone();
+    match write_behind {
+        Some(write_behind) => execute_async_task(&(*db).worker, async move {
+            write_behind.flush().await.map(|_| SQLITE_OK)
+        }),
+        None => SQLITE_OK,
+    }
+}
 
-    // Check if there's a current row
-    if let Some(row_index) = *current_row {
-        if let Some(value) = result_rows
-            .get(row_index)
-            .and_then(|row| row.get(col_index as usize))
-        {
-            // Match the value and extract it as f64
-            return match value {
-                Value::Real(f) => *f,                // Return the float directly
-                Value::Integer(i) => *i as f64,      // Cast integer to float
-                Value::Text(_) | Value::Null => 0.0, // Non-numeric or NULL
-            };
-        }
+/// Sends the writes queued by `PRAGMA turso.async_writes`, the only data the shim holds
+/// back from the server.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_db_cacheflush(db: *mut SQLite3) -> c_int {
+    sqlite3_turso_flush(db)
+}
+
+/// Sends queued writes, then frees the connection's result, schema and statement caches.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_db_release_memory(db: *mut SQLite3) -> c_int {
+    let rc = sqlite3_turso_flush(db);
+    if rc == SQLITE_MISUSE {
+        return rc;
     }
 
-    0.0
+    (*db).release_memory();
+    rc
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_column_int64(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> i64 {
-    if stmt.is_null() {
+pub extern "C" fn sqlite3_release_memory(bytes: c_int) -> c_int {
+    if bytes <= 0 {
         return 0;
     }
+    sqlite::release_memory(bytes as usize).min(c_int::MAX as usize) as c_int
+}
 
-    let stmt = unsafe { &mut *stmt };
-    let result_rows = stmt.result_rows.lock().unwrap();
-    let current_row = stmt.current_row.lock().unwrap();
-
-    // Check if there's a current row
-    if let Some(row_index) = *current_row {
-        // Get the row at the current index
-        if let Some(row) = result_rows.get(row_index) {
-            // Get the value at the specified column index
-            if let Some(value) = row.get(col_index as usize) {
-                // Match the value and extract it as i64
-                return match value {
-                    Value::Integer(i) => *i,           // Return the integer directly
-                    Value::Real(f) => *f as i64,       // Cast float to integer
-                    Value::Text(_) | Value::Null => 0, // Non-integer or NULL
-                };
-            }
-        }
+/// Brings the embedded replica of a `replica=` connection up to date with the primary, after
+/// sending the writes `PRAGMA turso.async_writes` queued.
+#[cfg(feature = "replica")]
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_sync(db: *mut SQLite3) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_MISUSE;
     }
 
-    0 // Invalid column or no current row
+    execute_async_task(&(*db).worker, async move {
+        replica::sync(db).await.map(|_| SQLITE_OK)
+    })
 }
 
+/// Shared-cache mode has connections of one process share a page cache and its table locks.
+/// Connections here hold no pages, each one is its own client of the server, so the call is
+/// accepted and changes nothing.
 #[no_mangle]
-pub extern "C" fn sqlite3_column_name(
-    stmt: *mut SQLite3PreparedStmt,
-    col_index: i32,
-) -> *const c_char {
-    if stmt.is_null() {
-        return std::ptr::null();
-    }
-
-    let stmt = unsafe { &mut *stmt };
+pub extern "C" fn sqlite3_enable_shared_cache(_: c_int) -> c_int {
+    SQLITE_OK
+}
 
-    // Check if the column index is valid
-    if col_index < 0 || col_index as usize >= stmt.column_names.len() {
-        return std::ptr::null(); // Invalid column index
+/// Allows `sqlite3_load_extension` on `db`, as in SQLite, where it is off by default.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_enable_load_extension(db: *mut SQLite3, onoff: c_int) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_MISUSE;
     }
-
-    let column_name = &stmt.column_names[col_index as usize];
-
-    // Convert column name to CString and return the pointer
-    CString::new(column_name.as_str()).unwrap().into_raw()
+    (*db).load_extension.store(onoff != 0, Ordering::Relaxed);
+    SQLITE_OK
 }
 
+/// Nothing is loaded into the client: the call asks the server whether it has the extension
+/// in `file` and succeeds if it does, see extension.rs. The entry point is not used. A
+/// failure's message is also written to `errmsg`, to be released with `sqlite3_free`.
 #[no_mangle]
-pub extern "C" fn sqlite3_column_table_name(
-    stmt: *mut SQLite3PreparedStmt,
-    col_index: i32,
-) -> *const c_char {
-    if stmt.is_null() {
-        return std::ptr::null();
+pub unsafe extern "C" fn sqlite3_load_extension(
+    db: *mut SQLite3,
+    file: *const c_char,
+    _entry_point: *const c_char,
+    errmsg: *mut *mut c_char,
+) -> c_int {
+    if !is_aligned(db) || file.is_null() {
+        return SQLITE_MISUSE;
+    }
+    if !errmsg.is_null() {
+        *errmsg = std::ptr::null_mut();
     }
 
-    // Safety: Dereference the pointer to access the prepared statement
-    let stmt = unsafe { &mut *stmt };
+    let db = &*db;
+    let file = CStr::from_ptr(file).to_string_lossy();
+    let result = if db.load_extension.load(Ordering::Relaxed) {
+        db.worker.run(extension::probe(db, &file))
+    } else {
+        Err(sqlite::SqliteError::new(
+            "not authorized",
+            Some(SQLITE_ERROR),
+        ))
+    };
 
-    // Validate the column index
-    if col_index < 0 || col_index as usize >= stmt.column_names.len() {
-        return std::ptr::null(); // Invalid column index
+    if let (Err(error), false) = (&result, errmsg.is_null()) {
+        *errmsg = cutil::to_c_string(error.message.as_bytes());
     }
+    result_code(result.map(|()| SQLITE_OK))
+}
 
-    // Get the SQL query
-    let sql = stmt.sql.as_str();
+/// Changes a setting of `db`, or with a NULL handle the process defaults every connection
+/// opened afterwards starts from. Process defaults take the URI parameter names and values,
+/// which the connection's own URI parameters still override; an open connection takes the
+/// settings of the `turso.*` pragmas. Unknown keys return SQLITE_NOTFOUND.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_config(
+    db: *mut SQLite3,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    if key.is_null() || value.is_null() || !(db.is_null() || is_aligned(db)) {
+        return SQLITE_MISUSE;
+    }
+    let (Ok(key), Ok(value)) = (CStr::from_ptr(key).to_str(), CStr::from_ptr(value).to_str())
+    else {
+        return SQLITE_MISUSE;
+    };
 
-    // Use a regex to extract table names from the SQL query
-    let table_regex = Regex::new(r"FROM\s+([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+    if key == logging::UNSAFE_LOG_PARAMS {
+        // Logging is set up once for the whole process
+        let Some(enabled) = config::parse_bool(value).filter(|_| db.is_null()) else {
+            return push_error((
+                format!(
+                    "'{}' applies to the whole process, set it on or off without a connection",
+                    key
+                ),
+                SQLITE_MISUSE,
+            ));
+        };
+        logging::set_unsafe_log_params(enabled);
+        return SQLITE_OK;
+    }
 
-    // Match and capture the table name
-    if let Some(captures) = table_regex.captures(sql) {
-        if let Some(table_name) = captures.get(1) {
-            // Return the table name as a CString
-            match CString::new(table_name.as_str()) {
-                Ok(c_string) => return c_string.into_raw(),
-                Err(_) => return std::ptr::null(), // Handle CString creation error
-            }
+    if key == utils::RUNTIME_KEY {
+        // One runtime serves the whole process
+        if !db.is_null() {
+            return push_error((
+                format!(
+                    "'{}' applies to the whole process, set it without a connection",
+                    key
+                ),
+                SQLITE_MISUSE,
+            ));
         }
+        return result_code(utils::configure_runtime(value).map(|()| SQLITE_OK));
     }
 
-    std::ptr::null() // No table name found
-}
+    if db.is_null() {
+        return match config::set_process_default(key, value) {
+            Ok(true) => SQLITE_OK,
+            Ok(false) => SQLITE_NOTFOUND,
+            Err(error) => push_error((error.message, SQLITE_MISUSE)),
+        };
+    }
 
-#[no_mangle]
-pub extern "C" fn sqlite3_errstr(errcode: c_int) -> *const c_char {
-    let message = match errcode {
-        SQLITE_OK => "Successful result",
-        SQLITE_ERROR => "SQL error or missing database",
-        SQLITE_MISUSE => "Library used incorrectly",
-        SQLITE_RANGE => "2nd parameter to sqlite3_bind out of range",
-        SQLITE_BUSY => "The database file is locked",
-        SQLITE_CANTOPEN => "Either database does not exist or cannot be opened",
-        _ => "Unknown error code",
-    };
+    if !sqlite::TURSO_SETTINGS.contains(&key) {
+        // Known, but only read while the connection opens
+        if !matches!(ConnectionOptions::default().set(key, value), Ok(false)) {
+            return push_error((
+                format!(
+                    "'{}' is fixed once the connection is open, set it as a URI parameter \
+                     or as a process default",
+                    key
+                ),
+                SQLITE_MISUSE,
+            ));
+        }
+        return SQLITE_NOTFOUND;
+    }
 
-    CString::new(message).unwrap().into_raw()
+    let db = &*db;
+    execute_async_task(&db.worker, async {
+        sqlite::handle_turso_pragma(db, key, Some(value))
+            .await
+            .map(|_| SQLITE_OK)
+    })
 }
 
+/// Called with every statement whose round trip took longer than the `slow_ms` threshold.
 #[no_mangle]
-pub unsafe extern "C" fn sqlite3_exec(
+pub unsafe extern "C" fn sqlite3_turso_slow_query_hook(
     db: *mut SQLite3,
-    sql: *const c_char,
-    callback: SQLite3ExecCallback,
-    arg: *mut c_void,
-    errmsg: *mut *mut c_char,
+    callback: Option<slow_query::SlowQueryHook>,
+    user_data: *mut c_void,
 ) -> c_int {
     if !is_aligned(db) {
-        return SQLITE_CANTOPEN;
-    }
-
-    let db = &mut *db;
-
-    let sql = CStr::from_ptr(sql).to_string_lossy().to_string();
-
-    if sql_is_pragma(&sql) {
-        return SQLITE_OK;
-    } else if sql_is_begin_transaction(&sql) {
-        return execute_async_task(sqlite::begin_tnx_on_db(db, &sql));
-    } else if sql_is_rollback(&sql) {
-        return reset_txn_on_db(db);
-    } else if sql_is_commit(&sql) {
-        return execute_async_task(sqlite::commit_tnx_on_db(db, &sql));
+        return SQLITE_MISUSE;
     }
 
-    execute_async_task(sqlite::handle_execute(db, &sql))
+    (*db)
+        .slow_queries
+        .lock()
+        .unwrap()
+        .set_hook(callback, user_data);
+    SQLITE_OK
 }
 
+/// Called with one of the `SQLITE_TURSO_STATE_*` values whenever the connection's state
+/// changes, and once with the current one when registered. Runs on the connection's worker.
 #[no_mangle]
-pub extern "C" fn sqlite3_update_hook(
+pub unsafe extern "C" fn sqlite3_turso_on_state_change(
     db: *mut SQLite3,
-    callback: Option<sqlite::SqliteHook>,
+    callback: Option<connection_state::StateChangeHook>,
     user_data: *mut c_void,
 ) -> c_int {
     if !is_aligned(db) {
-        return SQLITE_CANTOPEN;
+        return SQLITE_MISUSE;
     }
 
-    let db = unsafe { &mut *db };
-
-    db.register_hook(sqlite::SQLITE_UPDATE, callback, user_data)
+    (*db).state.set_hook(callback, user_data);
+    SQLITE_OK
 }
 
+/// Bounds the time remote calls may still take: statements stepped from now on fail with
+/// `SQLITE_INTERRUPT` once `ms` milliseconds have passed, including one waiting on the
+/// server then. Cleared when the open transaction ends, or with `ms <= 0`.
 #[no_mangle]
-pub extern "C" fn sqlite3_commit_hook(
-    db: *mut SQLite3,
-    x_callback: Option<unsafe extern "C" fn(*mut c_void) -> c_int>, // int (*xCallback)(void*)
-    p_arg: *mut c_void,                                             // void *pArg
-) -> c_int {
+pub unsafe extern "C" fn sqlite3_turso_set_deadline(db: *mut SQLite3, ms: c_int) -> c_int {
     if !is_aligned(db) {
-        return SQLITE_CANTOPEN;
+        return SQLITE_MISUSE;
     }
 
+    let deadline = (ms > 0).then(|| Instant::now() + Duration::from_millis(ms as u64));
+    *(*db).deadline.lock().unwrap() = deadline;
     SQLITE_OK
 }
 
+/// Called from a background thread for every queued write the server rejected.
 #[no_mangle]
-pub extern "C" fn sqlite3_rollback_hook(
+pub unsafe extern "C" fn sqlite3_turso_async_error_hook(
     db: *mut SQLite3,
-    x_callback: Option<unsafe extern "C" fn(*mut c_void) -> c_int>,
-    p_arg: *mut c_void,
+    callback: Option<write_behind::AsyncErrorHook>,
+    user_data: *mut c_void,
 ) -> c_int {
     if !is_aligned(db) {
-        return SQLITE_CANTOPEN;
+        return SQLITE_MISUSE;
     }
 
+    write_behind::set_error_hook(&(*db).async_error_hook, callback, user_data);
     SQLITE_OK
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_get_autocommit(db: *mut SQLite3) -> c_int {
+pub extern "C" fn sqlite3_reset(stmt: *mut SQLite3PreparedStmt) -> c_int {
+    if stmt.is_null() {
+        return SQLITE_ERROR;
+    }
+
+    let settled = is_aligned(unsafe { (*stmt).db })
+        .then(|| unsafe { (*(*stmt).db).cancel_step(stmt) })
+        .flatten();
+
+    // Safely convert the raw pointer to a mutable reference
+    let stmt = unsafe { &mut *stmt };
+
+    // Reset execution state to Prepared
+    if let Ok(mut exec_state) = stmt.execution_state.lock() {
+        *exec_state = ExecutionState::Prepared; // Reset to initial state
+    }
+
+    // Clear parameters
+    stmt.params.clear();
+
+    // Clear result
+    if let Ok(mut result_rows) = stmt.result_rows.lock() {
+        result_rows.clear(); // Remove all previously bound parameters
+    }
+    if let Ok(mut current_row) = stmt.current_row.lock() {
+        *current_row = None;
+    }
+    stmt.row_text.lock().unwrap().clear();
+    stmt.row_vectors.lock().unwrap().clear();
+
+    // Column metadata belongs to the statement, not to one execution
+    let generation = match unsafe { stmt.db.as_ref() } {
+        Some(db) => db.schema_generation(),
+        None => 0,
+    };
+    stmt.column_names = stmt
+        .statement
+        .current_column_names(generation)
+        .unwrap_or_default();
+
+    match settled {
+        Some(Err(err)) => result_code::<c_int>(Err(err)),
+        _ => SQLITE_OK,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_close_v2(db: *mut SQLite3) -> c_int {
     if !is_aligned(db) {
-        return 1;
+        return SQLITE_OK;
     }
 
-    let db = unsafe { &*db };
+    // Steps still in flight borrow the connection, statements left open wait for them here
+    let in_flight = std::mem::take(&mut *(*db).in_flight_steps.lock().unwrap());
+    in_flight
+        .into_values()
+        .for_each(|pending| drop(pending.wait()));
 
-    if db.has_began_transaction() {
-        0 // Transaction is active
-    } else {
-        1 // Autocommit mode
+    if let Some(warm_up) = (*db).warm_up.lock().unwrap().take() {
+        warm_up.cancel();
     }
+
+    // Queued writes must reach the server before the connection goes away
+    let write_behind = (*db).write_behind.lock().unwrap().take();
+    let worker = &(*db).worker;
+    if let Some(write_behind) = write_behind {
+        worker.run(write_behind.drain());
+    }
+
+    execute_async_task(worker, sqlite::end_tnx_on_db(db));
+    worker.run(attach::detach_all(&*db));
+    worker.run(async { (*db).connection.lock().await.close().await });
+
+    sqlite::unregister_connection(db);
+    drop(Box::from_raw(db));
+
+    SQLITE_OK
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_create_function_v2(
-    _db: *mut c_void,
-    _zFunctionName: *const c_char,
-    _nArg: c_int,
-    _eTextRep: c_int,
-    _pApp: *mut c_void,
-    _xFunc: Option<extern "C" fn(*mut c_void, c_int, *mut *mut c_void)>,
-    _xStep: Option<extern "C" fn(*mut c_void, c_int, *mut *mut c_void)>,
-    _xFinal: Option<extern "C" fn(*mut c_void)>,
-    _xDestroy: Option<extern "C" fn(*mut c_void)>,
-) -> c_int {
-    if cfg!(debug_assertions) {
-        eprintln!(
-            "Not Yet Supported: sqlite3_create_function_v2 : {:?}",
-            unsafe { CStr::from_ptr(_zFunctionName) }
-        );
+pub unsafe extern "C" fn sqlite3_errcode(db: *mut SQLite3) -> c_int {
+    sqlite3_extended_errcode(db) & 0xff
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_extended_errcode(_: *mut SQLite3) -> c_int {
+    let error_stack = get_latest_error();
+    if let Some((_, code)) = error_stack {
+        return code;
     }
+
     SQLITE_OK
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_stmt_isexplain(stmt: *mut SQLite3PreparedStmt) -> c_int {
-    if !is_aligned(stmt) {
+pub unsafe extern "C" fn sqlite3_changes(db: *mut SQLite3) -> c_int {
+    if !is_aligned(db) {
         return SQLITE_OK;
     }
+    let db = &*db;
 
-    let stmt_ref = unsafe { &*stmt };
-    let sql_trimmed = stmt_ref.sql.trim_start().to_uppercase();
+    if let Ok(rows_written) = db.rows_written.lock() {
+        if rows_written.is_some() {
+            return rows_written.unwrap() as c_int;
+        }
+    }
 
-    if sql_trimmed.starts_with("EXPLAIN QUERY PLAN") {
-        2 // EXPLAIN QUERY PLAN
-    } else if sql_trimmed.starts_with("EXPLAIN") {
-        1 // EXPLAIN (no QUERY PLAN)
-    } else {
-        SQLITE_OK
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_errmsg(_: *mut SQLite3) -> *const c_char {
+    if let Some(error_entry) = sqlite::get_latest_error() {
+        if let Ok(c_string) = CString::new(error_entry.0) {
+            return c_string.into_raw();
+        }
     }
+    std::ptr::null()
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_compileoption_used(opt_name: *const c_char) -> c_int {
-    if opt_name.is_null() {
-        return 0;
+pub extern "C" fn sqlite3_column_type(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> i32 {
+    if stmt.is_null() {
+        return SQLITE_NULL;
     }
-    let opt = unsafe { CStr::from_ptr(opt_name) };
-    let opt_str = match opt.to_str() {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
-    // SQLite C API allows callers to omit the "SQLITE_" prefix
-    let opt_str = opt_str.strip_prefix("SQLITE_").unwrap_or(opt_str);
 
-    match opt_str {
-        // sqlite3_column_table_name is already implemented in this proxy
-        "ENABLE_COLUMN_METADATA" => 1,
-        _ => 0,
+    let stmt = unsafe { &mut *stmt };
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+
+    // Check if there's a current row
+    if let Some(row_index) = *current_row {
+        // Get the row at the current index
+        if let Some(row) = result_rows.get(row_index) {
+            // Get the value at the specified column index
+            if let Some(value) = row.get(col_index as usize) {
+                // Match the value type to determine SQLite type
+                return match value {
+                    Value::Integer(_) => SQLITE_INTEGER,
+                    Value::Real(_) => SQLITE_FLOAT,
+                    Value::Text(_) => SQLITE_TEXT,
+                    Value::Blob(_) => SQLITE_BLOB,
+                    Value::Null => SQLITE_NULL,
+                };
+            }
+        }
     }
+
+    SQLITE_NULL // Invalid column or no current row
 }
 
 #[no_mangle]
-pub extern "C" fn sqlite3_compileoption_get(n: c_int) -> *const c_char {
-    match n {
-        0 => b"ENABLE_COLUMN_METADATA\0".as_ptr() as *const c_char,
-        _ => std::ptr::null(),
+pub extern "C" fn sqlite3_column_bytes(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> i32 {
+    if stmt.is_null() {
+        return 0;
     }
-}
\ No newline at end of file
+
+    let stmt = unsafe { &mut *stmt };
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+
+    // Check if there's a current row
+    if let Some(row_index) = *current_row {
+        // Get the row at the current index
+        if let Some(row) = result_rows.get(row_index) {
+            // Get the value at the specified column index
+            if let Some(value) = row.get(col_index as usize) {
+                // Once read as text, the value is as long as the text handed out. Until
+                // then numbers count the bytes of their text form, as in SQLite
+                let row_text = stmt.row_text.lock().unwrap();
+                return match row_text.get(&(col_index as usize)) {
+                    Some(text) => text.len() as i32,
+                    None => coercion::byte_len(value) as i32,
+                };
+            }
+        }
+    }
+
+    0 // Invalid column or no current row
+}
+
+/// Bytes of the value, valid until the statement is stepped, reset or finalized. A BLOB is
+/// handed out as it is, anything else as the text `sqlite3_column_text` gives, so that
+/// `sqlite3_column_bytes` agrees with both. NULL and empty values read as NULL.
+#[no_mangle]
+pub extern "C" fn sqlite3_column_blob(
+    stmt: *mut SQLite3PreparedStmt,
+    col_index: i32,
+) -> *const c_void {
+    if !is_aligned(stmt) {
+        return std::ptr::null();
+    }
+
+    let stmt = unsafe { &*stmt };
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+
+    let value = current_row
+        .and_then(|row_index| result_rows.get(row_index))
+        .and_then(|row| row.get(col_index as usize));
+    match value {
+        None | Some(Value::Null) => std::ptr::null(),
+        Some(Value::Blob(b)) if b.is_empty() => std::ptr::null(),
+        Some(Value::Blob(b)) => b.as_ptr().cast(),
+        Some(value) => {
+            let mut row_text = stmt.row_text.lock().unwrap();
+            let text = match row_text.entry(col_index as usize) {
+                Entry::Occupied(text) => text.into_mut(),
+                Entry::Vacant(slot) => match coercion::CText::new(value) {
+                    Some(text) => slot.insert(text),
+                    None => return std::ptr::null(),
+                },
+            };
+            if text.len() == 0 {
+                std::ptr::null()
+            } else {
+                text.as_ptr().cast()
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_text(
+    stmt: *mut SQLite3PreparedStmt,
+    col_index: i32,
+) -> *const c_char {
+    if stmt.is_null() {
+        return std::ptr::null();
+    }
+
+    let stmt = unsafe { &mut *stmt };
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+
+    // Check if there's a current row
+    if let Some(row_index) = *current_row {
+        if let Some(value) = result_rows
+            .get(row_index)
+            .and_then(|row| row.get(col_index as usize))
+        {
+            // NULL reads as a NULL pointer, everything else as its text form, kept on the
+            // statement so the pointer stays valid until the next step
+            let mut row_text = stmt.row_text.lock().unwrap();
+            return match row_text.entry(col_index as usize) {
+                Entry::Occupied(text) => text.get().as_ptr(),
+                Entry::Vacant(slot) => match coercion::CText::new(value) {
+                    Some(text) => slot.insert(text).as_ptr(),
+                    None => std::ptr::null(),
+                },
+            };
+        }
+    }
+
+    std::ptr::null() // Invalid column or no current row
+}
+
+/// The elements of a vector column as floats, with their count written to `dims`. Reads
+/// `F32_BLOB` and `F64_BLOB` values and the text of `vector_extract`. The array is aligned
+/// for `float` and valid until the statement is stepped, reset or finalized. NULL, with
+/// `dims` set to 0, for any other value.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_turso_column_vector_f32(
+    stmt: *mut SQLite3PreparedStmt,
+    col_index: c_int,
+    dims: *mut c_int,
+) -> *const f32 {
+    if !dims.is_null() {
+        *dims = 0;
+    }
+    if !is_aligned(stmt) || col_index < 0 {
+        return std::ptr::null();
+    }
+
+    let stmt = &*stmt;
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+    let Some(value) = current_row
+        .and_then(|row_index| result_rows.get(row_index))
+        .and_then(|row| row.get(col_index as usize))
+    else {
+        return std::ptr::null();
+    };
+
+    let mut row_vectors = stmt.row_vectors.lock().unwrap();
+    let vector = match row_vectors.entry(col_index as usize) {
+        Entry::Occupied(vector) => vector.into_mut(),
+        Entry::Vacant(slot) => match vector::from_value(value) {
+            Some(vector) => slot.insert(vector),
+            None => return std::ptr::null(),
+        },
+    };
+    if !dims.is_null() {
+        *dims = vector.len() as c_int;
+    }
+    vector.as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_double(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> f64 {
+    if stmt.is_null() {
+        return 0.0;
+    }
+
+    let stmt = unsafe { &mut *stmt };
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+
+    // Check if there's a current row
+    if let Some(row_index) = *current_row {
+        if let Some(value) = result_rows
+            .get(row_index)
+            .and_then(|row| row.get(col_index as usize))
+        {
+            return coercion::to_double(value);
+        }
+    }
+
+    0.0
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_int64(stmt: *mut SQLite3PreparedStmt, col_index: i32) -> i64 {
+    if stmt.is_null() {
+        return 0;
+    }
+
+    let stmt = unsafe { &mut *stmt };
+    let result_rows = stmt.result_rows.lock().unwrap();
+    let current_row = stmt.current_row.lock().unwrap();
+
+    // Check if there's a current row
+    if let Some(row_index) = *current_row {
+        // Get the row at the current index
+        if let Some(row) = result_rows.get(row_index) {
+            // Get the value at the specified column index
+            if let Some(value) = row.get(col_index as usize) {
+                return coercion::to_int64(value);
+            }
+        }
+    }
+
+    0 // Invalid column or no current row
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_name(
+    stmt: *mut SQLite3PreparedStmt,
+    col_index: i32,
+) -> *const c_char {
+    if stmt.is_null() {
+        return std::ptr::null();
+    }
+
+    let stmt = unsafe { &mut *stmt };
+    let db = unsafe { &*stmt.db };
+    db.worker.run(sqlite::describe_stmt(stmt));
+
+    // Check if the column index is valid
+    if col_index < 0 || col_index as usize >= stmt.column_names.len() {
+        return std::ptr::null(); // Invalid column index
+    }
+
+    let column_name = &stmt.column_names[col_index as usize];
+
+    // A name cannot end early, so NULs in it are dropped
+    CString::new(column_name.replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// The declared type of a result column, from the server's `describe` before the first step or
+/// from the last execution after it. NULL for expressions and when the type is unknown.
+#[no_mangle]
+pub extern "C" fn sqlite3_column_decltype(
+    stmt: *mut SQLite3PreparedStmt,
+    col_index: i32,
+) -> *const c_char {
+    if !is_aligned(stmt) {
+        return std::ptr::null();
+    }
+
+    let stmt = unsafe { &mut *stmt };
+    let db = unsafe { &*stmt.db };
+    db.worker.run(sqlite::describe_stmt(stmt));
+
+    if col_index < 0 || col_index as usize >= stmt.column_names.len() {
+        return std::ptr::null();
+    }
+    stmt.statement.decltype(col_index as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_column_table_name(
+    stmt: *mut SQLite3PreparedStmt,
+    col_index: i32,
+) -> *const c_char {
+    if stmt.is_null() {
+        return std::ptr::null();
+    }
+
+    // Safety: Dereference the pointer to access the prepared statement
+    let stmt = unsafe { &mut *stmt };
+
+    // Validate the column index
+    if col_index < 0 || col_index as usize >= stmt.column_names.len() {
+        return std::ptr::null(); // Invalid column index
+    }
+
+    // Get the SQL query
+    let sql = stmt.sql.as_str();
+
+    // Use a regex to extract table names from the SQL query
+    let table_regex = Regex::new(r"FROM\s+([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+
+    // Match and capture the table name
+    if let Some(captures) = table_regex.captures(sql) {
+        if let Some(table_name) = captures.get(1) {
+            // Return the table name as a CString
+            match CString::new(table_name.as_str()) {
+                Ok(c_string) => return c_string.into_raw(),
+                Err(_) => return std::ptr::null(), // Handle CString creation error
+            }
+        }
+    }
+
+    std::ptr::null() // No table name found
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_errstr(errcode: c_int) -> *const c_char {
+    let message = match errcode {
+        SQLITE_OK => "Successful result",
+        SQLITE_ERROR => "SQL error or missing database",
+        SQLITE_MISUSE => "Library used incorrectly",
+        SQLITE_RANGE => "2nd parameter to sqlite3_bind out of range",
+        SQLITE_BUSY => "The database file is locked",
+        SQLITE_PERM => "access permission denied",
+        SQLITE_READONLY => "attempt to write a readonly database",
+        SQLITE_FULL => "database or disk is full",
+        SQLITE_CONSTRAINT => "constraint failed",
+        SQLITE_AUTH => "authorization denied",
+        SQLITE_CANTOPEN => "Either database does not exist or cannot be opened",
+        sqlite::SQLITE_TOOBIG => "string or blob too big",
+        _ => "Unknown error code",
+    };
+
+    CString::new(message).unwrap().into_raw()
+}
+
+// Pragmas on the database header, always run on the server
+const HEADER_PRAGMAS: [&str; 3] = ["application_id", "schema_version", "user_version"];
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_exec(
+    db: *mut SQLite3,
+    sql: *const c_char,
+    callback: SQLite3ExecCallback,
+    arg: *mut c_void,
+    _errmsg: *mut *mut c_char,
+) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_CANTOPEN;
+    }
+
+    let sql = CStr::from_ptr(sql).to_string_lossy().to_string();
+
+    match authorize(&*db, &sql) {
+        Ok(Authorization::Allowed) => (),
+        Ok(Authorization::Ignored) => return SQLITE_OK,
+        Err(error) => return push_error((error.message, error.code)),
+    }
+
+    if let Some((name, value)) = parse_turso_pragma(&sql) {
+        return match (*db)
+            .worker
+            .run(sqlite::handle_turso_pragma(&*db, &name, value.as_deref()))
+        {
+            Ok(_) => SQLITE_OK,
+            Err(error) => push_error((error.to_string(), error.code)),
+        };
+    }
+
+    match classify(&sql) {
+        // Pragmas only reach the server when the caller asked for their rows, when they read
+        // or write the database header, where migration tools keep their version, or when
+        // they change a setting the connection's later statements must run with
+        StatementClass::Pragma { name, assigns }
+            if callback.is_none()
+                && !HEADER_PRAGMAS.contains(&name.as_str())
+                && !(assigns && session::SESSION_PRAGMAS.contains(&name.as_str())) =>
+        {
+            SQLITE_OK
+        }
+        StatementClass::Begin => {
+            execute_async_task(&(*db).worker, sqlite::begin_tnx_on_db(db, &sql))
+        }
+        StatementClass::Rollback => {
+            execute_async_task(&(*db).worker, sqlite::rollback_tnx_on_db(db, &sql))
+        }
+        StatementClass::Commit => {
+            execute_async_task(&(*db).worker, sqlite::commit_tnx_on_db(db, &sql))
+        }
+        StatementClass::Savepoint(name) => {
+            execute_async_task(&(*db).worker, sqlite::savepoint_on_db(db, &sql, &name))
+        }
+        StatementClass::Release(name) => execute_async_task(
+            &(*db).worker,
+            sqlite::release_savepoint_on_db(db, &sql, &name),
+        ),
+        StatementClass::RollbackTo(name) => execute_async_task(
+            &(*db).worker,
+            sqlite::rollback_to_savepoint_on_db(db, &sql, &name),
+        ),
+        StatementClass::Attach { database, schema } => {
+            execute_async_task(&(*db).worker, attach::attach_on_db(db, &database, &schema))
+        }
+        StatementClass::Detach(schema) => {
+            execute_async_task(&(*db).worker, attach::detach_on_db(db, &schema))
+        }
+        StatementClass::Pragma { .. }
+        | StatementClass::CreateTemp { .. }
+        | StatementClass::Drop(_)
+        | StatementClass::Other => execute_async_task(
+            &(*db).worker,
+            sqlite::handle_execute(db, &sql, callback, arg),
+        ),
+    }
+}
+
+/// The result of `sql` as one array of strings, column names first, released with
+/// `sqlite3_free_table`. Refused with `SQLITE_TOOBIG` above `PRAGMA turso.table_limit` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_get_table(
+    db: *mut SQLite3,
+    sql: *const c_char,
+    result: *mut *mut *mut c_char,
+    row_count: *mut c_int,
+    column_count: *mut c_int,
+    errmsg: *mut *mut c_char,
+) -> c_int {
+    if !is_aligned(db) || sql.is_null() || result.is_null() {
+        return SQLITE_MISUSE;
+    }
+    *result = std::ptr::null_mut();
+    if !errmsg.is_null() {
+        *errmsg = std::ptr::null_mut();
+    }
+
+    let mut table = Table::new((*db).table_limit.load(Ordering::Relaxed));
+    let rc = sqlite3_exec(
+        db,
+        sql,
+        Some(table::collect),
+        &mut table as *mut Table as *mut c_void,
+        std::ptr::null_mut(),
+    );
+    // The callback's own error explains why it stopped the query
+    let rc = match table.error.take() {
+        Some(error) => push_error(error),
+        None => rc,
+    };
+    if rc != SQLITE_OK {
+        if let (false, Some((message, _))) = (errmsg.is_null(), get_latest_error()) {
+            *errmsg = cutil::to_c_string(message.as_bytes());
+        }
+        return rc;
+    }
+
+    if !row_count.is_null() {
+        *row_count = table.rows() as c_int;
+    }
+    if !column_count.is_null() {
+        *column_count = table.columns() as c_int;
+    }
+    *result = table.into_block();
+    if (*result).is_null() {
+        return push_error(("out of memory".to_string(), sqlite::SQLITE_NOMEM));
+    }
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_free_table(result: *mut *mut c_char) {
+    table::free_table(result);
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_update_hook(
+    db: *mut SQLite3,
+    callback: Option<sqlite::SqliteHook>,
+    user_data: *mut c_void,
+) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_CANTOPEN;
+    }
+
+    let db = unsafe { &*db };
+
+    db.register_hook(sqlite::SQLITE_UPDATE, callback, user_data)
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_set_authorizer(
+    db: *mut SQLite3,
+    callback: Option<AuthorizerCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_MISUSE;
+    }
+
+    let db = unsafe { &*db };
+    *db.authorizer.lock().unwrap() = callback.map(|cb| Authorizer::new(cb, user_data));
+
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_commit_hook(
+    db: *mut SQLite3,
+    _x_callback: Option<unsafe extern "C" fn(*mut c_void) -> c_int>, // int (*xCallback)(void*)
+    _p_arg: *mut c_void,                                             // void *pArg
+) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_CANTOPEN;
+    }
+
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_rollback_hook(
+    db: *mut SQLite3,
+    _x_callback: Option<unsafe extern "C" fn(*mut c_void) -> c_int>,
+    _p_arg: *mut c_void,
+) -> c_int {
+    if !is_aligned(db) {
+        return SQLITE_CANTOPEN;
+    }
+
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_get_autocommit(db: *mut SQLite3) -> c_int {
+    if !is_aligned(db) {
+        return 1;
+    }
+
+    let db = unsafe { &*db };
+
+    if db.has_began_transaction() {
+        0 // Transaction is active
+    } else {
+        1 // Autocommit mode
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_create_function_v2(
+    db: *mut SQLite3,
+    z_function_name: *const c_char,
+    n_arg: c_int,
+    _e_text_rep: c_int,
+    p_app: *mut c_void,
+    x_func: Option<ScalarCallback>,
+    x_step: Option<extern "C" fn(*mut c_void, c_int, *mut *mut c_void)>,
+    _x_final: Option<extern "C" fn(*mut c_void)>,
+    x_destroy: Option<DestroyCallback>,
+) -> c_int {
+    if !is_aligned(db) || z_function_name.is_null() || !(-1..=127).contains(&n_arg) {
+        return SQLITE_MISUSE;
+    }
+
+    let db = &*db;
+    let name = CStr::from_ptr(z_function_name)
+        .to_string_lossy()
+        .to_ascii_lowercase();
+
+    if x_step.is_some() {
+        // As SQLite does when registration fails, the application data is released here
+        if let Some(destroy) = x_destroy {
+            destroy(p_app);
+        }
+        return push_error((
+            format!("{}: aggregate functions are not supported", name),
+            SQLITE_ERROR,
+        ));
+    }
+
+    let mut registry = db.functions.lock().unwrap();
+    match x_func {
+        Some(callback) => {
+            let function = ScalarFunction::new(p_app, callback, x_destroy);
+            registry.insert((name, n_arg), Arc::new(function));
+        }
+        // Registering without any callback deletes the function
+        None => {
+            registry.remove(&(name, n_arg));
+        }
+    }
+
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_create_function(
+    db: *mut SQLite3,
+    z_function_name: *const c_char,
+    n_arg: c_int,
+    e_text_rep: c_int,
+    p_app: *mut c_void,
+    x_func: Option<ScalarCallback>,
+    x_step: Option<extern "C" fn(*mut c_void, c_int, *mut *mut c_void)>,
+    x_final: Option<extern "C" fn(*mut c_void)>,
+) -> c_int {
+    sqlite3_create_function_v2(
+        db,
+        z_function_name,
+        n_arg,
+        e_text_rep,
+        p_app,
+        x_func,
+        x_step,
+        x_final,
+        None,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_create_collation_v2(
+    db: *mut SQLite3,
+    z_name: *const c_char,
+    _e_text_rep: c_int,
+    p_arg: *mut c_void,
+    x_compare: Option<CompareCallback>,
+    x_destroy: Option<DestroyCallback>,
+) -> c_int {
+    if !is_aligned(db) || z_name.is_null() {
+        return SQLITE_MISUSE;
+    }
+
+    let db = &*db;
+    let name = CStr::from_ptr(z_name).to_string_lossy().to_lowercase();
+
+    let mut registry = db.collations.lock().unwrap();
+    match x_compare {
+        Some(compare) => {
+            let collation = Collation::new(p_arg, compare, x_destroy);
+            registry.insert(name, Arc::new(collation));
+        }
+        // Registering without a callback deletes the collation
+        None => {
+            registry.remove(&name);
+        }
+    }
+
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_create_collation(
+    db: *mut SQLite3,
+    z_name: *const c_char,
+    e_text_rep: c_int,
+    p_arg: *mut c_void,
+    x_compare: Option<CompareCallback>,
+) -> c_int {
+    sqlite3_create_collation_v2(db, z_name, e_text_rep, p_arg, x_compare, None)
+}
+
+// Only the main schema takes part in a backup, NULL standing for it as in SQLite
+unsafe fn is_main_schema(name: *const c_char) -> bool {
+    name.is_null()
+        || CStr::from_ptr(name)
+            .to_string_lossy()
+            .eq_ignore_ascii_case("main")
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_backup_init(
+    dest: *mut SQLite3,
+    dest_name: *const c_char,
+    source: *mut SQLite3,
+    source_name: *const c_char,
+) -> *mut Backup {
+    if !is_aligned(dest) || !is_aligned(source) {
+        return std::ptr::null_mut();
+    }
+
+    if dest == source {
+        push_error((
+            "source and destination must be distinct".to_string(),
+            SQLITE_ERROR,
+        ));
+        return std::ptr::null_mut();
+    }
+    for name in [dest_name, source_name] {
+        if !is_main_schema(name) {
+            let name = CStr::from_ptr(name).to_string_lossy();
+            push_error((format!("unknown database {}", name), SQLITE_ERROR));
+            return std::ptr::null_mut();
+        }
+    }
+    if (*dest).has_began_transaction() {
+        push_error(("destination database is in use".to_string(), SQLITE_ERROR));
+        return std::ptr::null_mut();
+    }
+
+    Box::into_raw(Box::new(Backup::new(dest, source)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_backup_step(backup: *mut Backup, n_page: c_int) -> c_int {
+    if !is_aligned(backup) {
+        return SQLITE_MISUSE;
+    }
+
+    execute_async_task((*backup).worker(), (*backup).step(n_page))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_backup_finish(backup: *mut Backup) -> c_int {
+    if !is_aligned(backup) {
+        return SQLITE_OK;
+    }
+
+    let backup = Box::from_raw(backup);
+    match backup.error() {
+        Some(error) => error.code & 0xff,
+        None => SQLITE_OK,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_blob_open(
+    db: *mut SQLite3,
+    schema: *const c_char,
+    table: *const c_char,
+    column: *const c_char,
+    rowid: i64,
+    flags: c_int,
+    pp_blob: *mut *mut BlobHandle,
+) -> c_int {
+    if pp_blob.is_null() {
+        return SQLITE_MISUSE;
+    }
+    *pp_blob = std::ptr::null_mut();
+    if !is_aligned(db) || table.is_null() || column.is_null() {
+        return SQLITE_MISUSE;
+    }
+
+    let schema = if schema.is_null() {
+        "main".into()
+    } else {
+        CStr::from_ptr(schema).to_string_lossy()
+    };
+    let table = CStr::from_ptr(table).to_string_lossy();
+    let column = CStr::from_ptr(column).to_string_lossy();
+
+    let opened = (*db).worker.run(BlobHandle::open(
+        db,
+        &schema,
+        &table,
+        &column,
+        rowid,
+        flags != 0,
+    ));
+    match opened {
+        Ok(handle) => {
+            *pp_blob = Box::into_raw(Box::new(handle));
+            SQLITE_OK
+        }
+        Err(error) => push_error((error.message, error.code)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_blob_reopen(blob: *mut BlobHandle, rowid: i64) -> c_int {
+    if !is_aligned(blob) {
+        return SQLITE_MISUSE;
+    }
+
+    execute_async_task((*blob).worker(), async {
+        (*blob).reopen(rowid).await.map(|_| SQLITE_OK)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_blob_bytes(blob: *mut BlobHandle) -> c_int {
+    if !is_aligned(blob) {
+        return 0;
+    }
+
+    (*blob).size() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_blob_read(
+    blob: *mut BlobHandle,
+    out: *mut c_void,
+    n: c_int,
+    offset: c_int,
+) -> c_int {
+    if !is_aligned(blob) || n < 0 || offset < 0 || (out.is_null() && n > 0) {
+        return SQLITE_MISUSE;
+    }
+    if n == 0 {
+        return SQLITE_OK;
+    }
+
+    let out = slice::from_raw_parts_mut(out as *mut u8, n as usize);
+    execute_async_task((*blob).worker(), async {
+        (*blob).read(out, offset as usize).await.map(|_| SQLITE_OK)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_blob_write(
+    blob: *mut BlobHandle,
+    data: *const c_void,
+    n: c_int,
+    offset: c_int,
+) -> c_int {
+    if !is_aligned(blob) || n < 0 || offset < 0 || (data.is_null() && n > 0) {
+        return SQLITE_MISUSE;
+    }
+
+    let data = if n == 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(data as *const u8, n as usize)
+    };
+    execute_async_task((*blob).worker(), async {
+        (*blob)
+            .write(data, offset as usize)
+            .await
+            .map(|_| SQLITE_OK)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_blob_close(blob: *mut BlobHandle) -> c_int {
+    if is_aligned(blob) {
+        drop(Box::from_raw(blob));
+    }
+
+    SQLITE_OK
+}
+
+const SQLITE_SERIALIZE_NOCOPY: c_uint = 0x001;
+const SQLITE_DESERIALIZE_FREEONCLOSE: c_uint = 0x001;
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_serialize(
+    db: *mut SQLite3,
+    schema: *const c_char,
+    size: *mut i64,
+    flags: c_uint,
+) -> *mut u8 {
+    if !is_aligned(db) {
+        return std::ptr::null_mut();
+    }
+    if !size.is_null() {
+        *size = -1;
+    }
+
+    // There is no image in memory to hand out without copying
+    if flags & SQLITE_SERIALIZE_NOCOPY != 0 {
+        return std::ptr::null_mut();
+    }
+    if !is_main_schema(schema) {
+        return std::ptr::null_mut();
+    }
+
+    let image = match (*db).worker.run(serialize::serialize(db)) {
+        Ok(image) => image,
+        Err(error) => {
+            push_error((error.message, error.code));
+            return std::ptr::null_mut();
+        }
+    };
+
+    let buffer = allocate(image.len());
+    if buffer.is_null() {
+        return buffer;
+    }
+    std::ptr::copy_nonoverlapping(image.as_ptr(), buffer, image.len());
+    if !size.is_null() {
+        *size = image.len() as i64;
+    }
+
+    buffer
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_deserialize(
+    db: *mut SQLite3,
+    schema: *const c_char,
+    data: *mut u8,
+    size: i64,
+    _buffer_size: i64,
+    flags: c_uint,
+) -> c_int {
+    if !is_aligned(db) || data.is_null() || size < 0 {
+        return SQLITE_MISUSE;
+    }
+
+    // The image is replayed and not kept, so a buffer handed over is released right away
+    let result = if is_main_schema(schema) {
+        let image = slice::from_raw_parts(data, size as usize);
+        execute_async_task(&(*db).worker, async {
+            serialize::deserialize(db, image).await.map(|_| SQLITE_OK)
+        })
+    } else {
+        let name = CStr::from_ptr(schema).to_string_lossy();
+        push_error((format!("unknown database {}", name), SQLITE_ERROR))
+    };
+    if flags & SQLITE_DESERIALIZE_FREEONCLOSE != 0 {
+        release(data);
+    }
+
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_malloc(size: c_int) -> *mut c_void {
+    allocate(size.max(0) as usize) as *mut c_void
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_malloc64(size: u64) -> *mut c_void {
+    allocate(size as usize) as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_msize(ptr: *mut c_void) -> u64 {
+    allocation_size(ptr as *const u8) as u64
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_free(ptr: *mut c_void) {
+    release(ptr as *mut u8);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_stricmp(left: *const c_char, right: *const c_char) -> c_int {
+    cutil::compare(left, right, None)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_strnicmp(
+    left: *const c_char,
+    right: *const c_char,
+    length: c_int,
+) -> c_int {
+    cutil::compare(left, right, Some(length.max(0) as usize))
+}
+
+// sqlite3_mprintf and sqlite3_vmprintf, from cutil.c
+#[no_mangle]
+pub unsafe extern "C" fn turso_vmprintf(format: *const c_char, args: *mut c_void) -> *mut c_char {
+    if format.is_null() {
+        return std::ptr::null_mut();
+    }
+    let text = cutil::format(CStr::from_ptr(format).to_bytes(), &mut cutil::VaList(args));
+    cutil::to_c_string(&text)
+}
+
+// sqlite3_snprintf and sqlite3_vsnprintf, from cutil.c. Writes at most `size` bytes
+// including the terminator, and returns `buffer`
+#[no_mangle]
+pub unsafe extern "C" fn turso_vsnprintf(
+    size: c_int,
+    buffer: *mut c_char,
+    format: *const c_char,
+    args: *mut c_void,
+) -> *mut c_char {
+    if size <= 0 || buffer.is_null() {
+        return buffer;
+    }
+    let text = match format.is_null() {
+        true => Vec::new(),
+        false => cutil::format(CStr::from_ptr(format).to_bytes(), &mut cutil::VaList(args)),
+    };
+    let length = text.len().min(size as usize - 1);
+    std::ptr::copy_nonoverlapping(text.as_ptr() as *const c_char, buffer, length);
+    *buffer.add(length) = 0;
+    buffer
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_status64(
+    op: c_int,
+    current: *mut i64,
+    highwater: *mut i64,
+    reset: c_int,
+) -> c_int {
+    if current.is_null() || highwater.is_null() {
+        return SQLITE_MISUSE;
+    }
+
+    match status::status(op, reset != 0) {
+        Ok((cur, high)) => {
+            *current = cur;
+            *highwater = high;
+            SQLITE_OK
+        }
+        Err(err) => err.code,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_status(
+    op: c_int,
+    current: *mut c_int,
+    highwater: *mut c_int,
+    reset: c_int,
+) -> c_int {
+    if current.is_null() || highwater.is_null() {
+        return SQLITE_MISUSE;
+    }
+
+    let (mut cur, mut high) = (0, 0);
+    let rc = sqlite3_status64(op, &mut cur, &mut high, reset);
+    if rc == SQLITE_OK {
+        *current = cur as c_int;
+        *highwater = high as c_int;
+    }
+    rc
+}
+
+/// Counters for one statement: `RUN` executions, `VM_STEP` rows stepped, `FULLSCAN_STEP` rows
+/// the server read, `SORT` client-side collation sorts and `MEMUSED` buffered row bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_stmt_status(
+    stmt: *mut SQLite3PreparedStmt,
+    op: c_int,
+    reset: c_int,
+) -> c_int {
+    if !is_aligned(stmt) {
+        return 0;
+    }
+
+    status::stmt_status(&mut *stmt, op, reset != 0)
+}
+
+/// Status counters for a connection, mapped onto what the shim keeps: the result-row cache
+/// (`CACHE_USED`, `CACHE_HIT`, `CACHE_MISS`), the statement cache (`STMT_USED`) and requests
+/// waiting on the server (`LOOKASIDE_USED`).
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_db_status(
+    db: *mut SQLite3,
+    op: c_int,
+    current: *mut c_int,
+    highwater: *mut c_int,
+    reset: c_int,
+) -> c_int {
+    if !is_aligned(db) || current.is_null() || highwater.is_null() {
+        return SQLITE_MISUSE;
+    }
+
+    match status::db_status(&*db, op, reset != 0) {
+        Ok((cur, high)) => {
+            *current = cur as c_int;
+            *highwater = high as c_int;
+            SQLITE_OK
+        }
+        Err(err) => push_error((err.message, err.code)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_backup_remaining(backup: *mut Backup) -> c_int {
+    if !is_aligned(backup) {
+        return 0;
+    }
+
+    (*backup).remaining()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_backup_pagecount(backup: *mut Backup) -> c_int {
+    if !is_aligned(backup) {
+        return 0;
+    }
+
+    (*backup).pagecount()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_user_data(context: *mut FunctionContext) -> *mut c_void {
+    if !is_aligned(context) {
+        return std::ptr::null_mut();
+    }
+
+    (*context).app
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_context_db_handle(context: *mut FunctionContext) -> *mut SQLite3 {
+    if !is_aligned(context) {
+        return std::ptr::null_mut();
+    }
+
+    (*context).db
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_type(value: *mut FunctionArg) -> c_int {
+    if !is_aligned(value) {
+        return SQLITE_NULL;
+    }
+
+    match (*value).value {
+        Value::Integer(_) => SQLITE_INTEGER,
+        Value::Real(_) => SQLITE_FLOAT,
+        Value::Text(_) => SQLITE_TEXT,
+        Value::Blob(_) => SQLITE_BLOB,
+        Value::Null => SQLITE_NULL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_int64(value: *mut FunctionArg) -> i64 {
+    if !is_aligned(value) {
+        return 0;
+    }
+
+    coercion::to_int64(&(*value).value)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_int(value: *mut FunctionArg) -> c_int {
+    sqlite3_value_int64(value) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_double(value: *mut FunctionArg) -> f64 {
+    if !is_aligned(value) {
+        return 0.0;
+    }
+
+    coercion::to_double(&(*value).value)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_text(value: *mut FunctionArg) -> *const c_char {
+    if !is_aligned(value) {
+        return std::ptr::null();
+    }
+
+    (*value)
+        .text
+        .as_ref()
+        .map_or(std::ptr::null(), |text| text.as_ptr())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_bytes(value: *mut FunctionArg) -> c_int {
+    if !is_aligned(value) {
+        return 0;
+    }
+
+    (*value).text.as_ref().map_or(0, |text| text.len() as c_int)
+}
+
+/// `'J'` for JSON text from one of SQLite's JSON functions, else 0.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_value_subtype(value: *mut FunctionArg) -> c_uint {
+    if !is_aligned(value) {
+        return 0;
+    }
+
+    (*value).subtype
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_null(context: *mut FunctionContext) {
+    if is_aligned(context) {
+        (*context).result = Value::Null;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_int64(context: *mut FunctionContext, value: i64) {
+    if is_aligned(context) {
+        (*context).result = Value::Integer(value);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_int(context: *mut FunctionContext, value: c_int) {
+    sqlite3_result_int64(context, value as i64)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_double(context: *mut FunctionContext, value: f64) {
+    if is_aligned(context) {
+        (*context).result = Value::Real(value);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_text(
+    context: *mut FunctionContext,
+    text: *const c_char,
+    len: c_int,
+    destructor: *mut c_void, // SQLITE_STATIC, SQLITE_TRANSIENT or a destructor for `text`
+) {
+    if !is_aligned(context) {
+        return;
+    }
+
+    (*context).result = if text.is_null() {
+        Value::Null
+    } else {
+        Value::Text(functions::text_arg(text, len))
+    };
+
+    // The text is copied right away, so a real destructor can run immediately
+    if !destructor.is_null() && destructor as isize != -1 {
+        let destructor: DestroyCallback = std::mem::transmute(destructor);
+        destructor(text as *mut c_void);
+    }
+}
+
+/// Marks the result, e.g. with `'J'` as JSON text, which must then hold no NUL.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_subtype(context: *mut FunctionContext, subtype: c_uint) {
+    if is_aligned(context) {
+        (*context).subtype = subtype;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_result_error(
+    context: *mut FunctionContext,
+    message: *const c_char,
+    len: c_int,
+) {
+    if is_aligned(context) {
+        (*context).error = Some(functions::text_arg(message, len));
+    }
+}
+
+/// The statement's SQL with its values replaced by `?`, for grouping queries. Valid until
+/// the statement is finalized.
+#[no_mangle]
+pub extern "C" fn sqlite3_normalized_sql(stmt: *mut SQLite3PreparedStmt) -> *const c_char {
+    if !is_aligned(stmt) {
+        return std::ptr::null();
+    }
+    let stmt = unsafe { &*stmt };
+    stmt.statement.normalized.as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_stmt_isexplain(stmt: *mut SQLite3PreparedStmt) -> c_int {
+    if !is_aligned(stmt) {
+        return SQLITE_OK;
+    }
+
+    let stmt_ref = unsafe { &*stmt };
+    let sql_trimmed = stmt_ref.sql.trim_start().to_uppercase();
+
+    if sql_trimmed.starts_with("EXPLAIN QUERY PLAN") {
+        2 // EXPLAIN QUERY PLAN
+    } else if sql_trimmed.starts_with("EXPLAIN") {
+        1 // EXPLAIN (no QUERY PLAN)
+    } else {
+        SQLITE_OK
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_compileoption_used(opt_name: *const c_char) -> c_int {
+    if opt_name.is_null() {
+        return 0;
+    }
+    match unsafe { CStr::from_ptr(opt_name) }.to_str() {
+        Ok(name) => compile_options::is_used(name) as c_int,
+        Err(_) => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_compileoption_get(n: c_int) -> *const c_char {
+    let options = compile_options::options();
+    usize::try_from(n)
+        .ok()
+        .and_then(|n| options.get(n))
+        .map_or(std::ptr::null(), |option| option.as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn sqlite3_keyword_count() -> c_int {
+    keywords::KEYWORDS.len() as c_int
+}
+
+/// Keyword `index`. SQLite only promises `length` bytes at `name`, these are NUL-terminated too.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_keyword_name(
+    index: c_int,
+    name: *mut *const c_char,
+    length: *mut c_int,
+) -> c_int {
+    let keyword = usize::try_from(index)
+        .ok()
+        .and_then(|index| keywords::KEYWORDS.get(index));
+    let Some(keyword) = keyword else {
+        return SQLITE_ERROR;
+    };
+
+    if !name.is_null() {
+        *name = keyword.as_ptr();
+    }
+    if !length.is_null() {
+        *length = keyword.to_bytes().len() as c_int;
+    }
+    SQLITE_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_keyword_check(word: *const c_char, length: c_int) -> c_int {
+    if word.is_null() || length <= 0 {
+        return 0;
+    }
+    let word = std::slice::from_raw_parts(word.cast::<u8>(), length as usize);
+    keywords::is_keyword(word) as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // Answers every pipeline request with one row holding the statement's first argument, and
+    // an INSERT as having written a row with that argument as its rowid
+    fn start_echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut stream = stream;
+                    loop {
+                        let mut length = 0;
+                        let mut replication_index = None;
+                        loop {
+                            let mut line = String::new();
+                            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap();
+                                }
+                                if name.eq_ignore_ascii_case("x-turso-replication-index") {
+                                    replication_index = Some(value.trim().to_string());
+                                }
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                        }
+
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+                        let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                        let results: Vec<_> = request["requests"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|request| match request["type"].as_str() {
+                                Some("execute") => {
+                                    // Like sqld on a fresh stream: an INSERT reports the row
+                                    // it wrote, anything else the stream's rowid of 0
+                                    let arg = &request["stmt"]["args"][0];
+                                    let sql = request["stmt"]["sql"].as_str().unwrap_or("");
+                                    let inserted = sql.contains("INSERT");
+                                    let rowid = match arg["value"].as_str() {
+                                        Some(value) if inserted => value,
+                                        _ => "0",
+                                    };
+                                    // Selecting a BLOB returns the argument's digits as one,
+                                    // and replication_index the index the request was sent with
+                                    let value = if sql.contains("replication_index") {
+                                        match &replication_index {
+                                            Some(index) => {
+                                                serde_json::json!({"type": "text", "value": index})
+                                            }
+                                            None => serde_json::json!({"type": "null"}),
+                                        }
+                                    } else if sql.contains("BLOB") {
+                                        let digits = arg["value"].as_str().unwrap_or("");
+                                        serde_json::json!({
+                                            "type": "blob",
+                                            "base64": base64::engine::general_purpose::STANDARD
+                                                .encode(digits),
+                                        })
+                                    } else {
+                                        arg.clone()
+                                    };
+                                    serde_json::json!({
+                                        "type": "ok",
+                                        "response": {"type": "execute", "result": {
+                                            "cols": [{"name": "n"}],
+                                            "rows": [[value]],
+                                            "rows_written": inserted as u64,
+                                            "last_insert_rowid": rowid,
+                                            // The write is at the replica's index of its rowid
+                                            "replication_index": inserted.then_some(rowid),
+                                        }},
+                                    })
+                                }
+                                _ => {
+                                    serde_json::json!({"type": "ok", "response": {"type": "close"}})
+                                }
+                            })
+                            .collect();
+
+                        // Long enough for requests from other threads to pile up meanwhile
+                        thread::sleep(Duration::from_millis(1));
+                        let body =
+                            serde_json::json!({"baton": null, "results": results}).to_string();
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        if stream.write_all(response.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    fn open_echo_db() -> *mut SQLite3 {
+        open_echo_db_with(c"echo.db?auth=none&transport=http")
+    }
+
+    // One echo server for all tests, which connections find through TURSO_DB_URL
+    pub(crate) fn use_echo_server() {
+        static SERVER: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        SERVER.get_or_init(|| {
+            let url = start_echo_server();
+            std::env::set_var("TURSO_DB_URL", &url);
+            url
+        });
+    }
+
+    fn open_echo_db_with(filename: &CStr) -> *mut SQLite3 {
+        use_echo_server();
+
+        let mut db = std::ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_FULLMUTEX,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(rc, SQLITE_OK);
+        db
+    }
+
+    unsafe fn select_echo(db: *mut SQLite3, value: i64) -> i64 {
+        run_echo(db, c"SELECT ?", value)
+    }
+
+    unsafe fn prepare(db: *mut SQLite3, sql: &CStr) -> *mut SQLite3PreparedStmt {
+        let mut stmt = std::ptr::null_mut();
+        let rc = sqlite3_prepare_v3(
+            db,
+            sql.as_ptr(),
+            sql.to_bytes().len(),
+            0,
+            &mut stmt,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(rc, SQLITE_OK);
+        stmt
+    }
+
+    unsafe fn run_echo(db: *mut SQLite3, sql: &CStr, value: i64) -> i64 {
+        let stmt = prepare(db, sql);
+        assert_eq!(sqlite3_bind_int64(stmt, 1, value, None), SQLITE_OK);
+        assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+        let echoed = sqlite3_column_int64(stmt, 0);
+        assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+        sqlite3_finalize(stmt);
+        echoed
+    }
+
+    // Runs `test` in a forked copy of the test process and reports whether it passed
+    fn in_child(test: impl FnOnce() -> bool) -> bool {
+        extern "C" {
+            fn fork() -> i32;
+            fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+            fn _exit(status: i32) -> !;
+        }
+
+        unsafe {
+            let pid = fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                // Whatever happens, the child must not return into the test harness
+                let passed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(test));
+                _exit(if matches!(passed, Ok(true)) { 0 } else { 1 });
+            }
+
+            let mut status = -1;
+            assert_eq!(waitpid(pid, &mut status, 0), pid);
+            status == 0
+        }
+    }
+
+    #[test]
+    fn calls_from_inside_a_tokio_runtime() {
+        let db = open_echo_db();
+
+        // The application's own runtime, as in a Rust program linking this library
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let echoed = runtime.block_on(async { unsafe { select_echo(db, 42) } });
+        assert_eq!(echoed, 42);
+
+        // Calls that only look at the connection go through the worker as well
+        runtime.block_on(async {
+            unsafe {
+                assert!(!sqlite3_turso_transport(db).is_null());
+                assert_eq!(sqlite3_turso_ping(db, 0), SQLITE_OK);
+
+                let mut stmt = std::ptr::null_mut();
+                let rc = sqlite3_prepare_v3(
+                    db,
+                    c"SELECT ?".as_ptr(),
+                    8,
+                    SQLITE_PREPARE_PERSISTENT,
+                    &mut stmt,
+                    std::ptr::null_mut(),
+                );
+                assert_eq!(rc, SQLITE_OK);
+                sqlite3_finalize(stmt);
+            }
+        });
+        assert_eq!(unsafe { sqlite3_close_v2(db) }, SQLITE_OK);
+    }
+
+    #[test]
+    fn statements_from_many_threads_share_one_connection() {
+        let db = open_echo_db();
+
+        // Only compiles because the handle is Sync
+        let shared: &'static SQLite3 = unsafe { &*db };
+        let workers: Vec<_> = (0..8)
+            .map(|worker| {
+                thread::spawn(move || {
+                    let db = std::ptr::from_ref(shared).cast_mut();
+                    for i in 0..50 {
+                        let expected = worker * 1000 + i;
+                        assert_eq!(unsafe { select_echo(db, expected) }, expected);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let (outstanding, most) = shared.metrics.outstanding(false);
+        assert_eq!(outstanding, 0);
+        assert_eq!(most, 1, "requests on one connection must not overlap");
+        assert_eq!(unsafe { sqlite3_close_v2(db) }, SQLITE_OK);
+    }
+
+    #[test]
+    fn caches_are_released_on_request() {
+        let db = open_echo_db();
+        unsafe {
+            let used = |op| {
+                let (mut current, mut highwater) = (0, 0);
+                sqlite3_db_status(db, op, &mut current, &mut highwater, 0);
+                current
+            };
+            let fill = || {
+                assert_eq!(exec(db, c"PRAGMA turso.cache = ON"), SQLITE_OK);
+                assert_eq!(run_echo(db, c"SELECT n FROM t WHERE n = ?", 7), 7);
+                assert!(used(status::SQLITE_DBSTATUS_CACHE_USED) > 0);
+                assert!(used(status::SQLITE_DBSTATUS_STMT_USED) > 0);
+            };
+
+            fill();
+            assert_eq!(sqlite3_db_cacheflush(db), SQLITE_OK);
+            assert_eq!(sqlite3_db_release_memory(db), SQLITE_OK);
+            assert_eq!(used(status::SQLITE_DBSTATUS_CACHE_USED), 0);
+            assert_eq!(used(status::SQLITE_DBSTATUS_STMT_USED), 0);
+
+            fill();
+            assert!(sqlite3_release_memory(c_int::MAX) > 0);
+            assert_eq!(used(status::SQLITE_DBSTATUS_CACHE_USED), 0);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn async_step_answers_busy_until_the_response_is_in() {
+        let db = open_echo_db_with(c"echo.db?auth=none&transport=http&async_step=on");
+
+        unsafe {
+            let stmt = prepare(db, c"SELECT ?");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 7, None), SQLITE_OK);
+
+            assert_eq!(sqlite3_step(stmt), SQLITE_BUSY);
+            let rc = loop {
+                match sqlite3_step(stmt) {
+                    SQLITE_BUSY => thread::sleep(Duration::from_millis(1)),
+                    rc => break rc,
+                }
+            };
+            assert_eq!(rc, sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 7);
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+
+            // Resetting while the request is in flight waits for it
+            assert_eq!(sqlite3_reset(stmt), SQLITE_OK);
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 8, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), SQLITE_BUSY);
+            assert_eq!(sqlite3_reset(stmt), SQLITE_OK);
+            assert_eq!(sqlite3_finalize(stmt), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn last_insert_rowid_only_moves_on_insert() {
+        let db = open_echo_db();
+
+        unsafe {
+            assert_eq!(sqlite3_last_insert_rowid(db), 0);
+
+            run_echo(db, c"INSERT INTO t(id) VALUES (?) RETURNING id", 41);
+            assert_eq!(sqlite3_last_insert_rowid(db), 41);
+
+            // The server reports a rowid of 0 for these, which must not clobber the insert's
+            select_echo(db, 5);
+            run_echo(db, c"UPDATE t SET id = ? RETURNING id", 6);
+            assert_eq!(sqlite3_last_insert_rowid(db), 41);
+
+            run_echo(
+                db,
+                c"WITH v(x) AS (SELECT 1) INSERT INTO t(id) SELECT ? RETURNING id",
+                42,
+            );
+            assert_eq!(sqlite3_last_insert_rowid(db), 42);
+
+            sqlite3_set_last_insert_rowid(db, 7);
+            assert_eq!(sqlite3_last_insert_rowid(db), 7);
+            select_echo(db, 8);
+            assert_eq!(sqlite3_last_insert_rowid(db), 7);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn numbers_round_trip_exactly() {
+        let db = open_echo_db();
+
+        unsafe {
+            for value in [i64::MIN, i64::MIN + 1, -1, 0, i64::MAX - 1, i64::MAX] {
+                assert_eq!(select_echo(db, value), value);
+            }
+
+            let floats = [
+                f64::MIN_POSITIVE / 2.0, // Subnormal
+                f64::from_bits(1),       // Smallest subnormal
+                f64::MIN_POSITIVE,
+                0.1 + 0.2,
+                -1.0 / 3.0,
+                f64::MAX,
+                f64::MIN,
+                -0.0,
+            ];
+            for value in floats {
+                let stmt = prepare(db, c"SELECT ?");
+                assert_eq!(sqlite3_bind_double(stmt, 1, value, None), SQLITE_OK);
+                assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+                let echoed = sqlite3_column_double(stmt, 0);
+                assert_eq!(
+                    echoed.to_bits(),
+                    value.to_bits(),
+                    "{} came back as {}",
+                    value,
+                    echoed
+                );
+                sqlite3_finalize(stmt);
+            }
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn non_finite_floats_are_rejected() {
+        let db = open_echo_db();
+
+        unsafe {
+            for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+                let stmt = prepare(db, c"SELECT ?");
+                assert_eq!(sqlite3_bind_double(stmt, 1, value, None), SQLITE_OK);
+                assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_MISMATCH);
+                sqlite3_finalize(stmt);
+            }
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn blob_columns_are_decoded() {
+        let db = open_echo_db();
+
+        unsafe {
+            let stmt = prepare(db, c"SELECT CAST(? AS BLOB)");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 1234, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+
+            assert_eq!(sqlite3_column_type(stmt, 0), SQLITE_BLOB);
+            assert_eq!(sqlite3_column_bytes(stmt, 0), 4);
+            let blob = sqlite3_column_blob(stmt, 0).cast::<u8>();
+            assert_eq!(slice::from_raw_parts(blob, 4), b"1234");
+            let text = CStr::from_ptr(sqlite3_column_text(stmt, 0));
+            assert_eq!(text.to_str(), Ok("1234"));
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn vectors_bind_and_read_as_floats() {
+        let db = open_echo_db();
+
+        unsafe {
+            let vector = [1.5f32, -2.0, 3.25];
+            let stmt = prepare(db, c"SELECT ?");
+            assert_eq!(
+                sqlite3_turso_bind_vector_f32(stmt, 1, vector.as_ptr(), 0),
+                SQLITE_MISUSE
+            );
+            assert_eq!(
+                sqlite3_turso_bind_vector_f32(stmt, 2, vector.as_ptr(), 3),
+                SQLITE_RANGE
+            );
+            assert_eq!(
+                sqlite3_turso_bind_vector_f32(stmt, 1, vector.as_ptr(), 3),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+
+            assert_eq!(sqlite3_column_type(stmt, 0), SQLITE_BLOB);
+            assert_eq!(sqlite3_column_bytes(stmt, 0), 12);
+            let mut dims = -1;
+            let floats = sqlite3_turso_column_vector_f32(stmt, 0, &mut dims);
+            assert_eq!(dims, 3);
+            assert!(floats.is_aligned());
+            assert_eq!(slice::from_raw_parts(floats, 3), vector);
+            assert_eq!(sqlite3_turso_column_vector_f32(stmt, 0, &mut dims), floats);
+
+            assert!(sqlite3_turso_column_vector_f32(stmt, 1, &mut dims).is_null());
+            assert_eq!(dims, 0);
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn column_bytes_agrees_with_column_text() {
+        let mut stmt = SQLite3PreparedStmt::new(std::ptr::null_mut(), "SELECT 1");
+        *stmt.result_rows.lock().unwrap() = vec![vec![
+            Value::Integer(-1234),
+            Value::Real(0.5),
+            Value::Blob(b"ab\0cd".to_vec()),
+            Value::Null,
+        ]];
+        *stmt.current_row.lock().unwrap() = Some(0);
+        let stmt = &mut stmt as *mut SQLite3PreparedStmt;
+
+        let text = |col| {
+            let text = sqlite3_column_text(stmt, col);
+            (!text.is_null()).then(|| unsafe { CStr::from_ptr(text) }.to_str().unwrap())
+        };
+
+        assert_eq!(sqlite3_column_bytes(stmt, 0), 5);
+        assert_eq!(text(0), Some("-1234"));
+        assert_eq!(sqlite3_column_bytes(stmt, 1), 3);
+        assert_eq!(text(1), Some("0.5"));
+
+        // A BLOB read as text stops at its NUL, and from then on is as long as that text
+        assert_eq!(sqlite3_column_bytes(stmt, 2), 5);
+        assert_eq!(text(2), Some("ab"));
+        assert_eq!(sqlite3_column_bytes(stmt, 2), 2);
+        assert_eq!(sqlite3_column_text(stmt, 2), sqlite3_column_text(stmt, 2));
+
+        assert_eq!(text(3), None);
+        assert_eq!(sqlite3_column_bytes(stmt, 3), 0);
+
+        // Numbers read as a BLOB are the bytes of their text form
+        let blob = |col| {
+            let blob = sqlite3_column_blob(stmt, col).cast::<u8>();
+            let len = sqlite3_column_bytes(stmt, col) as usize;
+            (!blob.is_null()).then(|| unsafe { slice::from_raw_parts(blob, len) })
+        };
+        assert_eq!(blob(0), Some(&b"-1234"[..]));
+        assert_eq!(blob(1), Some(&b"0.5"[..]));
+        assert_eq!(
+            sqlite3_column_blob(stmt, 1).cast(),
+            sqlite3_column_text(stmt, 1)
+        );
+        let bytes = sqlite3_column_blob(stmt, 2).cast::<u8>();
+        assert_eq!(unsafe { slice::from_raw_parts(bytes, 5) }, b"ab\0cd");
+        assert_eq!(blob(3), None);
+    }
+
+    #[test]
+    fn forked_child_gets_a_connection_of_its_own() {
+        let db = open_echo_db();
+        unsafe {
+            assert_eq!(select_echo(db, 1), 1);
+            assert!(in_child(
+                || select_echo(db, 2) == 2 && sqlite3_close_v2(db) == SQLITE_OK
+            ));
+
+            // The parent's connection is untouched by the child's
+            assert_eq!(select_echo(db, 3), 3);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn open_connections_reconnect_after_shutdown() {
+        // Run apart so the runtime is not shut down under the other tests
+        assert!(in_child(|| unsafe {
+            let db = open_echo_db();
+            select_echo(db, 1) == 1
+                && sqlite3_shutdown() == SQLITE_OK
+                && sqlite3_config(sqlite::SQLITE_CONFIG_MULTITHREAD) == SQLITE_OK
+                && select_echo(db, 2) == 2
+                && sqlite3_close_v2(db) == SQLITE_OK
+        }));
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn open_mock_db(fixture_path: &str) -> *mut SQLite3 {
+        let filename = CString::new(format!("mock.db?transport=mock:{}", fixture_path)).unwrap();
+        let mut db = std::ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_FULLMUTEX,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(rc, SQLITE_OK);
+        db
+    }
+
+    unsafe fn exec(db: *mut SQLite3, sql: &CStr) -> c_int {
+        sqlite3_exec(
+            db,
+            sql.as_ptr(),
+            None,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    }
+
+    #[test]
+    fn replication_index_is_sent_back_over_http() {
+        // The index the echo server saw on the request, NULL without one
+        unsafe fn sent_index(db: *mut SQLite3) -> Option<String> {
+            let stmt = prepare(db, c"SELECT replication_index");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            let text = sqlite3_column_text(stmt, 0);
+            let index = (!text.is_null()).then(|| CStr::from_ptr(text).to_str().unwrap().into());
+            sqlite3_finalize(stmt);
+            index
+        }
+
+        let db = open_echo_db();
+        unsafe {
+            assert_eq!(sqlite3_turso_replication_index(db), 0);
+            assert_eq!(sent_index(db), None);
+
+            run_echo(db, c"INSERT INTO t(id) VALUES (?) RETURNING id", 41);
+            assert_eq!(sqlite3_turso_replication_index(db), 41);
+            assert_eq!(sent_index(db).as_deref(), Some("41"));
+
+            // An older index seen later does not move it back
+            run_echo(db, c"INSERT INTO t(id) VALUES (?) RETURNING id", 7);
+            assert_eq!(sqlite3_turso_replication_index(db), 41);
+            assert_eq!(sent_index(db).as_deref(), Some("41"));
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn mock_transport_replays_bound_statements() {
+        let db = open_mock_db(&fixture("select.jsonl"));
+        unsafe {
+            let stmt = prepare(db, c"SELECT id, name FROM users WHERE id = ?");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 2);
+            let name = CStr::from_ptr(sqlite3_column_text(stmt, 1));
+            assert_eq!(name, c"bob");
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+
+            // Rebinding sends a new request, answered by the fixture's other exchange
+            assert_eq!(sqlite3_reset(stmt), SQLITE_OK);
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 1, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            let name = CStr::from_ptr(sqlite3_column_text(stmt, 1));
+            assert_eq!(name, c"alice");
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn prepare_flags_drivers_always_pass_are_accepted() {
+        let db = open_mock_db(&fixture("select.jsonl"));
+        unsafe {
+            let sql = c"SELECT id, name FROM users WHERE id = ?";
+            let mut stmt = std::ptr::null_mut();
+            let flags = sqlite::SQLITE_PREPARE_NORMALIZE | sqlite::SQLITE_PREPARE_NO_VTAB;
+            let rc = sqlite3_prepare_v3(
+                db,
+                sql.as_ptr(),
+                sql.to_bytes().len(),
+                flags,
+                &mut stmt,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(rc, SQLITE_OK);
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            sqlite3_finalize(stmt);
+
+            // A flag this version does not know is still refused
+            let mut stmt = std::ptr::null_mut();
+            let rc = sqlite3_prepare_v3(
+                db,
+                sql.as_ptr(),
+                sql.to_bytes().len(),
+                0x100,
+                &mut stmt,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(rc, SQLITE_MISUSE);
+            assert!(stmt.is_null());
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn normalized_sql_groups_statements_by_shape() {
+        let db = open_mock_db(&fixture("select.jsonl"));
+        unsafe {
+            let stmt = prepare(
+                db,
+                c"select id,  \"Name\" -- who\n from users where id = 42 and name = 'o''k' or id = :id",
+            );
+            assert_eq!(
+                CStr::from_ptr(sqlite3_normalized_sql(stmt)),
+                c"SELECT id, \"Name\" FROM users WHERE id = ? AND name = ? OR id = ?"
+            );
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn max_rows_limits_queries_and_reports_truncation() {
+        let db = open_mock_db(&fixture("max_rows.jsonl"));
+        unsafe {
+            assert_eq!(
+                sqlite3_turso_config(db, c"max_rows".as_ptr(), c"1".as_ptr()),
+                SQLITE_OK
+            );
+            // Sent with LIMIT 2, one row past the limit to tell whether any were left out
+            let stmt = prepare(db, c"SELECT id, name FROM users;");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(CStr::from_ptr(sqlite3_column_text(stmt, 1)), c"alice");
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+
+            let stmt = prepare(db, c"PRAGMA turso.last_truncated");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 1);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn prepared_script_is_sent_in_one_batch() {
+        let db = open_mock_db(&format!("{}&batch_scripts=on", fixture("script.jsonl")));
+        let script = "CREATE TABLE t(x);\n\
+             CREATE TRIGGER t_log AFTER INSERT ON t BEGIN SELECT 1; END;\n\
+             INSERT INTO t VALUES (1);\n\
+             SELECT x FROM t;\n\
+             INSERT INTO t VALUES (?);\n";
+
+        let mut statements = vec![];
+        let mut rows = vec![];
+        unsafe {
+            // Every statement is prepared before the first is stepped
+            let mut sql = script.as_ptr() as *const c_char;
+            let end = sql.add(script.len());
+            while sql < end {
+                let mut stmt = std::ptr::null_mut();
+                let mut tail = std::ptr::null();
+                let rc = sqlite3_prepare_v3(
+                    db,
+                    sql,
+                    end.offset_from(sql) as usize,
+                    0,
+                    &mut stmt,
+                    &mut tail,
+                );
+                assert_eq!(rc, SQLITE_OK);
+                sql = tail;
+                if !stmt.is_null() {
+                    statements.push(stmt);
+                }
+            }
+
+            for &stmt in &statements {
+                if sqlite3_bind_parameter_count(stmt) == 1 {
+                    assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+                }
+                loop {
+                    match sqlite3_step(stmt) {
+                        sqlite::SQLITE_ROW => rows.push(sqlite3_column_int64(stmt, 0)),
+                        rc => {
+                            assert_eq!(rc, SQLITE_DONE, "{:?}", get_latest_error());
+                            break;
+                        }
+                    }
+                }
+            }
+            assert_eq!(
+                (*statements[1]).sql.trim(),
+                "CREATE TRIGGER t_log AFTER INSERT ON t BEGIN SELECT 1; END;"
+            );
+            for stmt in statements.drain(..) {
+                sqlite3_finalize(stmt);
+            }
+            assert_eq!(sqlite3_last_insert_rowid(db), 2);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+
+        // The fixture has the first four in one batch, the last on its own once bound
+        assert_eq!(rows, vec![1]);
+    }
+
+    #[test]
+    fn unprepared_tail_never_runs() {
+        let db = open_mock_db(&format!("{}&batch_scripts=on", fixture("script.jsonl")));
+        let script = c"SELECT 1; DROP TABLE t;";
+        unsafe {
+            let mut stmt = std::ptr::null_mut();
+            let mut tail = std::ptr::null();
+            let rc = sqlite3_prepare_v3(
+                db,
+                script.as_ptr(),
+                script.to_bytes().len(),
+                0,
+                &mut stmt,
+                &mut tail,
+            );
+            assert_eq!(rc, SQLITE_OK);
+            assert_eq!(CStr::from_ptr(tail), c" DROP TABLE t;");
+
+            // The fixture only answers the SELECT sent on its own
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 1);
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn state_change_hook_is_told_the_current_state() {
+        extern "C" fn record(user_data: *mut c_void, state: c_int) {
+            unsafe { &mut *(user_data as *mut Vec<c_int>) }.push(state);
+        }
+
+        let mut states: Vec<c_int> = vec![];
+        let db = open_echo_db();
+        unsafe {
+            let user_data = &mut states as *mut _ as *mut c_void;
+            assert_eq!(
+                sqlite3_turso_on_state_change(db, Some(record), user_data),
+                SQLITE_OK
+            );
+            assert_eq!(select_echo(db, 5), 5);
+            assert_eq!(
+                sqlite3_turso_on_state_change(db, None, user_data),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+
+        // A request answered as before changes nothing
+        assert_eq!(states, vec![connection_state::SQLITE_TURSO_STATE_CONNECTED]);
+    }
+
+    #[test]
+    fn turso_status_is_answered_locally() {
+        let db = open_echo_db();
+        unsafe {
+            let stmt = prepare(db, c"SELECT name, value FROM turso_status");
+            assert_eq!(sqlite3_column_count(stmt), 2);
+            let mut names = vec![];
+            while sqlite3_step(stmt) == sqlite::SQLITE_ROW {
+                names.push(CStr::from_ptr(sqlite3_column_text(stmt, 0)).to_owned());
+            }
+            assert_eq!(names.len(), 7);
+            assert_eq!(sqlite3_finalize(stmt), SQLITE_OK);
+
+            let stmt = prepare(
+                db,
+                c"SELECT value FROM turso_status WHERE name = 'protocol'",
+            );
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(CStr::from_ptr(sqlite3_column_text(stmt, 0)), c"hrana2");
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            assert_eq!(sqlite3_finalize(stmt), SQLITE_OK);
+
+            // Nothing reached the server, so no region was reported
+            let stmt = prepare(db, c"SELECT value FROM turso_status WHERE name = 'region'");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_type(stmt, 0), SQLITE_NULL);
+            assert_eq!(sqlite3_finalize(stmt), SQLITE_OK);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn warmup_answers_table_info_from_the_schema_cache() {
+        let filename = CString::new(format!(
+            "mock.db?transport=mock:{}&warmup=on",
+            fixture("warmup.jsonl")
+        ))
+        .unwrap();
+        let mut db = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(sqlite3_open(filename.as_ptr(), &mut db), SQLITE_OK);
+            // Runs at low priority, so the step below could otherwise overtake it
+            if let Some(warm_up) = (*db).warm_up.lock().unwrap().as_mut() {
+                warm_up.settle();
+            }
+            // The fixture has no exchange for it, only the warm-up query
+            let stmt = prepare(db, c"pragma table_info(\"Users\");");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(CStr::from_ptr(sqlite3_column_text(stmt, 1)), c"id");
+            assert_eq!(CStr::from_ptr(sqlite3_column_name(stmt, 2)), c"type");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(CStr::from_ptr(sqlite3_column_text(stmt, 2)), c"TEXT");
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn sqlite3_open_parses_options_like_open_v2() {
+        let filename = CString::new(format!(
+            "mock.db?transport=mock:{}",
+            fixture("select.jsonl")
+        ))
+        .unwrap();
+        let mut db = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(sqlite3_open(filename.as_ptr(), &mut db), SQLITE_OK);
+            let stmt = prepare(db, c"SELECT id, name FROM users WHERE id = ?");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 1, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            let name = CStr::from_ptr(sqlite3_column_text(stmt, 1));
+            assert_eq!(name, c"alice");
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn settings_are_changed_through_turso_config() {
+        let db = open_mock_db(&fixture("select.jsonl"));
+        unsafe {
+            assert_eq!(
+                sqlite3_turso_config(db, c"cache_size".as_ptr(), c"12".as_ptr()),
+                SQLITE_OK
+            );
+            let stmt = prepare(db, c"PRAGMA turso.cache_size");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 12);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(
+                sqlite3_turso_config(db, c"cache_ttl".as_ptr(), c"soon".as_ptr()),
+                SQLITE_MISUSE
+            );
+            // Only read while opening, and no setting at all
+            assert_eq!(
+                sqlite3_turso_config(db, c"compress".as_ptr(), c"gzip".as_ptr()),
+                SQLITE_MISUSE
+            );
+            assert_eq!(
+                sqlite3_turso_config(db, c"retries".as_ptr(), c"3".as_ptr()),
+                SQLITE_NOTFOUND
+            );
+
+            // Rejected process defaults leave them as they were for the other tests
+            let null = std::ptr::null_mut();
+            assert_eq!(
+                sqlite3_turso_config(null, c"retries".as_ptr(), c"3".as_ptr()),
+                SQLITE_NOTFOUND
+            );
+            assert_eq!(
+                sqlite3_turso_config(null, c"compress".as_ptr(), c"zip".as_ptr()),
+                SQLITE_MISUSE
+            );
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn slow_queries_reach_the_hook() {
+        extern "C" fn hook(
+            user_data: *mut c_void,
+            sql: *const c_char,
+            _: f64,
+            rows_read: i64,
+            _: i64,
+            retries: c_int,
+        ) {
+            let seen = unsafe { &*(user_data as *const Mutex<Vec<(String, i64, c_int)>>) };
+            let sql = unsafe { CStr::from_ptr(sql) }
+                .to_string_lossy()
+                .into_owned();
+            seen.lock().unwrap().push((sql, rows_read, retries));
+        }
+
+        let db = open_mock_db(&fixture("select.jsonl"));
+        let seen: Mutex<Vec<(String, i64, c_int)>> = Mutex::new(vec![]);
+        unsafe {
+            let user_data = &seen as *const _ as *mut c_void;
+            assert_eq!(
+                sqlite3_turso_slow_query_hook(db, Some(hook), user_data),
+                SQLITE_OK
+            );
+
+            // Off by default, and the mock answers well within any whole millisecond
+            let stmt = prepare(db, c"SELECT id, name FROM users WHERE id = ?");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert!(seen.lock().unwrap().is_empty());
+
+            (*db).slow_queries.lock().unwrap().threshold = Some(Duration::from_nanos(1));
+            assert_eq!(sqlite3_reset(stmt), SQLITE_OK);
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 1, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(
+                *seen.lock().unwrap(),
+                [("SELECT id, name FROM users WHERE id = ?".to_string(), 1, 0)]
+            );
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn mock_transport_replays_a_transaction() {
+        let db = open_mock_db(&fixture("transaction.jsonl"));
+        unsafe {
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            let stmt = prepare(db, c"INSERT INTO users (name) VALUES (?)");
+            let name = c"carol";
+            assert_eq!(
+                sqlite3_bind_text(stmt, 1, name.as_ptr(), name.to_bytes().len(), None),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_last_insert_rowid(db), 3);
+            assert_eq!(exec(db, c"COMMIT"), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn transaction_is_replayed_after_its_stream_expires() {
+        let db = open_mock_db(&fixture("txn_replay.jsonl"));
+        unsafe {
+            assert_eq!(exec(db, c"PRAGMA turso.txn_replay = ON"), SQLITE_OK);
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(
+                exec(db, c"INSERT INTO users (name) VALUES ('carol')"),
+                SQLITE_OK
+            );
+            // The server no longer knows the stream: BEGIN and carol go again on a new one
+            assert_eq!(
+                exec(db, c"INSERT INTO users (name) VALUES ('dave')"),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_last_insert_rowid(db), 4);
+            assert_eq!(exec(db, c"COMMIT"), SQLITE_OK);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn deadline_interrupts_statements_until_cleared() {
+        let db = open_echo_db();
+        unsafe {
+            assert_eq!(sqlite3_turso_set_deadline(db, 1), SQLITE_OK);
+            std::thread::sleep(Duration::from_millis(5));
+            let stmt = prepare(db, c"SELECT 1");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_INTERRUPT);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_turso_set_deadline(db, 0), SQLITE_OK);
+            assert_eq!(select_echo(db, 7), 7);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn deadline_ends_with_the_transaction() {
+        let db = open_mock_db(&fixture("transaction.jsonl"));
+        unsafe {
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(sqlite3_turso_set_deadline(db, 60_000), SQLITE_OK);
+            let stmt = prepare(db, c"INSERT INTO users (name) VALUES (?)");
+            let name = c"carol";
+            assert_eq!(
+                sqlite3_bind_text(stmt, 1, name.as_ptr(), name.to_bytes().len(), None),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+            assert_eq!(exec(db, c"COMMIT"), SQLITE_OK);
+
+            assert!((*db).deadline.lock().unwrap().is_none());
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn nested_json_text_round_trips_byte_for_byte() {
+        let json = format!(
+            "{}{{\"a\":\"\\u0000\"}}{}",
+            "[".repeat(5000),
+            "]".repeat(5000)
+        );
+        let text = CString::new(json.clone()).unwrap();
+        let db = open_echo_db();
+        unsafe {
+            let stmt = prepare(db, c"SELECT ?");
+            assert_eq!(
+                sqlite3_bind_text(stmt, 1, text.as_ptr(), json.len(), None),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_bytes(stmt, 0) as usize, json.len());
+            assert_eq!(
+                CStr::from_ptr(sqlite3_column_text(stmt, 0)),
+                text.as_c_str()
+            );
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn shared_cache_is_accepted() {
+        let db = open_echo_db();
+        unsafe {
+            assert_eq!(sqlite3_enable_shared_cache(1), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn load_extension_asks_the_server() {
+        let db = open_mock_db(&fixture("extensions.jsonl"));
+        unsafe {
+            let load = |file: &CStr, errmsg: &mut *mut c_char| {
+                sqlite3_load_extension(db, file.as_ptr(), std::ptr::null(), errmsg)
+            };
+            let mut errmsg = std::ptr::null_mut();
+            assert_eq!(load(c"fts5", &mut errmsg), SQLITE_ERROR);
+            assert_eq!(CStr::from_ptr(errmsg), c"not authorized");
+            sqlite3_free(errmsg as *mut c_void);
+
+            assert_eq!(sqlite3_enable_load_extension(db, 1), SQLITE_OK);
+            assert_eq!(load(c"/usr/lib/libfts5.so", &mut errmsg), SQLITE_OK);
+            assert!(errmsg.is_null());
+            assert_eq!(load(c"vector", &mut errmsg), SQLITE_ERROR);
+            assert_eq!(
+                CStr::from_ptr(errmsg),
+                c"Extension 'vector' is not available on the server"
+            );
+            sqlite3_free(errmsg as *mut c_void);
+            assert_eq!(load(c"uuid", &mut std::ptr::null_mut()), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn runtime_is_fixed_once_built() {
+        utils::get_tokio();
+        unsafe {
+            let key = c"runtime".as_ptr();
+            let config =
+                |value: &CStr| sqlite3_turso_config(std::ptr::null_mut(), key, value.as_ptr());
+            assert_eq!(config(c"current_thread"), SQLITE_MISUSE);
+            assert_eq!(config(c"multi_thread:0"), SQLITE_MISUSE);
+
+            let json = sqlite3_turso_metrics_json(std::ptr::null_mut());
+            let metrics: serde_json::Value =
+                serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+            sqlite3_turso_free_string(json);
+            assert!(metrics["runtime"]["workers"].as_u64().unwrap() >= 1);
+            assert!(metrics["runtime"]["flavor"].is_string());
+        }
+    }
+
+    #[test]
+    fn prewarmed_connections_are_taken_by_open() {
+        use_echo_server();
+        let filename = c"prewarm.db?auth=none&transport=http";
+        unsafe {
+            assert_eq!(sqlite3_turso_prewarm(std::ptr::null(), 1), SQLITE_MISUSE);
+            assert_eq!(sqlite3_turso_prewarm(filename.as_ptr(), -1), SQLITE_MISUSE);
+            assert_eq!(sqlite3_turso_prewarm(filename.as_ptr(), 3), SQLITE_OK);
+
+            let db = open_echo_db_with(filename);
+            assert_eq!(select_echo(db, 7), 7);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+
+            let name = filename.to_str().unwrap();
+            assert!(prewarm::take(name).is_some());
+            assert!(prewarm::take(name).is_some());
+            assert!(prewarm::take(name).is_none());
+        }
+    }
+
+    #[test]
+    fn requests_to_a_stalled_server_time_out_as_busy() {
+        // Run apart, as connections find the server through the process's environment
+        assert!(in_child(|| unsafe {
+            // The connection is queued but never answered
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            std::env::set_var(
+                "TURSO_DB_URL",
+                format!("http://{}", listener.local_addr().unwrap()),
+            );
+
+            let mut db = std::ptr::null_mut();
+            let filename = c"stalled.db?auth=none&transport=http&timeout=100";
+            let rc = sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_FULLMUTEX,
+                std::ptr::null(),
+            );
+            assert_eq!(rc, SQLITE_OK);
+
+            // Set locally, the pragma needs no answer from the server
+            assert_eq!(exec(db, c"PRAGMA turso.timeout = 200"), SQLITE_OK);
+            let stmt = prepare(db, c"PRAGMA turso.timeout");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 200);
+            sqlite3_finalize(stmt);
+
+            let stmt = prepare(db, c"SELECT 1");
+            assert_eq!(sqlite3_step(stmt), SQLITE_BUSY);
+            assert_eq!(sqlite3_extended_errcode(db), sqlite::SQLITE_BUSY_TIMEOUT);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_str().unwrap();
+            assert!(message.contains("timed out after 200ms"), "{}", message);
+            sqlite3_finalize(stmt);
+
+            sqlite3_close_v2(db) == SQLITE_OK
+        }));
+    }
+
+    #[test]
+    fn ping_reports_a_dead_transport() {
+        // The fixture has nothing to answer the ping with, as a server that went away would
+        let db = open_mock_db(&fixture("select.jsonl"));
+        unsafe {
+            assert_eq!(sqlite3_turso_ping(db, 100), SQLITE_IOERR);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_str().unwrap();
+            assert!(message.contains("No exchange left"), "{}", message);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn dead_prewarmed_connections_are_replaced_on_open() {
+        let path = std::env::temp_dir().join(format!("turso-prewarm-{}.jsonl", std::process::id()));
+        let ping = r#"{"request":{"baton":null,"requests":[]},"response":{"baton":null,"base_url":null,"results":[]}}"#;
+        std::fs::write(&path, ping).unwrap();
+        let filename =
+            CString::new(format!("prewarm.db?transport=mock:{}", path.display())).unwrap();
+        unsafe {
+            // Its fixture had one ping, taken by the prewarm, and no query
+            assert_eq!(sqlite3_turso_prewarm(filename.as_ptr(), 1), SQLITE_OK);
+            std::fs::copy(fixture("select.jsonl"), &path).unwrap();
+
+            let db = open_echo_db_with(&filename);
+            let stmt = prepare(db, c"SELECT id, name FROM users WHERE id = ?");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+            assert!(prewarm::take(filename.to_str().unwrap()).is_none());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn streams_are_closed_when_done() {
+        let path = fixture("close.jsonl");
+        let db = open_mock_db(&path);
+        unsafe {
+            // A statement outside a transaction closes its stream in the request that runs it,
+            // so there is none left for finalize to close
+            let stmt = prepare(db, c"SELECT 1");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_finalize(stmt), SQLITE_OK);
+
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(exec(db, c"INSERT INTO t VALUES (1)"), SQLITE_OK);
+            assert_eq!(exec(db, c"COMMIT"), SQLITE_OK);
+
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(exec(db, c"ROLLBACK"), SQLITE_OK);
+
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+
+        let closes: Vec<_> = transport::answered(std::path::Path::new(&path))
+            .into_iter()
+            .filter(|request| {
+                request["requests"].as_array().unwrap().last().unwrap()["type"] == "close"
+            })
+            .map(|request| request["baton"].clone())
+            .collect();
+        assert_eq!(
+            closes,
+            [
+                serde_json::Value::Null,
+                "c3".into(),
+                "r2".into(),
+                "x1".into()
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_client_certificates_fail_the_open() {
+        use_echo_server();
+        for transport in ["http", "websocket"] {
+            for key in ["other.key", "garbage.key"] {
+                let filename = CString::new(format!(
+                    "tls.db?auth=none&transport={}&tls_cert={}&tls_key={}",
+                    transport,
+                    fixture("tls/client.crt"),
+                    fixture(&format!("tls/{}", key))
+                ))
+                .unwrap();
+                let mut db = std::ptr::null_mut();
+                let rc = unsafe {
+                    sqlite3_open_v2(
+                        filename.as_ptr(),
+                        &mut db,
+                        SQLITE_OPEN_FULLMUTEX,
+                        std::ptr::null(),
+                    )
+                };
+                assert_eq!(rc, SQLITE_CANTOPEN, "{} {}", transport, key);
+                assert!(db.is_null());
+                let message = unsafe { CStr::from_ptr(sqlite3_errmsg(db)) }.to_string_lossy();
+                // A key that is not one fails on reading it, the wrong key once paired
+                let expected = match key {
+                    "garbage.key" => "Invalid client certificate",
+                    _ => "Failed to build HTTP client",
+                };
+                assert!(message.contains(expected), "{}", message);
+            }
+        }
+    }
+
+    #[test]
+    fn text_with_interior_nuls_reads_back_whole() {
+        let db = open_echo_db();
+        let adversarial: [&[u8]; 6] = [
+            b"\0",
+            b"a\0b",
+            b"\0\0\0\0",
+            b"trailing\0",
+            "\0é\0\u{10FFFF}".as_bytes(),
+            b"\x01\x7f\0\x1b[0m",
+        ];
+        unsafe {
+            for text in adversarial {
+                let stmt = prepare(db, c"SELECT ?");
+                let ptr = text.as_ptr() as *const c_char;
+                assert_eq!(sqlite3_bind_text(stmt, 1, ptr, text.len(), None), SQLITE_OK);
+                assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+
+                let bytes = sqlite3_column_bytes(stmt, 0) as usize;
+                assert_eq!(bytes, text.len());
+                let read =
+                    slice::from_raw_parts(sqlite3_column_text(stmt, 0) as *const u8, bytes + 1);
+                assert_eq!(&read[..bytes], text);
+                assert_eq!(read[bytes], 0);
+                let blob = slice::from_raw_parts(sqlite3_column_blob(stmt, 0) as *const u8, bytes);
+                assert_eq!(blob, text);
+                assert_eq!(CStr::from_ptr(sqlite3_column_name(stmt, 0)), c"n");
+                sqlite3_finalize(stmt);
+            }
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn json_subtype_reaches_registered_functions() {
+        unsafe extern "C" fn subtype(
+            context: *mut FunctionContext,
+            _: c_int,
+            argv: *mut *mut FunctionArg,
+        ) {
+            sqlite3_result_int64(context, sqlite3_value_subtype(*argv) as i64);
+        }
+        // Returns text with a NUL in it, marked as JSON
+        unsafe extern "C" fn broken_json(
+            context: *mut FunctionContext,
+            _: c_int,
+            _: *mut *mut FunctionArg,
+        ) {
+            sqlite3_result_text(
+                context,
+                b"[1,2]\0]".as_ptr() as *const c_char,
+                7,
+                std::ptr::null_mut(),
+            );
+            sqlite3_result_subtype(context, functions::JSON_SUBTYPE);
+        }
+
+        let db = open_echo_db();
+        unsafe {
+            for (name, callback) in [
+                (c"subtype", subtype as ScalarCallback),
+                (c"broken_json", broken_json as ScalarCallback),
+            ] {
+                let rc = sqlite3_create_function_v2(
+                    db,
+                    name.as_ptr(),
+                    -1,
+                    0,
+                    std::ptr::null_mut(),
+                    Some(callback),
+                    None,
+                    None,
+                    None,
+                );
+                assert_eq!(rc, SQLITE_OK);
+            }
+
+            for (sql, value, expected) in [
+                (c"SELECT subtype(json_extract(?, '$'))", c"{\"a\":1}", 74),
+                (c"SELECT subtype(json_extract(?, '$'))", c"abc", 0),
+                (c"SELECT subtype(? -> '$')", c"abc", 74),
+                (c"SELECT subtype(? ->> '$')", c"abc", 0),
+                (c"SELECT subtype(?)", c"{}", 0),
+            ] {
+                let stmt = prepare(db, sql);
+                let len = value.to_bytes().len();
+                assert_eq!(
+                    sqlite3_bind_text(stmt, 1, value.as_ptr(), len, None),
+                    SQLITE_OK
+                );
+                assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+                assert_eq!(sqlite3_column_int64(stmt, 0), expected, "{:?}", sql);
+                sqlite3_finalize(stmt);
+            }
+
+            let stmt = prepare(db, c"SELECT broken_json(?)");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 1, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), SQLITE_ERROR);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_string_lossy();
+            assert!(message.contains("malformed JSON"), "{}", message);
+            sqlite3_finalize(stmt);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn registered_functions_are_only_evaluated_as_result_columns() {
+        unsafe extern "C" fn double(
+            context: *mut FunctionContext,
+            _: c_int,
+            argv: *mut *mut FunctionArg,
+        ) {
+            sqlite3_result_int64(context, sqlite3_value_int64(*argv) * 2);
+        }
+
+        let db = open_echo_db();
+        unsafe {
+            let rc = sqlite3_create_function(
+                db,
+                c"double".as_ptr(),
+                1,
+                0,
+                std::ptr::null_mut(),
+                Some(double),
+                None,
+                None,
+            );
+            assert_eq!(rc, SQLITE_OK);
+            let queries = || (*db).metrics.to_json()["queries"].as_u64().unwrap();
+
+            // The server echoes the argument sent in the call's place
+            let stmt = prepare(db, c"SELECT double(?) AS d");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 21, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 42);
+            assert_eq!(CStr::from_ptr(sqlite3_column_name(stmt, 0)), c"d");
+            sqlite3_finalize(stmt);
+            assert_eq!(queries(), 1);
+
+            for sql in [
+                c"SELECT n FROM t WHERE double(n) = ?",
+                c"SELECT double(?) + 1",
+            ] {
+                let stmt = prepare(db, sql);
+                assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+                assert_eq!(sqlite3_step(stmt), SQLITE_ERROR, "{:?}", sql);
+                let message = CStr::from_ptr(sqlite3_errmsg(db)).to_string_lossy();
+                assert!(message.contains("whole result column"), "{}", message);
+                sqlite3_finalize(stmt);
+            }
+            assert_eq!(queries(), 1);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn aggregate_functions_are_refused() {
+        extern "C" fn step(_: *mut c_void, _: c_int, _: *mut *mut c_void) {}
+        extern "C" fn finalize(_: *mut c_void) {}
+        unsafe extern "C" fn destroy(app: *mut c_void) {
+            *(app as *mut bool) = true;
+        }
+
+        let db = open_echo_db();
+        unsafe {
+            let mut destroyed = false;
+            let rc = sqlite3_create_function_v2(
+                db,
+                c"my_sum".as_ptr(),
+                1,
+                0,
+                &mut destroyed as *mut bool as *mut c_void,
+                None,
+                Some(step),
+                Some(finalize),
+                Some(destroy),
+            );
+            assert_eq!(rc, SQLITE_ERROR);
+            assert!(destroyed);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_string_lossy();
+            assert!(
+                message.contains("aggregate functions are not supported"),
+                "{}",
+                message
+            );
+            assert!((*db).functions.lock().unwrap().is_empty());
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn mock_transport_fails_requests_missing_from_the_fixture() {
+        let db = open_mock_db(&fixture("select.jsonl"));
+        unsafe {
+            assert_eq!(exec(db, c"DELETE FROM users"), SQLITE_ERROR);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_string_lossy();
+            assert!(message.contains("No exchange left"), "{}", message);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn header_pragmas_are_sent_from_exec() {
+        let db = open_mock_db(&fixture("user_version.jsonl"));
+        unsafe {
+            let queries = || (*db).metrics.to_json()["queries"].as_u64().unwrap();
+
+            // Other pragmas stay local as before
+            assert_eq!(exec(db, c"PRAGMA cache_size = 10"), SQLITE_OK);
+            assert_eq!(queries(), 0);
+            assert_eq!(exec(db, c"PRAGMA user_version = 3"), SQLITE_OK);
+            assert_eq!(queries(), 1);
+
+            let stmt = prepare(db, c"PRAGMA user_version");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(
+                CStr::from_ptr(sqlite3_column_name(stmt, 0)),
+                c"user_version"
+            );
+            assert_eq!(sqlite3_column_int64(stmt, 0), 3);
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn session_pragmas_run_on_every_new_stream() {
+        // Each request in the fixture after the first opens its stream with the pragma
+        let db = open_mock_db(&fixture("foreign_keys.jsonl"));
+        unsafe {
+            assert_eq!(exec(db, c"PRAGMA foreign_keys = ON"), SQLITE_OK);
+            assert_eq!(
+                (*db).session.lock().unwrap().statements(),
+                ["PRAGMA foreign_keys = ON"]
+            );
+
+            let stmt = prepare(db, c"SELECT 1");
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(sqlite3_column_int64(stmt, 0), 1);
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(exec(db, c"COMMIT"), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn session_replay_can_be_turned_off() {
+        let path = format!("{}&session_replay=off", fixture("foreign_keys.jsonl"));
+        let db = open_mock_db(&path);
+        unsafe {
+            assert_eq!(exec(db, c"PRAGMA foreign_keys = ON"), SQLITE_OK);
+            assert!((*db).session.lock().unwrap().statements().is_empty());
+
+            // The fixture has this request only without the pragma in front
+            assert_eq!(exec(db, c"SELECT 2"), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn schema_queries_are_cached_until_the_schema_changes() {
+        let db = open_mock_db(&fixture("schema.jsonl"));
+        unsafe {
+            let queries = || (*db).metrics.to_json()["queries"].as_u64().unwrap();
+            let table_count = || {
+                let stmt = prepare(
+                    db,
+                    c"/* introspection */ select name, sql from main.sqlite_master",
+                );
+                let mut count = 0;
+                while sqlite3_step(stmt) == sqlite::SQLITE_ROW {
+                    count += 1;
+                }
+                assert_eq!(CStr::from_ptr(sqlite3_column_name(stmt, 1)), c"sql");
+                sqlite3_finalize(stmt);
+                count
+            };
+
+            // The first query checks the schema version, repeats are answered locally
+            assert_eq!(table_count(), 1);
+            assert_eq!(queries(), 2);
+            assert_eq!(table_count(), 1);
+            assert_eq!(queries(), 2);
+
+            assert_eq!(exec(db, c"CREATE TABLE posts (title)"), SQLITE_OK);
+            assert_eq!(table_count(), 2);
+            assert_eq!(queries(), 5);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn statements_are_described_again_after_a_schema_change() {
+        let db = open_mock_db(&fixture("schema_change.jsonl"));
+        unsafe {
+            // Check the schema version on every use instead of once a second
+            (*db).schema_cache.lock().unwrap().check_interval = Duration::ZERO;
+            let columns = || {
+                let stmt = prepare(db, c"SELECT * FROM t");
+                let count = sqlite3_column_count(stmt);
+                let reprepares = sqlite3_stmt_status(stmt, status::SQLITE_STMTSTATUS_REPREPARE, 0);
+                sqlite3_finalize(stmt);
+                (count, reprepares)
+            };
+
+            assert_eq!(columns(), (1, 0));
+            // Same schema version as before, the names learned first still hold
+            assert_eq!(columns(), (1, 0));
+            // Another client added a column
+            assert_eq!(columns(), (2, 1));
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn get_table_returns_names_then_rows_in_one_block() {
+        let db = open_mock_db(&fixture("get_table.jsonl"));
+        unsafe {
+            let mut result = std::ptr::null_mut();
+            let (mut rows, mut columns) = (0, 0);
+            let mut errmsg = std::ptr::null_mut();
+            let rc = sqlite3_get_table(
+                db,
+                c"SELECT id, name FROM users".as_ptr(),
+                &mut result,
+                &mut rows,
+                &mut columns,
+                &mut errmsg,
+            );
+            assert_eq!(rc, SQLITE_OK);
+            assert_eq!((rows, columns), (2, 2));
+            let cells = slice::from_raw_parts(result, 6);
+            let text = |index: usize| CStr::from_ptr(cells[index]).to_str().unwrap();
+            assert_eq!(
+                [text(0), text(1), text(2), text(3), text(4)],
+                ["id", "name", "1", "alice", "2"]
+            );
+            assert!(cells[5].is_null());
+            sqlite3_free_table(result);
+
+            // Two names and a row already take more than 32 bytes of pointers
+            assert_eq!(
+                sqlite3_turso_config(db, c"table_limit".as_ptr(), c"32".as_ptr()),
+                SQLITE_OK
+            );
+            let rc = sqlite3_get_table(
+                db,
+                c"SELECT id, name FROM users".as_ptr(),
+                &mut result,
+                &mut rows,
+                &mut columns,
+                &mut errmsg,
+            );
+            assert_eq!(rc, sqlite::SQLITE_TOOBIG);
+            assert!(result.is_null());
+            assert!(CStr::from_ptr(errmsg)
+                .to_string_lossy()
+                .contains("table_limit"));
+            sqlite3_free(errmsg as *mut c_void);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn declared_types_are_known_before_the_first_step() {
+        let db = open_mock_db(&fixture("describe.jsonl"));
+        unsafe {
+            let decltypes = || {
+                let stmt = prepare(db, c"SELECT id, name, length(name) FROM users");
+                let decltypes: Vec<_> = (0..sqlite3_column_count(stmt))
+                    .map(|i| {
+                        let decltype = sqlite3_column_decltype(stmt, i);
+                        (!decltype.is_null())
+                            .then(|| CStr::from_ptr(decltype).to_str().unwrap().to_string())
+                    })
+                    .collect();
+                sqlite3_finalize(stmt);
+                decltypes
+            };
+
+            let expected = vec![Some("INTEGER".to_string()), Some("TEXT".to_string()), None];
+            assert_eq!(decltypes(), expected);
+            // The fixture holds a single describe, so this prepare is answered from the cache
+            assert_eq!(decltypes(), expected);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn selftest_reports_each_step() {
+        let filename = format!("mock.db?transport=mock:{}", fixture("selftest.jsonl"));
+        let filename = CString::new(filename).unwrap();
+        unsafe {
+            let report = sqlite3_turso_selftest(filename.as_ptr());
+            let json: serde_json::Value =
+                serde_json::from_str(CStr::from_ptr(report).to_str().unwrap()).unwrap();
+            sqlite3_turso_free_string(report);
+
+            assert_eq!(json["ok"], true, "{}", json);
+            assert_eq!(json["transport"], "mock");
+            let checks: Vec<_> = json["checks"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|check| check["name"].as_str().unwrap())
+                .collect();
+            assert_eq!(
+                checks,
+                ["auth", "endpoints", "fixture", "select", "transaction"]
+            );
+
+            // An invalid option fails before anything is checked
+            let report = sqlite3_turso_selftest(c"mock.db?transport=carrier-pigeon".as_ptr());
+            assert!(report.is_null());
+        }
+    }
+
+    #[test]
+    fn server_errors_keep_their_result_codes() {
+        let db = open_mock_db(&fixture("server_errors.jsonl"));
+        unsafe {
+            assert_eq!(exec(db, c"INSERT INTO t VALUES (1)"), SQLITE_BUSY);
+            assert_eq!(exec(db, c"DELETE FROM t"), SQLITE_AUTH);
+            assert_eq!(sqlite3_errcode(db), SQLITE_AUTH);
+            // Extended codes the server names come through as well
+            assert_eq!(exec(db, c"UPDATE t SET a = 1"), SQLITE_READONLY);
+            assert_eq!(sqlite3_errcode(db), SQLITE_READONLY);
+            assert_eq!(sqlite3_extended_errcode(db), SQLITE_READONLY | (4 << 8));
+            assert_eq!(
+                exec(db, c"INSERT INTO t (id) VALUES (1)"),
+                SQLITE_CONSTRAINT
+            );
+            assert_eq!(sqlite3_extended_errcode(db), SQLITE_CONSTRAINT | (6 << 8));
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn failed_rollback_keeps_its_code_and_ends_the_transaction() {
+        let path = fixture("server_errors.jsonl");
+        let db = open_mock_db(&path);
+        unsafe {
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(exec(db, c"ROLLBACK"), SQLITE_IOERR);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_str().unwrap();
+            assert!(message.contains("disk I/O error"), "{}", message);
+            assert_eq!(sqlite3_get_autocommit(db), 1);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+        let close = serde_json::json!({"baton": "e1", "requests": [{"type": "close"}]});
+        assert!(transport::answered(std::path::Path::new(&path)).contains(&close));
+    }
+
+    #[test]
+    fn savepoints_nest_inside_the_transaction_they_open() {
+        let path = fixture("savepoint.jsonl");
+        let db = open_mock_db(&path);
+        unsafe {
+            assert_eq!(exec(db, c"SAVEPOINT a"), SQLITE_OK);
+            assert_eq!(sqlite3_get_autocommit(db), 0);
+            assert_eq!(exec(db, c"SAVEPOINT b"), SQLITE_OK);
+            assert_eq!(exec(db, c"INSERT INTO t VALUES (1)"), SQLITE_OK);
+
+            assert_eq!(exec(db, c"ROLLBACK TO a"), SQLITE_OK);
+            assert_eq!(sqlite3_get_autocommit(db), 0);
+
+            assert_eq!(exec(db, c"RELEASE nope"), SQLITE_ERROR);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_str().unwrap();
+            assert!(message.contains("no such savepoint: nope"), "{}", message);
+            assert_eq!(sqlite3_get_autocommit(db), 0);
+
+            // The savepoint that opened the transaction commits it
+            assert_eq!(exec(db, c"RELEASE a"), SQLITE_OK);
+            assert_eq!(sqlite3_get_autocommit(db), 1);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+        let close = serde_json::json!({"baton": "s5", "requests": [{"type": "close"}]});
+        assert!(transport::answered(std::path::Path::new(&path)).contains(&close));
+    }
+
+    #[test]
+    fn named_parameters_are_sent_by_name() {
+        let db = open_mock_db(&fixture("named_parameters.jsonl"));
+        unsafe {
+            let stmt = prepare(db, c"UPDATE t SET a = :a, b = @b WHERE id = $id");
+            assert_eq!(sqlite3_bind_parameter_count(stmt), 3);
+            assert_eq!(CStr::from_ptr(sqlite3_bind_parameter_name(stmt, 2)), c"@b");
+            assert_eq!(sqlite3_bind_parameter_index(stmt, c"$id".as_ptr()), 3);
+            assert_eq!(sqlite3_bind_parameter_index(stmt, c"id".as_ptr()), 0);
+
+            // Bound out of order and @b left unbound, each still goes out under its own name
+            assert_eq!(sqlite3_bind_int64(stmt, 3, 7, None), SQLITE_OK);
+            let text = c"x";
+            assert_eq!(
+                sqlite3_bind_text(stmt, 1, text.as_ptr(), 1, None),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+
+            // :a comes before the `?` and keeps its place among the positional arguments
+            let stmt = prepare(db, c"UPDATE t SET a = :a WHERE id = ? AND b = :b");
+            assert!(sqlite3_bind_parameter_name(stmt, 2).is_null());
+            assert_eq!(sqlite3_bind_int64(stmt, 3, 2, None), SQLITE_OK);
+            assert_eq!(sqlite3_bind_int64(stmt, 2, 7, None), SQLITE_OK);
+            assert_eq!(
+                sqlite3_bind_text(stmt, 1, text.as_ptr(), 1, None),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            assert_eq!(sqlite3_changes(db), 1);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn numbered_parameters_are_sent_by_position() {
+        let db = open_mock_db(&fixture("numbered_parameters.jsonl"));
+        unsafe {
+            let stmt = prepare(db, c"UPDATE t SET a = ?1, b = ?1 WHERE id = ?2");
+            assert_eq!(sqlite3_bind_parameter_count(stmt), 2);
+            sqlite3_finalize(stmt);
+
+            // ?2 is left unbound and goes out as NULL, keeping ?3 in third place
+            let stmt = prepare(db, c"UPDATE t SET a = ?1, b = ?1 WHERE id = ?3");
+            assert_eq!(sqlite3_bind_parameter_count(stmt), 3);
+            let text = c"x";
+            assert_eq!(
+                sqlite3_bind_text(stmt, 1, text.as_ptr(), 1, None),
+                SQLITE_OK
+            );
+            assert_eq!(sqlite3_bind_int64(stmt, 3, 7, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            assert_eq!(sqlite3_changes(db), 1);
+            sqlite3_finalize(stmt);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+    }
+
+    #[test]
+    fn recorded_exchanges_replay_without_the_server() {
+        use_echo_server();
+        let path = std::env::temp_dir().join(format!("turso-record-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let filename =
+            CString::new(format!("echo.db?auth=none&transport=record:{}", path)).unwrap();
+        let db = open_echo_db_with(&filename);
+        unsafe {
+            assert_eq!(select_echo(db, 7), 7);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+
+        let db = open_mock_db(path);
+        unsafe {
+            assert_eq!(select_echo(db, 7), 7);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // The fixture holds the syncs and the statements sent to the primary: a query missing
+    // from it only succeeds when the replica answers it
+    #[cfg(feature = "replica")]
+    unsafe fn count_rows(db: *mut SQLite3) -> i64 {
+        let stmt = prepare(db, c"SELECT count(*) FROM t");
+        assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+        let count = sqlite3_column_int64(stmt, 0);
+        sqlite3_finalize(stmt);
+        count
+    }
+
+    #[cfg(feature = "replica")]
+    fn open_replica_db(fixture_path: &str, path: &std::path::Path) -> (c_int, *mut SQLite3) {
+        let filename = CString::new(format!(
+            "mock.db?transport=mock:{}&replica={}",
+            fixture_path,
+            path.display()
+        ))
+        .unwrap();
+        let mut db = std::ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_FULLMUTEX,
+                std::ptr::null(),
+            )
+        };
+        (rc, db)
+    }
+
+    #[cfg(feature = "replica")]
+    #[test]
+    fn replica_serves_reads_and_syncs_after_a_write() {
+        let path = std::env::temp_dir().join(format!("turso-replica-{}.db", std::process::id()));
+        let (rc, db) = open_replica_db(&fixture("replica.jsonl"), &path);
+        assert_eq!(rc, SQLITE_OK);
+        unsafe {
+            let stmt = prepare(db, c"SELECT name FROM t WHERE id = ?");
+            assert_eq!(sqlite3_bind_int64(stmt, 1, 2, None), SQLITE_OK);
+            assert_eq!(sqlite3_step(stmt), sqlite::SQLITE_ROW);
+            assert_eq!(CStr::from_ptr(sqlite3_column_text(stmt, 0)), c"bob");
+            assert_eq!(sqlite3_step(stmt), SQLITE_DONE);
+            sqlite3_finalize(stmt);
+            assert_eq!(count_rows(db), 2);
+
+            // Stale from here on, so the next read syncs first and sees the new row
+            assert_eq!(
+                exec(db, c"INSERT INTO t (name) VALUES ('carol')"),
+                SQLITE_OK
+            );
+            assert_eq!(count_rows(db), 3);
+            assert_eq!(count_rows(db), 3);
+
+            assert_eq!(exec(db, c"BEGIN"), SQLITE_OK);
+            assert_eq!(sqlite3_turso_sync(db), SQLITE_ERROR);
+            let message = CStr::from_ptr(sqlite3_errmsg(db)).to_str().unwrap();
+            assert!(message.contains("within a transaction"), "{}", message);
+            assert_eq!(exec(db, c"COMMIT"), SQLITE_OK);
+
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "replica")]
+    #[test]
+    fn replica_opens_offline_from_the_copy_on_disk() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("turso-replica-offline-{}.db", std::process::id()));
+        let offline = dir.join(format!(
+            "turso-replica-offline-{}.jsonl",
+            std::process::id()
+        ));
+        std::fs::write(&offline, "").unwrap();
+        let offline = offline.to_str().unwrap();
+
+        // Nothing to fall back on yet
+        let (rc, db) = open_replica_db(offline, &path);
+        assert_eq!(rc, SQLITE_CANTOPEN);
+        assert!(db.is_null());
+
+        let (rc, db) = open_replica_db(&fixture("replica.jsonl"), &path);
+        assert_eq!(rc, SQLITE_OK);
+        unsafe { assert_eq!(sqlite3_close_v2(db), SQLITE_OK) };
+
+        let (rc, db) = open_replica_db(offline, &path);
+        assert_eq!(rc, SQLITE_OK);
+        unsafe {
+            assert_eq!(count_rows(db), 2);
+            assert_eq!(sqlite3_close_v2(db), SQLITE_OK);
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(offline).unwrap();
+    }
+}
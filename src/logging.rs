@@ -0,0 +1,284 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::{c_char, c_int, c_void, CString},
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+use tracing::{Level, Metadata};
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+use crate::{config::parse_bool, slow_query};
+
+pub const LOG_LEVEL_ERROR: c_int = 1;
+pub const LOG_LEVEL_WARN: c_int = 2;
+pub const LOG_LEVEL_INFO: c_int = 3;
+pub const LOG_LEVEL_DEBUG: c_int = 4;
+pub const LOG_LEVEL_TRACE: c_int = 5;
+
+pub const UNSAFE_LOG_PARAMS: &str = "unsafe_log_params";
+pub const UNSAFE_LOG_PARAMS_ENV: &str = "TURSO_UNSAFE_LOG_PARAMS";
+
+// Hrana value types, whose `value` or `base64` is what a statement was bound to or returned
+const VALUE_TYPES: [&str; 5] = ["null", "integer", "float", "text", "blob"];
+
+pub type LogHook = extern "C" fn(
+    user_data: *mut c_void, // User-provided data
+    level: c_int,           // One of the LOG_LEVEL_* constants
+    message: *const c_char, // Formatted log line, without a trailing newline
+);
+
+struct RegisteredHook {
+    callback: LogHook,
+    user_data: *mut c_void,
+}
+
+// The hook is only ever invoked with the pointer the caller handed us
+unsafe impl Send for RegisteredHook {}
+
+static LOG_HOOK: Mutex<Option<RegisteredHook>> = Mutex::new(None);
+static SUBSCRIBER: OnceLock<()> = OnceLock::new();
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(1);
+static LOG_PARAMS: AtomicBool = AtomicBool::new(false);
+
+/// Installs the global subscriber once. The filter comes from `RUST_LOG`; without it only
+/// warnings are shown, or this crate's debug output in debug builds.
+pub fn init() {
+    SUBSCRIBER.get_or_init(|| {
+        let default_filter = if cfg!(debug_assertions) {
+            "warn,sqlite3=debug"
+        } else {
+            "warn"
+        };
+        let filter =
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter));
+
+        if let Some(enabled) = std::env::var(UNSAFE_LOG_PARAMS_ENV)
+            .ok()
+            .and_then(|value| parse_bool(&value))
+        {
+            set_unsafe_log_params(enabled);
+        }
+
+        let _ = tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(HookMakeWriter)
+            .with_ansi(false)
+            .try_init();
+    });
+}
+
+pub fn set_log_hook(callback: Option<LogHook>, user_data: *mut c_void) {
+    *LOG_HOOK.lock().unwrap() = callback.map(|callback| RegisteredHook {
+        callback,
+        user_data,
+    });
+}
+
+/// Unique id attached to every remote request, sent to the server as `x-request-id`.
+pub fn new_request_id() -> String {
+    static PROCESS_TAG: OnceLock<u64> = OnceLock::new();
+    let tag = PROCESS_TAG.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        nanos ^ ((std::process::id() as u64) << 32)
+    });
+
+    format!(
+        "{:016x}-{:08x}",
+        tag,
+        REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Short stable fingerprint so statements can be correlated without logging their text.
+pub fn sql_hash(sql: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    sql.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Lets the log show bound values, row values and SQL literals as they are. Off by default,
+/// since they may hold personal data or secrets.
+pub fn set_unsafe_log_params(enabled: bool) {
+    LOG_PARAMS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn unsafe_log_params() -> bool {
+    LOG_PARAMS.load(Ordering::Relaxed)
+}
+
+/// SQL text as it may be logged: with its literals replaced by `?` unless
+/// `unsafe_log_params` is on.
+pub fn loggable_sql(sql: &str) -> String {
+    if unsafe_log_params() {
+        sql.to_string()
+    } else {
+        slow_query::redact(sql)
+    }
+}
+
+/// A Hrana request or response as it may be logged. Unless `unsafe_log_params` is on, every
+/// value is replaced by a hash of it, so equal values can still be told apart, and SQL text
+/// goes through `loggable_sql`.
+pub fn loggable_json(message: &Value) -> String {
+    if unsafe_log_params() {
+        return message.to_string();
+    }
+    let mut message = message.clone();
+    redact_values(&mut message);
+    message.to_string()
+}
+
+/// Like `loggable_json` for a body that has not been parsed yet. A body that is not JSON is
+/// only logged by its length.
+pub fn loggable_body(body: &str) -> String {
+    if unsafe_log_params() {
+        return body.to_string();
+    }
+    match serde_json::from_str::<Value>(body) {
+        Ok(message) => loggable_json(&message),
+        Err(_) => format!("<{} bytes>", body.len()),
+    }
+}
+
+fn redact_values(message: &mut Value) {
+    match message {
+        Value::Array(items) => items.iter_mut().for_each(redact_values),
+        Value::Object(fields) => {
+            let is_value = fields
+                .get("type")
+                .and_then(Value::as_str)
+                .is_some_and(|kind| VALUE_TYPES.contains(&kind));
+            for (key, field) in fields.iter_mut() {
+                match (key.as_str(), &*field) {
+                    ("value" | "base64", _) if is_value && !field.is_null() => {
+                        *field = Value::String(format!("<redacted {}>", value_hash(field)));
+                    }
+                    ("sql", Value::String(sql)) => *field = Value::String(slow_query::redact(sql)),
+                    _ => redact_values(field),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn value_hash(value: &Value) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.to_string().hash(&mut hasher);
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+struct HookMakeWriter;
+
+impl<'a> MakeWriter<'a> for HookMakeWriter {
+    type Writer = HookWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        HookWriter::new(LOG_LEVEL_INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let level = match *meta.level() {
+            Level::ERROR => LOG_LEVEL_ERROR,
+            Level::WARN => LOG_LEVEL_WARN,
+            Level::INFO => LOG_LEVEL_INFO,
+            Level::DEBUG => LOG_LEVEL_DEBUG,
+            Level::TRACE => LOG_LEVEL_TRACE,
+        };
+        HookWriter::new(level)
+    }
+}
+
+/// Buffers one formatted event and hands it to the C hook, or stderr when none is set.
+struct HookWriter {
+    level: c_int,
+    buffer: Vec<u8>,
+}
+
+impl HookWriter {
+    fn new(level: c_int) -> Self {
+        Self {
+            level,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Write for HookWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for HookWriter {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        let hook = LOG_HOOK.lock().unwrap();
+        match &*hook {
+            Some(hook) => {
+                let line = String::from_utf8_lossy(&self.buffer);
+                let line = line.trim_end().replace('\0', "");
+                if let Ok(message) = CString::new(line) {
+                    (hook.callback)(hook.user_data, self.level, message.as_ptr());
+                }
+            }
+            None => {
+                let _ = io::stderr().write_all(&self.buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn logged_messages_hide_values() {
+        let request = json!({"requests": [{"type": "execute", "stmt": {
+            "sql": "SELECT * FROM users WHERE email = 'a@example.com' AND id = ?",
+            "args": [{"type": "integer", "value": "7"}, {"type": "null"}],
+            "named_args": [{"name": ":token", "value": {"type": "blob", "base64": "c2VjcmV0"}}],
+        }}]});
+
+        let logged = loggable_json(&request);
+        for secret in ["a@example.com", "\"7\"", "c2VjcmV0"] {
+            assert!(
+                !logged.contains(secret),
+                "{} leaked into {}",
+                secret,
+                logged
+            );
+        }
+        assert!(logged.contains("SELECT * FROM users WHERE email = ? AND id = ?"));
+        assert!(logged.contains(r#"{"type":"null"}"#));
+        assert!(logged.contains(":token"));
+
+        // Equal values hash alike, so a log can still be followed
+        let again = json!([{"type": "integer", "value": "7"}]);
+        let hash = format!("<redacted {}>", value_hash(&json!("7")));
+        assert!(logged.contains(&hash) && loggable_json(&again).contains(&hash));
+
+        assert_eq!(loggable_body("Unauthorized"), "<12 bytes>");
+    }
+}
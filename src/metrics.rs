@@ -0,0 +1,335 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::runtime::RuntimeFlavor;
+
+use crate::{status::Gauge, utils};
+
+// Upper bounds of the latency histogram buckets in milliseconds; the last bucket is open
+const LATENCY_BUCKETS_MS: [u64; 13] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// What the async runtime reports of itself, `None` before the library has built it.
+pub fn runtime_json() -> Option<serde_json::Value> {
+    let runtime = utils::running_tokio()?;
+    let flavor = match runtime.handle().runtime_flavor() {
+        RuntimeFlavor::CurrentThread => "current_thread",
+        _ => "multi_thread",
+    };
+    let metrics = runtime.metrics();
+    Some(serde_json::json!({
+        "flavor": flavor,
+        "workers": metrics.num_workers(),
+        "alive_tasks": metrics.num_alive_tasks(),
+        "global_queue_depth": metrics.global_queue_depth(),
+    }))
+}
+
+/// `runtime_json` as Prometheus gauges, nothing before the runtime is built.
+#[cfg(feature = "prometheus")]
+pub fn runtime_prometheus() -> String {
+    use std::fmt::Write;
+
+    let Some(runtime) = utils::running_tokio() else {
+        return String::new();
+    };
+    let metrics = runtime.metrics();
+    let gauges = [
+        (
+            "workers",
+            "Async runtime worker threads",
+            metrics.num_workers(),
+        ),
+        (
+            "alive_tasks",
+            "Tasks alive on the async runtime",
+            metrics.num_alive_tasks(),
+        ),
+        (
+            "global_queue_depth",
+            "Tasks waiting in the async runtime's global queue",
+            metrics.global_queue_depth(),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP turso_runtime_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE turso_runtime_{} gauge", name);
+        let _ = writeln!(out, "turso_runtime_{} {}", name, value);
+    }
+    out
+}
+
+/// Process-wide totals across every connection.
+pub static GLOBAL_METRICS: Metrics = Metrics::new(None);
+
+/// Counters for the remote round trips done on the application's behalf. Every update on a
+/// connection's metrics is mirrored into `GLOBAL_METRICS`.
+pub struct Metrics {
+    parent: Option<&'static Metrics>,
+    queries: AtomicU64,
+    errors: AtomicU64,
+    rows_read: AtomicU64,
+    rows_written: AtomicU64,
+    server_time_us: AtomicU64, // Execution time reported by the server
+    retries: AtomicU64,
+    reconnects: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    latency_total_ms: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    outstanding: Gauge, // Requests sent and not answered yet
+}
+
+/// Marks a request as outstanding until dropped, so cancelled requests are counted out too.
+pub struct OutstandingRequest(Arc<Metrics>);
+
+impl Drop for OutstandingRequest {
+    fn drop(&mut self) {
+        self.0.each(|m| m.outstanding.sub(1));
+    }
+}
+
+impl Metrics {
+    const fn new(parent: Option<&'static Metrics>) -> Self {
+        Self {
+            parent,
+            queries: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            rows_read: AtomicU64::new(0),
+            rows_written: AtomicU64::new(0),
+            server_time_us: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            latency_total_ms: AtomicU64::new(0),
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_MS.len() + 1],
+            outstanding: Gauge::new(),
+        }
+    }
+
+    pub fn for_connection() -> Self {
+        Self::new(Some(&GLOBAL_METRICS))
+    }
+
+    fn each(&self, f: impl Fn(&Metrics)) {
+        f(self);
+        if let Some(parent) = self.parent {
+            f(parent);
+        }
+    }
+
+    pub fn record_query(&self, latency: Duration, failed: bool) {
+        let ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        self.each(|m| {
+            m.queries.fetch_add(1, Ordering::Relaxed);
+            if failed {
+                m.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            m.latency_total_ms.fetch_add(ms, Ordering::Relaxed);
+            m.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pub fn record_rows(&self, read: u64, written: u64) {
+        self.each(|m| {
+            m.rows_read.fetch_add(read, Ordering::Relaxed);
+            m.rows_written.fetch_add(written, Ordering::Relaxed);
+        });
+    }
+
+    pub fn record_server_time(&self, ms: f64) {
+        let us = (ms * 1000.0).round() as u64;
+        self.each(|m| {
+            m.server_time_us.fetch_add(us, Ordering::Relaxed);
+        });
+    }
+
+    pub fn rows_read(&self) -> u64 {
+        self.rows_read.load(Ordering::Relaxed)
+    }
+
+    pub fn rows_written(&self) -> u64 {
+        self.rows_written.load(Ordering::Relaxed)
+    }
+
+    /// Total execution time reported by the server, in milliseconds.
+    pub fn server_ms(&self) -> f64 {
+        self.server_time_us.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn record_retry(&self) {
+        self.each(|m| {
+            m.retries.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pub fn record_reconnect(&self) {
+        self.each(|m| {
+            m.reconnects.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pub fn record_bytes(&self, sent: u64, received: u64) {
+        self.each(|m| {
+            m.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+            m.bytes_received.fetch_add(received, Ordering::Relaxed);
+        });
+    }
+
+    pub fn start_request(self: &Arc<Self>) -> OutstandingRequest {
+        self.each(|m| m.outstanding.add(1));
+        OutstandingRequest(self.clone())
+    }
+
+    /// Outstanding requests now and at most since the last reset.
+    pub fn outstanding(&self, reset: bool) -> (i64, i64) {
+        self.outstanding.read(reset)
+    }
+
+    /// Upper bound of the bucket holding the given quantile, `None` without samples.
+    fn latency_quantile_ms(&self, quantile: f64) -> Option<u64> {
+        let counts: Vec<u64> = self
+            .latency_buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let target = ((total as f64) * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                // The open-ended bucket reports the largest finite bound
+                let bound = LATENCY_BUCKETS_MS
+                    .get(index)
+                    .or(LATENCY_BUCKETS_MS.last())
+                    .copied();
+                return bound;
+            }
+        }
+
+        None
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let queries = load(&self.queries);
+        let errors = load(&self.errors);
+
+        serde_json::json!({
+            "queries": queries,
+            "errors": errors,
+            "error_rate": if queries == 0 { 0.0 } else { errors as f64 / queries as f64 },
+            "rows_read": load(&self.rows_read),
+            "rows_written": load(&self.rows_written),
+            "server_ms": self.server_ms(),
+            "retries": load(&self.retries),
+            "reconnects": load(&self.reconnects),
+            "bytes_sent": load(&self.bytes_sent),
+            "bytes_received": load(&self.bytes_received),
+            "outstanding_requests": self.outstanding(false).0,
+            "latency_ms": {
+                "total": load(&self.latency_total_ms),
+                "p50": self.latency_quantile_ms(0.5),
+                "p99": self.latency_quantile_ms(0.99),
+            },
+        })
+    }
+
+    /// Prometheus text exposition format.
+    #[cfg(feature = "prometheus")]
+    pub fn to_prometheus(&self, scope: &str) -> String {
+        use std::fmt::Write;
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        let counters = [
+            ("queries", "Statements sent to the server", &self.queries),
+            ("errors", "Statements that failed", &self.errors),
+            ("rows_read", "Rows read by the server", &self.rows_read),
+            (
+                "rows_written",
+                "Rows written by the server",
+                &self.rows_written,
+            ),
+            (
+                "server_time_us",
+                "Execution time reported by the server in microseconds",
+                &self.server_time_us,
+            ),
+            ("retries", "HTTP request retries", &self.retries),
+            ("reconnects", "WebSocket reconnects", &self.reconnects),
+            ("bytes_sent", "Request bytes sent", &self.bytes_sent),
+            (
+                "bytes_received",
+                "Response bytes received",
+                &self.bytes_received,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP turso_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE turso_{}_total counter", name);
+            let _ = writeln!(
+                out,
+                "turso_{}_total{{scope=\"{}\"}} {}",
+                name,
+                scope,
+                load(counter)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP turso_query_latency_ms Statement round trip latency"
+        );
+        let _ = writeln!(out, "# TYPE turso_query_latency_ms histogram");
+        let mut cumulative = 0;
+        for (index, bucket) in self.latency_buckets.iter().enumerate() {
+            cumulative += load(bucket);
+            let bound = LATENCY_BUCKETS_MS
+                .get(index)
+                .map(|b| b.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            let _ = writeln!(
+                out,
+                "turso_query_latency_ms_bucket{{scope=\"{}\",le=\"{}\"}} {}",
+                scope, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "turso_query_latency_ms_sum{{scope=\"{}\"}} {}",
+            scope,
+            load(&self.latency_total_ms)
+        );
+        let _ = writeln!(
+            out,
+            "turso_query_latency_ms_count{{scope=\"{}\"}} {}",
+            scope,
+            load(&self.queries)
+        );
+
+        out
+    }
+}
@@ -0,0 +1,84 @@
+//! Connections opened ahead of time by `sqlite3_turso_prewarm`, so that a server's first
+//! requests do not each pay for resolving credentials, the handshake and a first round trip.
+//! They wait here, keyed by the exact filename they were opened with, until `sqlite3_open_v2`
+//! is called with that filename and takes one.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{
+    auth::{self, SharedStrategy},
+    config::ConnectionOptions,
+    sqlite::SqliteError,
+    transport::DatabaseConnection,
+    utils::get_tokio,
+    version,
+};
+
+static WARM: Mutex<Option<HashMap<String, Vec<DatabaseConnection>>>> = Mutex::new(None);
+
+/// Opens `count` connections to `db_name` at once on the shared runtime, resolving its
+/// credentials once for all of them, and pings each before keeping it for `filename`.
+/// Connections that could be opened are kept even when others failed, and the first
+/// failure is returned.
+pub async fn fill(
+    filename: &str,
+    db_name: &str,
+    options: &ConnectionOptions,
+    count: usize,
+) -> Result<(), SqliteError> {
+    let auth = SharedStrategy::new(auth::strategy_for(options.auth));
+    let opening: Vec<_> = (0..count)
+        .map(|_| get_tokio().spawn(open(db_name.to_string(), auth.clone(), options.clone())))
+        .collect();
+
+    let mut result = Ok(());
+    for opened in opening {
+        let opened = opened.await.unwrap_or_else(|e| {
+            Err(SqliteError::new(
+                format!("Failed to open a connection: {}", e),
+                None,
+            ))
+        });
+        match opened {
+            Ok(connection) => keep(filename, connection),
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to prewarm a connection");
+                result = result.and(Err(err));
+            }
+        }
+    }
+    result
+}
+
+// One connection, opened as sqlite3_open_v2 opens it and checked to answer
+async fn open(
+    db_name: String,
+    auth: SharedStrategy,
+    options: ConnectionOptions,
+) -> Result<DatabaseConnection, SqliteError> {
+    let mut connection = DatabaseConnection::open(&db_name, Box::new(auth), options).await?;
+    version::learn(&mut connection).await;
+    let timeout = connection.timeout;
+    connection.ping(timeout).await?;
+    Ok(connection)
+}
+
+fn keep(filename: &str, connection: DatabaseConnection) {
+    WARM.lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .entry(filename.to_string())
+        .or_default()
+        .push(connection);
+}
+
+/// A connection prewarmed for `filename`, if one is left.
+pub fn take(filename: &str) -> Option<DatabaseConnection> {
+    WARM.lock().unwrap().as_mut()?.get_mut(filename)?.pop()
+}
+
+/// Drops every waiting connection, for `sqlite3_shutdown`: they live on the runtime that is
+/// about to go away.
+pub fn clear() {
+    WARM.lock().unwrap().take();
+}
@@ -0,0 +1,818 @@
+//! The Hrana wire format shared by every transport: how statements and their arguments are
+//! encoded, and the responses they get back.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_int, CString},
+};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::sqlite::{
+    SqliteError, Value, SQLITE_ABORT, SQLITE_AUTH, SQLITE_BUSY, SQLITE_BUSY_TIMEOUT,
+    SQLITE_CANTOPEN, SQLITE_CONSTRAINT, SQLITE_CORRUPT, SQLITE_ERROR, SQLITE_FULL, SQLITE_IOERR,
+    SQLITE_MISMATCH, SQLITE_MISUSE, SQLITE_NOTADB, SQLITE_NOTFOUND, SQLITE_PERM, SQLITE_RANGE,
+    SQLITE_READONLY,
+};
+
+/// Bound parameters as they go into a Hrana `stmt`. The server binds `args` by position
+/// from index 1 and `named_args` by name, prefix included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementArgs {
+    pub positional: Vec<serde_json::Value>,
+    pub named: Vec<(String, serde_json::Value)>,
+    pub want_rows: bool, // Off when only the counts of a write are read
+}
+
+impl Default for StatementArgs {
+    fn default() -> Self {
+        Self {
+            positional: vec![],
+            named: vec![],
+            want_rows: true,
+        }
+    }
+}
+
+impl StatementArgs {
+    /// The Hrana `stmt` running `sql` with these arguments.
+    pub fn to_stmt(&self, sql: &str) -> Stmt {
+        Stmt {
+            sql: sql.to_string(),
+            args: Some(self.positional.clone()),
+            named_args: self
+                .named
+                .iter()
+                .map(|(name, value)| NamedArg {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            want_rows: self.want_rows,
+        }
+    }
+}
+
+impl From<Vec<serde_json::Value>> for StatementArgs {
+    fn from(positional: Vec<serde_json::Value>) -> Self {
+        Self {
+            positional,
+            ..Self::default()
+        }
+    }
+}
+
+/// Bound parameters as Hrana values. Every index up to the last `?` or `?NNN` goes by
+/// position, an unbound one as NULL, as SQLite leaves it, rather than skipped. `:name`,
+/// `@name` and `$name` parameters past it go by name, so they land wherever the server's
+/// own prepare puts them. `names` holds each index's name as `sqlite3_bind_parameter_name`
+/// reports it. Integers travel as decimal strings so the full `i64` range survives JSON,
+/// floats as numbers in their shortest exact form.
+pub fn convert_params_to_json(
+    params: &HashMap<i32, Value>,
+    names: &[Option<CString>],
+) -> Result<StatementArgs, SqliteError> {
+    let name_of = |index: i32| {
+        usize::try_from(index - 1)
+            .ok()
+            .and_then(|i| names.get(i))
+            .and_then(Option::as_deref)
+            .and_then(|name| name.to_str().ok())
+            .filter(|name| !name.starts_with('?'))
+    };
+    let unnamed = (1..=names.len() as i32)
+        .chain(params.keys().copied())
+        .filter(|&index| name_of(index).is_none())
+        .max()
+        .unwrap_or(0);
+
+    let mut args = StatementArgs::default();
+    for index in 1..=unnamed {
+        let value = params.get(&index).unwrap_or(&Value::Null);
+        args.positional.push(param_to_json(index, value)?);
+    }
+    for index in unnamed + 1..=names.len() as i32 {
+        if let Some(name) = name_of(index) {
+            let value = params.get(&index).unwrap_or(&Value::Null);
+            args.named
+                .push((name.to_string(), param_to_json(index, value)?));
+        }
+    }
+    Ok(args)
+}
+
+/// The Hrana value for parameter `index`.
+pub fn param_to_json(index: i32, value: &Value) -> Result<serde_json::Value, SqliteError> {
+    match value {
+        Value::Integer(i) => Ok(serde_json::json!({
+            "type": "integer",
+            "value": i.to_string()
+        })),
+        // JSON has no NaN or infinity, serde_json would quietly send them as null
+        Value::Real(f) if !f.is_finite() => Err(SqliteError::new(
+            format!(
+                "Parameter {} is {}, which cannot be sent to the server",
+                index, f
+            ),
+            Some(SQLITE_MISMATCH),
+        )),
+        Value::Real(f) => Ok(serde_json::json!({
+            "type": "float",
+            "value": f
+        })),
+        Value::Text(s) => Ok(serde_json::json!({
+            "type": "text",
+            "value": s
+        })),
+        Value::Blob(b) => Ok(serde_json::json!({
+            "type": "blob",
+            "base64": base64::engine::general_purpose::STANDARD_NO_PAD.encode(b)
+        })),
+        Value::Null => Ok(serde_json::json!({
+            "type": "null",
+            "value": null
+        })),
+    }
+}
+
+/// Body of an HTTP pipeline request.
+#[derive(Debug, Serialize)]
+pub struct PipelineReq {
+    // Left out to open a new stream; `Some(None)` sends a null baton, also a new stream, but
+    // one the connection's session is not replayed on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baton: Option<Option<String>>,
+    pub requests: Vec<StreamRequest>,
+}
+
+impl PipelineReq {
+    /// `requests` on a new stream, which is closed after them.
+    pub fn autocommit(mut requests: Vec<StreamRequest>) -> Self {
+        requests.push(StreamRequest::Close);
+        Self {
+            baton: None,
+            requests,
+        }
+    }
+}
+
+/// A request on a stream. Over HTTP the stream is the pipeline's and `stream_id` is left
+/// out; over a WebSocket every request names its stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamRequest {
+    Execute {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream_id: Option<i32>,
+        stmt: Stmt,
+    },
+    Describe {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream_id: Option<i32>,
+        sql: String,
+    },
+    Close, // HTTP only, ends the pipeline's stream
+    OpenStream {
+        stream_id: i32,
+    },
+    CloseStream {
+        stream_id: i32,
+    },
+    StoreSql {
+        sql_id: i32,
+        sql: String,
+    },
+    CloseSql {
+        sql_id: i32,
+    },
+    Batch {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream_id: Option<i32>,
+        batch: Batch,
+    },
+}
+
+impl StreamRequest {
+    pub fn execute(stmt: Stmt) -> Self {
+        StreamRequest::Execute {
+            stream_id: None,
+            stmt,
+        }
+    }
+}
+
+/// A statement to execute. `args` is left out of statements that never take any, such as
+/// the ones replayed for the session, while `named_args` and `want_rows` are left out unless
+/// they change anything, as older servers do not know them.
+#[derive(Debug, Serialize)]
+pub struct Stmt {
+    pub sql: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub named_args: Vec<NamedArg>,
+    #[serde(skip_serializing_if = "is_true")]
+    pub want_rows: bool,
+}
+
+impl Stmt {
+    /// `sql` on its own, without arguments.
+    pub fn new(sql: &str) -> Self {
+        Self {
+            sql: sql.to_string(),
+            args: None,
+            named_args: vec![],
+            want_rows: true,
+        }
+    }
+}
+
+/// Statements run in order on one stream by a single request.
+#[derive(Debug, Serialize)]
+pub struct Batch {
+    pub steps: Vec<BatchStep>,
+}
+
+impl Batch {
+    /// `stmts` as steps that each run only if the one before succeeded, the way a script
+    /// stops at its first error.
+    pub fn chained(stmts: Vec<Stmt>) -> Self {
+        let steps = stmts
+            .into_iter()
+            .enumerate()
+            .map(|(index, stmt)| BatchStep {
+                condition: index.checked_sub(1).map(|step| BatchCond::Ok { step }),
+                stmt,
+            })
+            .collect();
+        Self { steps }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchStep {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<BatchCond>,
+    pub stmt: Stmt,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchCond {
+    Ok { step: usize },
+}
+
+/// Turns the request a transport built for a single `execute` into one running `batch`
+/// instead, on the same stream. Over HTTP the execute is the pipeline's first request, over
+/// a WebSocket the request itself. False when neither holds, as for custom transports.
+pub fn into_batch(request: &mut serde_json::Value, batch: Batch) -> bool {
+    let execute = match request.get_mut("requests") {
+        Some(requests) => requests.get_mut(0),
+        None => Some(request),
+    };
+    let Some(execute) = execute.filter(|execute| execute["type"] == "execute") else {
+        return false;
+    };
+
+    let stream_id = execute.get("stream_id").and_then(|id| id.as_i64());
+    *execute = to_json(&StreamRequest::Batch {
+        stream_id: stream_id.map(|id| id as i32),
+        batch,
+    });
+    true
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamedArg {
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+/// A message from client to server over a WebSocket.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMsg {
+    Hello {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        jwt: Option<String>,
+    },
+    Request {
+        request_id: i32,
+        request: serde_json::Value,
+    },
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+/// The JSON the transports send for a request. None of these types hold maps, so
+/// serializing them cannot fail.
+pub fn to_json(request: &impl Serialize) -> serde_json::Value {
+    serde_json::to_value(request).expect("Hrana requests serialize to JSON")
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoteSqliteResponse {
+    pub baton: Option<String>,
+    pub results: Vec<RemoteSQliteResultType>,
+}
+
+impl RemoteSqliteResponse {
+    /// Result of the request's first statement, or the error the server reported for it.
+    pub fn first_result(&self) -> Result<&QueryResult, SqliteError> {
+        match self.results.first().map(|inner| &inner.response) {
+            Some(RemoteSQLiteResult::Execute { result }) => Ok(result),
+            Some(RemoteSQLiteResult::Error { message, code }) => Err(remote_error(code, message)),
+            Some(RemoteSQLiteResult::Close) => Err(SqliteError::new(
+                "Remote SQLite closed the connection unexpectedly",
+                None,
+            )),
+            Some(RemoteSQLiteResult::Batch { .. }) => Err(SqliteError::new(
+                "Remote SQLite answered with a batch result",
+                None,
+            )),
+            None => Err(SqliteError::new(
+                "No results returned from remote SQLite",
+                None,
+            )),
+        }
+    }
+
+    /// The response of each statement of a `batch` request, as if it had been executed on
+    /// its own, `None` for those skipped after one failed. Any other response is the single
+    /// statement's.
+    pub fn into_steps(mut self) -> Vec<Option<Result<RemoteSqliteResponse, SqliteError>>> {
+        let batch = match self.results.first_mut().map(|first| &mut first.response) {
+            Some(RemoteSQLiteResult::Batch { result }) => std::mem::take(result),
+            _ => return vec![Some(Ok(self))],
+        };
+
+        let mut baton = self.baton;
+        let mut errors = batch.step_errors.into_iter();
+        batch
+            .step_results
+            .into_iter()
+            .map(|result| {
+                let error = errors.next().flatten();
+                match (result, error) {
+                    (Some(result), _) => Some(Ok(RemoteSqliteResponse {
+                        baton: baton.take(),
+                        results: vec![RemoteSQliteResultType {
+                            response: RemoteSQLiteResult::Execute { result },
+                        }],
+                    })),
+                    (None, Some(error)) => Some(Err(remote_error(&error.code, &error.message))),
+                    (None, None) => None,
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoteSQliteResultType {
+    pub response: RemoteSQLiteResult,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteSQLiteResult {
+    Execute { result: QueryResult },
+    Error { message: String, code: String },
+    Close,
+    Batch { result: BatchResult },
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BatchResult {
+    pub step_results: Vec<Option<QueryResult>>,
+    pub step_errors: Vec<Option<StepError>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StepError {
+    pub message: String,
+    #[serde(default)]
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RemoteCol {
+    pub name: String,
+    #[serde(default)]
+    pub decltype: Option<String>, // As declared in CREATE TABLE, none for expressions
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RemoteRow {
+    pub r#type: String,
+    pub value: Option<serde_json::Value>,
+    pub base64: Option<String>, // BLOB values only
+}
+
+impl RemoteRow {
+    pub fn decode(&self) -> Value {
+        if self.r#type == "blob" {
+            let bytes = self.base64.as_deref().map(|b| {
+                // The server leaves out the padding
+                base64::engine::general_purpose::STANDARD_NO_PAD.decode(b.trim_end_matches('='))
+            });
+            return match bytes {
+                Some(Ok(bytes)) => Value::Blob(bytes),
+                _ => Value::Null,
+            };
+        }
+
+        let Some(value) = &self.value else {
+            return Value::Null;
+        };
+
+        match self.r#type.as_str() {
+            "integer" => match value {
+                serde_json::Value::String(s) => Value::Integer(s.parse::<i64>().unwrap_or(0)),
+                serde_json::Value::Number(n) => Value::Integer(n.as_i64().unwrap_or(0)),
+                _ => Value::Integer(0),
+            },
+            "float" => Value::Real(value.as_f64().unwrap_or(0.0)),
+            "text" => Value::Text(value.as_str().unwrap_or("").to_string()),
+            _ => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueryResult {
+    pub cols: Vec<RemoteCol>,
+    pub rows: Vec<Vec<RemoteRow>>,
+    pub rows_read: Option<u64>,
+    pub rows_written: Option<u64>,
+    pub last_insert_rowid: Option<String>,
+    pub replication_index: Option<String>,
+    pub query_duration_ms: Option<f64>,
+}
+
+fn remote_error(code: &str, message: &str) -> SqliteError {
+    SqliteError::new(
+        format!("Remote SQLite error (code {}): {}", code, message),
+        Some(error_code(code, message)),
+    )
+}
+
+// Result codes by the names the server reports them with. SQLITE_LOCKED is left out: a lock
+// on the server is contention like any other and reported as SQLITE_BUSY.
+const RESULT_CODES: [(&str, c_int); 47] = [
+    ("SQLITE_ERROR", SQLITE_ERROR),
+    ("SQLITE_INTERNAL", 2),
+    ("SQLITE_PERM", SQLITE_PERM),
+    ("SQLITE_ABORT", SQLITE_ABORT),
+    ("SQLITE_BUSY", SQLITE_BUSY),
+    ("SQLITE_NOMEM", 7),
+    ("SQLITE_READONLY", SQLITE_READONLY),
+    ("SQLITE_INTERRUPT", 9),
+    ("SQLITE_IOERR", SQLITE_IOERR),
+    ("SQLITE_CORRUPT", SQLITE_CORRUPT),
+    ("SQLITE_NOTFOUND", SQLITE_NOTFOUND),
+    ("SQLITE_FULL", SQLITE_FULL),
+    ("SQLITE_CANTOPEN", SQLITE_CANTOPEN),
+    ("SQLITE_PROTOCOL", 15),
+    ("SQLITE_EMPTY", 16),
+    ("SQLITE_SCHEMA", 17),
+    ("SQLITE_TOOBIG", 18),
+    ("SQLITE_CONSTRAINT", SQLITE_CONSTRAINT),
+    ("SQLITE_MISMATCH", SQLITE_MISMATCH),
+    ("SQLITE_MISUSE", SQLITE_MISUSE),
+    ("SQLITE_NOLFS", 22),
+    ("SQLITE_AUTH", SQLITE_AUTH),
+    ("SQLITE_FORMAT", 24),
+    ("SQLITE_RANGE", SQLITE_RANGE),
+    ("SQLITE_NOTADB", SQLITE_NOTADB),
+    ("SQLITE_AUTH_USER", SQLITE_AUTH | (1 << 8)),
+    ("SQLITE_BUSY_RECOVERY", SQLITE_BUSY | (1 << 8)),
+    ("SQLITE_BUSY_SNAPSHOT", SQLITE_BUSY | (2 << 8)),
+    ("SQLITE_BUSY_TIMEOUT", SQLITE_BUSY_TIMEOUT),
+    ("SQLITE_CONSTRAINT_CHECK", SQLITE_CONSTRAINT | (1 << 8)),
+    ("SQLITE_CONSTRAINT_COMMITHOOK", SQLITE_CONSTRAINT | (2 << 8)),
+    ("SQLITE_CONSTRAINT_FOREIGNKEY", SQLITE_CONSTRAINT | (3 << 8)),
+    ("SQLITE_CONSTRAINT_FUNCTION", SQLITE_CONSTRAINT | (4 << 8)),
+    ("SQLITE_CONSTRAINT_NOTNULL", SQLITE_CONSTRAINT | (5 << 8)),
+    ("SQLITE_CONSTRAINT_PRIMARYKEY", SQLITE_CONSTRAINT | (6 << 8)),
+    ("SQLITE_CONSTRAINT_TRIGGER", SQLITE_CONSTRAINT | (7 << 8)),
+    ("SQLITE_CONSTRAINT_UNIQUE", SQLITE_CONSTRAINT | (8 << 8)),
+    ("SQLITE_CONSTRAINT_VTAB", SQLITE_CONSTRAINT | (9 << 8)),
+    ("SQLITE_CONSTRAINT_ROWID", SQLITE_CONSTRAINT | (10 << 8)),
+    ("SQLITE_CONSTRAINT_PINNED", SQLITE_CONSTRAINT | (11 << 8)),
+    ("SQLITE_CONSTRAINT_DATATYPE", SQLITE_CONSTRAINT | (12 << 8)),
+    ("SQLITE_READONLY_RECOVERY", SQLITE_READONLY | (1 << 8)),
+    ("SQLITE_READONLY_CANTLOCK", SQLITE_READONLY | (2 << 8)),
+    ("SQLITE_READONLY_ROLLBACK", SQLITE_READONLY | (3 << 8)),
+    ("SQLITE_READONLY_DBMOVED", SQLITE_READONLY | (4 << 8)),
+    ("SQLITE_READONLY_CANTINIT", SQLITE_READONLY | (5 << 8)),
+    ("SQLITE_READONLY_DIRECTORY", SQLITE_READONLY | (6 << 8)),
+];
+
+// The code a name stands for. An extended name missing from the table still gives its
+// primary code, e.g. SQLITE_IOERR for SQLITE_IOERR_SHORT_READ.
+fn named_code(name: &str) -> Option<c_int> {
+    if let Some((_, code)) = RESULT_CODES.iter().find(|(known, _)| *known == name) {
+        return Some(*code);
+    }
+    let (primary, _) = name.rsplit_once('_')?;
+    named_code(primary).map(|code| code & 0xff)
+}
+
+/// The result code for an error the server reported. Hrana passes SQLite's own code names
+/// through, e.g. `SQLITE_CONSTRAINT_UNIQUE`, and sqld adds its own, e.g.
+/// `AUTH_JWT_EXPIRED`. Without a specific name the message decides: rejected credentials
+/// become SQLITE_AUTH so applications can ask for new ones, lock and write-delegation
+/// failures SQLITE_BUSY so the caller's busy handling retries them, exceeded quotas
+/// SQLITE_FULL, and failed constraints the code SQLite itself would have returned.
+pub fn error_code(code: &str, message: &str) -> c_int {
+    if let Some(named) = named_code(code).filter(|&named| named != SQLITE_ERROR) {
+        return named;
+    }
+
+    let message = message.to_ascii_lowercase();
+    if code.starts_with("SQLITE_AUTH")
+        || code.starts_with("AUTH_")
+        || code == "UNAUTHORIZED"
+        || message.contains("not authorized")
+        || message.contains("unauthorized")
+    {
+        SQLITE_AUTH
+    } else if code.starts_with("SQLITE_PERM")
+        || code == "FORBIDDEN"
+        || message.contains("permission denied")
+        || message.contains("forbidden")
+    {
+        SQLITE_PERM
+    } else if code.starts_with("SQLITE_READONLY")
+        || message.contains("readonly database")
+        || message.contains("read-only")
+    {
+        SQLITE_READONLY
+    } else if code.starts_with("SQLITE_BUSY")
+        || code.starts_with("SQLITE_LOCKED")
+        || message.contains("database is locked")
+        || message.contains("write delegation")
+    {
+        SQLITE_BUSY
+    } else if code.starts_with("SQLITE_FULL") || is_quota_error(&message) {
+        SQLITE_FULL
+    } else if let Some(constraint) = constraint_code(&message) {
+        constraint
+    } else {
+        SQLITE_ERROR
+    }
+}
+
+// SQLite's messages for failed constraints, e.g. "UNIQUE constraint failed: t.id"
+fn constraint_code(message: &str) -> Option<c_int> {
+    const KINDS: [(&str, &str); 4] = [
+        ("unique", "SQLITE_CONSTRAINT_UNIQUE"),
+        ("not null", "SQLITE_CONSTRAINT_NOTNULL"),
+        ("check", "SQLITE_CONSTRAINT_CHECK"),
+        ("foreign key", "SQLITE_CONSTRAINT_FOREIGNKEY"),
+    ];
+
+    let (kind, _) = message.split_once(" constraint failed")?;
+    let name = KINDS
+        .iter()
+        .find(|(prefix, _)| kind.ends_with(prefix))
+        .map_or("SQLITE_CONSTRAINT", |(_, name)| name);
+    named_code(name)
+}
+
+/// The result code for an HTTP status the server refused a request with, from the
+/// WebSocket handshake or a pipeline request.
+pub fn status_code(status: u16, message: &str) -> c_int {
+    match status {
+        401 => SQLITE_AUTH,
+        403 => SQLITE_PERM,
+        // Rate limiting and an unavailable primary clear up after a while, like a lock
+        // held by another connection
+        429 | 503 => SQLITE_BUSY,
+        507 => SQLITE_FULL,
+        _ => error_code("", message),
+    }
+}
+
+// Whether a lowercased error message says the database ran out of its quota
+fn is_quota_error(message: &str) -> bool {
+    message.contains("quota") || message.contains("storage limit")
+}
+
+/// The error a single Hrana result carries, if it failed.
+pub fn result_error(result: &serde_json::Value) -> Option<SqliteError> {
+    let error = result.get("error")?;
+    let message = error.get("message").and_then(|m| m.as_str())?;
+    let code = error
+        .get("code")
+        .and_then(|c| c.as_str())
+        .unwrap_or_default();
+    Some(SqliteError::new(message, Some(error_code(code, message))))
+}
+
+/// Pulls the column list out of a Hrana `describe` response.
+pub fn describe_columns(response: &serde_json::Value) -> Result<Vec<RemoteCol>, SqliteError> {
+    if let Some(error) = result_error(response) {
+        return Err(error);
+    }
+
+    let cols = response
+        .get("response")
+        .and_then(|r| r.get("result"))
+        .and_then(|r| r.get("cols"))
+        .cloned()
+        .unwrap_or_default();
+
+    serde_json::from_value(cols).map_err(|e| {
+        SqliteError::new(
+            format!("Failed to parse describe response: {}", e),
+            Some(SQLITE_ERROR),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The request of the fixture's exchange at `index`
+    fn recorded(name: &str, index: usize) -> serde_json::Value {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let line = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .nth(index)
+            .unwrap()
+            .to_string();
+        serde_json::from_str::<serde_json::Value>(&line).unwrap()["request"].take()
+    }
+
+    #[test]
+    fn pipelines_match_the_recorded_transaction() {
+        let begin = PipelineReq {
+            baton: None,
+            requests: vec![StreamRequest::execute(Stmt::new("BEGIN"))],
+        };
+        assert_eq!(to_json(&begin), recorded("transaction.jsonl", 0));
+
+        let params = HashMap::from([(1, Value::Text("carol".to_string()))]);
+        let mut args = convert_params_to_json(&params, &[None]).unwrap();
+        args.want_rows = false;
+        let insert = PipelineReq {
+            baton: Some(Some("b1".to_string())),
+            requests: vec![StreamRequest::execute(
+                args.to_stmt("INSERT INTO users (name) VALUES (?)"),
+            )],
+        };
+        assert_eq!(to_json(&insert), recorded("transaction.jsonl", 1));
+
+        let close = PipelineReq {
+            baton: Some(Some("b3".to_string())),
+            requests: vec![StreamRequest::Close],
+        };
+        assert_eq!(to_json(&close), recorded("transaction.jsonl", 3));
+    }
+
+    #[test]
+    fn named_arguments_match_the_recorded_pipeline() {
+        let params = HashMap::from([(1, Value::Text("x".to_string())), (3, Value::Integer(7))]);
+        let names = [":a", "@b", "$id"].map(|name| Some(CString::new(name).unwrap()));
+        let mut args = convert_params_to_json(&params, &names).unwrap();
+        assert!(args.positional.is_empty());
+        args.want_rows = false;
+
+        let sql = "UPDATE t SET a = :a, b = @b WHERE id = $id";
+        let request = PipelineReq::autocommit(vec![StreamRequest::execute(args.to_stmt(sql))]);
+        assert_eq!(to_json(&request), recorded("named_parameters.jsonl", 0));
+    }
+
+    #[test]
+    fn describe_matches_the_recorded_pipeline() {
+        let request = PipelineReq::autocommit(vec![StreamRequest::Describe {
+            stream_id: None,
+            sql: "SELECT * FROM t".to_string(),
+        }]);
+        assert_eq!(to_json(&request), recorded("schema_change.jsonl", 0));
+    }
+
+    #[test]
+    fn websocket_messages_name_their_stream() {
+        let execute = StreamRequest::Execute {
+            stream_id: Some(4),
+            stmt: Stmt::new("SELECT 1"),
+        };
+        let frame = ClientMsg::Request {
+            request_id: 9,
+            request: to_json(&execute),
+        };
+        assert_eq!(
+            to_json(&frame),
+            serde_json::json!({
+                "type": "request",
+                "request_id": 9,
+                "request": {
+                    "type": "execute",
+                    "stream_id": 4,
+                    "stmt": { "sql": "SELECT 1" }
+                }
+            })
+        );
+
+        assert_eq!(
+            to_json(&StreamRequest::OpenStream { stream_id: 4 }),
+            serde_json::json!({ "type": "open_stream", "stream_id": 4 })
+        );
+        assert_eq!(
+            to_json(&StreamRequest::StoreSql {
+                sql_id: 2,
+                sql: "SELECT 1".to_string()
+            }),
+            serde_json::json!({ "type": "store_sql", "sql_id": 2, "sql": "SELECT 1" })
+        );
+        assert_eq!(
+            to_json(&ClientMsg::Hello {
+                jwt: Some("t0k3n".to_string())
+            }),
+            serde_json::json!({ "type": "hello", "jwt": "t0k3n" })
+        );
+        // sqld without authentication gets no jwt at all
+        assert_eq!(
+            to_json(&ClientMsg::Hello { jwt: None }),
+            serde_json::json!({ "type": "hello" })
+        );
+    }
+
+    #[test]
+    fn server_errors_map_to_result_codes() {
+        assert_eq!(error_code("SQLITE_BUSY", "database is locked"), SQLITE_BUSY);
+        assert_eq!(error_code("SQLITE_LOCKED_SHAREDCACHE", ""), SQLITE_BUSY);
+        assert_eq!(
+            error_code("UNKNOWN", "Write delegation to the primary failed"),
+            SQLITE_BUSY
+        );
+        assert_eq!(
+            error_code("SQLITE_FULL", "database or disk is full"),
+            SQLITE_FULL
+        );
+        assert_eq!(
+            error_code("BLOCKED", "Storage quota exceeded for this database"),
+            SQLITE_FULL
+        );
+        assert_eq!(error_code("SQLITE_ERROR", "no such table: t"), SQLITE_ERROR);
+
+        // Failed constraints keep their extended code, named or not
+        let unique = SQLITE_CONSTRAINT | (8 << 8);
+        assert_eq!(
+            error_code("SQLITE_CONSTRAINT_UNIQUE", "UNIQUE constraint failed: t.a"),
+            unique
+        );
+        assert_eq!(
+            error_code(
+                "SQLITE_CONSTRAINT_PRIMARYKEY",
+                "UNIQUE constraint failed: t.id"
+            ),
+            SQLITE_CONSTRAINT | (6 << 8)
+        );
+        assert_eq!(error_code("", "UNIQUE constraint failed: t.a"), unique);
+        assert_eq!(
+            error_code("SQLITE_CONSTRAINT", "NOT NULL constraint failed: t.b"),
+            SQLITE_CONSTRAINT
+        );
+        assert_eq!(
+            error_code("UNKNOWN", "NOT NULL constraint failed: t.b"),
+            SQLITE_CONSTRAINT | (5 << 8)
+        );
+        assert_eq!(error_code("SQLITE_IOERR_SHORT_READ", ""), SQLITE_IOERR);
+
+        assert_eq!(
+            error_code("AUTH_JWT_EXPIRED", "The JWT has expired"),
+            SQLITE_AUTH
+        );
+        assert_eq!(error_code("SQLITE_AUTH_USER", ""), SQLITE_AUTH | (1 << 8));
+        assert_eq!(error_code("", "permission denied for table t"), SQLITE_PERM);
+        assert_eq!(
+            error_code("SQLITE_READONLY", "attempt to write a readonly database"),
+            SQLITE_READONLY
+        );
+        assert_eq!(
+            error_code("SQLITE_READONLY_DBMOVED", ""),
+            SQLITE_READONLY | (4 << 8)
+        );
+        assert_eq!(status_code(401, "Unauthorized"), SQLITE_AUTH);
+        assert_eq!(status_code(403, ""), SQLITE_PERM);
+        assert_eq!(status_code(429, ""), SQLITE_BUSY);
+
+        let result = serde_json::json!({"type": "error", "error": {
+            "message": "database is locked", "code": "SQLITE_BUSY"
+        }});
+        let error = result_error(&result).unwrap();
+        assert_eq!(
+            (error.code, error.message.as_str()),
+            (SQLITE_BUSY, "database is locked")
+        );
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * The SQLite engine behind embedded replicas (see replica.rs). This library exports the
+ * sqlite3_* API itself, so the amalgamation libsqlite3-sys bundles is compiled into this one
+ * file with every API function static, and only the turso_replica_* wrappers below leave it.
+ */
+#define SQLITE_API static
+#define SQLITE_EXTERN
+#define SQLITE_OMIT_LOAD_EXTENSION 1
+#define SQLITE_DEFAULT_MEMSTATUS 0
+#define SQLITE_DQS 0
+#include "sqlite3.c"
+
+/* writable: open read-write, creating the file, to build a copy; otherwise read-only */
+int turso_replica_open(const char *path, int writable, sqlite3 **db) {
+    int flags = writable ? SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE : SQLITE_OPEN_READONLY;
+    return sqlite3_open_v2(path, db, flags | SQLITE_OPEN_NOMUTEX, 0);
+}
+
+int turso_replica_close(sqlite3 *db) { return sqlite3_close_v2(db); }
+
+const char *turso_replica_errmsg(sqlite3 *db) { return sqlite3_errmsg(db); }
+
+int turso_replica_exec(sqlite3 *db, const char *sql) { return sqlite3_exec(db, sql, 0, 0, 0); }
+
+/* rest: the length of the SQL after the first statement */
+int turso_replica_prepare(sqlite3 *db, const char *sql, int length, sqlite3_stmt **stmt,
+                          int *rest) {
+    const char *tail = sql;
+    int rc = sqlite3_prepare_v2(db, sql, length, stmt, &tail);
+    *rest = tail ? length - (int)(tail - sql) : 0;
+    return rc;
+}
+
+int turso_replica_readonly(sqlite3_stmt *stmt) { return sqlite3_stmt_readonly(stmt); }
+
+int turso_replica_parameter_count(sqlite3_stmt *stmt) { return sqlite3_bind_parameter_count(stmt); }
+
+int turso_replica_bind_int64(sqlite3_stmt *stmt, int index, sqlite3_int64 value) {
+    return sqlite3_bind_int64(stmt, index, value);
+}
+
+int turso_replica_bind_double(sqlite3_stmt *stmt, int index, double value) {
+    return sqlite3_bind_double(stmt, index, value);
+}
+
+int turso_replica_bind_text(sqlite3_stmt *stmt, int index, const char *value, int length) {
+    return sqlite3_bind_text(stmt, index, value, length, SQLITE_TRANSIENT);
+}
+
+int turso_replica_bind_blob(sqlite3_stmt *stmt, int index, const void *value, int length) {
+    return sqlite3_bind_blob(stmt, index, value, length, SQLITE_TRANSIENT);
+}
+
+int turso_replica_bind_null(sqlite3_stmt *stmt, int index) { return sqlite3_bind_null(stmt, index); }
+
+int turso_replica_step(sqlite3_stmt *stmt) { return sqlite3_step(stmt); }
+
+int turso_replica_reset(sqlite3_stmt *stmt) { return sqlite3_reset(stmt); }
+
+int turso_replica_finalize(sqlite3_stmt *stmt) { return sqlite3_finalize(stmt); }
+
+int turso_replica_column_count(sqlite3_stmt *stmt) { return sqlite3_column_count(stmt); }
+
+const char *turso_replica_column_name(sqlite3_stmt *stmt, int index) {
+    return sqlite3_column_name(stmt, index);
+}
+
+int turso_replica_column_type(sqlite3_stmt *stmt, int index) {
+    return sqlite3_column_type(stmt, index);
+}
+
+sqlite3_int64 turso_replica_column_int64(sqlite3_stmt *stmt, int index) {
+    return sqlite3_column_int64(stmt, index);
+}
+
+double turso_replica_column_double(sqlite3_stmt *stmt, int index) {
+    return sqlite3_column_double(stmt, index);
+}
+
+/* Text and blob columns alike: the bytes, then their length */
+const void *turso_replica_column_bytes(sqlite3_stmt *stmt, int index, int *length) {
+    const void *bytes = sqlite3_column_type(stmt, index) == SQLITE_TEXT
+                            ? (const void *)sqlite3_column_text(stmt, index)
+                            : sqlite3_column_blob(stmt, index);
+    *length = sqlite3_column_bytes(stmt, index);
+    return bytes;
+}
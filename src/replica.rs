@@ -0,0 +1,648 @@
+//! Embedded replicas, for `replica=<path>`: a copy of the database is kept in a local file and
+//! queries are answered from it by the SQLite engine compiled into replica.c, without a round
+//! trip. Everything else goes to the remote primary as always.
+//!
+//! A sync is a full copy, not an incremental one: it reads the schema and every row of the
+//! primary in one read transaction, builds a new file next to the copy with them, and renames
+//! it over the copy. A write sent to the primary leaves the copy stale, and the next read
+//! syncs first so it sees the write, or goes to the primary when that fails. With a
+//! `sync_interval`, the copy is also only used for that long after a sync, and a read syncs
+//! at most once per interval.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fs,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::sqlite::{
+    begin_tnx_on_db, commit_tnx_on_db, execute_stmt, SQLite3, SQLite3PreparedStmt, SqliteError,
+    Value, SQLITE_BLOB, SQLITE_CANTOPEN, SQLITE_DONE, SQLITE_ERROR, SQLITE_FLOAT, SQLITE_INTEGER,
+    SQLITE_IOERR, SQLITE_MISUSE, SQLITE_OK, SQLITE_ROW, SQLITE_TEXT,
+};
+
+extern "C" {
+    fn turso_replica_open(path: *const c_char, writable: c_int, db: *mut *mut c_void) -> c_int;
+    fn turso_replica_close(db: *mut c_void) -> c_int;
+    fn turso_replica_errmsg(db: *mut c_void) -> *const c_char;
+    fn turso_replica_exec(db: *mut c_void, sql: *const c_char) -> c_int;
+    fn turso_replica_prepare(
+        db: *mut c_void,
+        sql: *const c_char,
+        length: c_int,
+        stmt: *mut *mut c_void,
+        rest: *mut c_int,
+    ) -> c_int;
+    fn turso_replica_readonly(stmt: *mut c_void) -> c_int;
+    fn turso_replica_parameter_count(stmt: *mut c_void) -> c_int;
+    fn turso_replica_bind_int64(stmt: *mut c_void, index: c_int, value: i64) -> c_int;
+    fn turso_replica_bind_double(stmt: *mut c_void, index: c_int, value: f64) -> c_int;
+    fn turso_replica_bind_text(
+        stmt: *mut c_void,
+        index: c_int,
+        value: *const c_char,
+        length: c_int,
+    ) -> c_int;
+    fn turso_replica_bind_blob(
+        stmt: *mut c_void,
+        index: c_int,
+        value: *const c_void,
+        length: c_int,
+    ) -> c_int;
+    fn turso_replica_bind_null(stmt: *mut c_void, index: c_int) -> c_int;
+    fn turso_replica_step(stmt: *mut c_void) -> c_int;
+    fn turso_replica_reset(stmt: *mut c_void) -> c_int;
+    fn turso_replica_finalize(stmt: *mut c_void) -> c_int;
+    fn turso_replica_column_count(stmt: *mut c_void) -> c_int;
+    fn turso_replica_column_name(stmt: *mut c_void, index: c_int) -> *const c_char;
+    fn turso_replica_column_type(stmt: *mut c_void, index: c_int) -> c_int;
+    fn turso_replica_column_int64(stmt: *mut c_void, index: c_int) -> i64;
+    fn turso_replica_column_double(stmt: *mut c_void, index: c_int) -> f64;
+    fn turso_replica_column_bytes(
+        stmt: *mut c_void,
+        index: c_int,
+        length: *mut c_int,
+    ) -> *const c_void;
+}
+
+/// Rows read from the primary per request while copying a table.
+const COPY_BATCH_SIZE: usize = 1000;
+
+type Rows = (Vec<String>, Vec<Vec<Value>>);
+
+pub struct Replica {
+    path: PathBuf,
+    interval: Duration,                // Zero when the copy is used until a write
+    local: Mutex<Option<LocalDb>>,     // None until the first sync or when it failed
+    synced_at: Mutex<Option<Instant>>, // None for a copy opened from an earlier run
+    stale: AtomicBool,                 // A write went to the primary since the last sync
+    attempted_at: Mutex<Option<Instant>>, // Last sync attempt, to space out retries
+}
+
+impl Replica {
+    pub fn new(path: &Path, interval: Duration) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            interval,
+            local: Mutex::new(None),
+            synced_at: Mutex::new(None),
+            stale: AtomicBool::new(false),
+            attempted_at: Mutex::new(None),
+        }
+    }
+
+    /// Answers `stmt` from the local copy and returns true, or returns false for the caller
+    /// to send it to the primary. Only queries outside a transaction are read locally, and a
+    /// failure of the local engine or of a sync falls back to the primary rather than
+    /// surfacing.
+    pub async fn read(&self, db: *mut SQLite3, stmt: &mut SQLite3PreparedStmt) -> bool {
+        let served = is_query(&stmt.sql) && self.serve(db, stmt).await;
+        if !served && !leaves_data(&stmt.sql) {
+            // Whatever else reaches the primary may have changed what the copy holds
+            self.stale.store(true, Ordering::Relaxed);
+        }
+        served
+    }
+
+    async fn serve(&self, db: *mut SQLite3, stmt: &mut SQLite3PreparedStmt) -> bool {
+        if unsafe { &*db }.has_began_transaction() {
+            return false;
+        }
+        if self.should_sync() {
+            // Boxed, as the sync's own queries come back through execute_stmt
+            if let Err(err) = Box::pin(sync(db)).await {
+                tracing::warn!(error = %err, "Failed to sync the replica");
+            }
+        }
+        if self.stale.load(Ordering::Relaxed) || !self.is_current() {
+            return false;
+        }
+
+        let local = self.local.lock().unwrap();
+        let Some(local) = local.as_ref() else {
+            return false;
+        };
+        match local.query(&stmt.sql, &stmt.params) {
+            Ok(Some((column_names, rows))) => {
+                stmt.column_names = column_names;
+                *stmt.result_rows.lock().unwrap() = rows;
+                true
+            }
+            Ok(None) => false,
+            Err(err) => {
+                tracing::debug!(error = %err, "Failed to read from the replica");
+                false
+            }
+        }
+    }
+
+    /// Opens the copy left on disk by an earlier run, for when the first sync failed.
+    fn open_existing(&self) -> Result<(), SqliteError> {
+        *self.local.lock().unwrap() = Some(LocalDb::open(&self.path, false)?);
+        Ok(())
+    }
+
+    // Where a sync builds the new copy before renaming it over the old one
+    fn staging_path(&self) -> PathBuf {
+        let mut staging = self.path.clone().into_os_string();
+        staging.push("-sync");
+        PathBuf::from(staging)
+    }
+
+    // Renames the copy a sync built over the file, so a reader of the file never sees half
+    // of it, then reopens it.
+    fn install(&self, staging: &Path) -> Result<(), SqliteError> {
+        // Closed first, for platforms that cannot replace a file still open
+        let mut local = self.local.lock().unwrap();
+        let closed = local.take().is_some();
+        if let Err(err) = fs::rename(staging, &self.path) {
+            let _ = fs::remove_file(staging);
+            if closed {
+                *local = LocalDb::open(&self.path, false).ok();
+            }
+            return Err(SqliteError::new(
+                format!(
+                    "Failed to replace the replica {}: {}",
+                    self.path.display(),
+                    err
+                ),
+                Some(SQLITE_IOERR),
+            ));
+        }
+        *local = Some(LocalDb::open(&self.path, false)?);
+        *self.synced_at.lock().unwrap() = Some(Instant::now());
+        self.stale.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn is_current(&self) -> bool {
+        if self.interval.is_zero() {
+            return true;
+        }
+        let synced_at = *self.synced_at.lock().unwrap();
+        synced_at.is_some_and(|at| at.elapsed() < self.interval)
+    }
+
+    // Whether a read should sync first: the copy is stale or has run out. Without an interval
+    // that is every time, with one the last attempt must be an interval ago, so a primary that
+    // cannot be read is not asked on every read.
+    fn should_sync(&self) -> bool {
+        if self.is_current() && !self.stale.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut attempted_at = self.attempted_at.lock().unwrap();
+        if attempted_at.is_some_and(|at| at.elapsed() < self.interval) {
+            return false;
+        }
+        *attempted_at = Some(Instant::now());
+        true
+    }
+}
+
+// The statement's first keyword, past whitespace and comments, in upper case
+fn leading_keyword(sql: &str) -> String {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.find('\n').map_or("", |end| &comment[end..]);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.find("*/").map_or("", |end| &comment[end + 2..]);
+        } else {
+            break;
+        }
+    }
+    rest.chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+// Statements the copy may answer. The local engine still refuses those that would write,
+// such as a WITH in front of an INSERT.
+fn is_query(sql: &str) -> bool {
+    matches!(leading_keyword(sql).as_str(), "SELECT" | "VALUES" | "WITH")
+}
+
+// Statements that cannot change the data when they run on the primary
+fn leaves_data(sql: &str) -> bool {
+    match leading_keyword(sql).as_str() {
+        "SELECT" | "VALUES" | "EXPLAIN" => true,
+        "PRAGMA" => !sql.contains('='),
+        _ => false,
+    }
+}
+
+/// Makes the first sync of a newly opened connection. When the primary cannot be read, the
+/// copy an earlier run left on disk is used until a sync succeeds.
+pub async fn open(db: *mut SQLite3) -> Result<(), SqliteError> {
+    let Some(replica) = (unsafe { &(*db).replica }) else {
+        return Ok(());
+    };
+    *replica.attempted_at.lock().unwrap() = Some(Instant::now());
+    match sync(db).await {
+        Err(err) if replica.path.exists() => {
+            tracing::warn!(
+                error = %err,
+                path = %replica.path.display(),
+                "Failed to sync the replica, reading the copy on disk"
+            );
+            replica.open_existing()
+        }
+        Err(err) => Err(SqliteError::new(
+            format!("Failed to sync the replica: {}", err),
+            Some(SQLITE_CANTOPEN),
+        )),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Brings the local copy up to date with the primary by copying the whole database.
+pub async fn sync(db: *mut SQLite3) -> Result<(), SqliteError> {
+    let Some(replica) = (unsafe { &(*db).replica }) else {
+        return Err(SqliteError::new(
+            "the connection has no replica",
+            Some(SQLITE_MISUSE),
+        ));
+    };
+    if unsafe { &*db }.has_began_transaction() {
+        return Err(SqliteError::new(
+            "cannot sync the replica within a transaction",
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    let staging = replica.staging_path();
+    let _ = fs::remove_file(&staging);
+    let copied = async {
+        let target = LocalDb::open(&staging, true)?;
+        // A half-written copy is thrown away, so it needs no journal
+        target.exec("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF; BEGIN")?;
+        begin_tnx_on_db(db, "BEGIN").await?;
+        let copied = copy(db, &target).await;
+        // The transaction only read, so ending it either way is the same
+        let ended = commit_tnx_on_db(db, "COMMIT").await;
+        copied?;
+        ended?;
+        target.exec("COMMIT")
+    }
+    .await;
+    if let Err(err) = copied {
+        let _ = fs::remove_file(&staging);
+        return Err(err);
+    }
+    replica.install(&staging)
+}
+
+// Copies the schema and rows of the primary into `target`: tables first, then their rows,
+// then the indexes, views and triggers, which are cheaper to build over the copied rows.
+// Objects the local engine cannot create, such as virtual tables of modules it lacks, are
+// left out, and queries over them go to the primary.
+async fn copy(db: *mut SQLite3, target: &LocalDb) -> Result<(), SqliteError> {
+    let objects = remote(
+        db,
+        "SELECT type, name, sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY rowid",
+        vec![],
+    )
+    .await?;
+    let objects: Vec<(String, String, String)> = objects
+        .into_iter()
+        .filter_map(|row| match <[Value; 3]>::try_from(row) {
+            Ok([Value::Text(kind), Value::Text(name), Value::Text(sql)]) => Some((kind, name, sql)),
+            _ => None,
+        })
+        .collect();
+
+    let mut tables = Vec::new();
+    let mut sequences = false;
+    for (_, name, sql) in objects.iter().filter(|(kind, ..)| kind == "table") {
+        // Made by the engine along with the first AUTOINCREMENT table
+        if name == "sqlite_sequence" {
+            sequences = true;
+            continue;
+        }
+        match target.exec(sql) {
+            Ok(()) => tables.push(name.as_str()),
+            Err(err) => tracing::debug!(error = %err, table = %name, "Not replicating a table"),
+        }
+    }
+    // Last, as copying the other tables moves the sequences on
+    if sequences {
+        tables.push("sqlite_sequence");
+    }
+    for name in tables {
+        copy_rows(db, target, name).await?;
+    }
+    for (kind, name, sql) in objects.iter().filter(|(kind, ..)| kind != "table") {
+        if let Err(err) = target.exec(sql) {
+            tracing::debug!(error = %err, kind = %kind, name = %name, "Not replicating an object");
+        }
+    }
+
+    for pragma in ["user_version", "application_id"] {
+        let rows = remote(db, &format!("PRAGMA {}", pragma), vec![]).await?;
+        if let Some(Value::Integer(value)) = rows.first().and_then(|row| row.first()) {
+            target.exec(&format!("PRAGMA {} = {}", pragma, value))?;
+        }
+    }
+    Ok(())
+}
+
+// Copies the rows of one table, a batch at a time. Rowid tables are read in rowid order past
+// the last rowid copied, and the rowids are kept, as queries can see them. WITHOUT ROWID
+// tables are read in primary key order.
+async fn copy_rows(db: *mut SQLite3, target: &LocalDb, table: &str) -> Result<(), SqliteError> {
+    let table = quote(table);
+    // Generated columns are computed by the copy itself
+    let columns: Vec<String> = target
+        .query(&format!("PRAGMA table_xinfo({})", table), &HashMap::new())?
+        .map(|(_, rows)| rows)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|row| matches!(row.get(6), Some(Value::Integer(0 | 1))))
+        .filter_map(|row| match row.into_iter().nth(1) {
+            Some(Value::Text(name)) => Some(quote(&name)),
+            _ => None,
+        })
+        .collect();
+    if columns.is_empty() {
+        return Ok(());
+    }
+    let has_rowid = target.prepares(&format!("SELECT rowid FROM {}", table));
+    if table == "\"sqlite_sequence\"" {
+        target.exec("DELETE FROM sqlite_sequence")?;
+    }
+
+    let (select, insert_columns) = if has_rowid {
+        (
+            format!(
+                "SELECT rowid, {} FROM {} WHERE rowid > ? ORDER BY rowid LIMIT {}",
+                columns.join(", "),
+                table,
+                COPY_BATCH_SIZE
+            ),
+            format!("rowid, {}", columns.join(", ")),
+        )
+    } else {
+        (
+            format!(
+                "SELECT {} FROM {} ORDER BY {} LIMIT {} OFFSET ?",
+                columns.join(", "),
+                table,
+                primary_key(target, &table)?.join(", "),
+                COPY_BATCH_SIZE
+            ),
+            columns.join(", "),
+        )
+    };
+    let placeholders = vec!["?"; insert_columns.split(", ").count()].join(", ");
+    let insert = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table, insert_columns, placeholders
+    );
+
+    let mut after = Value::Integer(i64::MIN);
+    let mut offset = 0;
+    loop {
+        // Until a batch comes back empty, as the server may return fewer rows than asked for
+        let position = match has_rowid {
+            true => after.clone(),
+            false => Value::Integer(offset),
+        };
+        let rows = remote(db, &select, vec![position]).await?;
+        match rows.last() {
+            None => return Ok(()),
+            Some(last) if has_rowid => after = last[0].clone(),
+            Some(_) => {}
+        }
+        offset += rows.len() as i64;
+        target.insert(&insert, rows)?;
+    }
+}
+
+// The primary key columns of a WITHOUT ROWID table, in key order
+fn primary_key(target: &LocalDb, table: &str) -> Result<Vec<String>, SqliteError> {
+    let rows = target
+        .query(&format!("PRAGMA table_info({})", table), &HashMap::new())?
+        .map(|(_, rows)| rows)
+        .unwrap_or_default();
+    let mut key: Vec<(i64, String)> = rows
+        .into_iter()
+        .filter_map(|row| match (row.get(5), row.get(1)) {
+            (Some(Value::Integer(position)), Some(Value::Text(name))) if *position > 0 => {
+                Some((*position, quote(name)))
+            }
+            _ => None,
+        })
+        .collect();
+    key.sort();
+    Ok(key.into_iter().map(|(_, name)| name).collect())
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Runs a query on the primary, through the same path as the application's
+async fn remote(
+    db: *mut SQLite3,
+    sql: &str,
+    params: Vec<Value>,
+) -> Result<Vec<Vec<Value>>, SqliteError> {
+    let mut stmt = SQLite3PreparedStmt::new(db, sql);
+    stmt.params = (1..).zip(params).collect();
+    execute_stmt(&mut stmt).await?;
+    let rows = std::mem::take(&mut *stmt.result_rows.lock().unwrap());
+    Ok(rows)
+}
+
+// A handle on a file of the local engine, used from one thread at a time through the
+// Replica's lock
+struct LocalDb(*mut c_void);
+
+unsafe impl Send for LocalDb {}
+
+impl LocalDb {
+    fn open(path: &Path, writable: bool) -> Result<Self, SqliteError> {
+        let c_path = CString::new(path.to_string_lossy().as_bytes()).map_err(|_| {
+            SqliteError::new("replica path contains a NUL byte", Some(SQLITE_CANTOPEN))
+        })?;
+        let mut handle = ptr::null_mut();
+        let rc = unsafe { turso_replica_open(c_path.as_ptr(), writable as c_int, &mut handle) };
+        let local = LocalDb(handle);
+        if rc != SQLITE_OK {
+            return Err(SqliteError::new(
+                format!(
+                    "Failed to open the replica {}: {}",
+                    path.display(),
+                    local.errmsg()
+                ),
+                Some(SQLITE_CANTOPEN),
+            ));
+        }
+        Ok(local)
+    }
+
+    fn errmsg(&self) -> String {
+        let message = unsafe { turso_replica_errmsg(self.0) };
+        if message.is_null() {
+            return "out of memory".to_string();
+        }
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn error(&self) -> SqliteError {
+        SqliteError::new(self.errmsg(), Some(SQLITE_ERROR))
+    }
+
+    fn exec(&self, sql: &str) -> Result<(), SqliteError> {
+        let c_sql = CString::new(sql).map_err(|_| self.error())?;
+        match unsafe { turso_replica_exec(self.0, c_sql.as_ptr()) } {
+            SQLITE_OK => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn prepare(&self, sql: &str) -> Result<(LocalStmt, bool), SqliteError> {
+        let length = c_int::try_from(sql.len()).map_err(|_| self.error())?;
+        let mut handle = ptr::null_mut();
+        let mut rest = 0;
+        let rc = unsafe {
+            turso_replica_prepare(self.0, sql.as_ptr().cast(), length, &mut handle, &mut rest)
+        };
+        let stmt = LocalStmt(handle);
+        if rc != SQLITE_OK || handle.is_null() {
+            return Err(self.error());
+        }
+        let tail = &sql.as_bytes()[sql.len() - rest as usize..];
+        Ok((stmt, tail.trim_ascii().is_empty()))
+    }
+
+    fn prepares(&self, sql: &str) -> bool {
+        self.prepare(sql).is_ok()
+    }
+
+    /// The rows of `sql`, or None when it is not a read here: more than one statement, or one
+    /// that would write.
+    fn query(&self, sql: &str, params: &HashMap<i32, Value>) -> Result<Option<Rows>, SqliteError> {
+        let (stmt, single) = self.prepare(sql)?;
+        // A script runs in one place, and that is the primary
+        if !single || unsafe { turso_replica_readonly(stmt.0) } == 0 {
+            return Ok(None);
+        }
+        stmt.bind(params).map_err(|()| self.error())?;
+
+        let column_count = unsafe { turso_replica_column_count(stmt.0) };
+        let column_names = (0..column_count)
+            .map(|index| {
+                let name = unsafe { turso_replica_column_name(stmt.0, index) };
+                match name.is_null() {
+                    true => String::new(),
+                    false => unsafe { CStr::from_ptr(name) }
+                        .to_string_lossy()
+                        .into_owned(),
+                }
+            })
+            .collect();
+
+        let mut rows = Vec::new();
+        loop {
+            match unsafe { turso_replica_step(stmt.0) } {
+                SQLITE_ROW => rows.push((0..column_count).map(|i| stmt.value(i)).collect()),
+                SQLITE_DONE => return Ok(Some((column_names, rows))),
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    // Runs the INSERT `sql` once for each row
+    fn insert(&self, sql: &str, rows: Vec<Vec<Value>>) -> Result<(), SqliteError> {
+        let (stmt, _) = self.prepare(sql)?;
+        for row in rows {
+            stmt.bind(&(1..).zip(row).collect())
+                .map_err(|()| self.error())?;
+            let rc = unsafe { turso_replica_step(stmt.0) };
+            unsafe { turso_replica_reset(stmt.0) };
+            if rc != SQLITE_DONE {
+                return Err(self.error());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LocalDb {
+    fn drop(&mut self) {
+        unsafe { turso_replica_close(self.0) };
+    }
+}
+
+struct LocalStmt(*mut c_void);
+
+impl LocalStmt {
+    fn bind(&self, params: &HashMap<i32, Value>) -> Result<(), ()> {
+        let count = unsafe { turso_replica_parameter_count(self.0) };
+        for (&index, value) in params.iter().filter(|(&index, _)| index <= count) {
+            let rc = unsafe {
+                match value {
+                    Value::Integer(value) => turso_replica_bind_int64(self.0, index, *value),
+                    Value::Real(value) => turso_replica_bind_double(self.0, index, *value),
+                    Value::Text(value) => turso_replica_bind_text(
+                        self.0,
+                        index,
+                        value.as_ptr().cast(),
+                        c_int::try_from(value.len()).map_err(|_| ())?,
+                    ),
+                    Value::Blob(value) => turso_replica_bind_blob(
+                        self.0,
+                        index,
+                        value.as_ptr().cast(),
+                        c_int::try_from(value.len()).map_err(|_| ())?,
+                    ),
+                    Value::Null => turso_replica_bind_null(self.0, index),
+                }
+            };
+            if rc != SQLITE_OK {
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, index: c_int) -> Value {
+        unsafe {
+            match turso_replica_column_type(self.0, index) {
+                SQLITE_INTEGER => Value::Integer(turso_replica_column_int64(self.0, index)),
+                SQLITE_FLOAT => Value::Real(turso_replica_column_double(self.0, index)),
+                kind @ (SQLITE_TEXT | SQLITE_BLOB) => {
+                    let mut length = 0;
+                    let bytes = turso_replica_column_bytes(self.0, index, &mut length);
+                    let bytes = match bytes.is_null() {
+                        true => &[][..],
+                        false => std::slice::from_raw_parts(bytes.cast::<u8>(), length as usize),
+                    };
+                    match kind {
+                        SQLITE_TEXT => Value::Text(String::from_utf8_lossy(bytes).into_owned()),
+                        _ => Value::Blob(bytes.to_vec()),
+                    }
+                }
+                _ => Value::Null,
+            }
+        }
+    }
+}
+
+impl Drop for LocalStmt {
+    fn drop(&mut self) {
+        unsafe { turso_replica_finalize(self.0) };
+    }
+}
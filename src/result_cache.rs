@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    mem::size_of,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::{
+    analyzer::{analyze, StatementEffect},
+    protocol::StatementArgs,
+    sqlite::Value,
+    status::value_size,
+};
+
+pub const DEFAULT_RESULT_CACHE_TTL: Duration = Duration::from_secs(5);
+pub const DEFAULT_RESULT_CACHE_SIZE: usize = 256;
+
+#[derive(Clone)]
+pub struct CachedResult {
+    pub column_names: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+struct Entry {
+    stored_at: Instant,
+    tables: Vec<String>,
+    result: CachedResult,
+}
+
+/// SELECT results kept for `ttl`, toggled by `PRAGMA turso.cache`. Writes issued through
+/// this connection evict entries over the tables they touch; writes from elsewhere are
+/// only bounded by the TTL.
+pub struct ResultCache {
+    pub enabled: bool,
+    pub ttl: Duration,
+    pub max_entries: usize,
+    entries: HashMap<String, Entry>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl: DEFAULT_RESULT_CACHE_TTL,
+            max_entries: DEFAULT_RESULT_CACHE_SIZE,
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+
+impl ResultCache {
+    /// Cache key for a statement, `None` when its result must not be cached.
+    pub fn key(&self, sql: &str, params: &StatementArgs) -> Option<String> {
+        if !self.enabled || !is_deterministic(sql) {
+            return None;
+        }
+
+        match analyze(sql) {
+            StatementEffect::Read(tables) if !tables.is_empty() => {
+                let normalized = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+                serde_json::to_string(&params.to_stmt(&normalized)).ok()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<CachedResult> {
+        let Some(entry) = self.entries.get(key) else {
+            self.misses += 1;
+            return None;
+        };
+        if entry.stored_at.elapsed() > self.ttl {
+            self.entries.remove(key);
+            self.misses += 1;
+            return None;
+        }
+
+        self.hits += 1;
+        Some(entry.result.clone())
+    }
+
+    pub fn hits(&mut self, reset: bool) -> u64 {
+        let hits = self.hits;
+        if reset {
+            self.hits = 0;
+        }
+        hits
+    }
+
+    pub fn misses(&mut self, reset: bool) -> u64 {
+        let misses = self.misses;
+        if reset {
+            self.misses = 0;
+        }
+        misses
+    }
+
+    /// Estimated bytes held by the cached keys and result sets.
+    pub fn memory_used(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(key, entry)| {
+                let result = &entry.result;
+                key.capacity()
+                    + size_of::<Entry>()
+                    + entry.tables.iter().map(|t| t.capacity()).sum::<usize>()
+                    + result
+                        .column_names
+                        .iter()
+                        .map(|c| c.capacity())
+                        .sum::<usize>()
+                    + result.rows.iter().flatten().map(value_size).sum::<usize>()
+            })
+            .sum()
+    }
+
+    pub fn insert(&mut self, key: String, sql: &str, result: CachedResult) {
+        let StatementEffect::Read(tables) = analyze(sql) else {
+            return;
+        };
+        if self.max_entries == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.max_entries {
+            let ttl = self.ttl;
+            self.entries
+                .retain(|_, entry| entry.stored_at.elapsed() <= ttl);
+        }
+        if self.entries.len() >= self.max_entries {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(
+            key,
+            Entry {
+                stored_at: Instant::now(),
+                tables,
+                result,
+            },
+        );
+    }
+
+    /// Evicts whatever a statement about to run on the connection may change.
+    pub fn invalidate_for(&mut self, sql: &str) {
+        if self.entries.is_empty() {
+            return;
+        }
+
+        match analyze(sql) {
+            StatementEffect::Read(_) => (),
+            StatementEffect::Write(tables) => self
+                .entries
+                .retain(|_, entry| !entry.tables.iter().any(|t| tables.contains(t))),
+            StatementEffect::Unknown => self.clear(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// Results that change between identical calls are never cached
+fn is_deterministic(sql: &str) -> bool {
+    let re = Regex::new(
+        r"(?i)\b(?:random|randomblob|changes|total_changes|last_insert_rowid)\s*\(|'now'",
+    )
+    .unwrap();
+    !re.is_match(sql)
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    mem::size_of,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    analyzer::{analyze, StatementEffect},
+    protocol::StatementArgs,
+    result_cache::CachedResult,
+    sql::{
+        select::{is_keyword, spanned_tokens},
+        tokenizer::{changes_schema, unquote, Token},
+    },
+    sqlite::Value,
+    status::value_size,
+};
+
+/// How long the server's `schema_version` is trusted before it is asked for again.
+pub const DEFAULT_SCHEMA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_SCHEMA_CACHE_SIZE: usize = 128;
+
+const SCHEMA_TABLES: [&str; 4] = [
+    "sqlite_master",
+    "sqlite_schema",
+    "sqlite_temp_master",
+    "sqlite_temp_schema",
+];
+
+// The columns of PRAGMA table_info
+const TABLE_INFO_COLUMNS: [&str; 6] = ["cid", "name", "type", "notnull", "dflt_value", "pk"];
+
+/// Results of queries over `sqlite_master` and of `PRAGMA table_info`, which tools run over
+/// and over while starting up. Entries are kept for as long as the server reports the same `schema_version`, which
+/// is checked at most once per `check_interval`. DDL run on this connection clears the
+/// cache at once; schema changes from elsewhere are seen by the next check.
+pub struct SchemaCache {
+    pub enabled: bool,
+    pub check_interval: Duration,
+    pub max_entries: usize,
+    version: Option<i64>,
+    checked_at: Option<Instant>,
+    entries: HashMap<String, CachedResult>,
+}
+
+impl SchemaCache {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            check_interval: DEFAULT_SCHEMA_CHECK_INTERVAL,
+            max_entries: DEFAULT_SCHEMA_CACHE_SIZE,
+            version: None,
+            checked_at: None,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Cache key for a query that reads nothing but the schema tables, `None` otherwise.
+    pub fn key(&self, sql: &str, params: &StatementArgs) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        // However it is spelled, so results from warm_up are found too
+        if let Some(table) = table_info_target(sql) {
+            return Some(table_info_key(&table));
+        }
+        // Spares every other statement the analysis below
+        if !sql.to_ascii_lowercase().contains("sqlite_") {
+            return None;
+        }
+
+        match analyze(sql) {
+            StatementEffect::Read(tables)
+                if !tables.is_empty()
+                    && tables.iter().all(|t| SCHEMA_TABLES.contains(&t.as_str())) =>
+            {
+                let normalized = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+                serde_json::to_string(&params.to_stmt(&normalized)).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the server's `schema_version` has to be read before the cache is used.
+    pub fn needs_check(&self) -> bool {
+        self.checked_at
+            .is_none_or(|checked_at| checked_at.elapsed() > self.check_interval)
+    }
+
+    /// Records the server's current `schema_version`, dropping results of an older schema.
+    /// Returns whether it differs from the version seen before.
+    pub fn observe_version(&mut self, version: i64) -> bool {
+        self.checked_at = Some(Instant::now());
+        let changed = self.version.is_some_and(|seen| seen != version);
+        if self.version != Some(version) {
+            self.entries.clear();
+            self.version = Some(version);
+        }
+        changed
+    }
+
+    /// Takes the `schema_version` and the `PRAGMA table_info` rows of each table from a
+    /// connection's warm-up.
+    pub fn prime(&mut self, version: i64, tables: Vec<(String, Vec<Vec<Value>>)>) {
+        self.observe_version(version);
+        for (table, rows) in tables {
+            let result = CachedResult {
+                column_names: TABLE_INFO_COLUMNS.map(str::to_string).to_vec(),
+                rows,
+            };
+            self.insert(table_info_key(&table.to_lowercase()), result);
+        }
+    }
+
+    /// Holds off the next check after a failed one, keeping what is cached.
+    pub fn check_failed(&mut self) {
+        self.checked_at = Some(Instant::now());
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResult> {
+        self.entries.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: String, result: CachedResult) {
+        if self.version.is_some() && self.entries.len() < self.max_entries {
+            self.entries.insert(key, result);
+        }
+    }
+
+    /// Estimated bytes held by the cached keys and result sets.
+    pub fn memory_used(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(key, result)| {
+                key.capacity()
+                    + size_of::<CachedResult>()
+                    + result
+                        .column_names
+                        .iter()
+                        .map(|c| c.capacity())
+                        .sum::<usize>()
+                    + result.rows.iter().flatten().map(value_size).sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Clears the cache before a statement that may change the schema runs.
+    pub fn invalidate_for(&mut self, sql: &str) {
+        if changes_schema(sql) {
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.version = None;
+        self.checked_at = None;
+    }
+}
+
+fn table_info_key(table: &str) -> String {
+    format!("PRAGMA table_info({})", table)
+}
+
+// The table `PRAGMA [main.]table_info(<table>)` describes, in lower case as names compare
+fn table_info_target(sql: &str) -> Option<String> {
+    let tokens = spanned_tokens(sql);
+    let mut tokens: Vec<&Token> = tokens.iter().map(|spanned| &spanned.token).collect();
+    while tokens.last() == Some(&&Token::Punct(";")) {
+        tokens.pop();
+    }
+    if tokens.len() > 3 && is_keyword(tokens[1], "main") && tokens[2] == &Token::Punct(".") {
+        tokens.drain(1..3);
+    }
+
+    match tokens.as_slice() {
+        [pragma, name, Token::Punct("("), table, Token::Punct(")")]
+            if is_keyword(pragma, "PRAGMA") && is_keyword(name, "table_info") =>
+        {
+            match table {
+                Token::Identifier(table) => Some(unquote(table)),
+                Token::Literal(table) if table.starts_with('\'') => {
+                    let inner = &table[1..table.len().saturating_sub(1).max(1)];
+                    Some(inner.replace("''", "'").to_lowercase())
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,392 @@
+//! `sqlite3_serialize` and `sqlite3_deserialize` over a remote database. Serializing reads the
+//! schema and every b-tree's contents from the server, inside one read transaction, and writes
+//! them out as a database image. Deserializing reads an image and replays its schema and rows
+//! into the remote database in a single transaction.
+
+use crate::{
+    backup::{drop_statements, query, quote, text},
+    image::{Image, ImageWriter, SchemaEntry},
+    sql::{
+        select::{closing_paren, is_keyword, spanned_tokens},
+        tokenizer::Token,
+    },
+    sqlite::{
+        begin_tnx_on_db, commit_tnx_on_db, rollback_tnx_on_db, SQLite3, SqliteError, Value,
+        SQLITE_ERROR,
+    },
+};
+
+/// Rows fetched per query.
+const BATCH_SIZE: i64 = 1000;
+
+/// Rows inserted per statement, well within the server's limit on bound parameters.
+const INSERT_BATCH_SIZE: usize = 100;
+
+/// Runs `work` in a transaction of its own, or in the caller's when one is open.
+async fn in_transaction<T>(
+    db: *mut SQLite3,
+    work: impl std::future::Future<Output = Result<T, SqliteError>>,
+) -> Result<T, SqliteError> {
+    if unsafe { &*db }.has_began_transaction() {
+        return work.await;
+    }
+
+    begin_tnx_on_db(db, "BEGIN").await?;
+    match work.await {
+        Ok(value) => {
+            commit_tnx_on_db(db, "COMMIT").await?;
+            Ok(value)
+        }
+        Err(error) => {
+            let _ = rollback_tnx_on_db(db, "ROLLBACK").await;
+            Err(error)
+        }
+    }
+}
+
+fn integer(value: &Value) -> i64 {
+    match value {
+        Value::Integer(i) => *i,
+        _ => 0,
+    }
+}
+
+/// Stored columns of a table as `PRAGMA table_xinfo` lists them: name, whether the record
+/// holds it (virtual generated columns are computed on read), whether it can be inserted
+/// into, and whether it is the INTEGER PRIMARY KEY standing for the rowid.
+struct Column {
+    name: String,
+    stored: bool,
+    writable: bool,
+    rowid_alias: bool,
+}
+
+async fn columns(db: *mut SQLite3, table: &str) -> Result<Vec<Column>, SqliteError> {
+    let info = query(db, &format!("PRAGMA table_xinfo({})", quote(table)), vec![]).await?;
+    let rows = info.result_rows.lock().unwrap();
+
+    let primary_keys = rows.iter().filter(|row| integer(&row[5]) > 0).count();
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let hidden = integer(&row[6]);
+            Column {
+                name: text(&row[1]),
+                stored: hidden != 1 && hidden != 2,
+                writable: hidden == 0,
+                rowid_alias: primary_keys == 1
+                    && integer(&row[5]) == 1
+                    && text(&row[2]).eq_ignore_ascii_case("INTEGER"),
+            }
+        })
+        .collect())
+}
+
+fn unsupported(what: &str, name: &str) -> SqliteError {
+    SqliteError::new(
+        format!("cannot serialize {} {}", what, name),
+        Some(SQLITE_ERROR),
+    )
+}
+
+fn is_without_rowid(sql: &str) -> bool {
+    let tokens = spanned_tokens(sql);
+    tokens
+        .windows(2)
+        .any(|pair| is_keyword(&pair[0].token, "WITHOUT") && is_keyword(&pair[1].token, "ROWID"))
+}
+
+/// Reads the whole database into an image.
+pub async fn serialize(db: *mut SQLite3) -> Result<Vec<u8>, SqliteError> {
+    in_transaction(db, async {
+        let objects = query(
+            db,
+            "SELECT type, name, tbl_name, sql FROM sqlite_master ORDER BY rowid",
+            vec![],
+        )
+        .await?;
+        let objects: Vec<Vec<Value>> = objects.result_rows.lock().unwrap().clone();
+
+        let mut writer = ImageWriter::new();
+        let mut schema = Vec::new();
+        for row in objects {
+            let (kind, name, table) = (text(&row[0]), text(&row[1]), text(&row[2]));
+            let sql = match &row[3] {
+                Value::Text(sql) => Some(sql.clone()),
+                _ => None,
+            };
+            let definition = sql.as_deref().unwrap_or_default();
+
+            let rootpage = match kind.as_str() {
+                "table" if definition.to_uppercase().starts_with("CREATE VIRTUAL") => 0,
+                "table" if is_without_rowid(definition) => {
+                    return Err(unsupported("WITHOUT ROWID table", &name));
+                }
+                "table" => writer.table(table_rows(db, &name).await?),
+                "index" => writer.index(index_entries(db, &name, &table, definition).await?),
+                _ => 0,
+            };
+
+            schema.push(SchemaEntry {
+                kind,
+                name,
+                table,
+                rootpage,
+                sql,
+            });
+        }
+
+        let user_version = pragma_value(db, "user_version").await?;
+        let application_id = pragma_value(db, "application_id").await?;
+        Ok(writer.finish(schema, user_version, application_id))
+    })
+    .await
+}
+
+async fn pragma_value(db: *mut SQLite3, name: &str) -> Result<i64, SqliteError> {
+    let result = query(db, &format!("PRAGMA {}", name), vec![]).await?;
+    let rows = result.result_rows.lock().unwrap();
+    Ok(rows.first().map_or(0, |row| integer(&row[0])))
+}
+
+// Rows in rowid order, the rowid alias column stored as NULL like SQLite stores it
+async fn table_rows(db: *mut SQLite3, table: &str) -> Result<Vec<(i64, Vec<Value>)>, SqliteError> {
+    let columns: Vec<Column> = columns(db, table)
+        .await?
+        .into_iter()
+        .filter(|column| column.stored)
+        .collect();
+    let select: Vec<String> = std::iter::once("rowid".to_string())
+        .chain(columns.iter().map(|column| quote(&column.name)))
+        .collect();
+
+    let mut rows = Vec::new();
+    let mut after: Option<i64> = None;
+    loop {
+        let (filter, params) = match after {
+            Some(rowid) => ("WHERE rowid > ? ", vec![Value::Integer(rowid)]),
+            None => ("", vec![]),
+        };
+        let sql = format!(
+            "SELECT {} FROM {} {}ORDER BY rowid LIMIT {}",
+            select.join(", "),
+            quote(table),
+            filter,
+            BATCH_SIZE
+        );
+        let batch = query(db, &sql, params).await?;
+        let batch = std::mem::take(&mut *batch.result_rows.lock().unwrap());
+        let fetched = batch.len() as i64;
+
+        for mut row in batch {
+            let rowid = integer(&row.remove(0));
+            for (value, column) in row.iter_mut().zip(&columns) {
+                if column.rowid_alias {
+                    *value = Value::Null;
+                }
+            }
+            after = Some(rowid);
+            rows.push((rowid, row));
+        }
+        if fetched < BATCH_SIZE {
+            return Ok(rows);
+        }
+    }
+}
+
+// WHERE clause of a partial index, following its column list
+fn partial_filter(sql: &str) -> Option<&str> {
+    let tokens = spanned_tokens(sql);
+    let on = tokens.iter().position(|t| is_keyword(&t.token, "ON"))?;
+    let open = tokens
+        .iter()
+        .skip(on)
+        .position(|t| matches!(t.token, Token::Punct("(")))?
+        + on;
+    let close = closing_paren(&tokens, open)?;
+    let next = tokens.get(close + 1)?;
+    is_keyword(&next.token, "WHERE").then(|| &sql[next.start..])
+}
+
+// Entries in index order, which the server works out with the index's own collations
+async fn index_entries(
+    db: *mut SQLite3,
+    index: &str,
+    table: &str,
+    sql: &str,
+) -> Result<Vec<Vec<Value>>, SqliteError> {
+    let info = query(db, &format!("PRAGMA index_xinfo({})", quote(index)), vec![]).await?;
+    let info = std::mem::take(&mut *info.result_rows.lock().unwrap());
+
+    let mut select = Vec::new();
+    let mut order = Vec::new();
+    for row in info {
+        let expression = match integer(&row[1]) {
+            -1 => "rowid".to_string(),
+            -2 => return Err(unsupported("index on expressions", index)),
+            _ => quote(&text(&row[2])),
+        };
+        let direction = if integer(&row[3]) == 1 { " DESC" } else { "" };
+        order.push(format!(
+            "{} COLLATE {}{}",
+            expression,
+            quote(&text(&row[4])),
+            direction
+        ));
+        select.push(expression);
+    }
+
+    let filter = partial_filter(sql).unwrap_or_default();
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let sql = format!(
+            "SELECT {} FROM {} {} ORDER BY {} LIMIT {} OFFSET {}",
+            select.join(", "),
+            quote(table),
+            filter,
+            order.join(", "),
+            BATCH_SIZE,
+            offset
+        );
+        let batch = query(db, &sql, vec![]).await?;
+        let batch = std::mem::take(&mut *batch.result_rows.lock().unwrap());
+        let fetched = batch.len() as i64;
+        entries.extend(batch);
+
+        if fetched < BATCH_SIZE {
+            return Ok(entries);
+        }
+        offset += BATCH_SIZE;
+    }
+}
+
+/// Replaces the contents of the remote database with those of `data`.
+pub async fn deserialize(db: *mut SQLite3, data: &[u8]) -> Result<(), SqliteError> {
+    let image = Image::open(data)?;
+    let schema = image.schema()?;
+
+    if unsafe { &*db }.has_began_transaction() {
+        return Err(SqliteError::new(
+            "cannot deserialize within a transaction",
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    in_transaction(db, async {
+        for sql in drop_statements(db).await? {
+            query(db, &sql, vec![]).await?;
+        }
+
+        for entry in schema.iter().filter(|entry| entry.kind == "table") {
+            // Internal tables come with the tables that need them, statistics are left out
+            if entry.name.starts_with("sqlite_stat") {
+                continue;
+            }
+            let exists = query(
+                db,
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?",
+                vec![Value::Text(entry.name.clone())],
+            )
+            .await?;
+            let exists = !exists.result_rows.lock().unwrap().is_empty();
+
+            // Shadow tables of virtual tables and sqlite_sequence exist by now, their rows
+            // are replaced with the image's
+            match (&entry.sql, exists) {
+                (_, true) => {
+                    query(db, &format!("DELETE FROM {}", quote(&entry.name)), vec![]).await?;
+                }
+                (Some(sql), false) => {
+                    query(db, sql, vec![]).await?;
+                }
+                (None, false) => continue,
+            }
+
+            if entry.rootpage > 0 {
+                insert_rows(db, &entry.name, image.table_rows(entry.rootpage)?).await?;
+            }
+        }
+
+        for entry in schema.iter().filter(|entry| entry.kind != "table") {
+            if let Some(sql) = &entry.sql {
+                query(db, sql, vec![]).await?;
+            }
+        }
+
+        for (name, value) in [
+            ("user_version", image.user_version()),
+            ("application_id", image.application_id()),
+        ] {
+            query(db, &format!("PRAGMA {} = {}", name, value), vec![]).await?;
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+async fn insert_rows(
+    db: *mut SQLite3,
+    table: &str,
+    rows: Vec<(i64, Vec<Value>)>,
+) -> Result<(), SqliteError> {
+    // Record values line up with the stored columns, trailing ones may be missing
+    let columns: Vec<Column> = columns(db, table)
+        .await?
+        .into_iter()
+        .filter(|column| column.stored)
+        .collect();
+    let rowid_alias = columns.iter().any(|column| column.rowid_alias);
+
+    if let Some((_, values)) = rows.iter().find(|(_, values)| values.len() > columns.len()) {
+        return Err(SqliteError::new(
+            format!(
+                "table {} has {} columns but the image has {} values",
+                table,
+                columns.len(),
+                values.len()
+            ),
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    // Rows are inserted in batches of the same width, so one column list covers each
+    for group in rows.chunk_by(|a, b| a.1.len() == b.1.len()) {
+        let width = group[0].1.len();
+        let used: Vec<&Column> = columns[..width].iter().filter(|c| c.writable).collect();
+        let mut names: Vec<String> = used.iter().map(|column| quote(&column.name)).collect();
+        if !rowid_alias {
+            names.insert(0, "rowid".to_string());
+        }
+        let placeholders = format!("({})", vec!["?"; names.len()].join(", "));
+
+        for batch in group.chunks(INSERT_BATCH_SIZE) {
+            let mut params = Vec::with_capacity(batch.len() * names.len());
+            for (rowid, values) in batch {
+                if !rowid_alias {
+                    params.push(Value::Integer(*rowid));
+                }
+                for (value, column) in values.iter().zip(&columns) {
+                    if !column.writable {
+                        continue;
+                    }
+                    params.push(match (column.rowid_alias, value) {
+                        (true, Value::Null) => Value::Integer(*rowid),
+                        _ => value.clone(),
+                    });
+                }
+            }
+
+            let sql = format!(
+                "INSERT INTO {} ({}) VALUES {}",
+                quote(table),
+                names.join(", "),
+                vec![placeholders.as_str(); batch.len()].join(", ")
+            );
+            query(db, &sql, params).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Connection-scoped state kept on the server's streams. Outside a transaction every
+//! statement runs on a stream of its own, and temporary objects and some pragma settings
+//! only last as long as the stream they were made on. The statements that made them are
+//! recorded here and replayed at the start of each new stream, so they hold for the
+//! whole connection.
+
+use crate::sqlite::{SqliteError, SQLITE_FULL};
+
+/// Pragmas whose assignments only last for the stream they run on.
+pub const SESSION_PRAGMAS: [&str; 6] = [
+    "case_sensitive_like",
+    "foreign_keys",
+    "ignore_check_constraints",
+    "legacy_alter_table",
+    "recursive_triggers",
+    "reverse_unordered_selects",
+];
+
+/// Most statements a session replays, as each of them is sent with every new stream.
+pub const MAX_SESSION_STATEMENTS: usize = 64;
+
+/// What a statement changes in the session once the server has accepted it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionChange {
+    Pragma(String), // Replaces the earlier assignment to the same pragma
+    CreateTemp { name: String, table: String },
+    Drop(String), // Forgets the temporary object and the indexes and triggers on it
+}
+
+#[derive(Debug)]
+enum Key {
+    Pragma(String),
+    Temp { name: String, table: String },
+}
+
+/// The statements replayed on new streams, toggled by `PRAGMA turso.session_replay`.
+pub struct Session {
+    pub enabled: bool,
+    entries: Vec<(Key, String)>, // In the order they first ran
+}
+
+impl Session {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The statements to run at the start of a new stream.
+    pub fn statements(&self) -> Vec<String> {
+        self.entries.iter().map(|(_, sql)| sql.clone()).collect()
+    }
+
+    /// Fails when recording `change` would take the session past `MAX_SESSION_STATEMENTS`,
+    /// before the statement is sent.
+    pub fn check(&self, change: &SessionChange) -> Result<(), SqliteError> {
+        let adds = match change {
+            SessionChange::Pragma(name) => self.position_of_pragma(name).is_none(),
+            SessionChange::CreateTemp { name, .. } => self.position_of_temp(name).is_none(),
+            SessionChange::Drop(_) => false,
+        };
+        if self.enabled && adds && self.entries.len() >= MAX_SESSION_STATEMENTS {
+            return Err(SqliteError::new(
+                format!(
+                    "Too many temporary objects and session pragmas to replay (at most {}), \
+                     drop some or turn off turso.session_replay",
+                    MAX_SESSION_STATEMENTS
+                ),
+                Some(SQLITE_FULL),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records `change`, made by `sql`.
+    pub fn apply(&mut self, change: &SessionChange, sql: &str) {
+        if !self.enabled {
+            return;
+        }
+        let sql = sql.trim().trim_end_matches(';').trim_end().to_string();
+
+        match change {
+            SessionChange::Pragma(name) => match self.position_of_pragma(name) {
+                Some(i) => self.entries[i].1 = sql,
+                None => self.entries.push((Key::Pragma(name.clone()), sql)),
+            },
+            // Created before with IF NOT EXISTS, or the server would have refused it
+            SessionChange::CreateTemp { name, .. } if self.position_of_temp(name).is_some() => {}
+            SessionChange::CreateTemp { name, table } => self.entries.push((
+                Key::Temp {
+                    name: name.clone(),
+                    table: table.clone(),
+                },
+                sql,
+            )),
+            SessionChange::Drop(dropped) => self.entries.retain(|(key, _)| match key {
+                Key::Temp { name, table } => name != dropped && table != dropped,
+                Key::Pragma(_) => true,
+            }),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.entries.clear();
+        }
+    }
+
+    fn position_of_pragma(&self, pragma: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(key, _)| matches!(key, Key::Pragma(name) if name == pragma))
+    }
+
+    fn position_of_temp(&self, object: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(key, _)| matches!(key, Key::Temp { name, .. } if name == object))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CachedStatement, StatementKind};
+
+    fn change(sql: &str) -> SessionChange {
+        match CachedStatement::parse(sql).kind {
+            StatementKind::Session(change) => change,
+            kind => panic!("{} is {:?}", sql, kind),
+        }
+    }
+
+    fn record(session: &mut Session, sql: &str) {
+        session.apply(&change(sql), sql);
+    }
+
+    #[test]
+    fn pragmas_replace_their_earlier_assignment() {
+        let mut session = Session::new(true);
+        record(&mut session, "PRAGMA foreign_keys = ON;");
+        record(&mut session, "PRAGMA recursive_triggers(1)");
+        record(&mut session, "pragma main.FOREIGN_KEYS = off");
+
+        assert_eq!(
+            session.statements(),
+            [
+                "pragma main.FOREIGN_KEYS = off",
+                "PRAGMA recursive_triggers(1)"
+            ]
+        );
+    }
+
+    #[test]
+    fn dropping_a_temp_table_forgets_what_is_on_it() {
+        let mut session = Session::new(true);
+        record(&mut session, "CREATE TEMP TABLE t (x)");
+        record(&mut session, "CREATE TEMPORARY TABLE IF NOT EXISTS t (x)");
+        record(&mut session, "CREATE INDEX temp.t_x ON t (x)");
+        record(
+            &mut session,
+            "CREATE TEMP TRIGGER t_ai AFTER INSERT ON \"T\" BEGIN SELECT 1; END",
+        );
+        record(&mut session, "CREATE TEMP VIEW v AS SELECT 1");
+        assert_eq!(session.statements().len(), 4);
+
+        record(&mut session, "DROP TABLE IF EXISTS temp.t");
+        assert_eq!(session.statements(), ["CREATE TEMP VIEW v AS SELECT 1"]);
+    }
+
+    #[test]
+    fn only_temporary_objects_change_the_session() {
+        for sql in [
+            "CREATE TABLE t (x)",
+            "CREATE INDEX i ON t (x)",
+            "CREATE TABLE main.t (x)",
+            "CREATE VIRTUAL TABLE t USING fts5(x)",
+            "DROP TABLE main.t",
+            "PRAGMA foreign_keys",
+            "PRAGMA journal_mode = WAL",
+        ] {
+            assert_eq!(
+                CachedStatement::parse(sql).kind,
+                StatementKind::Remote,
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn sessions_are_capped() {
+        let mut session = Session::new(true);
+        for i in 0..MAX_SESSION_STATEMENTS {
+            record(&mut session, &format!("CREATE TEMP TABLE t{} (x)", i));
+        }
+
+        assert!(session
+            .check(&change("CREATE TEMP TABLE more (x)"))
+            .is_err());
+        assert!(session.check(&change("PRAGMA foreign_keys = ON")).is_err());
+        assert!(session.check(&change("CREATE TEMP TABLE t0 (x)")).is_ok());
+        assert!(session.check(&change("DROP TABLE t0")).is_ok());
+
+        session.set_enabled(false);
+        assert!(session.check(&change("CREATE TEMP TABLE more (x)")).is_ok());
+        assert!(session.statements().is_empty());
+    }
+}
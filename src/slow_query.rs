@@ -0,0 +1,140 @@
+//! Statements whose remote round trip took longer than a threshold, logged as warnings and
+//! handed to the hook registered with `sqlite3_turso_slow_query_hook`.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    time::Duration,
+};
+
+use crate::{
+    logging,
+    sql::tokenizer::{Token, Tokenizer},
+};
+
+pub const SLOW_MS_ENV: &str = "TURSO_SLOW_MS";
+
+pub type SlowQueryHook = extern "C" fn(
+    user_data: *mut c_void, // User-provided data
+    sql: *const c_char,     // Statement with its literals replaced by `?`
+    duration_ms: f64,       // Round trip to the server
+    rows_read: i64,         // -1 when the server did not report it
+    rows_written: i64,      // -1 when the server did not report it
+    retries: c_int,         // HTTP requests sent again along the way
+);
+
+#[derive(Clone, Copy)]
+struct RegisteredHook {
+    callback: SlowQueryHook,
+    user_data: *mut c_void,
+}
+
+// The hook is only ever invoked with the pointer the caller handed us
+unsafe impl Send for RegisteredHook {}
+
+/// What a slow statement cost, as reported to the log and the hook.
+pub struct SlowQuery<'a> {
+    pub sql: &'a str,
+    pub duration: Duration,
+    pub rows_read: Option<u64>,
+    pub rows_written: Option<u64>,
+    pub retries: u64,
+}
+
+pub struct SlowQueryLog {
+    pub threshold: Option<Duration>, // None logs nothing
+    hook: Option<RegisteredHook>,
+}
+
+impl SlowQueryLog {
+    pub fn new(threshold: Option<Duration>) -> Self {
+        Self {
+            threshold,
+            hook: None,
+        }
+    }
+
+    pub fn set_hook(&mut self, callback: Option<SlowQueryHook>, user_data: *mut c_void) {
+        self.hook = callback.map(|callback| RegisteredHook {
+            callback,
+            user_data,
+        });
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        self.threshold.is_some_and(|threshold| duration > threshold)
+    }
+}
+
+/// Logs `query` and hands it to `log`'s hook. The hook runs without the log locked, so it
+/// may register another one.
+pub fn report(log: &std::sync::Mutex<SlowQueryLog>, query: SlowQuery) {
+    let hook = {
+        let log = log.lock().unwrap();
+        if !log.is_slow(query.duration) {
+            return;
+        }
+        log.hook
+    };
+
+    let sql = redact(query.sql);
+    let duration_ms = query.duration.as_secs_f64() * 1000.0;
+    tracing::warn!(
+        sql = %logging::loggable_sql(query.sql),
+        duration_ms,
+        rows_read = ?query.rows_read,
+        rows_written = ?query.rows_written,
+        retries = query.retries,
+        "Slow query"
+    );
+
+    let Some(hook) = hook else {
+        return;
+    };
+    let Ok(sql) = CString::new(sql) else {
+        return;
+    };
+    let count = |rows: Option<u64>| rows.map_or(-1, |rows| rows as i64);
+    (hook.callback)(
+        hook.user_data,
+        sql.as_ptr(),
+        duration_ms,
+        count(query.rows_read),
+        count(query.rows_written),
+        query.retries.min(c_int::MAX as u64) as c_int,
+    );
+}
+
+/// The statement with its string, blob and numeric literals replaced by `?`, so values
+/// written into the SQL text stay out of the log just like bound parameters.
+pub fn redact(sql: &str) -> String {
+    let mut redacted = String::with_capacity(sql.len());
+    let mut tokens = Tokenizer::new(sql);
+    loop {
+        let start = tokens.offset();
+        let Some(token) = tokens.next() else {
+            break;
+        };
+        match token {
+            Token::Literal(_) | Token::Number(_) => redacted.push('?'),
+            _ => redacted.push_str(&sql[start..tokens.offset()]),
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_are_redacted() {
+        assert_eq!(
+            redact("SELECT * FROM users WHERE email = 'a@b.c' AND age > 30 AND key = x'00ff' -- 'note'"),
+            "SELECT * FROM users WHERE email = ? AND age > ? AND key = ? -- 'note'"
+        );
+        assert_eq!(
+            redact("UPDATE \"t\" SET a = ?1, b = :b"),
+            "UPDATE \"t\" SET a = ?1, b = :b"
+        );
+    }
+}
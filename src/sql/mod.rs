@@ -0,0 +1,2 @@
+pub mod select;
+pub mod tokenizer;
@@ -0,0 +1,166 @@
+//! Token-level view of a SELECT, enough to rewrite its result columns and trailing clauses
+//! for work done on the client.
+
+use super::tokenizer::{is_dml, Token, Tokenizer};
+
+/// A token other than whitespace or a comment, with its byte span in the statement.
+pub struct Spanned<'a> {
+    pub token: Token<'a>,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub fn spanned_tokens(sql: &str) -> Vec<Spanned<'_>> {
+    let mut tokens = Vec::new();
+    let mut tokenizer = Tokenizer::new(sql);
+    loop {
+        let start = tokenizer.offset();
+        let Some(token) = tokenizer.next() else {
+            break;
+        };
+        if !matches!(token, Token::Whitespace | Token::Comment) {
+            tokens.push(Spanned {
+                token,
+                start,
+                end: tokenizer.offset(),
+            });
+        }
+    }
+    tokens
+}
+
+pub fn is_keyword(token: &Token, keyword: &str) -> bool {
+    matches!(token, Token::Identifier(w) if w.eq_ignore_ascii_case(keyword))
+}
+
+/// Index of the parenthesis closing the one at `open`.
+pub fn closing_paren(tokens: &[Spanned], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, spanned) in tokens.iter().enumerate().skip(open) {
+        match spanned.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Splits `tokens[start..end]` on commas outside parentheses.
+pub fn split_commas(tokens: &[Spanned], start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut part_start = start;
+    for (i, spanned) in tokens.iter().enumerate().take(end).skip(start) {
+        match spanned.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth -= 1,
+            Token::Punct(",") if depth == 0 => {
+                parts.push((part_start, i));
+                part_start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if part_start < end {
+        parts.push((part_start, end));
+    }
+    parts
+}
+
+/// Where the result columns of a simple SELECT sit among its tokens.
+pub struct Projection {
+    pub begin: usize, // First token after SELECT [ALL | DISTINCT]
+    pub end: usize,   // First token past the result columns
+    pub distinct: bool,
+}
+
+/// The projection of `tokens`, `None` unless they form a SELECT.
+pub fn projection(tokens: &[Spanned]) -> Option<Projection> {
+    if !is_keyword(&tokens.first()?.token, "SELECT") {
+        return None;
+    }
+
+    let mut begin = 1;
+    let mut distinct = false;
+    if let Some(spanned) = tokens.get(begin) {
+        distinct = is_keyword(&spanned.token, "DISTINCT");
+        if distinct || is_keyword(&spanned.token, "ALL") {
+            begin += 1;
+        }
+    }
+
+    Some(Projection {
+        begin,
+        end: clause_end(tokens, begin, &[]),
+        distinct,
+    })
+}
+
+const CLAUSES: [&str; 10] = [
+    "FROM",
+    "WHERE",
+    "GROUP",
+    "HAVING",
+    "WINDOW",
+    "ORDER",
+    "LIMIT",
+    "UNION",
+    "INTERSECT",
+    "EXCEPT",
+];
+
+/// First token from `start` that opens a clause outside parentheses or ends the statement,
+/// ignoring the keywords in `within`.
+pub fn clause_end(tokens: &[Spanned], start: usize, within: &[&str]) -> usize {
+    let mut depth = 0;
+    for (i, spanned) in tokens.iter().enumerate().skip(start) {
+        match spanned.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth -= 1,
+            Token::Punct(";") if depth == 0 => return i,
+            Token::Identifier(word) if depth == 0 => {
+                let opens = |k: &&str| word.eq_ignore_ascii_case(k);
+                if CLAUSES.iter().any(opens) && !within.iter().any(opens) {
+                    return i;
+                }
+            }
+            _ => (),
+        }
+    }
+    tokens.len()
+}
+
+/// `sql` with `LIMIT limit` appended, when a LIMIT at its end caps the rows it returns: a
+/// SELECT or VALUES, possibly behind WITH, that has no LIMIT of its own.
+pub fn with_limit(sql: &str, limit: u64) -> Option<String> {
+    let tokens = spanned_tokens(sql);
+    let first = &tokens.first()?.token;
+    let query = is_keyword(first, "SELECT")
+        || is_keyword(first, "VALUES")
+        || (is_keyword(first, "WITH") && !is_dml(sql));
+    if !query {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut end = 0;
+    for spanned in &tokens {
+        match spanned.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth -= 1,
+            Token::Punct(";") if depth == 0 => break,
+            Token::Identifier(word) if depth == 0 && word.eq_ignore_ascii_case("LIMIT") => {
+                return None;
+            }
+            _ => (),
+        }
+        end = spanned.end;
+    }
+    Some(format!("{} LIMIT {}{}", &sql[..end], limit, &sql[end..]))
+}
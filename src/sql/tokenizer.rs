@@ -0,0 +1,667 @@
+use std::{collections::HashMap, ffi::c_int};
+
+use crate::keywords::is_keyword;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token<'a> {
+    Whitespace,
+    Comment,
+    Literal(&'a str),    // String or blob literal, quotes included
+    Identifier(&'a str), // Bare or quoted identifier, keywords included
+    Number(&'a str),
+    Parameter(Parameter<'a>),
+    Punct(&'a str), // Operators and punctuation, `::` as a single token
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parameter<'a> {
+    Anonymous,       // ?
+    Numbered(c_int), // ?NNN
+    Named(&'a str),  // :name, @name or $name, prefix included
+}
+
+/// Splits SQL into tokens the way SQLite's lexer does, so that `?` or `:x` inside string
+/// literals, quoted identifiers and comments are never taken for parameters.
+pub struct Tokenizer<'a> {
+    sql: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(sql: &'a str) -> Self {
+        Self { sql, pos: 0 }
+    }
+
+    /// Byte offset of the next token.
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.sql[self.pos..]
+    }
+
+    fn take(&mut self, len: usize) -> &'a str {
+        let token = &self.sql[self.pos..self.pos + len];
+        self.pos += len;
+        token
+    }
+
+    // Length up to and including `close`, or the rest of the input when unterminated.
+    // A doubled closing character is an escape and does not end the token.
+    fn quoted_len(&self, close: char) -> usize {
+        let rest = self.rest();
+        let mut chars = rest.char_indices().skip(1).peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == close {
+                if close != ']' && chars.peek().map(|(_, next)| *next) == Some(close) {
+                    chars.next();
+                    continue;
+                }
+                return i + c.len_utf8();
+            }
+        }
+        rest.len()
+    }
+
+    fn word_len(&self, skip: usize) -> usize {
+        self.rest()[skip..]
+            .char_indices()
+            .find(|(_, c)| !(c.is_alphanumeric() || *c == '_' || *c == '$' || !c.is_ascii()))
+            .map_or(self.rest().len(), |(i, _)| skip + i)
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest();
+        let mut chars = rest.chars();
+        let c = chars.next()?;
+        let next = chars.next();
+
+        let token = match c {
+            c if c.is_whitespace() => {
+                let len = rest
+                    .char_indices()
+                    .find(|(_, c)| !c.is_whitespace())
+                    .map_or(rest.len(), |(i, _)| i);
+                self.take(len);
+                Token::Whitespace
+            }
+            '-' if next == Some('-') => {
+                let len = rest.find('\n').map_or(rest.len(), |i| i + 1);
+                self.take(len);
+                Token::Comment
+            }
+            '/' if next == Some('*') => {
+                let len = rest[2..].find("*/").map_or(rest.len(), |i| i + 4);
+                self.take(len);
+                Token::Comment
+            }
+            '\'' => Token::Literal(self.take(self.quoted_len('\''))),
+            'x' | 'X' if next == Some('\'') => {
+                self.pos += 1;
+                let len = self.quoted_len('\'');
+                self.pos -= 1;
+                Token::Literal(self.take(len + 1))
+            }
+            '"' => Token::Identifier(self.take(self.quoted_len('"'))),
+            '`' => Token::Identifier(self.take(self.quoted_len('`'))),
+            '[' => Token::Identifier(self.take(self.quoted_len(']'))),
+            '?' => {
+                let digits = rest[1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - 1);
+                let token = self.take(1 + digits);
+                match token[1..].parse::<c_int>() {
+                    Ok(index) => Token::Parameter(Parameter::Numbered(index)),
+                    Err(_) => Token::Parameter(Parameter::Anonymous),
+                }
+            }
+            ':' if next == Some(':') => Token::Punct(self.take(2)),
+            ':' | '@' | '$'
+                if next.is_some_and(|n| n.is_alphanumeric() || n == '_' || !n.is_ascii()) =>
+            {
+                let len = self.word_len(1);
+                Token::Parameter(Parameter::Named(self.take(len)))
+            }
+            c if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let len = rest
+                    .char_indices()
+                    .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '.'))
+                    .map_or(rest.len(), |(i, _)| i);
+                Token::Number(self.take(len))
+            }
+            c if c.is_alphabetic() || c == '_' || !c.is_ascii() => {
+                let len = self.word_len(0);
+                Token::Identifier(self.take(len))
+            }
+            c => Token::Punct(self.take(c.len_utf8())),
+        };
+
+        Some(token)
+    }
+}
+
+/// Highest `?NNN` SQLite accepts, its default `SQLITE_MAX_VARIABLE_NUMBER`.
+pub const MAX_VARIABLE_NUMBER: c_int = 32766;
+
+/// Name of each parameter index in the statement, as `sqlite3_bind_parameter_name` reports
+/// it, `None` for `?`. The length is the highest index, as `sqlite3_bind_parameter_count`
+/// reports it. `?` takes the next free index, `?NNN` sets it explicitly and a repeated name
+/// reuses the index it got the first time.
+pub fn parameter_names(sql: &str) -> Vec<Option<String>> {
+    let mut names: Vec<Option<String>> = Vec::new();
+    let mut named: HashMap<&str, usize> = HashMap::new();
+
+    for token in Tokenizer::new(sql) {
+        let Token::Parameter(parameter) = token else {
+            continue;
+        };
+
+        match parameter {
+            Parameter::Anonymous => names.push(None),
+            // SQLite refuses the statement outright, the server will say so
+            Parameter::Numbered(index) if !(1..=MAX_VARIABLE_NUMBER).contains(&index) => {}
+            Parameter::Numbered(index) => {
+                let index = index as usize;
+                if names.len() < index {
+                    names.resize(index, None);
+                }
+                names[index - 1].get_or_insert_with(|| format!("?{}", index));
+            }
+            Parameter::Named(name) => {
+                if !named.contains_key(name) {
+                    names.push(Some(name.to_string()));
+                    named.insert(name, names.len());
+                }
+            }
+        }
+    }
+
+    names
+}
+
+/// What a statement means for the shim's own transaction and pragma handling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementClass {
+    Begin,
+    Commit,   // COMMIT or END
+    Rollback, // ROLLBACK of the whole transaction
+    Savepoint(String),
+    Release(String),                             // RELEASE [SAVEPOINT] name
+    RollbackTo(String),                          // ROLLBACK [TRANSACTION] TO [SAVEPOINT] name
+    Attach { database: String, schema: String }, // ATTACH [DATABASE] 'name' AS schema
+    Detach(String),                              // DETACH [DATABASE] schema
+    Pragma { name: String, assigns: bool },      // PRAGMA [schema.]name [= value], lowercased
+    CreateTemp { name: String, table: String },  // Object in the temp schema and the table it is on
+    Drop(String),                                // DROP object [IF EXISTS] [temp.]name
+    Other,
+}
+
+/// Classifies a statement by its leading keywords, ignoring case, whitespace and comments.
+/// Savepoint names are returned unquoted and lowercased, as SQLite compares them.
+pub fn classify(sql: &str) -> StatementClass {
+    let words: Vec<&str> = Tokenizer::new(sql)
+        .filter(|token| !matches!(token, Token::Whitespace | Token::Comment))
+        .map_while(|token| match token {
+            Token::Identifier(word) => Some(word),
+            _ => None,
+        })
+        .take(5)
+        .collect();
+    let keyword = |i: usize| words.get(i).map(|w| w.to_ascii_uppercase());
+    let name = |i: usize| words.get(i).map(|w| unquote(w)).unwrap_or_default();
+
+    let Some(first) = keyword(0) else {
+        return StatementClass::Other;
+    };
+
+    match first.as_str() {
+        "BEGIN" => StatementClass::Begin,
+        "COMMIT" | "END" => StatementClass::Commit,
+        "SAVEPOINT" => StatementClass::Savepoint(name(1)),
+        "RELEASE" => match keyword(1).as_deref() {
+            Some("SAVEPOINT") => StatementClass::Release(name(2)),
+            _ => StatementClass::Release(name(1)),
+        },
+        "ROLLBACK" => {
+            let mut i = 1;
+            if keyword(i).as_deref() == Some("TRANSACTION") {
+                i += 1;
+            }
+            if keyword(i).as_deref() != Some("TO") {
+                return StatementClass::Rollback;
+            }
+            i += 1;
+            if keyword(i).as_deref() == Some("SAVEPOINT") {
+                i += 1;
+            }
+            StatementClass::RollbackTo(name(i))
+        }
+        "PRAGMA" => {
+            let (name, assigns) = parse_pragma(sql).unwrap_or_default();
+            StatementClass::Pragma { name, assigns }
+        }
+        "ATTACH" | "DETACH" => classify_attach(sql).unwrap_or(StatementClass::Other),
+        "CREATE" | "DROP" => classify_schema(sql).unwrap_or(StatementClass::Other),
+        _ => StatementClass::Other,
+    }
+}
+
+// The pragma's name and whether a value follows it, `= value` or `(value)`
+fn parse_pragma(sql: &str) -> Option<(String, bool)> {
+    let mut tokens = Tokenizer::new(sql)
+        .filter(|token| !matches!(token, Token::Whitespace | Token::Comment))
+        .skip(1);
+    let Some(Token::Identifier(first)) = tokens.next() else {
+        return None;
+    };
+    let (name, next) = match tokens.next() {
+        Some(Token::Punct(".")) => match tokens.next() {
+            Some(Token::Identifier(name)) => (name, tokens.next()),
+            _ => return None,
+        },
+        next => (first, next),
+    };
+    let assigns = matches!(next, Some(Token::Punct("=")) | Some(Token::Punct("(")));
+    Some((unquote(name), assigns))
+}
+
+// Only temporary objects and drops that may concern one are classified, everything else
+// in the main or an attached schema is `Other`
+fn classify_schema(sql: &str) -> Option<StatementClass> {
+    let mut tokens = Tokenizer::new(sql)
+        .filter(|token| !matches!(token, Token::Whitespace | Token::Comment))
+        .peekable();
+    let is_keyword = |token: Option<&Token>, keyword: &str| matches!(token, Some(Token::Identifier(w)) if w.eq_ignore_ascii_case(keyword));
+
+    let Some(Token::Identifier(verb)) = tokens.next() else {
+        return None;
+    };
+    let mut temp = false;
+    let object = loop {
+        let Some(Token::Identifier(word)) = tokens.next() else {
+            return None;
+        };
+        match word.to_ascii_uppercase().as_str() {
+            "TEMP" | "TEMPORARY" => temp = true,
+            "UNIQUE" => (),
+            object @ ("TABLE" | "VIEW" | "INDEX" | "TRIGGER") => break object.to_string(),
+            _ => return None,
+        }
+    };
+    for keyword in ["IF", "NOT", "EXISTS"] {
+        if is_keyword(tokens.peek(), keyword) {
+            tokens.next();
+        }
+    }
+
+    let Some(Token::Identifier(mut name)) = tokens.next() else {
+        return None;
+    };
+    if tokens.peek() == Some(&Token::Punct(".")) {
+        tokens.next();
+        if unquote(name) != "temp" {
+            return None;
+        }
+        temp = true;
+        let Some(Token::Identifier(object_name)) = tokens.next() else {
+            return None;
+        };
+        name = object_name;
+    }
+
+    if verb.eq_ignore_ascii_case("DROP") {
+        return Some(StatementClass::Drop(unquote(name)));
+    }
+    if !temp {
+        return None;
+    }
+
+    // Indexes and triggers name their table after ON, possibly schema-qualified
+    let table = match object.as_str() {
+        "INDEX" | "TRIGGER" => {
+            tokens.find(|token| is_keyword(Some(token), "ON"))?;
+            let Some(Token::Identifier(mut table)) = tokens.next() else {
+                return None;
+            };
+            if tokens.next() == Some(Token::Punct(".")) {
+                let Some(Token::Identifier(qualified)) = tokens.next() else {
+                    return None;
+                };
+                table = qualified;
+            }
+            unquote(table)
+        }
+        _ => unquote(name),
+    };
+
+    Some(StatementClass::CreateTemp {
+        name: unquote(name),
+        table,
+    })
+}
+
+// The attached database is usually a string literal, which `classify` stops at
+fn classify_attach(sql: &str) -> Option<StatementClass> {
+    let mut tokens = Tokenizer::new(sql)
+        .filter(|token| !matches!(token, Token::Whitespace | Token::Comment))
+        .peekable();
+    let is_keyword = |token: Option<&Token>, keyword: &str| matches!(token, Some(Token::Identifier(w)) if w.eq_ignore_ascii_case(keyword));
+
+    let Some(Token::Identifier(verb)) = tokens.next() else {
+        return None;
+    };
+    if is_keyword(tokens.peek(), "DATABASE") {
+        tokens.next();
+    }
+
+    if verb.eq_ignore_ascii_case("DETACH") {
+        return match tokens.next() {
+            Some(Token::Identifier(schema)) => Some(StatementClass::Detach(unquote(schema))),
+            _ => None,
+        };
+    }
+
+    let database = match tokens.next()? {
+        Token::Literal(literal) if literal.starts_with('\'') => literal
+            .trim_start_matches('\'')
+            .trim_end_matches('\'')
+            .replace("''", "'"),
+        Token::Identifier(name) => name
+            .trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'))
+            .to_string(),
+        _ => return None,
+    };
+    if !is_keyword(tokens.next().as_ref(), "AS") {
+        return None;
+    }
+    let Some(Token::Identifier(schema)) = tokens.next() else {
+        return None;
+    };
+
+    Some(StatementClass::Attach {
+        database,
+        schema: unquote(schema),
+    })
+}
+
+/// The statement as `sqlite3_normalized_sql` reports it, so queries differing only in their
+/// values group together: literals and parameters become `?`, keywords are upper-cased,
+/// comments dropped and whitespace collapsed to single spaces. Identifiers are kept as written.
+pub fn normalize(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut spaced = false;
+    for token in Tokenizer::new(sql) {
+        let keyword;
+        let text = match token {
+            Token::Whitespace | Token::Comment => {
+                spaced = true;
+                continue;
+            }
+            Token::Literal(_) | Token::Number(_) | Token::Parameter(_) => "?",
+            Token::Identifier(word) if is_keyword(word.as_bytes()) => {
+                keyword = word.to_ascii_uppercase();
+                &keyword
+            }
+            Token::Identifier(text) | Token::Punct(text) => text,
+        };
+        if spaced && !normalized.is_empty() {
+            normalized.push(' ');
+        }
+        spaced = false;
+        normalized.push_str(text);
+    }
+    normalized
+}
+
+pub fn unquote(word: &str) -> String {
+    let inner = match word.chars().next() {
+        Some('"') | Some('`') | Some('[') => &word[1..word.len().saturating_sub(1).max(1)],
+        _ => word,
+    };
+    inner.to_lowercase()
+}
+
+/// Whether a statement produces a result set: queries, `VALUES`, `EXPLAIN`, `PRAGMA` and
+/// DML with a `RETURNING` clause. A leading `WITH` is looked past to the statement it fronts.
+pub fn returns_rows(sql: &str) -> bool {
+    let mut depth = 0usize;
+    let mut first = true;
+    let mut in_with = false;
+    let mut dml = false;
+
+    for token in Tokenizer::new(sql) {
+        match token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth = depth.saturating_sub(1),
+            Token::Punct(";") if depth == 0 => break,
+            Token::Identifier(word) if depth == 0 => {
+                let keyword = word.to_ascii_uppercase();
+                let leading = first || in_with;
+                first = false;
+
+                match keyword.as_str() {
+                    "WITH" if leading => in_with = true,
+                    "SELECT" | "VALUES" | "EXPLAIN" | "PRAGMA" if leading => return true,
+                    "INSERT" | "REPLACE" | "UPDATE" | "DELETE" if leading => {
+                        in_with = false;
+                        dml = true;
+                    }
+                    "RETURNING" if dml => return true,
+                    // CTE names, AS and RECURSIVE sit between WITH and the main statement
+                    _ if in_with => (),
+                    _ if !dml => return false,
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    false
+}
+
+/// Whether a statement is an `INSERT` or `REPLACE`, looking past a leading `WITH`.
+/// Whether the statement is an INSERT, REPLACE, UPDATE or DELETE, after any WITH clause.
+pub fn is_dml(sql: &str) -> bool {
+    let mut depth = 0usize;
+    let mut first = true;
+    let mut in_with = false;
+
+    for token in Tokenizer::new(sql) {
+        match token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth = depth.saturating_sub(1),
+            Token::Punct(";") if depth == 0 => break,
+            Token::Identifier(word) if depth == 0 => {
+                let keyword = word.to_ascii_uppercase();
+                let leading = first || in_with;
+                first = false;
+
+                match keyword.as_str() {
+                    "WITH" if leading => in_with = true,
+                    "INSERT" | "REPLACE" | "UPDATE" | "DELETE" if leading => return true,
+                    "SELECT" | "VALUES" if leading => return false,
+                    // CTE names, AS and RECURSIVE sit between WITH and the main statement
+                    _ if in_with => (),
+                    _ => return false,
+                }
+            }
+            _ => (),
+        }
+    }
+
+    false
+}
+
+pub fn is_insert(sql: &str) -> bool {
+    let mut depth = 0usize;
+    let mut first = true;
+    let mut in_with = false;
+
+    for token in Tokenizer::new(sql) {
+        match token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth = depth.saturating_sub(1),
+            Token::Punct(";") if depth == 0 => break,
+            Token::Identifier(word) if depth == 0 => {
+                let keyword = word.to_ascii_uppercase();
+                let leading = first || in_with;
+                first = false;
+
+                match keyword.as_str() {
+                    "WITH" if leading => in_with = true,
+                    "INSERT" | "REPLACE" if leading => return true,
+                    "SELECT" | "VALUES" | "UPDATE" | "DELETE" if leading => return false,
+                    // CTE names, AS and RECURSIVE sit between WITH and the main statement
+                    _ if in_with => (),
+                    _ => return false,
+                }
+            }
+            _ => (),
+        }
+    }
+
+    false
+}
+
+/// Whether a statement may change the schema: `CREATE`, `DROP`, `ALTER` and `VACUUM`,
+/// which can move tables to other root pages.
+pub fn changes_schema(sql: &str) -> bool {
+    Tokenizer::new(sql)
+        .find(|token| !matches!(token, Token::Whitespace | Token::Comment))
+        .is_some_and(|token| match token {
+            Token::Identifier(word) => ["CREATE", "DROP", "ALTER", "VACUUM"]
+                .iter()
+                .any(|keyword| word.eq_ignore_ascii_case(keyword)),
+            _ => false,
+        })
+}
+
+/// Byte length of the first statement in `sql`, up to and including the `;` that ends it, or
+/// all of `sql` when none does. As in `sqlite3_complete`, the `;` inside a trigger's body
+/// only ends it when it follows `END`, itself right after a `;`.
+pub fn statement_end(sql: &str) -> usize {
+    let words: Vec<&str> = Tokenizer::new(sql)
+        .filter(|token| !matches!(token, Token::Whitespace | Token::Comment))
+        .map_while(|token| match token {
+            Token::Identifier(word) => Some(word),
+            _ => None,
+        })
+        .take(3)
+        .collect();
+    let keyword = |i: usize, keyword: &str| {
+        words
+            .get(i)
+            .is_some_and(|w| w.eq_ignore_ascii_case(keyword))
+    };
+    let trigger = keyword(0, "CREATE")
+        && (keyword(1, "TRIGGER")
+            || ((keyword(1, "TEMP") || keyword(1, "TEMPORARY")) && keyword(2, "TRIGGER")));
+
+    // Within a trigger: after a `;`, and after `END` following one
+    let (mut semicolon, mut end) = (false, false);
+    let mut tokens = Tokenizer::new(sql);
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Whitespace | Token::Comment => continue,
+            Token::Punct(";") if !trigger || end => return tokens.offset(),
+            Token::Punct(";") => (semicolon, end) = (true, false),
+            Token::Identifier(word) if semicolon && word.eq_ignore_ascii_case("END") => {
+                (semicolon, end) = (false, true)
+            }
+            _ => (semicolon, end) = (false, false),
+        }
+    }
+    sql.len()
+}
+
+/// Whether `sql` holds no statement at all, only whitespace, comments and `;`, which SQLite
+/// prepares to a NULL statement.
+pub fn is_empty_statement(sql: &str) -> bool {
+    Tokenizer::new(sql).all(|token| {
+        matches!(
+            token,
+            Token::Whitespace | Token::Comment | Token::Punct(";")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(sql: &str) -> Vec<Token<'_>> {
+        Tokenizer::new(sql)
+            .filter(|token| !matches!(token, Token::Whitespace))
+            .collect()
+    }
+
+    #[test]
+    fn parameters_are_not_read_inside_literals_identifiers_or_comments() {
+        assert_eq!(
+            tokens("SELECT 'a?b'"),
+            [Token::Identifier("SELECT"), Token::Literal("'a?b'")]
+        );
+        assert_eq!(
+            tokens(r#"SELECT "quoted""ident?""#),
+            [
+                Token::Identifier("SELECT"),
+                Token::Identifier(r#""quoted""ident?""#)
+            ]
+        );
+        assert!(parameter_names("SELECT 'a?b', \"c:d\"").is_empty());
+
+        let sql = "SELECT 1 -- ? :a\n, /* ? @b */ ?";
+        assert_eq!(
+            tokens(sql)
+                .into_iter()
+                .filter(|t| *t == Token::Comment)
+                .count(),
+            2
+        );
+        assert_eq!(parameter_names(sql), [None]);
+    }
+
+    #[test]
+    fn double_colon_is_a_cast_not_a_parameter() {
+        assert_eq!(
+            tokens("SELECT x::int"),
+            [
+                Token::Identifier("SELECT"),
+                Token::Identifier("x"),
+                Token::Punct("::"),
+                Token::Identifier("int"),
+            ]
+        );
+        assert!(parameter_names("SELECT x::int").is_empty());
+    }
+
+    #[test]
+    fn parameter_indexes_follow_sqlite() {
+        // A repeated ?NNN reuses its index, and the count is the highest one
+        assert_eq!(
+            parameter_names("SELECT ?1, ?1, ?2"),
+            [Some("?1".to_string()), Some("?2".to_string())]
+        );
+        // Names and ? take the next index past the highest ?NNN so far
+        assert_eq!(
+            parameter_names("SELECT ?2, :a, ?, :a"),
+            [None, Some("?2".to_string()), Some(":a".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn statements_are_classified_past_comments() {
+        assert_eq!(classify("/* c */ BEGIN"), StatementClass::Begin);
+        assert_eq!(classify("-- c\n  commit"), StatementClass::Commit);
+        assert_eq!(
+            classify("/* c */ SAVEPOINT \"Sp\""),
+            StatementClass::Savepoint("sp".to_string())
+        );
+    }
+}
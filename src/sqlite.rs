@@ -1,16 +1,49 @@
 use std::{
     collections::HashMap,
-    error::Error,
-    ffi::{c_char, c_int, c_void},
+    ffi::{c_char, c_int, c_uint, c_void, CString},
     fmt,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::ThreadId,
+    time::{Duration, Instant},
 };
 
+use tracing::Instrument;
+
 use crate::{
-    transport::{self, RemoteSqliteResponse},
-    utils::{convert_params_to_json, get_execution_result},
+    attach::{self, AttachedDatabase},
+    authorizer::{authorize, Authorization, Authorizer},
+    cache::{CachedStatement, StatementCache, StatementKind},
+    coercion::CText,
+    collation::{self, CollationRegistry, SortedQuery},
+    config::{parse_bool, parse_timeout_ms, ConnectionOptions},
+    connection_state::ConnectionState,
+    functions::{self, EmulatedQuery, FunctionRegistry},
+    group::StatementGroup,
+    journal::{self, Journal},
+    logging,
+    metrics::Metrics,
+    prewarm,
+    protocol::{
+        self, convert_params_to_json, Batch, QueryResult, RemoteRow, RemoteSQLiteResult,
+        RemoteSqliteResponse, StatementArgs, Stmt,
+    },
+    result_cache::{CachedResult, ResultCache},
+    schema_cache::SchemaCache,
+    session::Session,
+    slow_query::{self, SlowQuery, SlowQueryLog},
+    sql::{select::with_limit, tokenizer::changes_schema},
+    status::StmtCounters,
+    transport::{self, ActiveStrategy},
+    utils::{self, get_execution_result},
+    worker::{self, Pending, Priority, Worker},
+    write_behind::{self, SharedErrorHook, WriteBehind},
 };
 
+#[cfg(feature = "replica")]
+use crate::replica::Replica;
 use lazy_static::lazy_static;
 
 pub const SQLITE_OK: c_int = 0;
@@ -19,12 +52,131 @@ pub const SQLITE_MISUSE: c_int = 21;
 pub const SQLITE_ROW: c_int = 100;
 pub const SQLITE_DONE: c_int = 101;
 pub const SQLITE_RANGE: c_int = 25;
+pub const SQLITE_MISMATCH: c_int = 20;
+pub const SQLITE_CONSTRAINT: c_int = 19;
+pub const SQLITE_AUTH: c_int = 23;
+pub const SQLITE_PERM: c_int = 3;
+pub const SQLITE_NOTADB: c_int = 26;
+pub const SQLITE_ABORT: c_int = 4;
 pub const SQLITE_BUSY: c_int = 5;
+pub const SQLITE_READONLY: c_int = 8;
+pub const SQLITE_INTERRUPT: c_int = 9;
+pub const SQLITE_IOERR: c_int = 10;
+pub const SQLITE_CORRUPT: c_int = 11;
+pub const SQLITE_NOTFOUND: c_int = 12;
+pub const SQLITE_FULL: c_int = 13;
+pub const SQLITE_TOOBIG: c_int = 18;
 pub const SQLITE_CANTOPEN: c_int = 14;
+pub const SQLITE_LOCKED: c_int = 6;
+pub const SQLITE_NOMEM: c_int = 7;
+pub const SQLITE_OPEN_READWRITE: c_int = 0x00000002;
+pub const SQLITE_OPEN_CREATE: c_int = 0x00000004;
+pub const SQLITE_OPEN_NOMUTEX: c_int = 0x00008000;
+pub const SQLITE_OPEN_FULLMUTEX: c_int = 0x00010000;
+pub const SQLITE_PREPARE_PERSISTENT: c_uint = 0x01;
+pub const SQLITE_PREPARE_NORMALIZE: c_uint = 0x02; // No-op in SQLite itself since 3.30
+pub const SQLITE_PREPARE_NO_VTAB: c_uint = 0x04; // Virtual tables are the server's, nothing to check
+pub const SQLITE_PREPARE_DONT_LOG: c_uint = 0x10; // Keeps prepare failures out of the log
+
+pub const SQLITE_BUSY_TIMEOUT: c_int = SQLITE_BUSY | (3 << 8);
+
+pub const SQLITE_CONFIG_SINGLETHREAD: c_int = 1;
+pub const SQLITE_CONFIG_MULTITHREAD: c_int = 2;
+pub const SQLITE_CONFIG_SERIALIZED: c_int = 3;
+
+// Chosen with sqlite3_config, which is only allowed while the library is not initialized
+static THREADING_MODE: AtomicI32 = AtomicI32::new(SQLITE_CONFIG_SERIALIZED);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Addresses of the connections not closed yet, wound down by sqlite3_shutdown
+static OPEN_CONNECTIONS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+pub fn initialize() {
+    INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+/// Winds the library down for sqlite3_shutdown. Connections left open get their queued
+/// writes sent and their sockets closed before the runtime and its background tasks stop;
+/// their handles stay valid and reconnect if used again.
+pub fn shutdown() {
+    let open = OPEN_CONNECTIONS.lock().unwrap().clone();
+    for db in open {
+        unsafe { &*(db as *const SQLite3) }.shut_down();
+    }
+
+    prewarm::clear();
+    INITIALIZED.store(false, Ordering::SeqCst);
+    utils::shutdown_tokio();
+}
+
+pub fn register_connection(db: *const SQLite3) {
+    OPEN_CONNECTIONS.lock().unwrap().push(db as usize);
+}
+
+/// Drops cached statements and results from open connections until at least `limit` bytes
+/// are released, for sqlite3_release_memory. Returns the bytes released.
+pub fn release_memory(limit: usize) -> usize {
+    let open = OPEN_CONNECTIONS.lock().unwrap();
+    let mut released = 0;
+    for &db in open.iter() {
+        if released >= limit {
+            break;
+        }
+        released += unsafe { &*(db as *const SQLite3) }.release_memory();
+    }
+    released
+}
+
+/// Runs `f` with an open connection, if there is one, which cannot be closed meanwhile.
+pub fn with_open_connection<R>(f: impl FnOnce(&SQLite3) -> R) -> Option<R> {
+    let open = OPEN_CONNECTIONS.lock().unwrap();
+    let db = *open.first()?;
+    Some(f(unsafe { &*(db as *const SQLite3) }))
+}
+
+pub fn unregister_connection(db: *const SQLite3) {
+    OPEN_CONNECTIONS
+        .lock()
+        .unwrap()
+        .retain(|&open| open != db as usize);
+}
+
+pub fn configure(op: c_int) -> Result<(), SqliteError> {
+    if INITIALIZED.load(Ordering::SeqCst) {
+        return Err(SqliteError::new(
+            "sqlite3_config must be called before sqlite3_initialize",
+            Some(SQLITE_MISUSE),
+        ));
+    }
+
+    match op {
+        SQLITE_CONFIG_SINGLETHREAD | SQLITE_CONFIG_MULTITHREAD | SQLITE_CONFIG_SERIALIZED => {
+            THREADING_MODE.store(op, Ordering::SeqCst);
+            Ok(())
+        }
+        _ => Err(SqliteError::new(
+            format!("unsupported configuration option: {}", op),
+            Some(SQLITE_ERROR),
+        )),
+    }
+}
+
+/// Whether a connection opened with `flags` leaves locking to the caller: the open flags
+/// win over the mode chosen with `sqlite3_config`.
+pub fn is_caller_managed(flags: c_int) -> bool {
+    if flags & SQLITE_OPEN_FULLMUTEX != 0 {
+        false
+    } else if flags & SQLITE_OPEN_NOMUTEX != 0 {
+        true
+    } else {
+        THREADING_MODE.load(Ordering::SeqCst) != SQLITE_CONFIG_SERIALIZED
+    }
+}
 
 pub const SQLITE_INTEGER: c_int = 1;
 pub const SQLITE_FLOAT: c_int = 2;
 pub const SQLITE_TEXT: c_int = 3;
+pub const SQLITE_BLOB: c_int = 4;
 pub const SQLITE_NULL: c_int = 5;
 
 pub const SQLITE_UPDATE: c_int = 23;
@@ -51,13 +203,14 @@ pub struct SqliteHookData {
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Text(String), // TEXT
-    Integer(i64), // INTEGER
-    Real(f64),    // REAL
-    Null,         // NULL
+    Text(String),  // TEXT
+    Integer(i64),  // INTEGER
+    Real(f64),     // REAL
+    Blob(Vec<u8>), // BLOB
+    Null,          // NULL
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SqliteError {
     pub message: String,
     pub code: c_int, //  defaults to SQLITE_ERROR
@@ -78,23 +231,108 @@ impl fmt::Display for SqliteError {
     }
 }
 
+impl std::error::Error for SqliteError {}
+
 lazy_static! {
     pub static ref ERROR_STACK: Mutex<Vec<(String, c_int)>> = Mutex::new(Vec::new());
 }
 
 #[repr(C)]
 pub struct SQLite3 {
-    pub connection: transport::DatabaseConnection, // Connection to the database
-    pub last_insert_rowid: Mutex<Option<i64>>,     // Last inserted row ID
-    pub rows_written: Mutex<Option<u64>>,          // Number of rows written
-    pub transaction_baton: Mutex<Option<String>>,  // Baton for transaction management
-    pub transaction_has_began: Mutex<bool>,        // Flag to check if a transaction has started
+    pub connection: tokio::sync::Mutex<transport::DatabaseConnection>, // One request at a time
+    pub metrics: Arc<Metrics>, // The connection's counters, readable unlocked
+    pub state: Arc<ConnectionState>, // Connected, degraded or disconnected, as last seen
+    pub worker: Worker,        // Thread running the connection's requests
+    pub options: ConnectionOptions, // Options the database was opened with
+    pub attached: Mutex<HashMap<String, AttachedDatabase>>, // ATTACHed databases by schema name
+    pub functions: Mutex<FunctionRegistry>, // Scalar functions evaluated on the client
+    pub collations: Mutex<CollationRegistry>, // Collations applied to fetched rows
+    pub authorizer: Mutex<Option<Authorizer>>, // Checks statements as they are prepared
+    pub last_insert_rowid: Mutex<Option<i64>>, // Last inserted row ID
+    pub rows_written: Mutex<Option<u64>>, // Number of rows written
+    pub replication_index: Mutex<Option<u64>>, // Highest replication index seen
+    pub schema_generation: AtomicU64, // Bumped whenever the schema is known to have changed
+    pub last_query_stats: Mutex<Option<QueryStats>>, // Timing of the last remote statement
+    pub statement_cache: Mutex<StatementCache>, // Parsed statements keyed by SQL text
+    pub script: Mutex<Option<(Arc<StatementGroup>, usize)>>, // Next statement pz_tail points at
+    pub opened_at: Instant,    // Reported as the connection's uptime by turso_status
+    pub result_cache: Mutex<ResultCache>, // SELECT results while PRAGMA turso.cache is on
+    pub schema_cache: Mutex<SchemaCache>, // sqlite_master results for the current schema_version
+    pub slow_queries: Mutex<SlowQueryLog>, // Threshold and hook of the slow query log
+    pub write_behind: Mutex<Option<WriteBehind>>, // Set while PRAGMA turso.async_writes is on
+    pub async_error_hook: SharedErrorHook, // Receives failures of queued writes
+    pub transaction_baton: Mutex<Option<String>>, // Baton for transaction management
+    pub savepoints: Mutex<Vec<Savepoint>>, // Open savepoints, innermost last
+    pub session: Mutex<Session>, // Temp objects and pragmas replayed on every new stream
+    pub transaction_has_began: Mutex<bool>, // Flag to check if a transaction has started
+    pub transaction_owner: Mutex<Option<ThreadId>>, // Thread that began the transaction
+    pub async_step: AtomicBool, // Set while PRAGMA turso.async_step is on
+    pub batch_scripts: AtomicBool, // Set while PRAGMA turso.batch_scripts is on, see group.rs
+    pub txn_replay: AtomicBool, // Transactions begun while set are journaled, see journal.rs
+    pub load_extension: AtomicBool, // Set by sqlite3_enable_load_extension
+    pub journal: Mutex<Option<Journal>>, // Statements of the open transaction, to replay
+    pub deadline: Mutex<Option<Instant>>, // From sqlite3_turso_set_deadline, cleared at transaction end
+    pub in_flight_steps: Mutex<InFlightSteps>, // Non-blocking steps waiting on the server
+    pub priority: Mutex<Priority>,        // Given to statements prepared from now on
+    pub warm_up: Mutex<Option<Pending<()>>>, // The schema prefetch of warmup=on, until it is done
+    pub table_limit: AtomicUsize, // Most bytes sqlite3_get_table hands out, zero for no limit
+    pub max_rows: AtomicU64,      // Most rows a query returns, zero for no limit
+    pub last_truncated: AtomicBool, // The last query returned more than max_rows rows
+    pub serialized: bool,         // Opened with SQLITE_OPEN_FULLMUTEX
+    pub caller_managed: bool,     // Multi-thread mode, the caller does the locking
     pub update_hook: Mutex<Option<(SqliteHook, *mut c_void)>>, // Update hook callback
     pub insert_hook: Mutex<Option<(SqliteHook, *mut c_void)>>, // Insert hook callback
     pub delete_hook: Mutex<Option<(SqliteHook, *mut c_void)>>, // Delete hook callback
+    #[cfg(feature = "replica")]
+    pub replica: Option<Replica>, // The local copy reads are served from, see replica.rs
 }
 
+/// Steps sent to the server in non-blocking mode, keyed by statement address.
+pub type InFlightSteps = HashMap<usize, Pending<Result<c_int, SqliteError>>>;
+
+// Serialized threading mode: every field is behind a lock, requests included, so the handle
+// may be used from any thread. Hook user data is only handed back to the caller's callback.
+unsafe impl Send for SQLite3 {}
+unsafe impl Sync for SQLite3 {}
+
 impl SQLite3 {
+    /// Settles a non-blocking step of `stmt` so the statement can be reset or freed: one still
+    /// queued is withdrawn, one already sent is waited for and its outcome returned.
+    pub fn cancel_step(
+        &self,
+        stmt: *const SQLite3PreparedStmt,
+    ) -> Option<Result<c_int, SqliteError>> {
+        let pending = self
+            .in_flight_steps
+            .lock()
+            .unwrap()
+            .remove(&(stmt as usize));
+        pending.and_then(Pending::cancel)
+    }
+
+    // Leaves nothing of the connection running on the runtime. Steps in flight finish, their
+    // outcome kept for the next sqlite3_step; queued writes go out and PRAGMA
+    // turso.async_writes is turned off.
+    fn shut_down(&self) {
+        for pending in self.in_flight_steps.lock().unwrap().values_mut() {
+            pending.settle();
+        }
+        if let Some(warm_up) = self.warm_up.lock().unwrap().as_mut() {
+            warm_up.settle();
+        }
+
+        let write_behind = self.write_behind.lock().unwrap().take();
+        self.worker.run(async {
+            if let Some(write_behind) = write_behind {
+                write_behind.drain().await;
+            }
+            attach::shut_down_all(self).await;
+            if let Err(err) = self.connection.lock().await.shut_down().await {
+                tracing::warn!(error = %err, "Failed to shut down connection");
+            }
+        });
+    }
+
     pub fn trigger_hook(&self, data: SqliteHookData) {
         let hook = match data.op {
             SQLITE_UPDATE => &self.update_hook,
@@ -105,8 +343,8 @@ impl SQLite3 {
         let hook = hook.lock().unwrap();
 
         if let Some((callback, user_data)) = &*hook {
-            let db_name_c = std::ffi::CString::new(data.db_name).unwrap();
-            let tbl_name_c = std::ffi::CString::new(data.tbl_name).unwrap();
+            let db_name_c = CString::new(data.db_name.replace('\0', "")).unwrap_or_default();
+            let tbl_name_c = CString::new(data.tbl_name.replace('\0', "")).unwrap_or_default();
 
             // Call the registered callback
             callback(
@@ -138,9 +376,175 @@ impl SQLite3 {
         SQLITE_OK
     }
 
+    /// The connection for one request. Serialized handles wait their turn; in multi-thread
+    /// mode the caller promised not to share the handle, so finding it busy is a misuse.
+    pub async fn lock_connection(
+        &self,
+    ) -> Result<tokio::sync::MutexGuard<'_, transport::DatabaseConnection>, SqliteError> {
+        let mut connection = if self.caller_managed {
+            self.connection.try_lock().map_err(|_| {
+                SqliteError::new(
+                    "database connection used by two threads at once",
+                    Some(SQLITE_MISUSE),
+                )
+            })?
+        } else {
+            self.connection.lock().await
+        };
+
+        self.adopt_after_fork(&mut connection)?;
+        Ok(connection)
+    }
+
+    /// Takes over a connection inherited through fork(): the child gets transports of its
+    /// own, and the parent's transaction and queued writes stay with the parent.
+    pub fn adopt_after_fork(
+        &self,
+        connection: &mut transport::DatabaseConnection,
+    ) -> Result<(), SqliteError> {
+        if !connection.is_inherited() {
+            return Ok(());
+        }
+
+        connection.after_fork()?;
+        if self.has_began_transaction() {
+            tracing::warn!("Transaction of the parent process dropped in the forked child");
+        }
+        *self.transaction_has_began.lock().unwrap() = false;
+        *self.transaction_owner.lock().unwrap() = None;
+        self.transaction_baton.lock().unwrap().take();
+        self.savepoints.lock().unwrap().clear();
+        self.journal.lock().unwrap().take();
+
+        let mut write_behind = self.write_behind.lock().unwrap();
+        if let Some(inherited) = write_behind.take() {
+            std::mem::forget(inherited);
+            let mut http = connection.http.clone();
+            http.set_request_id(None);
+            *write_behind = Some(WriteBehind::start(http, self.async_error_hook.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Runs a remote call, failing it with SQLITE_INTERRUPT once the deadline set with
+    /// `sqlite3_turso_set_deadline` has passed. A call due after it is not made at all.
+    pub async fn within_deadline<T>(
+        &self,
+        call: impl std::future::Future<Output = Result<T, SqliteError>>,
+    ) -> Result<T, SqliteError> {
+        let interrupted = || SqliteError::new("interrupted", Some(SQLITE_INTERRUPT));
+        let Some(deadline) = *self.deadline.lock().unwrap() else {
+            return call.await;
+        };
+        if Instant::now() >= deadline {
+            return Err(interrupted());
+        }
+
+        tokio::time::timeout_at(deadline.into(), call)
+            .await
+            .unwrap_or_else(|_| Err(interrupted()))
+    }
+
     pub fn has_began_transaction(&self) -> bool {
         *self.transaction_has_began.lock().unwrap()
     }
+
+    /// In serialized mode only the thread that began a transaction may run statements in it;
+    /// anything else would go out with a baton another thread is about to replace.
+    fn check_transaction_owner(&self) -> Result<(), SqliteError> {
+        let owner = *self.transaction_owner.lock().unwrap();
+        if self.serialized && owner.is_some_and(|owner| owner != worker::caller_thread()) {
+            return Err(SqliteError::new(
+                "database is locked by a transaction on another thread",
+                Some(SQLITE_LOCKED),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Empties the caches that can be refilled from the server, returning the bytes they held.
+    pub fn release_memory(&self) -> usize {
+        let mut released = 0;
+        {
+            let mut cache = self.result_cache.lock().unwrap();
+            released += cache.memory_used();
+            cache.clear();
+        }
+        {
+            let mut cache = self.schema_cache.lock().unwrap();
+            released += cache.memory_used();
+            cache.clear();
+        }
+        let mut cache = self.statement_cache.lock().unwrap();
+        released += cache.memory_used();
+        cache.clear();
+        released
+    }
+
+    pub fn schema_generation(&self) -> u64 {
+        self.schema_generation.load(Ordering::Relaxed)
+    }
+
+    /// Marks column names learned so far as stale, to be described again before use.
+    pub fn schema_changed(&self) {
+        self.schema_generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn replication_index(&self) -> Option<u64> {
+        *self.replication_index.lock().unwrap()
+    }
+
+    pub fn observe_replication_index(&self, index: u64) {
+        let mut replication_index = self.replication_index.lock().unwrap();
+        if replication_index.is_none_or(|current| index > current) {
+            *replication_index = Some(index);
+        }
+    }
+}
+
+pub struct Savepoint {
+    pub name: String,             // Lowercased, as SQLite compares savepoint names
+    pub opened_transaction: bool, // Outside a transaction SAVEPOINT behaves like BEGIN
+}
+
+/// Timing breakdown of one remote statement, read back through `PRAGMA turso.last_query_stats`.
+pub struct QueryStats {
+    pub request_id: String,
+    pub transport: &'static str,
+    pub queue: Duration, // Local work before the request went out, e.g. reconnecting
+    pub round_trip: Duration, // From sending the request until the response was parsed
+    pub server_ms: Option<f64>, // Execution time reported by the server, if any
+    pub rows_read: Option<u64>,
+    pub rows_written: Option<u64>,
+}
+
+impl QueryStats {
+    fn to_columns(&self) -> Vec<(String, Value)> {
+        let ms = |duration: Duration| Value::Real(duration.as_secs_f64() * 1000.0);
+        let round_trip_ms = self.round_trip.as_secs_f64() * 1000.0;
+        let optional_int =
+            |value: Option<u64>| value.map_or(Value::Null, |v| Value::Integer(v as i64));
+
+        vec![
+            ("request_id".into(), Value::Text(self.request_id.clone())),
+            ("transport".into(), Value::Text(self.transport.to_string())),
+            ("queue_ms".into(), ms(self.queue)),
+            (
+                "network_ms".into(),
+                // Without a server figure the whole round trip counts as network time
+                Value::Real((round_trip_ms - self.server_ms.unwrap_or(0.0)).max(0.0)),
+            ),
+            (
+                "server_ms".into(),
+                self.server_ms.map_or(Value::Null, Value::Real),
+            ),
+            ("total_ms".into(), ms(self.queue + self.round_trip)),
+            ("rows_read".into(), optional_int(self.rows_read)),
+            ("rows_written".into(), optional_int(self.rows_written)),
+        ]
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -156,29 +560,55 @@ pub enum ExecutionState {
 #[repr(C)]
 #[derive(Debug)]
 pub struct SQLite3PreparedStmt {
-    pub sql: String,                            // SQL statement as a CString
-    pub param_count: c_int,                     // Number of parameters in the statement
-    pub params: HashMap<i32, Value>,            // Bound parameters (index -> value)
-    pub execution_state: Mutex<ExecutionState>, // Execution state
-    pub result_rows: Mutex<Vec<Vec<Value>>>,    // Result rows
-    pub current_row: Mutex<Option<usize>>,      // Index of the current row
+    pub sql: String,                                  // SQL statement as a CString
+    pub param_count: c_int,                           // Number of parameters in the statement
+    pub params: HashMap<i32, Value>,                  // Bound parameters (index -> value)
+    pub execution_state: Mutex<ExecutionState>,       // Execution state
+    pub result_rows: Mutex<Vec<Vec<Value>>>,          // Result rows
+    pub current_row: Mutex<Option<usize>>,            // Index of the current row
+    pub row_text: Mutex<HashMap<usize, CText>>, // Text handed out for the current row's columns
+    pub row_vectors: Mutex<HashMap<usize, Vec<f32>>>, // Vectors handed out for the current row
     pub column_names: Vec<String>,              // Column names for the result set
     pub db: *mut SQLite3,                       // Pointer to the associated database
+    pub statement: Arc<CachedStatement>,        // Parse results shared through the cache
+    pub persistent: bool,                       // Prepared with SQLITE_PREPARE_PERSISTENT
+    pub priority: Priority,                     // Where its requests queue, see turso.priority
+    pub ignored: bool,                          // The authorizer answered SQLITE_IGNORE
+    pub counters: StmtCounters,                 // Reported by sqlite3_stmt_status
+    pub group: Option<(Arc<StatementGroup>, usize)>, // The script it is a statement of
 }
 
 impl SQLite3PreparedStmt {
     pub fn new(db: *mut SQLite3, sql: &str) -> Self {
         SQLite3PreparedStmt {
             sql: sql.to_string(),
+            statement: Arc::new(CachedStatement::parse(sql)),
+            persistent: false,
+            priority: Priority::Normal,
+            ignored: false,
+            counters: StmtCounters::default(),
+            group: None,
             param_count: 0,
             params: HashMap::new(),
             execution_state: Mutex::new(ExecutionState::Prepared),
             result_rows: Mutex::new(Vec::new()),
             current_row: Mutex::new(None),
+            row_text: Mutex::new(HashMap::new()),
+            row_vectors: Mutex::new(HashMap::new()),
             column_names: Vec::new(),
             db,
         }
     }
+
+    /// Keeps the statement's column names for later prepares of the same SQL.
+    pub fn remember_column_names(&self) {
+        let generation = match unsafe { self.db.as_ref() } {
+            Some(db) => db.schema_generation(),
+            None => 0,
+        };
+        self.statement
+            .set_column_names(&self.column_names, generation);
+    }
 }
 
 pub type SQLite3ExecCallback = Option<
@@ -205,22 +635,70 @@ pub unsafe fn get_latest_error() -> Option<(String, c_int)> {
     }
 }
 
-pub fn reset_txn_on_db(db: *mut SQLite3) -> c_int {
-    let db = unsafe { &mut *db };
+pub fn reset_txn_on_db(db: *const SQLite3) -> c_int {
+    let db = unsafe { &*db };
 
     if !db.has_began_transaction() {
         return SQLITE_OK;
     }
 
     *db.transaction_has_began.lock().unwrap() = false;
+    *db.transaction_owner.lock().unwrap() = None;
     db.transaction_baton.lock().unwrap().take();
+    db.savepoints.lock().unwrap().clear();
+    db.journal.lock().unwrap().take();
+    db.deadline.lock().unwrap().take();
 
     SQLITE_OK
 }
 
-pub fn iterate_rows(stmt: &mut SQLite3PreparedStmt) -> Result<c_int, Box<dyn Error>> {
+/// One `sqlite3_step`: the statement runs on the first call, after which every call walks
+/// the rows that came back. Success is always SQLITE_ROW or SQLITE_DONE, never SQLITE_OK.
+pub async fn step_stmt(
+    stmt: &mut SQLite3PreparedStmt,
+    needs_execution: bool,
+) -> Result<c_int, SqliteError> {
+    if needs_execution && !stmt.ignored {
+        let (db, sql) = (stmt.db, stmt.sql.clone());
+        let result = match stmt.statement.kind.clone() {
+            StatementKind::TursoPragma(name, value) => {
+                execute_turso_pragma(stmt, &name, value.as_deref()).await
+            }
+            StatementKind::Begin => begin_tnx_on_db(db, &sql).await,
+            StatementKind::Commit => commit_tnx_on_db(db, &sql).await,
+            StatementKind::Rollback => rollback_tnx_on_db(db, &sql).await,
+            StatementKind::Savepoint(name) => savepoint_on_db(db, &sql, &name).await,
+            StatementKind::Release(name) => release_savepoint_on_db(db, &sql, &name).await,
+            StatementKind::RollbackTo(name) => rollback_to_savepoint_on_db(db, &sql, &name).await,
+            StatementKind::Attach { database, schema } => {
+                attach::attach_on_db(db, &database, &schema).await
+            }
+            StatementKind::Detach(schema) => attach::detach_on_db(db, &schema).await,
+            StatementKind::Remote | StatementKind::Session(_) | StatementKind::Status(_) => {
+                execute_stmt(stmt).await
+            }
+        };
+
+        // The statement has to be reset before it can be stepped again
+        if let Err(err) = result {
+            *stmt.execution_state.lock().unwrap() = ExecutionState::Error(err.message.clone());
+            return Err(err);
+        }
+        stmt.counters.runs += 1;
+    }
+
+    let rc = iterate_rows(stmt);
+    if rc == SQLITE_ROW {
+        stmt.counters.rows += 1;
+    }
+    Ok(rc)
+}
+
+pub fn iterate_rows(stmt: &mut SQLite3PreparedStmt) -> c_int {
     let result_rows = stmt.result_rows.lock().unwrap();
     let mut current_row = stmt.current_row.lock().unwrap();
+    stmt.row_text.lock().unwrap().clear();
+    stmt.row_vectors.lock().unwrap().clear();
 
     match *current_row {
         Some(row_index) if row_index + 1 < result_rows.len() => {
@@ -231,7 +709,7 @@ pub fn iterate_rows(stmt: &mut SQLite3PreparedStmt) -> Result<c_int, Box<dyn Err
                 *exec_state = ExecutionState::Row;
             }
 
-            Ok(SQLITE_ROW)
+            SQLITE_ROW
         }
         Some(_) => {
             *current_row = None;
@@ -241,7 +719,7 @@ pub fn iterate_rows(stmt: &mut SQLite3PreparedStmt) -> Result<c_int, Box<dyn Err
                 *exec_state = ExecutionState::Done;
             }
 
-            Ok(SQLITE_DONE)
+            SQLITE_DONE
         }
         None if !result_rows.is_empty() => {
             *current_row = Some(0);
@@ -251,7 +729,7 @@ pub fn iterate_rows(stmt: &mut SQLite3PreparedStmt) -> Result<c_int, Box<dyn Err
                 *exec_state = ExecutionState::Row;
             }
 
-            Ok(SQLITE_ROW)
+            SQLITE_ROW
         }
         None => {
             // Update state
@@ -259,22 +737,401 @@ pub fn iterate_rows(stmt: &mut SQLite3PreparedStmt) -> Result<c_int, Box<dyn Err
                 *exec_state = ExecutionState::Done;
             }
 
-            Ok(SQLITE_DONE)
+            SQLITE_DONE
+        }
+    }
+}
+
+/// Runs a `PRAGMA turso.<name>` locally and returns its result row as (column, value) pairs.
+/// The `turso.*` pragmas that change a setting, which `sqlite3_turso_config` also takes for an
+/// open connection.
+pub const TURSO_SETTINGS: [&str; 14] = [
+    "timeout",
+    "async_writes",
+    "async_step",
+    "batch_scripts",
+    "txn_replay",
+    "schema_cache",
+    "session_replay",
+    "cache",
+    "cache_ttl",
+    "cache_size",
+    "slow_ms",
+    "priority",
+    "table_limit",
+    "max_rows",
+];
+
+pub async fn handle_turso_pragma(
+    db: &SQLite3,
+    name: &str,
+    value: Option<&str>,
+) -> Result<Vec<(String, Value)>, SqliteError> {
+    match name {
+        "timeout" => {
+            if let Some(value) = value {
+                let timeout = parse_timeout_ms(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.timeout value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                db.lock_connection().await?.set_timeout(timeout);
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(db.lock_connection().await?.timeout.as_millis() as i64),
+            )])
+        }
+        "async_writes" => {
+            if let Some(value) = value {
+                let enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.async_writes value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                set_async_writes(db, enabled).await?;
+            }
+
+            let enabled = db.write_behind.lock().unwrap().is_some();
+            Ok(vec![(name.to_string(), Value::Integer(enabled as i64))])
+        }
+        "async_step" => {
+            if let Some(value) = value {
+                let enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.async_step value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                db.async_step.store(enabled, Ordering::Relaxed);
+            }
+
+            let enabled = db.async_step.load(Ordering::Relaxed);
+            Ok(vec![(name.to_string(), Value::Integer(enabled as i64))])
+        }
+        "batch_scripts" => {
+            if let Some(value) = value {
+                let enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.batch_scripts value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                db.batch_scripts.store(enabled, Ordering::Relaxed);
+            }
+
+            let enabled = db.batch_scripts.load(Ordering::Relaxed);
+            Ok(vec![(name.to_string(), Value::Integer(enabled as i64))])
+        }
+        "txn_replay" => {
+            if let Some(value) = value {
+                let enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.txn_replay value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                // The open transaction, if any, keeps being journaled or not as it began
+                db.txn_replay.store(enabled, Ordering::Relaxed);
+            }
+
+            let enabled = db.txn_replay.load(Ordering::Relaxed);
+            Ok(vec![(name.to_string(), Value::Integer(enabled as i64))])
+        }
+        "schema_cache" => {
+            let mut cache = db.schema_cache.lock().unwrap();
+            if let Some(value) = value {
+                cache.enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.schema_cache value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                if !cache.enabled {
+                    cache.clear();
+                }
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(cache.enabled as i64),
+            )])
+        }
+        "session_replay" => {
+            let mut session = db.session.lock().unwrap();
+            if let Some(value) = value {
+                let enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.session_replay value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                session.set_enabled(enabled);
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(session.enabled as i64),
+            )])
+        }
+        "cache" => {
+            let mut cache = db.result_cache.lock().unwrap();
+            if let Some(value) = value {
+                cache.enabled = parse_bool(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.cache value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                if !cache.enabled {
+                    cache.clear();
+                }
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(cache.enabled as i64),
+            )])
+        }
+        "cache_ttl" => {
+            let mut cache = db.result_cache.lock().unwrap();
+            if let Some(value) = value {
+                cache.ttl = parse_timeout_ms(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.cache_ttl value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(cache.ttl.as_millis() as i64),
+            )])
+        }
+        "cache_size" => {
+            let mut cache = db.result_cache.lock().unwrap();
+            if let Some(value) = value {
+                cache.max_entries = value.trim().parse().map_err(|_| {
+                    SqliteError::new(
+                        format!("Invalid turso.cache_size value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                if cache.max_entries == 0 {
+                    cache.clear();
+                }
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(cache.max_entries as i64),
+            )])
+        }
+        "slow_ms" => {
+            let mut log = db.slow_queries.lock().unwrap();
+            if let Some(value) = value {
+                let threshold = value.trim().parse::<u64>().map_err(|_| {
+                    SqliteError::new(
+                        format!("Invalid turso.slow_ms value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                log.threshold = Some(Duration::from_millis(threshold)).filter(|t| !t.is_zero());
+            }
+
+            let threshold = log.threshold.unwrap_or_default();
+            Ok(vec![(
+                name.to_string(),
+                Value::Integer(threshold.as_millis() as i64),
+            )])
+        }
+        "priority" => {
+            let mut priority = db.priority.lock().unwrap();
+            if let Some(value) = value {
+                *priority = Priority::parse(value).ok_or_else(|| {
+                    SqliteError::new(
+                        format!("Invalid turso.priority value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+            }
+
+            Ok(vec![(
+                name.to_string(),
+                Value::Text(priority.name().to_string()),
+            )])
+        }
+        "table_limit" => {
+            if let Some(value) = value {
+                let limit = value.trim().parse().map_err(|_| {
+                    SqliteError::new(
+                        format!("Invalid turso.table_limit value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                db.table_limit.store(limit, Ordering::Relaxed);
+            }
+
+            let limit = db.table_limit.load(Ordering::Relaxed);
+            Ok(vec![(name.to_string(), Value::Integer(limit as i64))])
+        }
+        "max_rows" => {
+            if let Some(value) = value {
+                let max_rows = value.trim().parse().map_err(|_| {
+                    SqliteError::new(
+                        format!("Invalid turso.max_rows value '{}'", value),
+                        Some(SQLITE_MISUSE),
+                    )
+                })?;
+                db.max_rows.store(max_rows, Ordering::Relaxed);
+            }
+
+            let max_rows = db.max_rows.load(Ordering::Relaxed);
+            Ok(vec![(name.to_string(), Value::Integer(max_rows as i64))])
+        }
+        "last_truncated" => {
+            let truncated = db.last_truncated.load(Ordering::Relaxed);
+            Ok(vec![(name.to_string(), Value::Integer(truncated as i64))])
+        }
+        "stats" => Ok(vec![
+            (
+                "rows_read".to_string(),
+                Value::Integer(db.metrics.rows_read() as i64),
+            ),
+            (
+                "rows_written".to_string(),
+                Value::Integer(db.metrics.rows_written() as i64),
+            ),
+            ("server_ms".to_string(), Value::Real(db.metrics.server_ms())),
+        ]),
+        "last_query_stats" => {
+            let stats = db.last_query_stats.lock().unwrap();
+            Ok(stats
+                .as_ref()
+                .map(QueryStats::to_columns)
+                .unwrap_or_default())
+        }
+        _ => Err(SqliteError::new(
+            format!("Unknown pragma turso.{}", name),
+            Some(SQLITE_ERROR),
+        )),
+    }
+}
+
+async fn set_async_writes(db: &SQLite3, enabled: bool) -> Result<(), SqliteError> {
+    if enabled {
+        let connection = db.lock_connection().await?;
+        // Queued writes go straight to the HTTP transport, which would bypass the fixture or
+        // the application's own transport
+        if matches!(
+            connection.strategy,
+            ActiveStrategy::Mock | ActiveStrategy::Custom
+        ) {
+            return Err(SqliteError::new(
+                format!(
+                    "turso.async_writes is not supported by the {} transport",
+                    connection.strategy.name().to_string_lossy()
+                ),
+                Some(SQLITE_ERROR),
+            ));
+        }
+        let mut http = connection.http.clone();
+        drop(connection);
+        http.set_request_id(None);
+
+        let mut slot = db.write_behind.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(WriteBehind::start(http, db.async_error_hook.clone()));
         }
+        return Ok(());
+    }
+
+    let write_behind = db.write_behind.lock().unwrap().take();
+    if let Some(write_behind) = write_behind {
+        write_behind.drain().await;
+    }
+    Ok(())
+}
+
+/// Waits for queued writes so the next statement sees them.
+pub async fn drain_write_behind(db: &SQLite3) {
+    let write_behind = db.write_behind.lock().unwrap().clone();
+    if let Some(write_behind) = write_behind {
+        write_behind.drain().await;
     }
 }
 
-pub async fn handle_execute(db: *mut SQLite3, sql: &str) -> Result<c_int, SqliteError> {
+pub async fn execute_turso_pragma(
+    stmt: &mut SQLite3PreparedStmt,
+    name: &str,
+    value: Option<&str>,
+) -> Result<c_int, SqliteError> {
+    let db = unsafe { &*stmt.db };
+    let (columns, row): (Vec<String>, Vec<Value>) = handle_turso_pragma(db, name, value)
+        .await?
+        .into_iter()
+        .unzip();
+
+    // An empty result, e.g. stats before any statement ran, yields no rows at all
+    *stmt.result_rows.lock().unwrap() = if row.is_empty() { vec![] } else { vec![row] };
+    stmt.column_names = columns;
+
+    Ok(SQLITE_OK)
+}
+
+pub async fn handle_execute(
+    db: *mut SQLite3,
+    sql: &str,
+    callback: SQLite3ExecCallback,
+    arg: *mut c_void,
+) -> Result<c_int, SqliteError> {
     let mut stmt = SQLite3PreparedStmt::new(db, sql);
+    execute_stmt(&mut stmt).await?;
 
-    match execute_stmt(&mut stmt).await {
-        Ok(_) => Ok(SQLITE_OK),
-        Err(e) => Err(e),
+    let Some(callback) = callback else {
+        return Ok(SQLITE_OK);
+    };
+
+    // Whatever the server returned is reported, so RETURNING rows reach the callback too
+    let names: Vec<CString> = stmt
+        .column_names
+        .iter()
+        .map(|name| CString::new(name.replace('\0', "")).unwrap_or_default())
+        .collect();
+    let mut name_ptrs: Vec<*mut c_char> = names.iter().map(|n| n.as_ptr() as *mut c_char).collect();
+
+    for row in stmt.result_rows.lock().unwrap().iter() {
+        let values: Vec<Option<CText>> = row.iter().map(CText::new).collect();
+        let mut value_ptrs: Vec<*mut c_char> = values
+            .iter()
+            .map(|v| {
+                v.as_ref()
+                    .map_or(std::ptr::null_mut(), |v| v.as_ptr() as *mut c_char)
+            })
+            .collect();
+
+        let abort = unsafe {
+            callback(
+                arg,
+                value_ptrs.len() as c_int,
+                value_ptrs.as_mut_ptr(),
+                name_ptrs.as_mut_ptr(),
+            )
+        };
+        if abort != 0 {
+            return Err(SqliteError::new("query aborted", Some(SQLITE_ABORT)));
+        }
     }
+
+    Ok(SQLITE_OK)
 }
 
 pub async fn begin_tnx_on_db(db: *mut SQLite3, sql: &str) -> Result<c_int, SqliteError> {
-    let db = unsafe { &mut *db };
+    let db = unsafe { &*db };
 
     if db.has_began_transaction() {
         return Err(SqliteError::new(
@@ -283,15 +1140,27 @@ pub async fn begin_tnx_on_db(db: *mut SQLite3, sql: &str) -> Result<c_int, Sqlit
         ));
     }
 
-    let baton_value = db.connection.get_transaction_baton(&sql).await?;
+    drain_write_behind(db).await;
+
+    let mut connection = db.lock_connection().await?;
+    connection.set_replication_index(db.replication_index());
+    connection.set_session(db.session.lock().unwrap().statements());
+
+    let baton_value = db
+        .within_deadline(connection.get_transaction_baton(sql))
+        .await?;
     db.transaction_baton.lock().unwrap().replace(baton_value);
     *db.transaction_has_began.lock().unwrap() = true;
+    *db.transaction_owner.lock().unwrap() = Some(worker::caller_thread());
+    if db.txn_replay.load(Ordering::Relaxed) {
+        *db.journal.lock().unwrap() = Some(Journal::new(sql));
+    }
 
     Ok(SQLITE_OK)
 }
 
 pub async fn commit_tnx_on_db(db: *mut SQLite3, sql: &str) -> Result<c_int, SqliteError> {
-    let db = unsafe { &mut *db };
+    let db = unsafe { &*db };
 
     if !db.has_began_transaction() {
         return Err(SqliteError::new(
@@ -300,83 +1169,782 @@ pub async fn commit_tnx_on_db(db: *mut SQLite3, sql: &str) -> Result<c_int, Sqli
         ));
     }
 
-    execute_sql_and_params(db, &sql, vec![]).await?;
+    // A COMMIT whose answer was lost may have been applied, so it is never replayed
+    db.journal.lock().unwrap().take();
+    execute_sql_and_params(db, sql, StatementArgs::default(), false).await?;
 
-    db.transaction_baton.lock().unwrap().take();
+    end_tnx_on_db(db).await
+}
+
+/// Rolls the transaction back on the server before dropping the local state. Local state is
+/// cleared even when the server cannot be told, closing the stream releases its locks anyway.
+pub async fn rollback_tnx_on_db(db: *mut SQLite3, sql: &str) -> Result<c_int, SqliteError> {
+    let db = unsafe { &*db };
+
+    if !db.has_began_transaction() {
+        return Err(SqliteError::new(
+            SQLITE_NO_ACTIVE_TRANSACTION_ERR_MSG,
+            Some(SQLITE_ERROR),
+        ));
+    }
+
+    // A lost stream has rolled the transaction back already
+    db.journal.lock().unwrap().take();
+    let rolled_back = execute_sql_and_params(db, sql, StatementArgs::default(), false).await;
+    let ended = end_tnx_on_db(db).await;
+
+    // The server's reason comes first, with its code
+    rolled_back?;
+    ended?;
+    Ok(SQLITE_OK)
+}
+
+/// Runs a statement on the open transaction's stream and picks up the next baton.
+async fn execute_in_tnx(db: &SQLite3, sql: &str) -> Result<(), SqliteError> {
+    let response = execute_sql_and_params(db, sql, StatementArgs::default(), false).await?;
+    get_execution_result(db, &response)?;
+    Ok(())
+}
+
+pub async fn savepoint_on_db(
+    db: *mut SQLite3,
+    sql: &str,
+    name: &str,
+) -> Result<c_int, SqliteError> {
+    let opened_transaction = {
+        let db = unsafe { &*db };
+        !db.has_began_transaction()
+    };
+
+    if opened_transaction {
+        begin_tnx_on_db(db, sql).await?;
+    } else {
+        execute_in_tnx(unsafe { &*db }, sql).await?;
+    }
+
+    let db = unsafe { &*db };
+    db.savepoints.lock().unwrap().push(Savepoint {
+        name: name.to_string(),
+        opened_transaction,
+    });
+
+    Ok(SQLITE_OK)
+}
+
+fn find_savepoint(db: &SQLite3, name: &str) -> Result<usize, SqliteError> {
+    db.savepoints
+        .lock()
+        .unwrap()
+        .iter()
+        .rposition(|savepoint| savepoint.name == name)
+        .ok_or_else(|| SqliteError::new(format!("no such savepoint: {}", name), Some(SQLITE_ERROR)))
+}
+
+/// RELEASE drops the savepoint and everything above it. Releasing the savepoint that
+/// started the transaction commits it.
+pub async fn release_savepoint_on_db(
+    db: *mut SQLite3,
+    sql: &str,
+    name: &str,
+) -> Result<c_int, SqliteError> {
+    let (index, commits) = {
+        let db = unsafe { &*db };
+        let index = find_savepoint(db, name)?;
+        let commits = index == 0 && db.savepoints.lock().unwrap()[0].opened_transaction;
+        (index, commits)
+    };
+
+    if commits {
+        return commit_tnx_on_db(db, sql).await;
+    }
+
+    let db = unsafe { &*db };
+    execute_in_tnx(db, sql).await?;
+    db.savepoints.lock().unwrap().truncate(index);
+
+    Ok(SQLITE_OK)
+}
+
+/// ROLLBACK TO undoes work since the savepoint but keeps it, and the transaction, open.
+pub async fn rollback_to_savepoint_on_db(
+    db: *mut SQLite3,
+    sql: &str,
+    name: &str,
+) -> Result<c_int, SqliteError> {
+    let db = unsafe { &*db };
+    let index = find_savepoint(db, name)?;
 
-    reset_txn_on_db(db);
+    execute_in_tnx(db, sql).await?;
+    db.savepoints.lock().unwrap().truncate(index + 1);
 
     Ok(SQLITE_OK)
 }
 
+/// Closes the transaction's stream on the server and clears the local transaction state.
+pub async fn end_tnx_on_db(db: *const SQLite3) -> Result<c_int, SqliteError> {
+    let db = unsafe { &*db };
+
+    let baton = db.transaction_baton.lock().unwrap().take();
+    if let Some(baton) = baton {
+        // Best effort: the server reclaims abandoned streams eventually anyway
+        let closed = match db.lock_connection().await {
+            Ok(mut connection) => connection.close_stream(&baton).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = closed {
+            tracing::debug!(%baton, error = %err, "Failed to close stream");
+        }
+    }
+
+    Ok(reset_txn_on_db(db))
+}
+
+/// Work done here on a statement the server cannot run as written: ordering by registered
+/// collations and calls to registered functions. Both rewrite the SQL that is sent.
+struct ClientSide {
+    sql: String,
+    sorted: Option<SortedQuery>,
+    emulated: Option<EmulatedQuery>,
+}
+
+impl ClientSide {
+    fn plan(db: &SQLite3, sql: &str) -> Result<Option<Self>, SqliteError> {
+        let sorted = collation::plan(db, sql)?;
+        let sql = sorted.as_ref().map_or(sql, |query| query.sql.as_str());
+        let emulated = functions::plan(db, sql)?;
+        if sorted.is_none() && emulated.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            sql: emulated
+                .as_ref()
+                .map_or(sql, |query| query.sql.as_str())
+                .to_string(),
+            sorted,
+            emulated,
+        }))
+    }
+
+    // Functions first, since sorting may need their results
+    fn apply(&self, stmt: &mut SQLite3PreparedStmt) -> Result<(), SqliteError> {
+        if let Some(emulated) = &self.emulated {
+            emulated.apply(stmt)?;
+        }
+        if let Some(sorted) = &self.sorted {
+            sorted.apply(stmt)?;
+            stmt.counters.sorts += 1;
+        }
+        Ok(())
+    }
+
+    fn column_names(&self, fetched: &[String]) -> Vec<String> {
+        let names = match &self.emulated {
+            Some(emulated) => emulated.column_names(fetched),
+            None => fetched.to_vec(),
+        };
+        match &self.sorted {
+            Some(sorted) => sorted.column_names(&names),
+            None => names,
+        }
+    }
+}
+
+/// Fills in the column names of a statement that has not run yet, for callers asking for
+/// column metadata before the first step. The answer is shared through the statement cache
+/// and replaced by the server's columns once the statement executes.
+pub async fn describe_stmt(stmt: &mut SQLite3PreparedStmt) {
+    let not_run = *stmt.execution_state.lock().unwrap() == ExecutionState::Prepared;
+    if let StatementKind::Status(query) = &stmt.statement.kind {
+        stmt.column_names = query.column_names();
+        return;
+    }
+    if !not_run || stmt.statement.kind != StatementKind::Remote || !stmt.statement.returns_rows {
+        return;
+    }
+
+    // Names learned before hold until the schema changes, like a statement SQLite reprepares
+    let db = unsafe { &*stmt.db };
+    if stmt.statement.column_names().is_some() {
+        check_schema_version(db).await;
+        if let Some(names) = stmt.statement.current_column_names(db.schema_generation()) {
+            stmt.column_names = names;
+            return;
+        }
+        stmt.counters.reprepares += 1;
+    }
+    let query = match ClientSide::plan(db, &stmt.sql) {
+        Ok(query) => query,
+        Err(err) => {
+            tracing::debug!(error = %err, "Failed to describe statement");
+            return;
+        }
+    };
+    let sql = query
+        .as_ref()
+        .map_or(stmt.sql.as_str(), |query| query.sql.as_str());
+    let described = match attach::route(db, sql) {
+        Ok(Some((database, sql))) => database.lock().await.describe(&sql).await,
+        Ok(None) => match db.lock_connection().await {
+            Ok(mut connection) => {
+                connection.set_session(db.session.lock().unwrap().statements());
+                connection.describe(sql).await
+            }
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    match described {
+        Ok(cols) => {
+            let names: Vec<String> = cols.iter().map(|col| col.name.clone()).collect();
+            stmt.column_names = match &query {
+                Some(query) => query.column_names(&names),
+                None => {
+                    stmt.statement.set_decltypes(&cols);
+                    names
+                }
+            };
+            stmt.remember_column_names();
+        }
+        Err(err) => tracing::debug!(error = %err, "Failed to describe statement"),
+    }
+}
+
 pub async fn execute_stmt(stmt: &mut SQLite3PreparedStmt) -> Result<c_int, SqliteError> {
-    let db: &mut SQLite3 = unsafe { &mut *stmt.db };
+    let db: &SQLite3 = unsafe { &*stmt.db };
+
+    // sqlite3_exec reaches here without going through step_stmt's dispatch
+    if let StatementKind::Status(query) = &stmt.statement.kind {
+        *stmt.result_rows.lock().unwrap() = query.rows(db).await?;
+        stmt.column_names = query.column_names();
+        return Ok(SQLITE_OK);
+    }
+
+    let mut params = convert_params_to_json(&stmt.params, &stmt.statement.param_names)?;
+    db.last_truncated.store(false, Ordering::Relaxed);
+
+    if let Some(query) = ClientSide::plan(db, &stmt.sql)? {
+        let response = match attach::route(db, &query.sql)? {
+            Some((database, sql)) => {
+                attach::execute_on_attached(db, &database, &sql, params).await?
+            }
+            None => execute_sql_and_params(db, &query.sql, params, false).await?,
+        };
+        store_result(stmt, get_execution_result(db, &response)?);
+        query.apply(stmt)?;
+        return Ok(SQLITE_OK);
+    }
+
+    // Rewritten queries keep their rows, the counts of a write are all that is read of it
+    params.want_rows = stmt.statement.wants_rows;
+
+    if let Some((database, sql)) = attach::route(db, &stmt.sql)? {
+        let response = attach::execute_on_attached(db, &database, &sql, params).await?;
+        store_result(stmt, get_execution_result(db, &response)?);
+        return Ok(SQLITE_OK);
+    }
+
+    // After the shim's own functions and the attached databases, which the copy knows nothing of
+    #[cfg(feature = "replica")]
+    if let Some(replica) = &db.replica {
+        if replica.read(stmt.db, stmt).await {
+            truncate_rows(db, stmt);
+            return Ok(SQLITE_OK);
+        }
+    }
+
+    let schema_key = {
+        let mut cache = db.schema_cache.lock().unwrap();
+        cache.invalidate_for(&stmt.sql);
+        if db.has_began_transaction() {
+            None
+        } else {
+            cache.key(&stmt.sql, &params)
+        }
+    };
+    let schema_key = match schema_key {
+        Some(key) if check_schema_version(db).await => Some(key),
+        _ => None,
+    };
+    if let Some(hit) = schema_key
+        .as_ref()
+        .and_then(|key| db.schema_cache.lock().unwrap().get(key))
+    {
+        stmt.column_names = hit.column_names;
+        *stmt.result_rows.lock().unwrap() = hit.rows;
+        truncate_rows(db, stmt);
+        return Ok(SQLITE_OK);
+    }
+
+    let cache_key = {
+        let mut cache = db.result_cache.lock().unwrap();
+        cache.invalidate_for(&stmt.sql);
+        if db.has_began_transaction() {
+            None
+        } else {
+            cache.key(&stmt.sql, &params)
+        }
+    };
+    if let Some(hit) = cache_key
+        .as_ref()
+        .and_then(|key| db.result_cache.lock().unwrap().get(key))
+    {
+        stmt.column_names = hit.column_names;
+        *stmt.result_rows.lock().unwrap() = hit.rows;
+        truncate_rows(db, stmt);
+        return Ok(SQLITE_OK);
+    }
 
-    let params = convert_params_to_json(&stmt.params);
-    let response = execute_sql_and_params(db, &stmt.sql, params).await?;
-    let response = get_execution_result(db, &response)?;
+    if !db.has_began_transaction() && write_behind::is_fire_and_forget(&stmt.sql) {
+        // A queue inherited through fork() is restarted once the statement takes the connection
+        let write_behind = db.write_behind.lock().unwrap();
+        if let Some(write_behind) = write_behind.as_ref().filter(|w| !w.is_inherited()) {
+            write_behind.enqueue(&stmt.sql, params)?;
+            stmt.column_names = vec![];
+            return Ok(SQLITE_OK);
+        }
+    }
+
+    if let StatementKind::Session(change) = &stmt.statement.kind {
+        db.session.lock().unwrap().check(change)?;
+    }
+    // One row past the limit tells a truncated result from one that just fits
+    let limited = match db.max_rows.load(Ordering::Relaxed) {
+        0 => None,
+        max_rows => with_limit(&stmt.sql, max_rows.saturating_add(1)),
+    };
+    let response = match (&limited, stmt.group.clone()) {
+        (Some(sql), _) => execute_sql_and_params(db, sql, params, false).await?,
+        (None, Some((group, index))) if !stmt.persistent => {
+            execute_in_group(stmt, params, &group, index).await?
+        }
+        (None, _) => execute_sql_and_params(db, &stmt.sql, params, stmt.persistent).await?,
+    };
+    if changes_schema(&stmt.sql) {
+        db.schema_changed();
+    }
+    store_result(stmt, get_execution_result(db, &response)?);
+    if let StatementKind::Session(change) = &stmt.statement.kind {
+        db.session.lock().unwrap().apply(change, &stmt.sql);
+    }
+    if truncate_rows(db, stmt) {
+        // Not the statement's result, only as much of it as this limit let through
+        return Ok(SQLITE_OK);
+    }
+
+    if let Some(key) = schema_key {
+        let result = CachedResult {
+            column_names: stmt.column_names.clone(),
+            rows: stmt.result_rows.lock().unwrap().clone(),
+        };
+        db.schema_cache.lock().unwrap().insert(key, result);
+    }
+    if let Some(key) = cache_key {
+        let result = CachedResult {
+            column_names: stmt.column_names.clone(),
+            rows: stmt.result_rows.lock().unwrap().clone(),
+        };
+        db.result_cache
+            .lock()
+            .unwrap()
+            .insert(key, &stmt.sql, result);
+    }
+
+    Ok(SQLITE_OK)
+}
+
+// Runs a statement of a script prepared through pz_tail, see group.rs. Unless it already ran
+// ahead, the first run of one with turso.batch_scripts on also sends the statements after it
+// that are prepared and can run ahead.
+async fn execute_in_group(
+    stmt: &SQLite3PreparedStmt,
+    params: StatementArgs,
+    group: &StatementGroup,
+    index: usize,
+) -> Result<RemoteSqliteResponse, SqliteError> {
+    let db: &SQLite3 = unsafe { &*stmt.db };
+    if let Some(result) = group.take(index) {
+        return result;
+    }
+
+    let mut ahead = vec![];
+    if stmt.counters.runs == 0
+        && db.batch_scripts.load(Ordering::Relaxed)
+        && db.max_rows.load(Ordering::Relaxed) == 0
+    {
+        ahead = (index + 1..group.len())
+            .take_while(|next| group.is_prepared(*next))
+            .map_while(|next| run_ahead(db, group.statement(next)))
+            .collect();
+    }
+    let claimed = group.claim(index + 1, index + 1 + ahead.len());
+    if claimed.is_empty() {
+        return execute_sql_and_params(db, &stmt.sql, params, false).await;
+    }
+
+    let mut stmts = vec![params.to_stmt(&stmt.sql)];
+    stmts.extend(ahead.into_iter().skip(claimed.start - index - 1));
+    let batch = Batch::chained(stmts);
+    let mut steps = send_statement(db, &stmt.sql, params, false, Some(batch))
+        .await?
+        .into_steps()
+        .into_iter();
+    let first = steps.next().flatten().unwrap_or_else(|| {
+        Err(SqliteError::new(
+            "No results returned from remote SQLite",
+            None,
+        ))
+    });
+    group.store(claimed.start, steps);
+    first
+}
+
+// The statement to send for `sql` ahead of its own step, if it can be: one that takes no
+// parameters, goes to the server as it is and is neither queued by turso.async_writes nor
+// refused or ignored by the authorizer.
+fn run_ahead(db: &SQLite3, sql: &str) -> Option<Stmt> {
+    let statement = db.statement_cache.lock().unwrap().get_or_parse(sql);
+    let queued = db.write_behind.lock().unwrap().is_some()
+        && !db.has_began_transaction()
+        && write_behind::is_fire_and_forget(sql);
+    // Statements sent in a batch are not journaled one by one
+    let journaled = db.journal.lock().unwrap().is_some();
+    let runs_ahead = statement.kind == StatementKind::Remote
+        && statement.param_count == 0
+        && !queued
+        && !journaled
+        && matches!(ClientSide::plan(db, sql), Ok(None))
+        && matches!(attach::route(db, sql), Ok(None))
+        && matches!(authorize(db, sql), Ok(Authorization::Allowed));
+
+    runs_ahead.then(|| {
+        StatementArgs {
+            want_rows: statement.wants_rows,
+            ..StatementArgs::default()
+        }
+        .to_stmt(sql)
+    })
+}
+
+// Cuts the rows down to PRAGMA turso.max_rows, and records whether any were cut for
+// PRAGMA turso.last_truncated
+fn truncate_rows(db: &SQLite3, stmt: &SQLite3PreparedStmt) -> bool {
+    let max_rows = db.max_rows.load(Ordering::Relaxed);
+    let mut rows = stmt.result_rows.lock().unwrap();
+    let truncated = max_rows > 0 && rows.len() as u64 > max_rows;
+    if truncated {
+        rows.truncate(max_rows as usize);
+        tracing::warn!(
+            sql = %logging::loggable_sql(&stmt.sql),
+            max_rows,
+            "Result truncated to turso.max_rows"
+        );
+    }
+    db.last_truncated.store(truncated, Ordering::Relaxed);
+    truncated
+}
+
+// The schema_version with every column of every table and view, as PRAGMA table_info lists them
+const WARM_UP_SQL: &str = "SELECT (SELECT schema_version FROM pragma_schema_version), m.name, \
+     p.cid, p.name, p.type, p.\"notnull\", p.dflt_value, p.pk \
+     FROM sqlite_schema AS m JOIN pragma_table_info(m.name) AS p \
+     WHERE m.type IN ('table', 'view') ORDER BY m.name, p.cid";
+
+/// Fills the schema cache as soon as the connection is open, for `warmup=on`: one query
+/// brings the `schema_version` and what `PRAGMA table_info` would report for each table, so
+/// the schema checks and table_info calls of the first statements are answered locally.
+pub async fn warm_up(db: *const SQLite3) {
+    let db = unsafe { &*db };
+    if !db.schema_cache.lock().unwrap().enabled {
+        return;
+    }
+
+    let response =
+        match execute_sql_and_params(db, WARM_UP_SQL, StatementArgs::default(), false).await {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::debug!(error = %err, "Failed to warm up the schema cache");
+                return;
+            }
+        };
+    let Ok(result) = response.first_result() else {
+        return;
+    };
+
+    let mut version = None;
+    let mut tables: Vec<(String, Vec<Vec<Value>>)> = Vec::new();
+    for row in &result.rows {
+        let mut values = row.iter().map(RemoteRow::decode);
+        let (Some(Value::Integer(row_version)), Some(Value::Text(table))) =
+            (values.next(), values.next())
+        else {
+            continue;
+        };
+        version = Some(row_version);
+        match tables.last_mut() {
+            Some((last, rows)) if *last == table => rows.push(values.collect()),
+            _ => tables.push((table, vec![values.collect()])),
+        }
+    }
+
+    // Without a table there is nothing to answer, and no version either
+    if let Some(version) = version {
+        tracing::debug!(tables = tables.len(), "Schema cache warmed up");
+        db.schema_cache.lock().unwrap().prime(version, tables);
+    }
+}
+
+// Reads the server's schema_version when the last check is out of date, so the schema cache
+// and described columns follow changes made by other clients. The cache is only used once
+// it is known to be current, a failed check just skips it.
+async fn check_schema_version(db: &SQLite3) -> bool {
+    if !db.schema_cache.lock().unwrap().needs_check() {
+        return true;
+    }
 
+    let response =
+        match execute_sql_and_params(db, "PRAGMA schema_version", StatementArgs::default(), false)
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::debug!(error = %err, "Failed to read the schema version");
+                db.schema_cache.lock().unwrap().check_failed();
+                return false;
+            }
+        };
+    let version = response
+        .first_result()
+        .ok()
+        .and_then(|result| result.rows.first())
+        .and_then(|row| row.first())
+        .map(|value| value.decode());
+    match version {
+        Some(Value::Integer(version)) => {
+            if db.schema_cache.lock().unwrap().observe_version(version) {
+                db.schema_changed();
+            }
+            true
+        }
+        version => {
+            tracing::debug!(?version, "Unexpected answer to PRAGMA schema_version");
+            db.schema_cache.lock().unwrap().check_failed();
+            false
+        }
+    }
+}
+
+// Columns and rows of a finished execution become the statement's result set
+fn store_result(stmt: &mut SQLite3PreparedStmt, response: &QueryResult) {
+    // Like SQLite, only an INSERT that wrote a row moves last_insert_rowid. The server reports
+    // its connection's value after every statement, which on a fresh stream is 0.
+    if stmt.statement.inserts && response.rows_written != Some(0) {
+        let rowid = response
+            .last_insert_rowid
+            .as_deref()
+            .and_then(|r| r.parse().ok());
+        if let Some(rowid) = rowid {
+            let db = unsafe { &*stmt.db };
+            *db.last_insert_rowid.lock().unwrap() = Some(rowid);
+        }
+    }
+
+    stmt.counters.rows_read += response.rows_read.unwrap_or(0);
+    stmt.counters.server_ms += response.query_duration_ms.unwrap_or(0.0);
     stmt.column_names = response.cols.iter().map(|col| col.name.clone()).collect();
+    stmt.remember_column_names();
+    stmt.statement.set_decltypes(&response.cols);
 
     let mut result_rows = stmt.result_rows.lock().unwrap();
     *result_rows = response
         .rows
         .iter()
-        .map(|row| {
-            let result = row
-                .iter()
-                .map(|row| {
-                    if row.value.is_none() {
-                        return Value::Null;
-                    }
-
-                    let value = row.value.as_ref().unwrap();
-
-                    match row.r#type.as_str() {
-                        "integer" => match &value {
-                            serde_json::Value::String(s) => {
-                                Value::Integer(s.parse::<i64>().unwrap_or(0))
-                            }
-                            serde_json::Value::Number(n) => Value::Integer(n.as_i64().unwrap_or(0)),
-                            _ => Value::Integer(0),
-                        },
-                        "float" => Value::Real(value.as_f64().unwrap_or(0.0)),
-                        "text" => Value::Text(value.as_str().unwrap_or("").to_string()),
-                        "null" => Value::Null,
-                        _ => Value::Null,
-                    }
-                })
-                .collect();
-
-            result
-        })
+        .map(|row| row.iter().map(RemoteRow::decode).collect())
         .collect();
-
-    Ok(SQLITE_OK)
 }
 
 async fn execute_sql_and_params(
-    db: &mut SQLite3,
+    db: &SQLite3,
     sql: &str,
-    params: Vec<serde_json::Value>,
+    params: StatementArgs,
+    persistent: bool,
 ) -> Result<RemoteSqliteResponse, SqliteError> {
-    if let transport::ActiveStrategy::Websocket = db.connection.strategy {
-        let mut request = db.connection.get_json_request(db, sql, &params);
-        match db.connection.send(&mut request).await {
-            Ok(response) => return Ok(response),
-            Err(err) => {
-                db.connection.strategy = transport::ActiveStrategy::Http;
-                return Err(err);
+    send_statement(db, sql, params, persistent, None).await
+}
+
+// Sends the statement with `send_once`, journaling it for PRAGMA turso.txn_replay. When the
+// transaction's stream turns out to be gone, the transaction is replayed on a new one and
+// the statement sent again, once.
+async fn send_statement(
+    db: &SQLite3,
+    sql: &str,
+    params: StatementArgs,
+    persistent: bool,
+    mut batch: Option<Batch>,
+) -> Result<RemoteSqliteResponse, SqliteError> {
+    let mut replayed = false;
+    loop {
+        let before = db.state.state();
+        match send_once(db, sql, params.clone(), persistent, batch.take()).await {
+            Err(err) if journal::lost_stream(db, before, &err) => {
+                if replayed || journal::replay(db).await.is_err() {
+                    return Err(journal::abandon(db, err).await);
+                }
+                replayed = true;
+            }
+            result => {
+                journal::record(db, sql, &params, &result);
+                return result;
+            }
+        }
+    }
+}
+
+/// Sends `sql`, or `batch` in its place when given, where the request for `sql` would go:
+/// on the transaction's stream, or a new one with the session replayed. `sql` and `params`
+/// are what is logged and timed.
+pub async fn send_once(
+    db: &SQLite3,
+    sql: &str,
+    params: StatementArgs,
+    persistent: bool,
+    batch: Option<Batch>,
+) -> Result<RemoteSqliteResponse, SqliteError> {
+    let entered = Instant::now();
+    drain_write_behind(db).await;
+
+    // Held until the response's baton is stored, so no other send can pick up a stale one
+    let mut connection = db.lock_connection().await?;
+    if db.has_began_transaction() {
+        db.check_transaction_owner()?;
+    } else {
+        connection.maybe_restore_websocket().await;
+    }
+    connection.set_replication_index(db.replication_index());
+    connection.set_session(db.session.lock().unwrap().statements());
+
+    let request_id = logging::new_request_id();
+    connection.set_request_id(Some(request_id.clone()));
+
+    let span = tracing::info_span!(
+        "statement",
+        request_id = %request_id,
+        sql_hash = %logging::sql_hash(sql),
+        transport = ?connection.strategy.name(),
+        latency_ms = tracing::field::Empty,
+    );
+    let started = Instant::now();
+    let retries = db.metrics.retries();
+
+    let outstanding = db.metrics.start_request();
+    let result = db
+        .within_deadline(
+            async {
+                let mut request = connection.get_json_request(db, sql, &params);
+                // A transport with a request format of its own gets the first statement alone
+                if let Some(batch) = batch {
+                    protocol::into_batch(&mut request, batch);
+                }
+                if persistent {
+                    connection.send_persistent(&mut request).await
+                } else {
+                    connection.send(&mut request).await
+                }
+            }
+            .instrument(span.clone()),
+        )
+        .await;
+    drop(outstanding);
+
+    let latency = started.elapsed();
+    db.metrics.record_query(latency, result.is_err());
+    let execution = result.as_ref().ok().and_then(|response| {
+        response.results.first().and_then(|r| match &r.response {
+            RemoteSQLiteResult::Execute { result } => Some(result),
+            _ => None,
+        })
+    });
+    if let Ok(response) = &result {
+        if let Some(baton) = &response.baton {
+            db.transaction_baton.lock().unwrap().replace(baton.clone());
+        }
+
+        *db.last_query_stats.lock().unwrap() = Some(QueryStats {
+            request_id: request_id.clone(),
+            transport: connection.strategy.name().to_str().unwrap_or_default(),
+            queue: started - entered,
+            round_trip: latency,
+            server_ms: execution.and_then(|e| e.query_duration_ms),
+            rows_read: execution.and_then(|e| e.rows_read),
+            rows_written: execution.and_then(|e| e.rows_written),
+        });
+    }
+    span.record("latency_ms", latency.as_millis() as u64);
+    span.in_scope(|| {
+        match &result {
+            Ok(_) => tracing::debug!("Statement completed"),
+            Err(err) => tracing::debug!(error = %err, "Statement failed"),
+        }
+        slow_query::report(
+            &db.slow_queries,
+            SlowQuery {
+                sql,
+                duration: latency,
+                rows_read: execution.and_then(|e| e.rows_read),
+                rows_written: execution.and_then(|e| e.rows_written),
+                retries: db.metrics.retries() - retries,
+            },
+        );
+    });
+
+    if result.is_err() && connection.strategy == transport::ActiveStrategy::Websocket {
+        connection.on_websocket_error().await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::get_tokio;
+
+    // Steps a statement whose rows already came back, as after the first remote round trip
+    fn step_all(rows: Vec<Vec<Value>>) -> Vec<c_int> {
+        let mut stmt = SQLite3PreparedStmt::new(std::ptr::null_mut(), "SELECT x FROM t");
+        *stmt.result_rows.lock().unwrap() = rows;
+        *stmt.execution_state.lock().unwrap() = ExecutionState::Executing;
+
+        let mut codes = Vec::new();
+        loop {
+            let code = get_tokio().block_on(step_stmt(&mut stmt, false)).unwrap();
+            codes.push(code);
+            if code != SQLITE_ROW {
+                break;
             }
         }
+        codes
     }
 
-    let request = &mut db.connection.get_json_request(db, sql, &params);
-    let result = db.connection.send(request).await;
+    #[test]
+    fn select_without_rows_is_done() {
+        assert_eq!(step_all(vec![]), vec![SQLITE_DONE]);
+    }
 
-    if let Err(e) = result {
-        return Err(SqliteError::new(e.to_string(), Some(SQLITE_ERROR)));
+    #[test]
+    fn select_with_one_row() {
+        assert_eq!(
+            step_all(vec![vec![Value::Integer(1)]]),
+            vec![SQLITE_ROW, SQLITE_DONE]
+        );
     }
 
-    Ok(result.unwrap())
+    #[test]
+    fn select_with_many_rows() {
+        let rows = (0..3).map(|i| vec![Value::Integer(i)]).collect();
+        assert_eq!(
+            step_all(rows),
+            vec![SQLITE_ROW, SQLITE_ROW, SQLITE_ROW, SQLITE_DONE]
+        );
+    }
 }
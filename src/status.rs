@@ -0,0 +1,183 @@
+//! `sqlite3_status` and `sqlite3_db_status` over what this library actually holds. There is
+//! no page cache or lookaside here, so the opcodes monitoring tools poll report the closest
+//! equivalents: memory from `sqlite3_malloc`, cached result rows and statements, and requests
+//! waiting on the server.
+
+use std::{
+    ffi::c_int,
+    mem::size_of,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use crate::{
+    metrics::GLOBAL_METRICS,
+    sqlite::{SQLite3, SQLite3PreparedStmt, SqliteError, Value, SQLITE_ERROR, SQLITE_MISUSE},
+};
+
+pub const SQLITE_STATUS_MEMORY_USED: c_int = 0;
+pub const SQLITE_STATUS_PAGECACHE_USED: c_int = 1;
+pub const SQLITE_STATUS_MALLOC_SIZE: c_int = 5;
+pub const SQLITE_STATUS_MALLOC_COUNT: c_int = 9;
+const SQLITE_STATUS_MAX: c_int = 9;
+
+pub const SQLITE_DBSTATUS_LOOKASIDE_USED: c_int = 0;
+pub const SQLITE_DBSTATUS_CACHE_USED: c_int = 1;
+pub const SQLITE_DBSTATUS_STMT_USED: c_int = 3;
+pub const SQLITE_DBSTATUS_CACHE_HIT: c_int = 7;
+pub const SQLITE_DBSTATUS_CACHE_MISS: c_int = 8;
+pub const SQLITE_DBSTATUS_CACHE_USED_SHARED: c_int = 11;
+const SQLITE_DBSTATUS_MAX: c_int = 12;
+
+pub const SQLITE_STMTSTATUS_FULLSCAN_STEP: c_int = 1;
+pub const SQLITE_STMTSTATUS_SORT: c_int = 2;
+pub const SQLITE_STMTSTATUS_VM_STEP: c_int = 4;
+pub const SQLITE_STMTSTATUS_REPREPARE: c_int = 5;
+pub const SQLITE_STMTSTATUS_RUN: c_int = 6;
+pub const SQLITE_STMTSTATUS_MEMUSED: c_int = 99;
+// Not in SQLite: milliseconds the server spent executing the statement
+pub const SQLITE_STMTSTATUS_TURSO_SERVER_MS: c_int = 1000;
+
+/// Current value and highest value since the last reset.
+pub struct Gauge {
+    current: AtomicI64,
+    highwater: AtomicI64,
+}
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self {
+            current: AtomicI64::new(0),
+            highwater: AtomicI64::new(0),
+        }
+    }
+
+    pub fn add(&self, n: i64) {
+        let current = self.current.fetch_add(n, Ordering::Relaxed) + n;
+        self.highwater.fetch_max(current, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: i64) {
+        self.current.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Replaces the current value, as for sizes where only the largest one matters.
+    pub fn set(&self, n: i64) {
+        self.current.store(n, Ordering::Relaxed);
+        self.highwater.fetch_max(n, Ordering::Relaxed);
+    }
+
+    /// Current and highwater values; a reset brings the highwater down to the current value.
+    pub fn read(&self, reset: bool) -> (i64, i64) {
+        let current = self.current.load(Ordering::Relaxed);
+        let highwater = if reset {
+            self.highwater.swap(current, Ordering::Relaxed)
+        } else {
+            self.highwater.load(Ordering::Relaxed)
+        };
+        (current, highwater.max(current))
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Blocks handed out by `sqlite3_malloc` and not yet freed
+pub static MEMORY_USED: Gauge = Gauge::new();
+pub static MALLOC_COUNT: Gauge = Gauge::new();
+pub static MALLOC_SIZE: Gauge = Gauge::new();
+
+/// Per-statement counters behind `sqlite3_stmt_status`.
+#[derive(Debug, Default)]
+pub struct StmtCounters {
+    pub runs: u64,       // Executions, cache hits included
+    pub rows: u64,       // Rows stepped through
+    pub rows_read: u64,  // Rows the server read to answer
+    pub sorts: u64,      // Result sets sorted on the client for a registered collation
+    pub reprepares: u64, // Columns described again after a schema change
+    pub server_ms: f64,
+}
+
+/// Rough heap footprint of a value, used for the cache size estimates.
+pub fn value_size(value: &Value) -> usize {
+    size_of::<Value>()
+        + match value {
+            Value::Text(s) => s.capacity(),
+            Value::Blob(b) => b.capacity(),
+            _ => 0,
+        }
+}
+
+pub fn status(op: c_int, reset: bool) -> Result<(i64, i64), SqliteError> {
+    Ok(match op {
+        SQLITE_STATUS_MEMORY_USED => MEMORY_USED.read(reset),
+        SQLITE_STATUS_MALLOC_COUNT => MALLOC_COUNT.read(reset),
+        SQLITE_STATUS_MALLOC_SIZE => MALLOC_SIZE.read(reset),
+        SQLITE_STATUS_PAGECACHE_USED => GLOBAL_METRICS.outstanding(reset),
+        // Page cache, scratch and parser stack have nothing behind them here
+        op if (0..=SQLITE_STATUS_MAX).contains(&op) => (0, 0),
+        _ => {
+            return Err(SqliteError::new(
+                format!("unknown status parameter: {}", op),
+                Some(SQLITE_MISUSE),
+            ))
+        }
+    })
+}
+
+pub fn db_status(db: &SQLite3, op: c_int, reset: bool) -> Result<(i64, i64), SqliteError> {
+    Ok(match op {
+        SQLITE_DBSTATUS_LOOKASIDE_USED => db.metrics.outstanding(reset),
+        SQLITE_DBSTATUS_CACHE_USED | SQLITE_DBSTATUS_CACHE_USED_SHARED => {
+            let result_cache = db.result_cache.lock().unwrap().memory_used();
+            let schema_cache = db.schema_cache.lock().unwrap().memory_used();
+            ((result_cache + schema_cache) as i64, 0)
+        }
+        SQLITE_DBSTATUS_STMT_USED => (db.statement_cache.lock().unwrap().memory_used() as i64, 0),
+        SQLITE_DBSTATUS_CACHE_HIT => (db.result_cache.lock().unwrap().hits(reset) as i64, 0),
+        SQLITE_DBSTATUS_CACHE_MISS => (db.result_cache.lock().unwrap().misses(reset) as i64, 0),
+        op if (0..=SQLITE_DBSTATUS_MAX).contains(&op) => (0, 0),
+        _ => {
+            return Err(SqliteError::new(
+                format!("unknown database status parameter: {}", op),
+                Some(SQLITE_ERROR),
+            ))
+        }
+    })
+}
+
+pub fn stmt_status(stmt: &mut SQLite3PreparedStmt, op: c_int, reset: bool) -> c_int {
+    fn take<T: Default + Copy>(value: &mut T, reset: bool) -> T {
+        if reset {
+            std::mem::take(value)
+        } else {
+            *value
+        }
+    }
+
+    let counters = &mut stmt.counters;
+    let value = match op {
+        SQLITE_STMTSTATUS_FULLSCAN_STEP => take(&mut counters.rows_read, reset),
+        SQLITE_STMTSTATUS_SORT => take(&mut counters.sorts, reset),
+        SQLITE_STMTSTATUS_VM_STEP => take(&mut counters.rows, reset),
+        SQLITE_STMTSTATUS_REPREPARE => take(&mut counters.reprepares, reset),
+        SQLITE_STMTSTATUS_RUN => take(&mut counters.runs, reset),
+        SQLITE_STMTSTATUS_TURSO_SERVER_MS => take(&mut counters.server_ms, reset).round() as u64,
+        // Like SQLite's, the memory figure is a current size and ignores the reset flag
+        SQLITE_STMTSTATUS_MEMUSED => {
+            let rows = stmt.result_rows.lock().unwrap();
+            (size_of::<SQLite3PreparedStmt>()
+                + stmt.sql.capacity()
+                + stmt
+                    .column_names
+                    .iter()
+                    .map(|c| c.capacity())
+                    .sum::<usize>()
+                + rows.iter().flatten().map(value_size).sum::<usize>()) as u64
+        }
+        _ => 0,
+    };
+    value.min(c_int::MAX as u64) as c_int
+}
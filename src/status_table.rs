@@ -0,0 +1,148 @@
+//! `turso_status`, a table that exists only on the client and describes the connection as
+//! `name`/`value` rows, so dashboards can read it with plain SQL. Queries of the forms
+//! `SELECT * FROM turso_status`, `SELECT name, value FROM ...` and
+//! `SELECT value FROM turso_status WHERE name = '...'` are answered without a round trip.
+
+use crate::{
+    sql::{
+        select::{is_keyword, spanned_tokens},
+        tokenizer::{unquote, Token},
+    },
+    sqlite::{SQLite3, SqliteError, Value},
+};
+
+pub const TABLE_NAME: &str = "turso_status";
+
+const COLUMNS: [&str; 2] = ["name", "value"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusQuery {
+    columns: Vec<usize>,  // Indexes into COLUMNS, in the order selected
+    name: Option<String>, // From `WHERE name = '...'`
+}
+
+impl StatusQuery {
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|&i| COLUMNS[i].to_string())
+            .collect()
+    }
+
+    /// The selected columns of the rows the query asks for.
+    pub async fn rows(&self, db: &SQLite3) -> Result<Vec<Vec<Value>>, SqliteError> {
+        let rows = status(db).await?;
+        Ok(rows
+            .into_iter()
+            .filter(|(name, _)| self.name.as_deref().is_none_or(|wanted| wanted == *name))
+            .map(|(name, value)| {
+                let row = [Value::Text(name.to_string()), value];
+                self.columns.iter().map(|&i| row[i].clone()).collect()
+            })
+            .collect())
+    }
+}
+
+/// The query `sql` runs on `turso_status`, `None` for any other statement, which goes to
+/// the server as usual.
+pub fn parse(sql: &str) -> Option<StatusQuery> {
+    let tokens = spanned_tokens(sql);
+    let mut tokens: Vec<&Token> = tokens.iter().map(|spanned| &spanned.token).collect();
+    if let Some(Token::Punct(";")) = tokens.last() {
+        tokens.pop();
+    }
+    if !is_keyword(tokens.first()?, "SELECT") {
+        return None;
+    }
+
+    let from = tokens.iter().position(|token| is_keyword(token, "FROM"))?;
+    let columns = match &tokens[1..from] {
+        [Token::Punct("*")] => vec![0, 1],
+        projection => {
+            let mut columns = vec![];
+            for (i, token) in projection.iter().enumerate() {
+                match token {
+                    Token::Punct(",") if i % 2 == 1 => (),
+                    Token::Identifier(word) if i % 2 == 0 => {
+                        let word = unquote(word);
+                        columns.push(COLUMNS.iter().position(|column| *column == word)?);
+                    }
+                    _ => return None,
+                }
+            }
+            (projection.len() % 2 == 1).then_some(columns)?
+        }
+    };
+
+    let mut rest = &tokens[from + 1..];
+    if let [Token::Identifier(schema), Token::Punct("."), tail @ ..] = rest {
+        if unquote(schema) != "main" {
+            return None;
+        }
+        rest = tail;
+    }
+    let name = match rest {
+        [Token::Identifier(table)] if unquote(table) == TABLE_NAME => None,
+        [Token::Identifier(table), filter, Token::Identifier(column), Token::Punct("="), Token::Literal(literal)]
+            if unquote(table) == TABLE_NAME
+                && is_keyword(filter, "WHERE")
+                && literal.starts_with('\'')
+                && unquote(column) == "name" =>
+        {
+            Some(literal[1..literal.len() - 1].replace("''", "'"))
+        }
+        _ => return None,
+    };
+
+    Some(StatusQuery { columns, name })
+}
+
+// Every row of the table
+async fn status(db: &SQLite3) -> Result<Vec<(&'static str, Value)>, SqliteError> {
+    let (transport, endpoint, protocol) = {
+        let connection = db.lock_connection().await?;
+        (
+            connection.strategy.name().to_string_lossy().into_owned(),
+            connection.endpoint(),
+            connection.protocol(),
+        )
+    };
+    let text = |value: Option<String>| value.map_or(Value::Null, Value::Text);
+
+    Ok(vec![
+        ("transport", Value::Text(transport)),
+        ("endpoint", Value::Text(endpoint)),
+        ("protocol", Value::Text(protocol.to_string())),
+        ("region", text(db.state.region())),
+        ("state", Value::Text(db.state.name().to_string())),
+        (
+            "replication_index",
+            db.replication_index()
+                .map_or(Value::Null, |index| Value::Integer(index as i64)),
+        ),
+        (
+            "uptime_ms",
+            Value::Integer(db.opened_at.elapsed().as_millis() as i64),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_only_queries_of_the_table() {
+        let query = parse("select * from main.turso_status;").unwrap();
+        assert_eq!(query.column_names(), vec!["name", "value"]);
+
+        let query = parse("SELECT value FROM \"turso_status\" WHERE name = 'region'").unwrap();
+        assert_eq!(query.column_names(), vec!["value"]);
+        assert_eq!(query.name.as_deref(), Some("region"));
+
+        assert_eq!(parse("SELECT * FROM turso_status_log"), None);
+        assert_eq!(parse("SELECT count(*) FROM turso_status"), None);
+        assert_eq!(parse("SELECT * FROM aux.turso_status"), None);
+        assert_eq!(parse("DELETE FROM turso_status"), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! `sqlite3_get_table`: a query's whole result as one array of strings, the column names
+//! first and then each row, for C code written against the legacy interface. The array and
+//! its strings are a single `sqlite3_malloc` block, so `sqlite3_free_table` releases it in
+//! one call. Results are collected row by row from `sqlite3_exec` and refused once they
+//! would take more than the connection's `table_limit` bytes.
+
+use std::{
+    ffi::{c_int, c_void, CStr, CString},
+    os::raw::c_char,
+};
+
+use crate::{
+    sqlite::{SQLITE_ERROR, SQLITE_TOOBIG},
+    utils::{allocate, release},
+};
+
+pub const DEFAULT_TABLE_LIMIT: usize = 64 * 1024 * 1024;
+
+const POINTER: usize = std::mem::size_of::<*mut c_char>();
+
+pub struct Table {
+    columns: usize,
+    cells: Vec<Option<CString>>, // Column names, then the rows, one cell after another
+    bytes: usize,                // Size of the block the cells will be copied into
+    limit: usize,                // Zero for no limit
+    pub error: Option<(String, c_int)>,
+}
+
+impl Table {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            columns: 0,
+            cells: Vec::new(),
+            // The first slot holds the number of cells, as SQLite's own does
+            bytes: POINTER,
+            limit,
+            error: None,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        match self.columns {
+            0 => 0,
+            columns => self.cells.len() / columns - 1,
+        }
+    }
+
+    // Adds one row, or the names and one row for the first, unless that goes over the limit
+    unsafe fn push_row(&mut self, values: &[*mut c_char], names: &[*mut c_char]) -> bool {
+        if self.cells.is_empty() {
+            self.columns = names.len();
+            if !self.push(names) {
+                return false;
+            }
+        } else if values.len() != self.columns {
+            self.error = Some((
+                "sqlite3_get_table() called with two or more incompatible queries".to_string(),
+                SQLITE_ERROR,
+            ));
+            return false;
+        }
+        self.push(values)
+    }
+
+    unsafe fn push(&mut self, cells: &[*mut c_char]) -> bool {
+        for &cell in cells {
+            let cell = (!cell.is_null()).then(|| CStr::from_ptr(cell).to_owned());
+            self.bytes += POINTER
+                + cell
+                    .as_ref()
+                    .map_or(0, |cell| cell.as_bytes_with_nul().len());
+            self.cells.push(cell);
+        }
+
+        if self.limit > 0 && self.bytes > self.limit {
+            self.error = Some((
+                format!(
+                    "sqlite3_get_table() result is larger than turso.table_limit ({} bytes)",
+                    self.limit
+                ),
+                SQLITE_TOOBIG,
+            ));
+            return false;
+        }
+        true
+    }
+
+    /// Copies the cells into one block: their count, a pointer per cell, then the strings
+    /// the pointers point at. Returns the pointer after the count, which is what the caller
+    /// receives and hands back to `free_table`.
+    pub fn into_block(self) -> *mut *mut c_char {
+        let block = allocate(self.bytes) as *mut *mut c_char;
+        if block.is_null() {
+            return block;
+        }
+
+        unsafe {
+            block.write(self.cells.len() as *mut c_char);
+            let slots = block.add(1);
+            let mut text = slots.add(self.cells.len()) as *mut c_char;
+            for (index, cell) in self.cells.iter().enumerate() {
+                let Some(cell) = cell else {
+                    slots.add(index).write(std::ptr::null_mut());
+                    continue;
+                };
+                let bytes = cell.as_bytes_with_nul();
+                std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, text, bytes.len());
+                slots.add(index).write(text);
+                text = text.add(bytes.len());
+            }
+            slots
+        }
+    }
+}
+
+/// The `sqlite3_exec` callback filling the `Table` passed as its argument.
+pub unsafe extern "C" fn collect(
+    arg: *mut c_void,
+    column_count: c_int,
+    column_values: *mut *mut c_char,
+    column_names: *mut *mut c_char,
+) -> c_int {
+    let table = &mut *(arg as *mut Table);
+    let count = column_count.max(0) as usize;
+    let values = std::slice::from_raw_parts(column_values, count);
+    let names = std::slice::from_raw_parts(column_names, count);
+    !table.push_row(values, names) as c_int
+}
+
+/// Releases a result of `into_block`.
+pub unsafe fn free_table(result: *mut *mut c_char) {
+    if !result.is_null() {
+        release(result.sub(1) as *mut u8);
+    }
+}
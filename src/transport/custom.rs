@@ -0,0 +1,67 @@
+//! Transports supplied by the application, for deployments that reach libSQL some other way,
+//! e.g. gRPC or a unix socket to a sidecar. A transport is registered under a name once per
+//! process and picked with `transport=custom:<name>`. Most only need to carry `/v2/pipeline`
+//! bodies and can implement `Pipeline`, which provides the rest of `LibsqlInterface`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    sqlite::{SqliteError, SQLITE_CANTOPEN},
+    transport::{LibsqlInterface, TursoConfig},
+};
+
+/// Builds a transport for a connection to the database `TursoConfig` names.
+pub type TransportFactory =
+    dyn Fn(&TursoConfig) -> Result<Box<dyn LibsqlInterface + Send>, SqliteError> + Send + Sync;
+
+static TRANSPORTS: Mutex<Option<HashMap<String, Arc<TransportFactory>>>> = Mutex::new(None);
+
+/// Makes `factory` available to connections opened with `transport=custom:<name>`. A name
+/// registered again is replaced for connections opened afterwards.
+///
+/// ```no_run
+/// # use sqlite3::client::{register_transport, Connection, Error, LibsqlInterface};
+/// # fn sidecar(url: &str) -> Box<dyn LibsqlInterface + Send> { unimplemented!() }
+/// # async fn example() -> Result<(), Error> {
+/// register_transport("sidecar", |config| Ok(sidecar(&config.db_url)));
+/// let db = Connection::open("my-db?transport=custom:sidecar").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "rust-api")]
+pub fn register_transport(
+    name: &str,
+    factory: impl Fn(&TursoConfig) -> Result<Box<dyn LibsqlInterface + Send>, SqliteError>
+        + Send
+        + Sync
+        + 'static,
+) {
+    let mut transports = TRANSPORTS.lock().unwrap();
+    transports
+        .get_or_insert_with(HashMap::new)
+        .insert(name.to_string(), Arc::new(factory));
+}
+
+/// A new transport from the factory registered as `name`.
+pub fn create(
+    name: &str,
+    config: &TursoConfig,
+) -> Result<Box<dyn LibsqlInterface + Send>, SqliteError> {
+    let factory = TRANSPORTS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|transports| transports.get(name).cloned())
+        .ok_or_else(|| {
+            SqliteError::new(
+                format!("No transport registered as '{}'", name),
+                Some(SQLITE_CANTOPEN),
+            )
+        })?;
+
+    // Called without the lock, so a factory may register transports itself
+    factory(config)
+}
@@ -1,96 +1,205 @@
-use std::{sync::Arc, time::Duration};
+use std::{io::Write, sync::Arc, time::Duration};
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
 
 use crate::{
-    sqlite::{SqliteError, SQLITE_ERROR},
-    transport::{LibsqlInterface, RemoteSqliteResponse, TursoConfig},
+    config::Compression,
+    config::DEFAULT_REQUEST_TIMEOUT,
+    connection_state::ConnectionState,
+    logging,
+    metrics::Metrics,
+    protocol::{
+        describe_columns, result_error, status_code, to_json, PipelineReq, RemoteCol,
+        RemoteSqliteResponse, StatementArgs, Stmt, StreamRequest,
+    },
+    sqlite::{
+        SqliteError, SQLITE_AUTH, SQLITE_BUSY_TIMEOUT, SQLITE_ERROR, SQLITE_FULL, SQLITE_PERM,
+    },
+    transport::{resolver::Hosts, LibsqlInterface, TransportFuture},
 };
 
+pub const REPLICATION_INDEX_HEADER: &str = "x-turso-replication-index";
+
+// Bodies smaller than this are cheaper to send as-is than to compress
+const COMPRESSION_THRESHOLD_BYTES: usize = 4 * 1024;
+
+#[derive(Clone)]
 pub struct HttpStrategy {
     client: reqwest::Client,
-    turso_config: Arc<TursoConfig>,
+    base_url: String,               // scheme://host of the Hrana HTTP endpoint
+    hosts: Option<Arc<Hosts>>,      // The database's host and its failover hosts
+    authorization: Option<String>,  // Bearer token or basic credentials, if required
+    replication_index: Option<u64>, // Highest replication index observed by this connection
+    compression: Compression,
+    timeout: Duration,
+    request_id: Option<String>, // Sent as x-request-id with the next request
+    session: Vec<String>,       // Replayed first on every new stream
+    metrics: Arc<Metrics>,
+    state: Option<Arc<ConnectionState>>, // Told whether requests reach the server
 }
 
 impl HttpStrategy {
-    pub fn new(client: reqwest::Client, turso_config: Arc<TursoConfig>) -> Self {
+    pub fn new(
+        client: reqwest::Client,
+        base_url: String,
+        authorization: Option<String>,
+        compression: Compression,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             client,
-            turso_config,
+            base_url,
+            hosts: None,
+            authorization,
+            replication_index: None,
+            compression,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            request_id: None,
+            session: Vec::new(),
+            metrics,
+            state: None,
         }
     }
-}
 
-impl LibsqlInterface for HttpStrategy {
-    async fn get_transaction_baton(&mut self, sql: &str) -> Result<String, SqliteError> {
-        let mut request = serde_json::json!({
-            "requests": [
-                {
-                    "type": "execute",
-                    "stmt": {
-                        "sql": sql
-                    }
-                }
-            ]
-        });
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
 
-        let result = self.send(&mut request).await;
-        if let Err(e) = result {
-            return Err(SqliteError::new(
-                format!("Failed to get transaction baton: {}", e),
-                Some(SQLITE_ERROR),
-            ));
+    pub fn set_request_id(&mut self, request_id: Option<String>) {
+        self.request_id = request_id;
+    }
+
+    /// Swaps in a new client, returning the old one with its pooled connections.
+    pub fn replace_client(&mut self, client: reqwest::Client) -> reqwest::Client {
+        std::mem::replace(&mut self.client, client)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn set_hosts(&mut self, hosts: Arc<Hosts>) {
+        self.hosts = Some(hosts);
+    }
+
+    pub fn set_state(&mut self, state: Arc<ConnectionState>) {
+        self.state = Some(state);
+    }
+
+    fn reached(&self, reachable: bool) {
+        if let Some(state) = &self.state {
+            state.set_reachable(reachable);
         }
-        let result = result.unwrap();
-        let baton = result.baton.ok_or(SqliteError::new(
-            "Failed to get transaction baton",
-            Some(SQLITE_ERROR),
-        ))?;
+    }
+
+    /// The endpoint on the host currently in use.
+    pub fn url(&self) -> String {
+        match &self.hosts {
+            Some(hosts) => hosts.url(&self.base_url),
+            None => self.base_url.clone(),
+        }
+    }
 
-        Ok(baton)
+    pub fn set_replication_index(&mut self, index: Option<u64>) {
+        self.replication_index = index;
     }
 
-    async fn send(
-        &mut self,
-        request: &mut serde_json::Value,
-    ) -> Result<RemoteSqliteResponse, SqliteError> {
+    pub fn set_session(&mut self, statements: Vec<String>) {
+        self.session = statements;
+    }
+
+    /// Posts a pipeline request with retries and returns the decoded JSON body, leaving the
+    /// per-request results for the caller to inspect.
+    pub async fn send_raw(
+        &self,
+        request: &serde_json::Value,
+    ) -> Result<serde_json::Value, SqliteError> {
         const MAX_ATTEMPTS: usize = 5;
         let mut last_error = String::new();
 
+        let body = serde_json::to_vec(&request).map_err(|e| {
+            SqliteError::new(
+                format!("Failed to serialize request: {}", e),
+                Some(SQLITE_ERROR),
+            )
+        })?;
+        let (body, content_encoding) = compress_body(body, self.compression)?;
+
         for attempt in 1..=MAX_ATTEMPTS {
-            if cfg!(debug_assertions) {
-                println!(
-                    "Attempt {}: Sending request to {}",
-                    attempt, self.turso_config.db_url
-                );
+            let url = self.url();
+            tracing::debug!(attempt, %url, "Sending pipeline request");
+            if attempt > 1 {
+                self.metrics.record_retry();
             }
+            self.metrics.record_bytes(body.len() as u64, 0);
 
-            let resp = self
+            let mut builder = self
                 .client
-                .post(format!("https://{}/v2/pipeline", self.turso_config.db_url))
-                .header("Content-Type", "application/json")
-                .header(
-                    "Authorization",
-                    format!("Bearer {}", self.turso_config.db_token),
-                )
-                .json(&request)
-                .send()
-                .await;
+                .post(format!("{}/v2/pipeline", url))
+                .timeout(self.timeout)
+                .header("Content-Type", "application/json");
+
+            if let Some(authorization) = &self.authorization {
+                builder = builder.header("Authorization", authorization);
+            }
+
+            // Ask the replica to catch up with our own writes before serving the request
+            if let Some(index) = self.replication_index {
+                builder = builder.header(REPLICATION_INDEX_HEADER, index.to_string());
+            }
+
+            if let Some(request_id) = &self.request_id {
+                builder = builder.header("x-request-id", request_id);
+            }
+
+            if let Some(encoding) = content_encoding {
+                builder = builder.header("Content-Encoding", encoding);
+            }
+
+            let resp = builder.body(body.clone()).send().await;
 
             let resp = match resp {
-                Ok(r) => r,
+                Ok(r) => {
+                    self.reached(true);
+                    if let Some(state) = &self.state {
+                        state.observe_region(|name| r.headers().get(name)?.to_str().ok());
+                    }
+                    r
+                }
+                Err(e) if e.is_timeout() => {
+                    // Retrying would multiply the caller's deadline, report it straight away
+                    return Err(SqliteError::new(
+                        format!("Request timed out after {}ms", self.timeout.as_millis()),
+                        Some(SQLITE_BUSY_TIMEOUT),
+                    ));
+                }
                 Err(e) => {
                     last_error = format!("Request failed: {}", e);
+                    // The next attempt goes to the next host, if the database has more
+                    if let Some(hosts) = self.hosts.as_ref().filter(|_| e.is_connect()) {
+                        hosts.fail_over(&url);
+                    }
                     if attempt < MAX_ATTEMPTS {
                         tokio::time::sleep(Duration::from_millis(100)).await;
                         continue;
                     } else {
+                        self.reached(false);
                         return Err(SqliteError::new(last_error, Some(SQLITE_ERROR)));
                     }
                 }
             };
 
             let status = resp.status();
+            let retry_after = retry_after(resp.headers());
             let text = match resp.text().await {
-                Ok(t) => t,
+                Ok(t) => {
+                    self.metrics.record_bytes(0, t.len() as u64);
+                    t
+                }
                 Err(e) => {
                     last_error = format!("Failed to read response body: {}", e);
                     if attempt < MAX_ATTEMPTS {
@@ -102,9 +211,11 @@ impl LibsqlInterface for HttpStrategy {
                 }
             };
 
-            if cfg!(debug_assertions) {
-                println!("Response received, status: {} : {}", status, text);
-            }
+            tracing::trace!(
+                %status,
+                body = %logging::loggable_body(&text),
+                "Pipeline response received"
+            );
 
             if !status.is_success() {
                 if let Ok(err_json) = serde_json::from_str::<serde_json::Value>(&text) {
@@ -117,36 +228,105 @@ impl LibsqlInterface for HttpStrategy {
                     last_error = format!("HTTP error {} with invalid JSON: {}", status, text);
                 }
 
-                if attempt < MAX_ATTEMPTS {
-                    tokio::time::sleep(Duration::from_millis(100)).await;
+                let code = status_code(status.as_u16(), &last_error);
+                // Rejected credentials and an exhausted quota stay that way however often
+                // the request is sent
+                let permanent = matches!(code & 0xff, SQLITE_AUTH | SQLITE_PERM | SQLITE_FULL);
+                if attempt < MAX_ATTEMPTS && !permanent {
+                    // The server's own estimate, within the time a single request may take
+                    let delay = retry_after.unwrap_or(Duration::from_millis(100));
+                    tokio::time::sleep(delay.min(self.timeout)).await;
                     continue;
                 } else {
-                    return Err(SqliteError::new(last_error, Some(SQLITE_ERROR)));
+                    return Err(SqliteError::new(last_error, Some(code)));
                 }
             }
 
-            let parsed: serde_json::Value = match serde_json::from_str(&text) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(SqliteError::new(
-                        format!("Failed to parse JSON: {}", e),
-                        Some(SQLITE_ERROR),
-                    ))
-                }
+            return serde_json::from_str(&text).map_err(|e| {
+                SqliteError::new(format!("Failed to parse JSON: {}", e), Some(SQLITE_ERROR))
+            });
+        }
+
+        Err(SqliteError::new(last_error, Some(SQLITE_ERROR)))
+    }
+}
+
+/// Hrana over HTTP, where every request is one `/v2/pipeline` exchange. Implementors get
+/// `LibsqlInterface` from it.
+pub trait Pipeline: Send + Sync {
+    /// Sends a pipeline request body and returns the decoded response body.
+    fn pipeline<'a>(
+        &'a self,
+        request: &'a serde_json::Value,
+    ) -> TransportFuture<'a, serde_json::Value>;
+
+    /// Session statements to replay first on every new stream.
+    fn session(&self) -> &[String];
+}
+
+impl Pipeline for HttpStrategy {
+    fn pipeline<'a>(
+        &'a self,
+        request: &'a serde_json::Value,
+    ) -> TransportFuture<'a, serde_json::Value> {
+        Box::pin(self.send_raw(request))
+    }
+
+    fn session(&self) -> &[String] {
+        &self.session
+    }
+}
+
+impl<P: Pipeline> LibsqlInterface for P {
+    fn get_transaction_baton<'a>(&'a mut self, sql: &'a str) -> TransportFuture<'a, String> {
+        Box::pin(async move {
+            let mut request = to_json(&PipelineReq {
+                baton: None,
+                requests: vec![StreamRequest::execute(Stmt::new(sql))],
+            });
+
+            let result = self.send(&mut request).await;
+            if let Err(e) = result {
+                return Err(SqliteError::new(
+                    format!("Failed to get transaction baton: {}", e),
+                    Some(SQLITE_ERROR),
+                ));
+            }
+            let result = result.unwrap();
+            let baton = result.baton.ok_or(SqliteError::new(
+                "Failed to get transaction baton",
+                Some(SQLITE_ERROR),
+            ))?;
+
+            Ok(baton)
+        })
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        request: &'a mut serde_json::Value,
+    ) -> TransportFuture<'a, RemoteSqliteResponse> {
+        Box::pin(async move {
+            // Without a baton the request opens a new stream, so the session is replayed first
+            let session = match request.get("baton") {
+                None => self.session(),
+                Some(_) => &[],
+            };
+            let mut parsed = if session.is_empty() {
+                self.pipeline(request).await?
+            } else {
+                self.pipeline(&with_session(request, session)).await?
             };
 
             // Check for embedded DB errors
             if let Some(results) = parsed.get("results").and_then(|r| r.as_array()) {
-                for result in results {
-                    if let Some(msg) = result
-                        .get("error")
-                        .and_then(|e| e.get("message"))
-                        .and_then(|m| m.as_str())
-                    {
-                        return Err(SqliteError::new(msg.to_string(), Some(SQLITE_ERROR)));
-                    }
+                if let Some(error) = results.iter().find_map(result_error) {
+                    return Err(error);
                 }
             }
+            if let Some(results) = parsed.get_mut("results").and_then(|r| r.as_array_mut()) {
+                results.drain(..session.len().min(results.len()));
+            }
 
             let parsed: RemoteSqliteResponse = serde_json::from_value(parsed).map_err(|e| {
                 SqliteError::new(
@@ -154,41 +334,178 @@ impl LibsqlInterface for HttpStrategy {
                     Some(SQLITE_ERROR),
                 )
             })?;
-            return Ok(parsed);
-        }
+            Ok(parsed)
+        })
+    }
 
-        Err(SqliteError::new(last_error, Some(SQLITE_ERROR)))
+    fn close_stream<'a>(&'a mut self, baton: &'a str) -> TransportFuture<'a, ()> {
+        Box::pin(async move {
+            let mut request = to_json(&PipelineReq {
+                baton: Some(Some(baton.to_string())),
+                requests: vec![StreamRequest::Close],
+            });
+
+            self.send(&mut request).await?;
+            Ok(())
+        })
+    }
+
+    fn ping(&mut self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            // An empty pipeline still authenticates the request but executes nothing
+            let mut request = to_json(&PipelineReq {
+                baton: Some(None),
+                requests: vec![],
+            });
+
+            self.send(&mut request).await?;
+            Ok(())
+        })
+    }
+
+    fn describe<'a>(&'a mut self, sql: &'a str) -> TransportFuture<'a, Vec<RemoteCol>> {
+        Box::pin(async move {
+            let request = to_json(&PipelineReq::autocommit(vec![StreamRequest::Describe {
+                stream_id: None,
+                sql: sql.to_string(),
+            }]));
+
+            // Runs on a new stream, which needs the session's temporary objects too
+            let session = self.session();
+            let response = self.pipeline(&with_session(&request, session)).await?;
+            let result = response
+                .get("results")
+                .and_then(|r| r.get(session.len()))
+                .cloned()
+                .unwrap_or_default();
+
+            describe_columns(&result)
+        })
     }
 
     fn get_json_request(
         &self,
         sql: &str,
-        params: &Vec<serde_json::Value>,
+        params: &StatementArgs,
         baton: Option<&String>,
         is_transacting: bool,
     ) -> serde_json::Value {
-        let mut query_request = serde_json::Map::new();
+        let execute = StreamRequest::execute(params.to_stmt(sql));
+        let request = if is_transacting {
+            PipelineReq {
+                baton: Some(baton.cloned()),
+                requests: vec![execute],
+            }
+        } else {
+            PipelineReq::autocommit(vec![execute])
+        };
 
-        let mut json_array: Vec<serde_json::Value> = Vec::new();
+        to_json(&request)
+    }
+}
 
-        json_array.push(serde_json::json!({
-        "type": "execute",
-        "stmt": {
-            "sql": sql,
-            "args": params
-                }
-            }));
+// Only the delay-seconds form; an HTTP date falls back to the default delay
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
 
-        if is_transacting {
-            query_request.insert("baton".to_string(), serde_json::json!(baton));
-        } else {
-            json_array.push(serde_json::json!({
-                "type": "close"
-            }));
+fn with_session(request: &serde_json::Value, statements: &[String]) -> serde_json::Value {
+    let mut request = request.clone();
+    if let Some(requests) = request["requests"].as_array_mut() {
+        let statements = statements
+            .iter()
+            .map(|sql| to_json(&StreamRequest::execute(Stmt::new(sql))));
+        requests.splice(0..0, statements);
+    }
+    request
+}
+
+fn compress_body(
+    body: Vec<u8>,
+    compression: Compression,
+) -> Result<(Vec<u8>, Option<&'static str>), SqliteError> {
+    if body.len() < COMPRESSION_THRESHOLD_BYTES {
+        return Ok((body, None));
+    }
+
+    let to_error = |e: std::io::Error| {
+        SqliteError::new(
+            format!("Failed to compress request body: {}", e),
+            Some(SQLITE_ERROR),
+        )
+    };
+
+    match compression {
+        Compression::Off => Ok((body, None)),
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&body).map_err(to_error)?;
+            Ok((encoder.finish().map_err(to_error)?, Some("gzip")))
+        }
+        Compression::Brotli => {
+            let mut compressed = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                encoder.write_all(&body).map_err(to_error)?;
+            }
+            Ok((compressed, Some("br")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn body(len: usize) -> Vec<u8> {
+        br#"{"sql":"INSERT INTO t VALUES (?)"}"#.iter().copied().cycle().take(len).collect()
+    }
+
+    #[test]
+    fn small_bodies_are_sent_as_is() {
+        for compression in [Compression::Off, Compression::Gzip, Compression::Brotli] {
+            let small = body(COMPRESSION_THRESHOLD_BYTES - 1);
+            let (sent, encoding) = compress_body(small.clone(), compression).unwrap();
+            assert_eq!(sent, small);
+            assert_eq!(encoding, None);
         }
 
-        query_request.insert("requests".to_string(), json_array.into());
+        let large = body(COMPRESSION_THRESHOLD_BYTES * 4);
+        let (sent, encoding) = compress_body(large.clone(), Compression::Off).unwrap();
+        assert_eq!(sent, large);
+        assert_eq!(encoding, None);
+    }
+
+    #[test]
+    fn large_bodies_round_trip_through_gzip_and_brotli() {
+        let large = body(COMPRESSION_THRESHOLD_BYTES * 4);
+
+        let (sent, encoding) = compress_body(large.clone(), Compression::Gzip).unwrap();
+        assert_eq!(encoding, Some("gzip"));
+        assert!(sent.len() < large.len());
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(sent.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, large);
 
-        serde_json::Value::from(query_request)
+        let (sent, encoding) = compress_body(large.clone(), Compression::Brotli).unwrap();
+        assert_eq!(encoding, Some("br"));
+        assert!(sent.len() < large.len());
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(sent.as_slice(), 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, large);
     }
 }
@@ -0,0 +1,163 @@
+//! Transport for hermetic tests. With `transport=mock:<path>` requests are answered from a
+//! fixture file and nothing goes over the network; `transport=record:<path>` talks to the
+//! real database over HTTP and writes every exchange to the file, to be replayed later.
+//!
+//! A fixture holds one exchange per line, `{"request": ..., "response": ...}`, with the
+//! bodies of a Hrana `/v2/pipeline` call. A request is answered by the first exchange not
+//! used yet whose request is equal to it, so hand-written fixtures need not follow the
+//! order statements run in.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sqlite::{SqliteError, SQLITE_CANTOPEN, SQLITE_ERROR, SQLITE_IOERR},
+    transport::{
+        http::{HttpStrategy, Pipeline},
+        TransportFuture,
+    },
+};
+
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    request: serde_json::Value,
+    response: serde_json::Value,
+}
+
+enum Fixture {
+    Replay(Mutex<Vec<Option<Exchange>>>), // Taken once used
+    Record(Mutex<File>),
+}
+
+// Every request a fixture answered, for tests of requests that fail silently when unanswered
+#[cfg(test)]
+static ANSWERED: Mutex<Vec<(PathBuf, serde_json::Value)>> = Mutex::new(Vec::new());
+
+/// The requests the fixture at `path` has answered so far, in order.
+#[cfg(test)]
+pub fn answered(path: &Path) -> Vec<serde_json::Value> {
+    ANSWERED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(answered, _)| answered == path)
+        .map(|(_, request)| request.clone())
+        .collect()
+}
+
+pub struct MockStrategy {
+    path: PathBuf,
+    fixture: Fixture,
+    http: HttpStrategy, // Sends the requests being recorded
+}
+
+impl MockStrategy {
+    /// Serves the exchanges in the fixture at `path`.
+    pub fn replay(path: &Path, http: HttpStrategy) -> Result<Self, SqliteError> {
+        let file = File::open(path).map_err(|e| fixture_error(path, e))?;
+
+        let mut exchanges = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| fixture_error(path, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange = serde_json::from_str(&line)
+                .map_err(|e| fixture_error(path, format!("line {}: {}", number + 1, e)))?;
+            exchanges.push(Some(exchange));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            fixture: Fixture::Replay(Mutex::new(exchanges)),
+            http,
+        })
+    }
+
+    /// Sends requests with `http` and writes the exchanges to a new fixture at `path`.
+    pub fn record(path: &Path, http: HttpStrategy) -> Result<Self, SqliteError> {
+        let file = File::create(path).map_err(|e| fixture_error(path, e))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            fixture: Fixture::Record(Mutex::new(file)),
+            http,
+        })
+    }
+
+    pub fn http_mut(&mut self) -> &mut HttpStrategy {
+        &mut self.http
+    }
+}
+
+impl Pipeline for MockStrategy {
+    fn pipeline<'a>(
+        &'a self,
+        request: &'a serde_json::Value,
+    ) -> TransportFuture<'a, serde_json::Value> {
+        Box::pin(async move {
+            match &self.fixture {
+                Fixture::Replay(exchanges) => exchanges
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .find(|slot| slot.as_ref().is_some_and(|e| e.request == *request))
+                    .and_then(Option::take)
+                    .map(|exchange| {
+                        #[cfg(test)]
+                        ANSWERED
+                            .lock()
+                            .unwrap()
+                            .push((self.path.clone(), exchange.request));
+                        exchange.response
+                    })
+                    .ok_or_else(|| {
+                        SqliteError::new(
+                            format!(
+                                "No exchange left in {} for request {}",
+                                self.path.display(),
+                                request
+                            ),
+                            Some(SQLITE_ERROR),
+                        )
+                    }),
+                Fixture::Record(file) => {
+                    let response = self.http.send_raw(request).await?;
+                    let exchange = Exchange {
+                        request: request.clone(),
+                        response,
+                    };
+
+                    let line = serde_json::to_string(&exchange).map_err(|e| {
+                        SqliteError::new(format!("Failed to encode exchange: {}", e), None)
+                    })?;
+                    writeln!(file.lock().unwrap(), "{}", line).map_err(|e| {
+                        SqliteError::new(
+                            format!("Failed to write {}: {}", self.path.display(), e),
+                            Some(SQLITE_IOERR),
+                        )
+                    })?;
+
+                    Ok(exchange.response)
+                }
+            }
+        })
+    }
+
+    fn session(&self) -> &[String] {
+        self.http.session()
+    }
+}
+
+fn fixture_error(path: &Path, error: impl std::fmt::Display) -> SqliteError {
+    SqliteError::new(
+        format!("Cannot use fixture {}: {}", path.display(), error),
+        Some(SQLITE_CANTOPEN),
+    )
+}
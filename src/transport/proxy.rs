@@ -0,0 +1,268 @@
+use base64::Engine;
+use reqwest::Url;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::sqlite::{SqliteError, SQLITE_ERROR};
+
+/// Picks the proxy for a connection to `host:port`: an explicit `proxy=` URI parameter wins,
+/// otherwise the conventional environment variables are consulted.
+pub fn resolve_proxy(
+    explicit: Option<&str>,
+    host: &str,
+    port: u16,
+    secure: bool,
+) -> Option<String> {
+    resolve_proxy_with(explicit, host, port, secure, |name| {
+        std::env::var(name).ok()
+    })
+}
+
+// resolve_proxy reading the environment through `var`
+fn resolve_proxy_with(
+    explicit: Option<&str>,
+    host: &str,
+    port: u16,
+    secure: bool,
+    var: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    if let Some(proxy) = explicit {
+        return Some(proxy.to_string());
+    }
+
+    let no_proxy = var("NO_PROXY")
+        .or_else(|| var("no_proxy"))
+        .unwrap_or_default();
+    if is_excluded(&no_proxy, host, port) {
+        return None;
+    }
+
+    let names: &[&str] = if secure {
+        &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+    } else {
+        &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]
+    };
+
+    names
+        .iter()
+        .filter_map(|name| var(name))
+        .find(|value| !value.is_empty())
+}
+
+// NO_PROXY is a comma separated list of hosts, each also matching its subdomains and limited
+// to one port when given as `host:port`, or `*` for everything
+fn is_excluded(no_proxy: &str, host: &str, port: u16) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            // A bare IPv6 address has colons of its own
+            let (name, only_port) = match entry.rsplit_once(':') {
+                Some((name, entry_port)) if !name.contains(':') || name.ends_with(']') => {
+                    match entry_port.parse::<u16>() {
+                        Ok(entry_port) => (name, Some(entry_port)),
+                        Err(_) => return false,
+                    }
+                }
+                _ => (entry, None),
+            };
+            let name = name
+                .trim_start_matches('.')
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            only_port.is_none_or(|only_port| only_port == port)
+                && (host.eq_ignore_ascii_case(name)
+                    || host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", name.to_ascii_lowercase())))
+        })
+}
+
+/// Opens a TCP stream to `host:port` tunnelled through an HTTP CONNECT or SOCKS5 proxy.
+pub async fn connect_via_proxy(
+    proxy: &str,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, SqliteError> {
+    let proxy_url = Url::parse(proxy)
+        .map_err(|e| proxy_error(format!("Invalid proxy URL '{}': {}", proxy, e)))?;
+    let proxy_host = proxy_url
+        .host_str()
+        .ok_or_else(|| proxy_error(format!("Proxy URL '{}' has no host", proxy)))?;
+
+    match proxy_url.scheme() {
+        "http" => {
+            let proxy_port = proxy_url.port().unwrap_or(80);
+            http_connect(&proxy_url, proxy_host, proxy_port, host, port).await
+        }
+        "socks5" | "socks5h" => {
+            let proxy_port = proxy_url.port().unwrap_or(1080);
+            let proxy_addr = (proxy_host, proxy_port);
+            let target = (host, port);
+
+            let stream = if proxy_url.username().is_empty() {
+                tokio_socks::tcp::Socks5Stream::connect(proxy_addr, target).await
+            } else {
+                tokio_socks::tcp::Socks5Stream::connect_with_password(
+                    proxy_addr,
+                    target,
+                    proxy_url.username(),
+                    proxy_url.password().unwrap_or(""),
+                )
+                .await
+            }
+            .map_err(|e| proxy_error(format!("SOCKS5 proxy connection failed: {}", e)))?;
+
+            Ok(stream.into_inner())
+        }
+        scheme => Err(proxy_error(format!(
+            "Unsupported proxy scheme '{}', expected http or socks5",
+            scheme
+        ))),
+    }
+}
+
+async fn http_connect(
+    proxy_url: &Url,
+    proxy_host: &str,
+    proxy_port: u16,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, SqliteError> {
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .map_err(|e| proxy_error(format!("Failed to connect to proxy: {}", e)))?;
+
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if !proxy_url.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            proxy_url.username(),
+            proxy_url.password().unwrap_or("")
+        );
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encoded));
+    }
+    request.push_str("\r\n");
+
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| proxy_error(format!("Failed to send CONNECT request: {}", e)))?;
+
+    // Read the proxy's response headers; the tunnel starts right after the blank line
+    let mut reader = BufReader::new(&mut stream);
+    let mut status_line = String::new();
+    reader
+        .read_line(&mut status_line)
+        .await
+        .map_err(|e| proxy_error(format!("Failed to read CONNECT response: {}", e)))?;
+
+    let status = status_line.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(proxy_error(format!(
+            "Proxy refused CONNECT: {}",
+            status_line.trim()
+        )));
+    }
+
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| proxy_error(format!("Failed to read CONNECT response: {}", e)))?;
+        if read == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    Ok(stream)
+}
+
+fn proxy_error(message: String) -> SqliteError {
+    SqliteError::new(message, Some(SQLITE_ERROR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(env: &[(&str, &str)], host: &str, port: u16, secure: bool) -> Option<String> {
+        resolve_proxy_with(None, host, port, secure, |name| {
+            env.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn proxy_follows_the_scheme() {
+        let env = [
+            ("HTTPS_PROXY", "http://secure:1"),
+            ("HTTP_PROXY", "http://plain:2"),
+            ("ALL_PROXY", "socks5://all:3"),
+        ];
+        assert_eq!(
+            resolve(&env, "db.turso.io", 443, true).as_deref(),
+            Some("http://secure:1")
+        );
+        assert_eq!(
+            resolve(&env, "db.turso.io", 80, false).as_deref(),
+            Some("http://plain:2")
+        );
+        assert_eq!(
+            resolve(&env[2..], "db.turso.io", 443, true).as_deref(),
+            Some("socks5://all:3")
+        );
+        // Lower case names count too, an empty value does not
+        let env = [("HTTPS_PROXY", ""), ("https_proxy", "http://lower:4")];
+        assert_eq!(
+            resolve(&env, "db.turso.io", 443, true).as_deref(),
+            Some("http://lower:4")
+        );
+        assert_eq!(resolve(&[], "db.turso.io", 443, true), None);
+
+        let explicit =
+            resolve_proxy_with(Some("socks5://mine:5"), "db.turso.io", 443, true, |_| {
+                Some("http://env:6".to_string())
+            });
+        assert_eq!(explicit.as_deref(), Some("socks5://mine:5"));
+    }
+
+    #[test]
+    fn no_proxy_excludes_hosts_and_their_subdomains() {
+        let proxied = |no_proxy: &str, host: &str, port: u16| {
+            let env = [("HTTPS_PROXY", "http://proxy:1"), ("NO_PROXY", no_proxy)];
+            resolve(&env, host, port, true).is_some()
+        };
+
+        assert!(!proxied("turso.io", "turso.io", 443));
+        assert!(!proxied("turso.io", "db.turso.io", 443));
+        assert!(proxied("turso.io", "notturso.io", 443));
+        assert!(!proxied(".turso.io", "db.turso.io", 443));
+        assert!(!proxied(".turso.io", "turso.io", 443));
+        assert!(!proxied("example.com, Turso.IO", "db.turso.io", 443));
+        assert!(!proxied("*", "anything.example", 443));
+
+        assert!(!proxied("turso.io:443", "db.turso.io", 443));
+        assert!(proxied("turso.io:8080", "db.turso.io", 443));
+        assert!(!proxied("::1", "[::1]", 443));
+        assert!(!proxied("[::1]:8080", "[::1]", 8080));
+        assert!(proxied("[::1]:8080", "[::1]", 443));
+
+        let env = [("HTTPS_PROXY", "http://proxy:1"), ("no_proxy", "turso.io")];
+        assert_eq!(resolve(&env, "db.turso.io", 443, true), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Where requests go. Host names are looked up once per `dns_ttl` and the addresses shared by
+//! every connection, so reconnects skip DNS. A database opened with `failover=` hosts moves
+//! to the next one when the host in use cannot be reached, and connections to that database
+//! follow it there.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+// Addresses by host name, with when they stop being used
+type CachedAddrs = (Vec<IpAddr>, Instant);
+static ADDRESSES: Mutex<Option<HashMap<String, CachedAddrs>>> = Mutex::new(None);
+
+// The hosts of every database, keyed by its own host followed by the failover hosts
+static HOSTS: Mutex<Option<HashMap<Vec<String>, Arc<Hosts>>>> = Mutex::new(None);
+
+/// The addresses of `host`, from the cache while they are younger than `ttl`.
+pub async fn lookup(host: &str, ttl: Duration) -> io::Result<Vec<IpAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![ip]);
+    }
+
+    if let Some((addrs, expires)) = ADDRESSES
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|cache| cache.get(host))
+    {
+        if *expires > Instant::now() {
+            return Ok(addrs.clone());
+        }
+    }
+
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, 0))
+        .await?
+        .map(|addr| addr.ip())
+        .collect();
+    if !ttl.is_zero() && !addrs.is_empty() {
+        let mut cache = ADDRESSES.lock().unwrap();
+        cache
+            .get_or_insert_with(HashMap::new)
+            .insert(host.to_string(), (addrs.clone(), Instant::now() + ttl));
+    }
+    Ok(addrs)
+}
+
+/// Drops what is cached for `host`, so the next connection looks it up again.
+pub fn forget(host: &str) {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Some(cache) = ADDRESSES.lock().unwrap().as_mut() {
+        cache.remove(host);
+    }
+}
+
+/// Looks up names for the HTTP client through the shared cache.
+pub struct CachingResolver {
+    pub ttl: Duration,
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let ttl = self.ttl;
+        Box::pin(async move {
+            let addrs = lookup(name.as_str(), ttl).await?;
+            // The client fills in the port of the URL
+            let addrs: Addrs = Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// A database's host followed by its failover hosts, and which of them is in use.
+#[derive(Debug)]
+pub struct Hosts {
+    authorities: Vec<String>, // host[:port], the database's own first
+    current: AtomicUsize,
+}
+
+impl Hosts {
+    /// The hosts of the database at `authority`, shared with every connection to it that
+    /// names the same failover hosts.
+    pub fn of(authority: &str, failover: &[String]) -> Arc<Self> {
+        let authorities: Vec<String> = std::iter::once(authority.to_string())
+            .chain(failover.iter().cloned())
+            .collect();
+
+        let mut hosts = HOSTS.lock().unwrap();
+        hosts
+            .get_or_insert_with(HashMap::new)
+            .entry(authorities.clone())
+            .or_insert_with(|| {
+                Arc::new(Self {
+                    authorities,
+                    current: AtomicUsize::new(0),
+                })
+            })
+            .clone()
+    }
+
+    pub fn count(&self) -> usize {
+        self.authorities.len()
+    }
+
+    /// `base_url`, `scheme://host`, pointed at the host in use.
+    pub fn url(&self, base_url: &str) -> String {
+        let scheme = base_url
+            .split_once("://")
+            .map_or("https", |(scheme, _)| scheme);
+        let current = self.current.load(Ordering::Acquire);
+        format!("{}://{}", scheme, self.authorities[current])
+    }
+
+    /// Moves on from the host `url` points at, unless another connection already did. Its
+    /// addresses are looked up again when it next comes round.
+    pub fn fail_over(&self, url: &str) {
+        let failed = url
+            .split_once("://")
+            .map_or(url, |(_, authority)| authority);
+        if let Some(host) = host_of(failed) {
+            forget(&host);
+        }
+
+        let current = self.current.load(Ordering::Acquire);
+        if self.authorities.len() == 1 || self.authorities[current] != failed {
+            return;
+        }
+        let next = (current + 1) % self.authorities.len();
+        if self
+            .current
+            .compare_exchange(current, next, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            tracing::warn!(
+                from = failed,
+                to = %self.authorities[next],
+                "Host unreachable, failing over"
+            );
+        }
+    }
+}
+
+// The host of `host[:port]`
+fn host_of(authority: &str) -> Option<String> {
+    let url = reqwest::Url::parse(&format!("http://{}", authority)).ok()?;
+    url.host_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failover_moves_through_the_hosts_once_per_failure() {
+        let failover = ["b.example:8080".to_string(), "c.example".to_string()];
+        let hosts = Hosts::of("a.example", &failover);
+        assert!(Arc::ptr_eq(&hosts, &Hosts::of("a.example", &failover)));
+        assert_eq!(hosts.url("wss://a.example"), "wss://a.example");
+
+        hosts.fail_over("https://a.example");
+        assert_eq!(hosts.url("https://a.example"), "https://b.example:8080");
+        // A connection that still saw the first host does not skip the second
+        hosts.fail_over("wss://a.example");
+        assert_eq!(hosts.url("wss://a.example"), "wss://b.example:8080");
+
+        hosts.fail_over("https://b.example:8080");
+        hosts.fail_over("https://c.example");
+        assert_eq!(hosts.url("http://a.example"), "http://a.example");
+    }
+}
@@ -0,0 +1,189 @@
+//! `sqlite3_turso_selftest`: goes through the steps of opening a connection one at a time and
+//! reports which of them worked and how long each took, so a deployment can be checked before
+//! it serves traffic.
+
+use std::{future::Future, sync::Arc, time::Instant};
+
+use serde::Serialize;
+
+use crate::{
+    auth::DbAuthStrategy,
+    config::{ConnectionOptions, TransportPolicy},
+    metrics::Metrics,
+    protocol::StatementArgs,
+    sqlite::{SqliteError, SQLITE_AUTH},
+    transport::{
+        custom, http_client, mock::MockStrategy, transports, ActiveStrategy, LibsqlInterface,
+        TursoConfig,
+    },
+};
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub ok: bool,                        // Every check passed
+    pub transport: Option<&'static str>, // The one a connection would use, if any works
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    // Runs one step and records its outcome. A step whose prerequisites failed is not run
+    // at all, so the report ends at the first check that could not pass.
+    async fn check<T>(
+        &mut self,
+        name: &'static str,
+        step: impl Future<Output = Result<T, SqliteError>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let result = step.await;
+        self.checks.push(Check {
+            name,
+            ok: result.is_ok(),
+            ms: started.elapsed().as_secs_f64() * 1000.0,
+            error: result.as_ref().err().map(|err| err.message.clone()),
+        });
+        result.ok()
+    }
+}
+
+/// Checks, in order: that credentials resolve (`auth`) into a usable URL (`endpoints`), that
+/// the HTTP endpoint answers (`http_ping`), that the WebSocket handshake succeeds
+/// (`websocket`, skipped with `transport=http`), and then over the transport a connection
+/// would pick that `SELECT 1` runs (`select`) and a transaction opens and commits
+/// (`transaction`). The mock and record transports check their `fixture` instead of
+/// connecting, and `transport=custom:<name>` that the registered transport is created
+/// (`custom`).
+pub async fn self_test(
+    db_name: &str,
+    auth: Box<dyn DbAuthStrategy>,
+    options: ConnectionOptions,
+) -> Report {
+    let mut report = Report::default();
+    run(&mut report, db_name, auth, options).await;
+    report.ok = !report.checks.is_empty() && report.checks.iter().all(|check| check.ok);
+    report
+}
+
+async fn run(
+    report: &mut Report,
+    db_name: &str,
+    auth: Box<dyn DbAuthStrategy>,
+    options: ConnectionOptions,
+) -> Option<()> {
+    let policy = options.transport;
+    let replaying = matches!(policy, TransportPolicy::Mock | TransportPolicy::Record);
+
+    let (client, config) = report
+        .check("auth", async {
+            let client = http_client(&options)?;
+            // Replaying never connects, so it needs no credentials
+            let config = if replaying {
+                TursoConfig {
+                    db_url: db_name.to_string(),
+                    db_token: String::new(),
+                }
+            } else {
+                auth.resolve(db_name, &client)
+                    .await
+                    .map_err(|err| SqliteError::new(err.to_string(), Some(SQLITE_AUTH)))?
+            };
+            let config = Arc::new(config);
+            Ok((client, config))
+        })
+        .await?;
+
+    let metrics = Arc::new(Metrics::for_connection());
+    let (mut http, mut websocket) = report
+        .check("endpoints", async {
+            transports(
+                config.clone(),
+                client,
+                &options,
+                metrics,
+                Default::default(),
+            )
+        })
+        .await?;
+    // A socket shared with open connections would skip the handshake being checked
+    websocket.share(None);
+
+    if replaying {
+        let mut mock = report
+            .check("fixture", async {
+                match (policy, &options.fixture) {
+                    (TransportPolicy::Mock, Some(path)) => MockStrategy::replay(path, http),
+                    (_, Some(path)) => MockStrategy::record(path, http),
+                    _ => Err(SqliteError::new("mock transport without a fixture", None)),
+                }
+            })
+            .await?;
+        report.transport = ActiveStrategy::Mock.name().to_str().ok();
+        return queries(report, &mut mock).await;
+    }
+
+    if policy == TransportPolicy::Custom {
+        let mut custom = report
+            .check("custom", async {
+                let name = options.custom_transport.as_deref().unwrap_or_default();
+                custom::create(name, &config)
+            })
+            .await?;
+        report.transport = ActiveStrategy::Custom.name().to_str().ok();
+        return queries(report, custom.as_mut()).await;
+    }
+
+    let http_ok = report.check("http_ping", http.ping()).await.is_some();
+    let websocket_ok = policy != TransportPolicy::Http
+        && report
+            .check("websocket", websocket.connect())
+            .await
+            .is_some();
+
+    if websocket_ok {
+        report.transport = ActiveStrategy::Websocket.name().to_str().ok();
+        let result = queries(report, &mut websocket).await;
+        websocket.close().await;
+        result
+    } else if http_ok && policy != TransportPolicy::Websocket {
+        report.transport = ActiveStrategy::Http.name().to_str().ok();
+        queries(report, &mut http).await
+    } else {
+        None
+    }
+}
+
+// The statements every application runs first, over the transport it would use
+async fn queries(
+    report: &mut Report,
+    transport: &mut (impl LibsqlInterface + ?Sized),
+) -> Option<()> {
+    report
+        .check("select", async {
+            let mut request =
+                transport.get_json_request("SELECT 1", &StatementArgs::default(), None, false);
+            transport.send(&mut request).await?.first_result()?;
+            Ok(())
+        })
+        .await?;
+
+    report
+        .check("transaction", async {
+            let baton = transport.get_transaction_baton("BEGIN").await?;
+            let mut request =
+                transport.get_json_request("COMMIT", &StatementArgs::default(), Some(&baton), true);
+            let response = transport.send(&mut request).await?;
+            response.first_result()?;
+            // The stream outlives the transaction until it is closed
+            let baton = response.baton.unwrap_or(baton);
+            transport.close_stream(&baton).await
+        })
+        .await
+}
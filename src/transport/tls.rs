@@ -0,0 +1,274 @@
+use std::path::Path;
+
+use tokio_tungstenite::Connector;
+
+use crate::{
+    config::TlsOptions,
+    sqlite::{SqliteError, SQLITE_CANTOPEN},
+};
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the native-tls or the rustls feature must be enabled");
+
+#[cfg(feature = "native-tls")]
+use native as backend;
+#[cfg(not(feature = "native-tls"))]
+use rustls_backend as backend;
+
+/// Applies custom roots, client identity and the insecure flag to the HTTP client.
+pub fn configure_http_client(
+    mut builder: reqwest::ClientBuilder,
+    tls: &TlsOptions,
+) -> Result<reqwest::ClientBuilder, SqliteError> {
+    if let Some(ca_file) = &tls.ca_file {
+        let pem = read_file(ca_file)?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| tls_error(format!("Invalid CA bundle {}: {}", ca_file.display(), e)))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let (Some(cert_file), Some(key_file)) = (&tls.cert_file, &tls.key_file) {
+        let identity = backend::http_identity(&read_file(cert_file)?, &read_file(key_file)?)
+            .map_err(|e| {
+                let cause =
+                    std::error::Error::source(&e).map_or(String::new(), |s| format!(": {}", s));
+                tls_error(format!("Invalid client certificate: {}{}", e, cause))
+            })?;
+        builder = builder.identity(identity);
+    }
+
+    if tls.accept_invalid_certs {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+/// Builds the TLS connector for the WebSocket transport, or `None` to use the defaults.
+pub fn websocket_connector(tls: &TlsOptions) -> Result<Option<Connector>, SqliteError> {
+    let is_default = tls.ca_file.is_none() && tls.cert_file.is_none() && !tls.accept_invalid_certs;
+    if !tls.enabled || is_default {
+        return Ok(None);
+    }
+
+    backend::websocket_connector(tls).map(Some)
+}
+
+#[cfg(feature = "native-tls")]
+mod native {
+    use tokio_tungstenite::Connector;
+
+    use super::{read_file, tls_error};
+    use crate::{config::TlsOptions, sqlite::SqliteError};
+
+    pub fn http_identity(cert: &[u8], key: &[u8]) -> reqwest::Result<reqwest::Identity> {
+        reqwest::Identity::from_pkcs8_pem(cert, key)
+    }
+
+    pub fn websocket_connector(tls: &TlsOptions) -> Result<Connector, SqliteError> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(ca_file) = &tls.ca_file {
+            let pem = read_file(ca_file)?;
+            for block in split_pem_bundle(&pem) {
+                let certificate = native_tls::Certificate::from_pem(&block).map_err(|e| {
+                    tls_error(format!("Invalid CA bundle {}: {}", ca_file.display(), e))
+                })?;
+                builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let (Some(cert_file), Some(key_file)) = (&tls.cert_file, &tls.key_file) {
+            let identity =
+                native_tls::Identity::from_pkcs8(&read_file(cert_file)?, &read_file(key_file)?)
+                    .map_err(|e| tls_error(format!("Invalid client certificate: {}", e)))?;
+            builder.identity(identity);
+        }
+
+        if tls.accept_invalid_certs {
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        let connector = builder
+            .build()
+            .map_err(|e| tls_error(format!("Failed to build TLS connector: {}", e)))?;
+
+        Ok(Connector::NativeTls(connector))
+    }
+
+    // native-tls only parses a single certificate per PEM buffer. Text around the blocks, such
+    // as the comments some bundles carry, is left out.
+    fn split_pem_bundle(pem: &[u8]) -> Vec<Vec<u8>> {
+        const BEGIN_MARKER: &str = "-----BEGIN CERTIFICATE-----";
+        const END_MARKER: &str = "-----END CERTIFICATE-----";
+
+        String::from_utf8_lossy(pem)
+            .split_inclusive(END_MARKER)
+            .filter(|block| block.ends_with(END_MARKER))
+            .filter_map(|block| block.find(BEGIN_MARKER).map(|begin| &block[begin..]))
+            .map(|block| block.as_bytes().to_vec())
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const FIRST: &str = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----";
+        const SECOND: &str = "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----";
+
+        fn split(pem: &str) -> Vec<String> {
+            split_pem_bundle(pem.as_bytes())
+                .into_iter()
+                .map(|block| String::from_utf8(block).unwrap())
+                .collect()
+        }
+
+        #[test]
+        fn bundles_split_into_one_certificate_each() {
+            assert_eq!(split(&format!("{}\n{}\n", FIRST, SECOND)), [FIRST, SECOND]);
+            // Without a final newline, with text around the blocks, and with a block cut short
+            assert_eq!(split(&format!("{}\n{}", FIRST, SECOND)), [FIRST, SECOND]);
+            assert_eq!(
+                split(&format!(
+                    "# Root one\n{}\n\n# Root two\r\n{}\ntrailing\n",
+                    FIRST, SECOND
+                )),
+                [FIRST, SECOND]
+            );
+            assert_eq!(
+                split(&format!("{}\n-----BEGIN CERTIFICATE-----\nCCCC\n", FIRST)),
+                [FIRST]
+            );
+            assert!(split("no certificates here").is_empty());
+        }
+    }
+}
+
+#[cfg(not(feature = "native-tls"))]
+mod rustls_backend {
+    use std::sync::Arc;
+
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    };
+    use tokio_tungstenite::Connector;
+
+    use super::{read_file, tls_error};
+    use crate::{config::TlsOptions, sqlite::SqliteError};
+
+    // rustls takes the certificate chain and the key from a single PEM buffer
+    pub fn http_identity(cert: &[u8], key: &[u8]) -> reqwest::Result<reqwest::Identity> {
+        let mut pem = cert.to_vec();
+        pem.push(b'\n');
+        pem.extend_from_slice(key);
+        reqwest::Identity::from_pem(&pem)
+    }
+
+    pub fn websocket_connector(tls: &TlsOptions) -> Result<Connector, SqliteError> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| tls_error(format!("Failed to build TLS connector: {}", e)))?;
+
+        let builder = if tls.accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+        } else {
+            let mut roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            if let Some(ca_file) = &tls.ca_file {
+                let invalid = |e: String| {
+                    tls_error(format!("Invalid CA bundle {}: {}", ca_file.display(), e))
+                };
+                for certificate in CertificateDer::pem_slice_iter(&read_file(ca_file)?) {
+                    let certificate = certificate.map_err(|e| invalid(e.to_string()))?;
+                    roots.add(certificate).map_err(|e| invalid(e.to_string()))?;
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let config = match (&tls.cert_file, &tls.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let invalid = |e: String| tls_error(format!("Invalid client certificate: {}", e));
+                let chain = CertificateDer::pem_slice_iter(&read_file(cert_file)?)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| invalid(e.to_string()))?;
+                let key = PrivateKeyDer::from_pem_slice(&read_file(key_file)?)
+                    .map_err(|e| invalid(e.to_string()))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .map_err(|e| invalid(e.to_string()))?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+
+        Ok(Connector::Rustls(Arc::new(config)))
+    }
+
+    // accept_invalid_certs: any certificate is taken, but the handshake signatures are still
+    // checked so the session is with whoever holds the certificate's key
+    #[derive(Debug)]
+    struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            crypto::verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            crypto::verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, SqliteError> {
+    std::fs::read(path).map_err(|e| tls_error(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn tls_error(message: String) -> SqliteError {
+    SqliteError::new(message, Some(SQLITE_CANTOPEN))
+}
@@ -1,34 +1,63 @@
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
     },
     time::Duration,
 };
 
 use crate::{
-    sqlite::{SqliteError, SQLITE_ERROR},
+    config::{
+        DEFAULT_DNS_TTL, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_MESSAGE, DEFAULT_PING_INTERVAL,
+        DEFAULT_REQUEST_TIMEOUT,
+    },
+    logging,
+    metrics::Metrics,
+    protocol::{
+        describe_columns, error_code, status_code, to_json, ClientMsg, RemoteCol,
+        RemoteSQLiteResult, RemoteSQliteResultType, RemoteSqliteResponse, StatementArgs, Stmt,
+        StreamRequest,
+    },
+    sqlite::{SqliteError, SQLITE_AUTH, SQLITE_BUSY_TIMEOUT, SQLITE_ERROR},
     transport::{
-        LibsqlInterface, RemoteSQLiteResult, RemoteSQliteResultType, RemoteSqliteResponse,
-        TursoConfig,
+        proxy::{connect_via_proxy, resolve_proxy},
+        resolver::{self, Hosts},
+        LibsqlInterface, TransportFuture, TursoConfig,
     },
     utils::get_tokio,
 };
-use futures_util::{sink::SinkExt, stream::SplitSink, StreamExt};
+use futures_util::{
+    sink::SinkExt,
+    stream::{SplitSink, SplitStream},
+    StreamExt,
+};
 use tokio_tungstenite::{
-    tungstenite::{Message, Utf8Bytes},
-    MaybeTlsStream, WebSocketStream,
+    tungstenite::{client::IntoClientRequest, protocol::WebSocketConfig, Message, Utf8Bytes},
+    Connector, MaybeTlsStream, WebSocketStream,
 };
 
 use serde_json::Value;
 use tokio::{
     net::TcpStream,
-    sync::{oneshot, Mutex},
+    sync::{oneshot, Mutex, Semaphore},
+    time::Instant,
 };
 
+// Ids are unique across the process, so connections sharing a socket never mix them up
 static REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 static STREAM_ID: AtomicU64 = AtomicU64::new(1);
+static SQL_ID: AtomicU64 = AtomicU64::new(1);
+
+// Requests a shared socket has written and not had answered yet. Beyond this, connections
+// wait their turn to write.
+const MAX_IN_FLIGHT: usize = 256;
+
+// The socket currently shared per endpoint, see `WebSocketStrategy::share`
+type SocketSlot = Arc<Mutex<Option<Arc<Socket>>>>;
+static SHARED_SOCKETS: std::sync::Mutex<Option<HashMap<String, SocketSlot>>> =
+    std::sync::Mutex::new(None);
 
 #[derive(PartialEq)]
 enum WebSocketConnState {
@@ -36,261 +65,363 @@ enum WebSocketConnState {
     Disconnected,
 }
 
+type WebSocketWriter = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type WebSocketReader = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// One physical WebSocket. Connections to the same endpoint with the same credentials share
+/// it, each running its own Hrana streams, so opening many of them costs one handshake.
+struct Socket {
+    writer: Mutex<WebSocketWriter>, // Tokio's lock is fair, writers take turns in order
+    bus: ResponseBus,
+    state: Arc<Mutex<WebSocketConnState>>,
+    in_flight: Semaphore, // Granted in order too, one connection cannot starve the others
+    users: AtomicUsize,   // Connections holding the socket
+    pid: u32,             // A forked child must not use its parent's socket
+}
+
+impl Socket {
+    async fn is_connected(&self) -> bool {
+        self.pid == std::process::id() && *self.state.lock().await == WebSocketConnState::Connected
+    }
+
+    /// Marks the socket disconnected and fails the requests waiting on it with `reason`.
+    async fn close(&self, reason: &str) {
+        *self.state.lock().await = WebSocketConnState::Disconnected;
+        self.bus.lose(reason).await;
+        let _ = self.writer.lock().await.close().await;
+    }
+}
+
+/// SQL text kept on the server for persistent prepared statements.
+struct PersistentSql {
+    handles: usize,      // Open statements using this text
+    sql_id: Option<i32>, // Stored on the current socket; reset on reconnect
+}
+
 pub struct WebSocketStrategy {
     turso_config: Arc<TursoConfig>,
-    bus: ResponseBus,
-    websocket_handle: Option<
-        SplitSink<
-            WebSocketStream<MaybeTlsStream<TcpStream>>,
-            tokio_tungstenite::tungstenite::Message,
-        >,
-    >,
-    websocket_state: Arc<Mutex<WebSocketConnState>>,
+    url: String,                   // ws:// or wss:// endpoint
+    hosts: Option<Arc<Hosts>>,     // The database's host and its failover hosts
+    dns_ttl: Duration,             // How long looked up addresses are reused
+    authorization: Option<String>, // Sent on the upgrade request for basic auth servers
+    tls_connector: Option<Connector>,
+    socket: Option<Arc<Socket>>,
+    share_key: Option<String>, // Set when the socket may be shared, see `share`
+    timeout: Duration,
+    proxy: Option<String>,   // Explicit proxy from the connection options
+    ping_interval: Duration, // Zero disables keepalive pings
+    idle_timeout: Duration,  // Connection is considered dead after this long without traffic
+    max_message: usize,      // Largest message read, zero for no limit
+    metrics: Arc<Metrics>,
+    has_connected: bool, // Later connects are counted as reconnects
+    persistent_sql: HashMap<String, PersistentSql>,
+    session: Vec<String>, // Replayed first on every new stream
 }
 
 impl WebSocketStrategy {
-    pub fn new(turso_config: Arc<TursoConfig>) -> Self {
+    pub fn new(
+        turso_config: Arc<TursoConfig>,
+        url: String,
+        authorization: Option<String>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             turso_config,
-            bus: ResponseBus::new(),
-            websocket_handle: None,
-            websocket_state: Arc::new(Mutex::new(WebSocketConnState::Disconnected)),
+            url,
+            hosts: None,
+            dns_ttl: DEFAULT_DNS_TTL,
+            authorization,
+            tls_connector: None,
+            socket: None,
+            share_key: None,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            proxy: None,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_message: DEFAULT_MAX_MESSAGE,
+            metrics,
+            has_connected: false,
+            persistent_sql: HashMap::new(),
+            session: Vec::new(),
         }
     }
 
-    fn next_request_id() -> i32 {
-        REQUEST_ID.fetch_add(1, Ordering::Relaxed) as i32
+    pub fn set_keepalive(&mut self, ping_interval: Duration, idle_timeout: Duration) {
+        self.ping_interval = ping_interval;
+        self.idle_timeout = idle_timeout;
     }
 
-    fn next_stream_id() -> i32 {
-        STREAM_ID.fetch_add(1, Ordering::Relaxed) as i32
+    pub fn set_max_message(&mut self, max_message: usize) {
+        self.max_message = max_message;
     }
 
-    async fn open_stream(&mut self) -> Result<(i32, ResponseBus), SqliteError> {
-        let (writer, bus) = self.get_client().await?;
-        let request_id = WebSocketStrategy::next_request_id();
-        let stream_id = WebSocketStrategy::next_stream_id();
-
-        let request = serde_json::json!({
-            "type": "request",
-            "request_id": request_id,
-            "request": {
-            "type": "open_stream",
-            "stream_id": stream_id,
-        }
-        });
+    pub fn set_tls_connector(&mut self, connector: Option<Connector>) {
+        self.tls_connector = connector;
+    }
 
-        writer
-            .send(Message::Text(Utf8Bytes::from(request.to_string())))
-            .await
-            .map_err(|e| {
-                SqliteError::new(
-                    format!("Failed to send open_stream message: {}", e),
-                    Some(SQLITE_ERROR),
-                )
-            })?;
+    pub fn set_proxy(&mut self, proxy: Option<String>) {
+        self.proxy = proxy;
+    }
 
-        let id = format!("request_id:{}", request_id);
-        bus.wait_for(id.as_str()).await?;
+    pub fn set_hosts(&mut self, hosts: Arc<Hosts>, dns_ttl: Duration) {
+        self.hosts = Some(hosts);
+        self.dns_ttl = dns_ttl;
+    }
 
-        Ok((stream_id, bus))
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
     }
 
-    pub async fn connect(&mut self) -> Result<(), SqliteError> {
-        let url = format!("wss://{}", self.turso_config.db_url);
-        if cfg!(debug_assertions) {
-            println!("Connecting to WebSocket at {}", url);
-        }
+    pub fn set_session(&mut self, statements: Vec<String>) {
+        self.session = statements;
+    }
 
-        let (socket, _) = tokio_tungstenite::connect_async(url).await.map_err(|e| {
-            SqliteError::new(
-                format!("Failed to connect to WebSocket: {}", e),
-                Some(SQLITE_ERROR),
+    /// Lets the connection share its socket with every other one whose endpoint, token and
+    /// `options` match, as the settings the socket was opened with. Without it the
+    /// connection opens a socket of its own.
+    pub fn share(&mut self, options: Option<String>) {
+        self.share_key = options.map(|options| {
+            format!(
+                "{}|{:?}|{}|{}",
+                self.url, self.authorization, self.turso_config.db_token, options
             )
-        })?;
-        let (mut writer, mut reader) = socket.split();
-
-        let bus = self.bus.clone();
-        let websocket_state = self.websocket_state.clone();
-
-        get_tokio().spawn(async move {
-            while let Some(message) = reader.next().await {
-                match message {
-                    Err(_) | Ok(Message::Close(_)) => {
-                        let mut state = websocket_state.lock().await;
-                        *state = WebSocketConnState::Disconnected;
-                        break;
-                    }
-                    _ => (),
-                }
+        });
+    }
 
-                let message = message.unwrap();
-                let value: Value = match message {
-                    Message::Text(text) => match serde_json::from_str(&text) {
-                        Ok(value) => value,
-                        Err(_) => {
-                            continue;
-                        }
-                    },
-                    Message::Binary(binary) => {
-                        match serde_json::from_slice(&binary) {
-                            Ok(value) => value,
-                            Err(e) => {
-                                eprintln!(
-                                    "Failed to parse WebSocket binary message as JSON: {}",
-                                    e
-                                );
-                                continue;
-                            }
-                        }
-                        continue;
-                    }
-                    _ => {
-                        eprintln!("Received unsupported WebSocket message type: {:?}", message);
-                        continue;
-                    }
-                };
+    fn next_request_id() -> i32 {
+        REQUEST_ID.fetch_add(1, Ordering::Relaxed) as i32
+    }
 
-                // store the key and value as {key:value}
-
-                let key = {
-                    if value.get("request_id").is_some() {
-                        format!(
-                            "request_id:{}",
-                            value.get("request_id").unwrap().as_i64().unwrap()
-                        )
-                    } else if value.get("id").is_some() {
-                        format!("id:{}", value.get("id").unwrap().as_i64().unwrap())
-                    } else {
-                        format!("type:{}", value.get("type").unwrap().as_str().unwrap())
-                    }
-                };
+    fn next_stream_id() -> i32 {
+        STREAM_ID.fetch_add(1, Ordering::Relaxed) as i32
+    }
 
-                bus.respond(key.as_str(), value.clone()).await;
-            }
-        });
+    fn next_sql_id() -> i32 {
+        SQL_ID.fetch_add(1, Ordering::Relaxed) as i32
+    }
 
-        let json = serde_json::json!({
-            "type": "hello",
-            "jwt": self.turso_config.db_token,
+    fn request_frame(request_id: i32, request: Value) -> Message {
+        let frame = to_json(&ClientMsg::Request {
+            request_id,
+            request,
         });
 
-        writer
-            .send(tokio_tungstenite::tungstenite::Message::Text(
-                Utf8Bytes::from(json.to_string()),
-            ))
-            .await
-            .map_err(|e| {
-                SqliteError::new(
-                    format!("Failed to send initial message over WebSocket: {}", e),
-                    Some(SQLITE_ERROR),
-                )
+        tracing::trace!(
+            frame = %logging::loggable_json(&frame),
+            "Sending request over WebSocket"
+        );
+
+        Message::Text(Utf8Bytes::from(frame.to_string()))
+    }
+
+    /// Writes all requests back-to-back without waiting for responses in between, then
+    /// collects the responses in order. Waiters are registered before anything is written
+    /// so a fast response can never race its waiter. Holding the writer lock for the whole
+    /// batch keeps the requests of one stream contiguous and in order, as Hrana requires.
+    async fn pipeline(&mut self, requests: Vec<Value>) -> Result<Vec<Value>, SqliteError> {
+        let socket = self.get_client().await?;
+        let bus = &socket.bus;
+        let permits = requests.len().clamp(1, MAX_IN_FLIGHT) as u32;
+        let _in_flight =
+            socket.in_flight.acquire_many(permits).await.map_err(|_| {
+                SqliteError::new("WebSocket connection is closed", Some(SQLITE_ERROR))
             })?;
 
-        let result = self.bus.wait_for("type:hello_ok").await;
-        if result.is_err() {
+        let mut pending = Vec::with_capacity(requests.len());
+        let mut frames = Vec::with_capacity(requests.len());
+        for request in requests {
+            let request_id = WebSocketStrategy::next_request_id();
+            let id = format!("request_id:{}", request_id);
+            pending.push((id.clone(), bus.register(&id, &self.metrics).await));
+            frames.push(WebSocketStrategy::request_frame(request_id, request));
+        }
+
+        let bytes_sent = frames.iter().map(|frame| frame.len() as u64).sum();
+        self.metrics.record_bytes(bytes_sent, 0);
+
+        let written = {
+            let mut writer = socket.writer.lock().await;
+            let mut written = Ok(());
+            for frame in frames {
+                written = writer.feed(frame).await;
+                if written.is_err() {
+                    break;
+                }
+            }
+            match written {
+                Ok(()) => writer.flush().await,
+                err => err,
+            }
+        };
+        if let Err(e) = written {
+            // A socket that cannot be written to is gone for every request sent on it
+            socket.close(&e.to_string()).await;
             return Err(SqliteError::new(
-                "Failed to validate database URL & Token. Try again".to_string(),
+                format!("Failed to send request over WebSocket: {}", e),
                 Some(SQLITE_ERROR),
             ));
         }
 
-        self.websocket_handle = Some(writer);
-        self.websocket_state = Arc::new(Mutex::new(WebSocketConnState::Connected));
-        Ok(())
+        let mut responses = Vec::with_capacity(pending.len());
+        for (id, receiver) in pending {
+            let response = bus.wait(&id, receiver, self.timeout).await?;
+            if let Some(error) = response_error(&response) {
+                return Err(error);
+            }
+            responses.push(response);
+        }
+
+        Ok(responses)
     }
 
-    async fn get_client(
+    /// Opens a fresh stream and runs `request` on it in a single round trip. Unless the
+    /// stream is kept open for a transaction, it is closed in the same batch.
+    async fn send_on_new_stream(
         &mut self,
-    ) -> Result<
-        (
-            &mut SplitSink<
-                WebSocketStream<MaybeTlsStream<TcpStream>>,
-                tokio_tungstenite::tungstenite::Message,
-            >,
-            ResponseBus,
-        ),
-        SqliteError,
-    > {
-        let state = self.websocket_state.lock().await;
-        let need_connect =
-            self.websocket_handle.is_none() || *state == WebSocketConnState::Disconnected;
-        drop(state);
-
-        if need_connect {
-            self.connect().await?;
+        mut request: Value,
+        prelude: Vec<Value>,
+        keep_open: bool,
+    ) -> Result<(i32, Value), SqliteError> {
+        let stream_id = WebSocketStrategy::next_stream_id();
+        request["stream_id"] = serde_json::Value::from(stream_id);
+
+        let session = self.session.iter().map(|sql| {
+            to_json(&StreamRequest::Execute {
+                stream_id: Some(stream_id),
+                stmt: Stmt::new(sql),
+            })
+        });
+        let mut requests = vec![to_json(&StreamRequest::OpenStream { stream_id })];
+        requests.extend(session);
+        requests.extend(prelude);
+        let request_index = requests.len();
+        requests.push(request);
+        if !keep_open {
+            requests.push(close_stream_request(stream_id));
         }
 
-        Ok((self.websocket_handle.as_mut().unwrap(), self.bus.clone()))
+        let mut responses = self.pipeline(requests).await?;
+        let response = responses.swap_remove(request_index);
+
+        Ok((stream_id, response))
     }
-}
 
-impl LibsqlInterface for WebSocketStrategy {
-    async fn get_transaction_baton(&mut self, sql: &str) -> Result<String, SqliteError> {
-        let (stream_id, _) = self.open_stream().await?;
-        let mut request = serde_json::json!({
-            "type": "execute",
-            "stream_id": stream_id,
-            "stmt": {
-                "sql": sql
-            }
-        });
+    /// Registers a persistent statement handle for `sql`. The text is stored lazily on
+    /// first execution so preparing stays free of round trips.
+    pub fn retain_sql(&mut self, sql: &str) {
+        self.persistent_sql
+            .entry(sql.to_string())
+            .or_insert(PersistentSql {
+                handles: 0,
+                sql_id: None,
+            })
+            .handles += 1;
+    }
 
-        let result = self.send(&mut request).await;
-        if let Err(e) = result {
-            return Err(SqliteError::new(
-                format!("Failed to get transaction baton: {}", e),
-                Some(SQLITE_ERROR),
-            ));
+    /// Drops a persistent statement handle, closing the stored text once nothing uses it.
+    pub async fn release_sql(&mut self, sql: &str) {
+        let Some(entry) = self.persistent_sql.get_mut(sql) else {
+            return;
+        };
+        entry.handles = entry.handles.saturating_sub(1);
+        if entry.handles > 0 {
+            return;
+        }
+
+        let sql_id = entry.sql_id;
+        self.persistent_sql.remove(sql);
+        if let Some(sql_id) = sql_id {
+            if self.is_connected().await {
+                let close_sql = to_json(&StreamRequest::CloseSql { sql_id });
+                if let Err(err) = self.pipeline(vec![close_sql]).await {
+                    tracing::debug!(sql_id, error = %err, "Failed to close stored SQL");
+                }
+            }
         }
+    }
+
+    /// Replaces the statement's text with its sql_id. When the current socket has not
+    /// stored the text yet, the store_sql request to send ahead of it is returned.
+    fn use_stored_sql(&mut self, request: &mut Value) -> Option<Value> {
+        let sql = request["stmt"]["sql"].as_str()?;
+        let entry = self.persistent_sql.get_mut(sql)?;
+
+        let store = match entry.sql_id {
+            Some(_) => None,
+            None => {
+                let sql_id = WebSocketStrategy::next_sql_id();
+                entry.sql_id = Some(sql_id);
+                Some(to_json(&StreamRequest::StoreSql {
+                    sql_id,
+                    sql: sql.to_string(),
+                }))
+            }
+        };
+
+        let stmt = request["stmt"].as_object_mut()?;
+        stmt.remove("sql");
+        stmt.insert("sql_id".to_string(), Value::from(entry.sql_id));
 
-        Ok(stream_id.to_string())
+        store
     }
 
-    async fn send(
+    /// Like `send`, but sends only the sql_id of statements prepared as persistent.
+    pub async fn send_persistent(
         &mut self,
         request: &mut serde_json::Value,
     ) -> Result<RemoteSqliteResponse, SqliteError> {
-        if let WebSocketConnState::Disconnected = *self.websocket_state.lock().await {
+        self.check_stream_alive(request).await?;
+        // Connect first so the stored ids below belong to the socket that will be used
+        self.get_client().await?;
+
+        let sql = request["stmt"]["sql"].as_str().map(str::to_string);
+        let store = self.use_stored_sql(request);
+        let stored_now = store.is_some();
+
+        let result = self
+            .send_with_prelude(request, store.into_iter().collect())
+            .await;
+        if result.is_err() && stored_now {
+            // The server may not have kept the text, store it again next time
+            if let Some(entry) = sql.and_then(|sql| self.persistent_sql.get_mut(&sql)) {
+                entry.sql_id = None;
+            }
+        }
+
+        result
+    }
+
+    async fn check_stream_alive(&self, request: &Value) -> Result<(), SqliteError> {
+        // Streams do not survive a reconnect, but stateless requests can simply reconnect
+        if request.get("stream_id").is_some() && !self.is_connected().await {
             return Err(SqliteError::new(
                 "WebSocket connection is disconnected".to_string(),
                 Some(SQLITE_ERROR),
             ));
         }
 
-        let bus: ResponseBus;
+        Ok(())
+    }
+
+    async fn send_with_prelude(
+        &mut self,
+        request: &mut serde_json::Value,
+        prelude: Vec<Value>,
+    ) -> Result<RemoteSqliteResponse, SqliteError> {
+        self.check_stream_alive(request).await?;
 
-        if request.get("stream_id").is_none() {
-            let (stream_id, actual_bus) = self.open_stream().await?;
+        let result = if request.get("stream_id").is_none() {
+            let (stream_id, response) = self
+                .send_on_new_stream(request.clone(), prelude, false)
+                .await?;
             request["stream_id"] = serde_json::Value::from(stream_id);
-            bus = actual_bus;
+            response
         } else {
-            bus = self.bus.clone();
-        }
-
-        let request_id = WebSocketStrategy::next_request_id();
-        let request = serde_json::json!({
-            "type": "request",
-            "request_id": request_id,
-            "request": request
-        });
-
-        let writer = self.websocket_handle.as_mut().unwrap();
-
-        if cfg!(debug_assertions) {
-            println!("Sending request over WebSocket: {:?}", request);
-        }
-
-        writer
-            .send(tokio_tungstenite::tungstenite::Message::Text(
-                Utf8Bytes::from(request.to_string()),
-            ))
-            .await
-            .unwrap();
-
-        let result = bus
-            .wait_for(format!("request_id:{}", request_id).as_str())
-            .await?;
+            let mut requests = prelude;
+            requests.push(request.clone());
+            let mut responses = self.pipeline(requests).await?;
+            responses.pop().unwrap_or(Value::Null)
+        };
 
         let parsed: RemoteSQliteResultType = serde_json::from_value(result).map_err(|e| {
             SqliteError::new(
@@ -302,7 +433,7 @@ impl LibsqlInterface for WebSocketStrategy {
         if let RemoteSQLiteResult::Error { message, code } = result {
             return Err(SqliteError::new(
                 format!("Remote SQLite error (code {}): {}", code, message),
-                Some(SQLITE_ERROR),
+                Some(error_code(&code, &message)),
             ));
         }
         if let RemoteSQLiteResult::Close = result {
@@ -312,12 +443,10 @@ impl LibsqlInterface for WebSocketStrategy {
             ));
         }
 
-        if let RemoteSQLiteResult::Execute { result } = result {
+        if let RemoteSQLiteResult::Execute { .. } | RemoteSQLiteResult::Batch { .. } = result {
             return Ok(RemoteSqliteResponse {
                 baton: None,
-                results: vec![RemoteSQliteResultType {
-                    response: RemoteSQLiteResult::Execute { result },
-                }],
+                results: vec![RemoteSQliteResultType { response: result }],
             });
         }
 
@@ -327,64 +456,998 @@ impl LibsqlInterface for WebSocketStrategy {
         })
     }
 
+    pub async fn is_connected(&self) -> bool {
+        match &self.socket {
+            Some(socket) => socket.is_connected().await,
+            None => false,
+        }
+    }
+
+    /// Lets go of the socket. The last connection using it sends a Close frame so the server
+    /// can release the connection right away.
+    pub async fn close(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            if socket.users.fetch_sub(1, Ordering::AcqRel) == 1 {
+                socket.close("closed").await;
+            }
+        }
+    }
+
+    /// Drops the socket inherited by a forked child without touching it: it is still the
+    /// parent's, and its reader task did not survive the fork. The next request reconnects.
+    pub fn forget_socket(&mut self) {
+        std::mem::forget(self.socket.take());
+        for entry in self.persistent_sql.values_mut() {
+            entry.sql_id = None;
+        }
+    }
+
+    /// Connects, on the socket shared with other connections when there is one.
+    pub async fn connect(&mut self) -> Result<(), SqliteError> {
+        self.close().await;
+
+        let socket = match &self.share_key {
+            Some(key) => {
+                let slot = shared_slot(key);
+                let mut shared = slot.lock().await;
+                match shared.as_ref() {
+                    Some(socket) if socket.is_connected().await => socket.clone(),
+                    _ => {
+                        let socket = self.open_socket().await?;
+                        *shared = Some(socket.clone());
+                        socket
+                    }
+                }
+            }
+            None => self.open_socket().await?,
+        };
+        socket.users.fetch_add(1, Ordering::AcqRel);
+        self.socket = Some(socket);
+
+        // Stored SQL belongs to the old socket, re-store it on next use
+        for entry in self.persistent_sql.values_mut() {
+            entry.sql_id = None;
+        }
+
+        if self.has_connected {
+            self.metrics.record_reconnect();
+        }
+        self.has_connected = true;
+
+        Ok(())
+    }
+
+    /// The endpoint on the host currently in use.
+    pub fn endpoint(&self) -> String {
+        match &self.hosts {
+            Some(hosts) => hosts.url(&self.url),
+            None => self.url.clone(),
+        }
+    }
+
+    // Dials the server, on the next host whenever one cannot be reached, and says hello
+    async fn open_socket(&self) -> Result<Arc<Socket>, SqliteError> {
+        let attempts = self.hosts.as_ref().map_or(1, |hosts| hosts.count());
+        let mut tried = 0;
+        let socket = loop {
+            let url = self.endpoint();
+            tried += 1;
+            match self.dial(&url).await {
+                Ok(socket) => break socket,
+                Err((err, unreachable)) => {
+                    if unreachable {
+                        if let Some(hosts) = &self.hosts {
+                            hosts.fail_over(&url);
+                        }
+                    }
+                    if !unreachable || tried >= attempts {
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        let (mut writer, reader) = socket.split();
+
+        let bus = ResponseBus::new();
+        // Every socket gets its own state so a stale reader cannot flag a newer socket
+        let websocket_state = Arc::new(Mutex::new(WebSocketConnState::Connected));
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+
+        get_tokio().spawn(read_socket(
+            reader,
+            bus.clone(),
+            websocket_state.clone(),
+            last_activity.clone(),
+        ));
+
+        let hello = bus.register("type:hello", &self.metrics).await;
+
+        // sqld without authentication expects no jwt rather than an empty one
+        let jwt = Some(&self.turso_config.db_token).filter(|token| !token.is_empty());
+        let json = to_json(&ClientMsg::Hello { jwt: jwt.cloned() });
+
+        writer
+            .send(tokio_tungstenite::tungstenite::Message::Text(
+                Utf8Bytes::from(json.to_string()),
+            ))
+            .await
+            .map_err(|e| {
+                SqliteError::new(
+                    format!("Failed to send initial message over WebSocket: {}", e),
+                    Some(SQLITE_ERROR),
+                )
+            })?;
+
+        match bus.wait("type:hello", hello, self.timeout).await {
+            Ok(hello) if hello.get("type").and_then(|t| t.as_str()) == Some("hello_ok") => {}
+            Ok(hello) => {
+                let error = hello.get("error");
+                let message = error
+                    .and_then(|e| e.get("message"))
+                    .and_then(|m| m.as_str())
+                    .unwrap_or("Unknown error");
+                let code = error
+                    .and_then(|e| e.get("code"))
+                    .and_then(|c| c.as_str())
+                    .unwrap_or_default();
+                // The hello only carries the token, whatever the server says went wrong
+                let code = match error_code(code, message) {
+                    SQLITE_ERROR => SQLITE_AUTH,
+                    code => code,
+                };
+                return Err(SqliteError::new(
+                    format!("The server refused the database token: {}", message),
+                    Some(code),
+                ));
+            }
+            Err(_) => {
+                return Err(SqliteError::new(
+                    "Failed to validate database URL & Token. Try again".to_string(),
+                    Some(SQLITE_ERROR),
+                ));
+            }
+        }
+
+        let socket = Arc::new(Socket {
+            writer: Mutex::new(writer),
+            bus,
+            state: websocket_state,
+            in_flight: Semaphore::new(MAX_IN_FLIGHT),
+            users: AtomicUsize::new(0),
+            pid: std::process::id(),
+        });
+        self.spawn_keepalive(&socket, last_activity);
+
+        Ok(socket)
+    }
+
+    // Opens the WebSocket at `url`. Errors say whether the host could not be reached at all.
+    async fn dial(
+        &self,
+        url: &str,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, (SqliteError, bool)> {
+        let invalid_url = |e: &dyn std::fmt::Display| {
+            let message = format!("Invalid database URL: {}", e);
+            (SqliteError::new(message, Some(SQLITE_ERROR)), false)
+        };
+        let mut request = url.into_client_request().map_err(|e| invalid_url(&e))?;
+        if let Some(authorization) = &self.authorization {
+            if let Ok(value) = authorization.parse() {
+                request.headers_mut().insert("Authorization", value);
+            }
+        }
+        tracing::debug!(%url, "Connecting to WebSocket");
+
+        let to_error = |e: tokio_tungstenite::tungstenite::Error| {
+            let message = format!("Failed to connect to WebSocket: {}", e);
+            // The handshake is plain HTTP, the server refuses bad credentials there too
+            let code = match &e {
+                tokio_tungstenite::tungstenite::Error::Http(response) => {
+                    status_code(response.status().as_u16(), &message)
+                }
+                _ => SQLITE_ERROR,
+            };
+            SqliteError::new(message, Some(code))
+        };
+
+        let parsed_url = reqwest::Url::parse(url).map_err(|e| invalid_url(&e))?;
+        let host = parsed_url.host_str().unwrap_or_default().to_string();
+        let port = parsed_url.port_or_known_default().unwrap_or(443);
+        let secure = parsed_url.scheme() == "wss";
+        let connector = self.tls_connector.clone();
+
+        let stream = match resolve_proxy(self.proxy.as_deref(), &host, port, secure) {
+            Some(proxy) => connect_via_proxy(&proxy, &host, port)
+                .await
+                .map_err(|err| (err, false))?,
+            None => self.connect_tcp(&host, port).await.map_err(|e| {
+                let message = format!("Failed to connect to WebSocket: {}", e);
+                (SqliteError::new(message, Some(SQLITE_ERROR)), true)
+            })?,
+        };
+        let max_message = Some(self.max_message).filter(|max| *max > 0);
+        let config = WebSocketConfig::default()
+            .max_message_size(max_message)
+            .max_frame_size(max_message);
+        let (socket, _) = tokio_tungstenite::client_async_tls_with_config(
+            request,
+            stream,
+            Some(config),
+            connector,
+        )
+        .await
+        .map_err(|e| (to_error(e), false))?;
+        Ok(socket)
+    }
+
+    // A TCP connection to `host`, through the shared DNS cache
+    async fn connect_tcp(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = resolver::lookup(host, self.dns_ttl)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        TcpStream::connect(addrs.as_slice()).await
+    }
+
+    fn spawn_keepalive(&self, socket: &Arc<Socket>, last_activity: Arc<Mutex<Instant>>) {
+        if self.ping_interval.is_zero() {
+            return;
+        }
+
+        // A weak handle lets the task end once no connection holds this socket anymore
+        get_tokio().spawn(keepalive(
+            Arc::downgrade(socket),
+            last_activity,
+            self.ping_interval,
+            self.idle_timeout,
+        ));
+    }
+
+    async fn get_client(&mut self) -> Result<Arc<Socket>, SqliteError> {
+        if !self.is_connected().await {
+            self.connect().await?;
+        }
+
+        Ok(self.socket.clone().expect("connected without a socket"))
+    }
+}
+
+// Reads the socket until it drops, handing every response to the request waiting for it
+async fn read_socket(
+    mut reader: WebSocketReader,
+    bus: ResponseBus,
+    state: Arc<Mutex<WebSocketConnState>>,
+    last_activity: Arc<Mutex<Instant>>,
+) {
+    let reason = loop {
+        let message = match reader.next().await {
+            Some(Ok(Message::Close(frame))) => {
+                break match frame {
+                    Some(frame) => format!("closed by the server ({})", frame.code),
+                    None => "closed by the server".to_string(),
+                }
+            }
+            Some(Ok(message)) => message,
+            Some(Err(e)) => break e.to_string(),
+            None => break "closed".to_string(),
+        };
+        *last_activity.lock().await = Instant::now();
+
+        let size = message.len() as u64;
+        let Some(value) = read_frame(message) else {
+            continue;
+        };
+        match response_key(&value) {
+            Some(key) => bus.respond(&key, value, size).await,
+            None => tracing::warn!(
+                frame = %logging::loggable_json(&value),
+                "Ignoring WebSocket message that answers no request"
+            ),
+        }
+    };
+
+    tracing::warn!(%reason, "WebSocket connection lost");
+    *state.lock().await = WebSocketConnState::Disconnected;
+    bus.lose(&reason).await;
+}
+
+/// Pings the server periodically and marks the connection as disconnected when nothing
+/// has been heard for `idle_timeout`, so the next request reconnects up front instead
+/// of waiting for a response that will never arrive.
+async fn keepalive(
+    socket: Weak<Socket>,
+    last_activity: Arc<Mutex<Instant>>,
+    ping_interval: Duration,
+    idle_timeout: Duration,
+) {
+    let mut ticker = tokio::time::interval(ping_interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let Some(socket) = socket.upgrade() else {
+            break;
+        };
+        if *socket.state.lock().await == WebSocketConnState::Disconnected {
+            break;
+        }
+
+        let idle_for = last_activity.lock().await.elapsed();
+        let ping = socket
+            .writer
+            .lock()
+            .await
+            .send(Message::Ping(Vec::new().into()))
+            .await;
+
+        if ping.is_err() || idle_for > idle_timeout {
+            tracing::warn!(?idle_for, "WebSocket idle, marking disconnected");
+            let reason = match ping {
+                Err(_) => "keepalive ping failed",
+                Ok(_) => "no traffic within the idle timeout",
+            };
+            socket.close(reason).await;
+            break;
+        }
+    }
+}
+
+impl LibsqlInterface for WebSocketStrategy {
+    fn get_transaction_baton<'a>(&'a mut self, sql: &'a str) -> TransportFuture<'a, String> {
+        Box::pin(async move {
+            let request = to_json(&StreamRequest::execute(Stmt::new(sql)));
+
+            let (stream_id, _) = self
+                .send_on_new_stream(request, vec![], true)
+                .await
+                .map_err(|e| {
+                    SqliteError::new(
+                        format!("Failed to get transaction baton: {}", e),
+                        Some(SQLITE_ERROR),
+                    )
+                })?;
+
+            Ok(stream_id.to_string())
+        })
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        request: &'a mut serde_json::Value,
+    ) -> TransportFuture<'a, RemoteSqliteResponse> {
+        Box::pin(self.send_with_prelude(request, vec![]))
+    }
+
+    fn close_stream<'a>(&'a mut self, stream_id: &'a str) -> TransportFuture<'a, ()> {
+        Box::pin(async move {
+            let stream_id = stream_id.parse::<i32>().map_err(|_| {
+                SqliteError::new(
+                    format!("Invalid stream id '{}'", stream_id),
+                    Some(SQLITE_ERROR),
+                )
+            })?;
+
+            // Nothing to release if the socket (and with it the stream) is already gone
+            if !self.is_connected().await {
+                return Ok(());
+            }
+
+            self.pipeline(vec![close_stream_request(stream_id)]).await?;
+            Ok(())
+        })
+    }
+
+    fn ping(&mut self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            // Hrana over WebSocket has no no-op request, opening and closing a stream is the cheapest
+            let stream_id = WebSocketStrategy::next_stream_id();
+            let open_stream = to_json(&StreamRequest::OpenStream { stream_id });
+
+            self.pipeline(vec![open_stream, close_stream_request(stream_id)])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn describe<'a>(&'a mut self, sql: &'a str) -> TransportFuture<'a, Vec<RemoteCol>> {
+        Box::pin(async move {
+            let request = to_json(&StreamRequest::Describe {
+                stream_id: None,
+                sql: sql.to_string(),
+            });
+
+            let (_, response) = self.send_on_new_stream(request, vec![], false).await?;
+            describe_columns(&response)
+        })
+    }
+
     fn get_json_request(
         &self,
         sql: &str,
-        params: &Vec<serde_json::Value>,
+        params: &StatementArgs,
         stream_id: Option<&String>,
         is_transacting: bool,
     ) -> serde_json::Value {
-        let mut request = serde_json::json!({
-            "type": "execute",
-            "stmt": {
-                "sql": sql,
-                "args": params
-            }
-        });
-
-        if is_transacting {
-            let stream_id: i32 = stream_id.and_then(|s| s.parse::<i32>().ok()).unwrap();
-            request["stream_id"] = serde_json::json!(stream_id);
-        }
+        // Outside a transaction the stream is filled in once it has been opened
+        let stream_id =
+            is_transacting.then(|| stream_id.and_then(|s| s.parse::<i32>().ok()).unwrap());
 
-        request
+        to_json(&StreamRequest::Execute {
+            stream_id,
+            stmt: params.to_stmt(sql),
+        })
     }
 }
 
+// A waiter, with the metrics of the connection the response is counted for
+type Waiter = (oneshot::Sender<Result<Value, SqliteError>>, Arc<Metrics>);
+
+#[derive(Default)]
+struct Waiters {
+    pending: HashMap<String, Waiter>,
+    lost: Option<String>, // Why the socket went away, nothing is answered after that
+}
+
 #[derive(Clone)]
 struct ResponseBus {
-    map: Arc<Mutex<HashMap<String, oneshot::Sender<Value>>>>,
+    waiters: Arc<Mutex<Waiters>>,
 }
 
 impl ResponseBus {
     pub fn new() -> Self {
         Self {
-            map: Arc::new(Mutex::new(HashMap::new())),
+            waiters: Arc::default(),
         }
     }
 
-    pub async fn wait_for(&self, id: &str) -> Result<Value, SqliteError> {
+    /// Registers interest in `id`. Must happen before the request is written. On a socket
+    /// that is already gone the wait fails straight away.
+    pub async fn register(
+        &self,
+        id: &str,
+        metrics: &Arc<Metrics>,
+    ) -> oneshot::Receiver<Result<Value, SqliteError>> {
         let (tx, rx) = oneshot::channel();
-        self.map.lock().await.insert(id.to_string(), tx);
+        let mut waiters = self.waiters.lock().await;
+        match &waiters.lost {
+            Some(reason) => {
+                let _ = tx.send(Err(connection_lost(reason)));
+            }
+            None => {
+                waiters
+                    .pending
+                    .insert(id.to_string(), (tx, metrics.clone()));
+            }
+        }
+        rx
+    }
+
+    pub async fn cancel(&self, id: &str) {
+        self.waiters.lock().await.pending.remove(id);
+    }
 
-        match tokio::time::timeout(Duration::from_secs(10), rx).await {
+    pub async fn wait(
+        &self,
+        id: &str,
+        rx: oneshot::Receiver<Result<Value, SqliteError>>,
+        timeout: Duration,
+    ) -> Result<Value, SqliteError> {
+        match tokio::time::timeout(timeout, rx).await {
             Ok(result) => match result {
-                Ok(value) => Ok(value),
+                Ok(answer) => answer,
                 Err(_) => Err(SqliteError::new(
                     "Failed to receive response".to_string(),
                     Some(SQLITE_ERROR),
                 )),
             },
-            Err(_) => Err(SqliteError::new(
-                "Response timed out".to_string(),
-                Some(SQLITE_ERROR),
-            )),
+            Err(_) => {
+                // Drop the stale sender so a late response is not delivered to nobody
+                self.cancel(id).await;
+                Err(SqliteError::new(
+                    format!("Response timed out after {}ms", timeout.as_millis()),
+                    Some(SQLITE_BUSY_TIMEOUT),
+                ))
+            }
+        }
+    }
+
+    /// Fails every request still waiting, and any registered later, with the socket's loss
+    /// rather than letting them run into their timeout. The first reason given is kept.
+    pub async fn lose(&self, reason: &str) {
+        let mut waiters = self.waiters.lock().await;
+        let reason = waiters
+            .lost
+            .get_or_insert_with(|| reason.to_string())
+            .clone();
+        for (_, (sender, _)) in waiters.pending.drain() {
+            let _ = sender.send(Err(connection_lost(&reason)));
+        }
+    }
+
+    /// Hands `value`, a frame of `size` bytes, to whoever waits for `id`.
+    pub async fn respond(&self, id: &str, value: Value, size: u64) {
+        if let Some((sender, metrics)) = self.waiters.lock().await.pending.remove(id) {
+            metrics.record_bytes(0, size);
+            let _ = sender.send(Ok(value));
+        }
+    }
+}
+
+fn connection_lost(reason: &str) -> SqliteError {
+    SqliteError::new(
+        format!("WebSocket connection lost: {}", reason),
+        Some(SQLITE_ERROR),
+    )
+}
+
+// The slot for sockets shared under `key`. Connecting holds its lock, so connections opened
+// together wait for one handshake instead of each making their own.
+fn shared_slot(key: &str) -> SocketSlot {
+    let mut sockets = SHARED_SOCKETS.lock().unwrap();
+    sockets
+        .get_or_insert_with(HashMap::new)
+        .entry(key.to_string())
+        .or_default()
+        .clone()
+}
+
+// The JSON of a message from the server. Anything else is logged and skipped: tungstenite
+// answers pings on its own, and pongs only refresh the activity clock.
+fn read_frame(message: Message) -> Option<Value> {
+    let parsed = match &message {
+        Message::Text(text) => serde_json::from_str(text),
+        Message::Binary(binary) => serde_json::from_slice(binary),
+        Message::Ping(_) | Message::Pong(_) => return None,
+        _ => {
+            tracing::warn!(?message, "Received unsupported WebSocket message type");
+            return None;
+        }
+    };
+
+    parsed
+        .inspect_err(|e| tracing::warn!("Failed to parse WebSocket message as JSON: {}", e))
+        .ok()
+}
+
+// The waiter a message from the server is for: responses carry the id of their request,
+// anything else is told apart by its type
+fn response_key(value: &Value) -> Option<String> {
+    if let Some(request_id) = value.get("request_id") {
+        return request_id
+            .as_i64()
+            .map(|request_id| format!("request_id:{}", request_id));
+    }
+    if let Some(id) = value.get("id") {
+        return id.as_i64().map(|id| format!("id:{}", id));
+    }
+
+    match value.get("type")?.as_str()? {
+        // Both answers to the hello go to the one waiting for it
+        "hello_ok" | "hello_error" => Some("type:hello".to_string()),
+        other => Some(format!("type:{}", other)),
+    }
+}
+
+fn close_stream_request(stream_id: i32) -> Value {
+    to_json(&StreamRequest::CloseStream { stream_id })
+}
+
+// Hrana reports failed requests as `response_error` frames that carry no `response` body
+fn response_error(value: &Value) -> Option<SqliteError> {
+    if value.get("type").and_then(|t| t.as_str()) != Some("response_error") {
+        return None;
+    }
+
+    let error = value.get("error");
+    let message = error
+        .and_then(|e| e.get("message"))
+        .and_then(|m| m.as_str())
+        .unwrap_or("Unknown error");
+    let code = error
+        .and_then(|e| e.get("code"))
+        .and_then(|c| c.as_str())
+        .unwrap_or("UNKNOWN");
+
+    Some(SqliteError::new(
+        format!("Remote SQLite error (code {}): {}", code, message),
+        Some(error_code(code, message)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    use super::*;
+
+    // A Hrana server that says hello and answers every request with an empty `response_ok`,
+    // counting the sockets it accepted
+    async fn serve(listener: TcpListener, accepted: Arc<AtomicUsize>) {
+        serve_with(listener, accepted, |reply| vec![text(reply)]).await
+    }
+
+    // Like `serve`, sending the messages `frames` makes of each reply instead of the reply
+    async fn serve_with(
+        listener: TcpListener,
+        accepted: Arc<AtomicUsize>,
+        frames: fn(Value) -> Vec<Message>,
+    ) {
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(message))) = socket.next().await {
+                    let message: Value = serde_json::from_str(&message).unwrap();
+                    let reply = match message["type"].as_str() {
+                        Some("hello") => serde_json::json!({"type": "hello_ok"}),
+                        _ => serde_json::json!({
+                            "type": "response_ok",
+                            "request_id": message["request_id"],
+                            "response": {},
+                        }),
+                    };
+                    for frame in frames(reply) {
+                        if socket.send(frame).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
         }
     }
 
-    pub async fn respond(&self, id: &str, value: Value) {
-        if let Some(sender) = self.map.lock().await.remove(id) {
-            let _ = sender.send(value);
+    fn text(value: Value) -> Message {
+        Message::Text(Utf8Bytes::from(value.to_string()))
+    }
+
+    fn connection_to(url: &str) -> WebSocketStrategy {
+        let config = TursoConfig {
+            db_url: url.to_string(),
+            db_token: String::new(),
+        };
+        let metrics = Arc::new(Metrics::for_connection());
+        WebSocketStrategy::new(Arc::new(config), url.to_string(), None, metrics)
+    }
+
+    #[test]
+    fn connections_to_one_endpoint_share_a_socket() {
+        get_tokio().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let accepted = Arc::new(AtomicUsize::new(0));
+            tokio::spawn(serve(listener, accepted.clone()));
+
+            let connection = |shared: bool| {
+                let config = TursoConfig {
+                    db_url: url.clone(),
+                    db_token: "token".to_string(),
+                };
+                let metrics = Arc::new(Metrics::for_connection());
+                let mut websocket =
+                    WebSocketStrategy::new(Arc::new(config), url.clone(), None, metrics);
+                websocket.share(shared.then(String::new));
+                websocket
+            };
+
+            let (mut first, mut second) = (connection(true), connection(true));
+            first.ping().await.unwrap();
+            second.ping().await.unwrap();
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+            // The socket stays open for as long as any connection uses it
+            first.close().await;
+            second.ping().await.unwrap();
+            assert!(second.is_connected().await);
+            second.close().await;
+
+            let mut own = connection(false);
+            own.ping().await.unwrap();
+            assert_eq!(accepted.load(Ordering::SeqCst), 2);
+            own.close().await;
+        });
+    }
+
+    #[test]
+    fn an_unreachable_host_fails_over_to_the_next() {
+        get_tokio().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let failover = format!("localhost:{}", listener.local_addr().unwrap().port());
+            tokio::spawn(serve(listener, Arc::new(AtomicUsize::new(0))));
+            // Nothing listens on a port just given back
+            let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let primary = closed.local_addr().unwrap().to_string();
+            drop(closed);
+
+            let url = format!("ws://{}", primary);
+            let config = TursoConfig {
+                db_url: url.clone(),
+                db_token: String::new(),
+            };
+            let metrics = Arc::new(Metrics::for_connection());
+            let mut websocket =
+                WebSocketStrategy::new(Arc::new(config), url.clone(), None, metrics);
+            let hosts = Hosts::of(&primary, std::slice::from_ref(&failover));
+            websocket.set_hosts(hosts.clone(), DEFAULT_DNS_TTL);
+
+            websocket.ping().await.unwrap();
+            assert_eq!(hosts.url(&url), format!("ws://{}", failover));
+            websocket.close().await;
+        });
+    }
+
+    #[test]
+    fn malformed_messages_are_skipped() {
+        get_tokio().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            tokio::spawn(serve_with(listener, Arc::default(), |reply| {
+                vec![
+                    Message::Text(Utf8Bytes::from_static("not json")),
+                    Message::Binary(vec![0xff, 0x00].into()),
+                    text(serde_json::json!({})),
+                    text(serde_json::json!({"type": 7})),
+                    text(serde_json::json!({"request_id": "x", "type": "response_ok"})),
+                    Message::Binary(reply.to_string().into_bytes().into()),
+                ]
+            }));
+
+            let mut websocket = connection_to(&url);
+            websocket.ping().await.unwrap();
+            websocket.ping().await.unwrap();
+            assert!(websocket.is_connected().await);
+            websocket.close().await;
+        });
+    }
+
+    #[test]
+    fn an_oversized_message_drops_the_socket() {
+        get_tokio().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            tokio::spawn(serve_with(listener, Arc::default(), |mut reply| {
+                if reply["type"] == "response_ok" {
+                    reply["response"]["padding"] = Value::from("x".repeat(4096));
+                }
+                vec![text(reply)]
+            }));
+
+            let mut websocket = connection_to(&url);
+            websocket.set_max_message(1024);
+            let started = Instant::now();
+            assert!(websocket.ping().await.is_err());
+            // The request fails with the socket rather than when it times out
+            assert!(started.elapsed() < DEFAULT_REQUEST_TIMEOUT / 2);
+            assert!(!websocket.is_connected().await);
+            websocket.close().await;
+        });
+    }
+
+    #[test]
+    fn requests_fail_as_soon_as_the_socket_drops() {
+        get_tokio().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let accepted = Arc::new(AtomicUsize::new(0));
+            tokio::spawn(serve_with(listener, accepted.clone(), |reply| {
+                if reply["type"] == "response_ok" {
+                    vec![Message::Close(None)]
+                } else {
+                    vec![text(reply)]
+                }
+            }));
+
+            let mut websocket = connection_to(&url);
+            let started = Instant::now();
+            let err = websocket.ping().await.unwrap_err();
+            assert!(started.elapsed() < DEFAULT_REQUEST_TIMEOUT / 2);
+            assert!(err.message.contains("connection lost"), "{}", err.message);
+            assert!(!websocket.is_connected().await);
+
+            // The next request gets a socket of its own
+            let _ = websocket.ping().await;
+            assert_eq!(accepted.load(Ordering::SeqCst), 2);
+            websocket.close().await;
+        });
+    }
+
+    #[test]
+    fn a_lost_bus_fails_its_waiters_and_later_ones() {
+        get_tokio().block_on(async {
+            let bus = ResponseBus::new();
+            let metrics = Arc::new(Metrics::for_connection());
+            let pending = bus.register("request_id:1", &metrics).await;
+
+            bus.lose("closed by the server").await;
+            bus.lose("closed").await;
+            let late = bus.register("request_id:2", &metrics).await;
+            for (id, rx) in [("request_id:1", pending), ("request_id:2", late)] {
+                let err = bus.wait(id, rx, Duration::from_secs(60)).await.unwrap_err();
+                assert_eq!(
+                    err.message,
+                    "WebSocket connection lost: closed by the server"
+                );
+            }
+        });
+    }
+
+    // A runtime whose clock only moves when every task is waiting on it
+    fn paused_runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    // A connected socket whose reader and keepalive run on the current runtime, and the
+    // server's end of it
+    async fn socket_pair(
+        ping_interval: Duration,
+        idle_timeout: Duration,
+    ) -> (Arc<Socket>, WebSocketStream<TcpStream>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        for stream in [&client, &server] {
+            stream.set_nonblocking(true).unwrap();
         }
+
+        let client = MaybeTlsStream::Plain(TcpStream::from_std(client).unwrap());
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let server = TcpStream::from_std(server).unwrap();
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+
+        let (writer, reader) = client.split();
+        let bus = ResponseBus::new();
+        let state = Arc::new(Mutex::new(WebSocketConnState::Connected));
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        tokio::spawn(read_socket(
+            reader,
+            bus.clone(),
+            state.clone(),
+            last_activity.clone(),
+        ));
+
+        let socket = Arc::new(Socket {
+            writer: Mutex::new(writer),
+            bus,
+            state,
+            in_flight: Semaphore::new(MAX_IN_FLIGHT),
+            users: AtomicUsize::new(1),
+            pid: std::process::id(),
+        });
+        tokio::spawn(keepalive(
+            Arc::downgrade(&socket),
+            last_activity,
+            ping_interval,
+            idle_timeout,
+        ));
+
+        (socket, server)
+    }
+
+    #[test]
+    fn the_idle_timeout_closes_a_silent_socket() {
+        paused_runtime().block_on(async {
+            let idle_timeout = Duration::from_secs(45);
+            // The server never reads, so no ping gets its pong
+            let (socket, _server) = socket_pair(Duration::from_secs(15), idle_timeout).await;
+            let metrics = Arc::new(Metrics::for_connection());
+            let pending = socket.bus.register("request_id:1", &metrics).await;
+
+            // The ping at 45s finds the socket idle for exactly the timeout, not longer
+            tokio::time::sleep(idle_timeout + Duration::from_secs(5)).await;
+            assert!(socket.is_connected().await);
+
+            tokio::time::sleep(Duration::from_secs(15)).await;
+            assert!(!socket.is_connected().await);
+            let err = socket
+                .bus
+                .wait("request_id:1", pending, Duration::from_secs(60))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.message,
+                "WebSocket connection lost: no traffic within the idle timeout"
+            );
+        });
+    }
+
+    #[test]
+    fn a_missed_pong_reconnects_on_the_next_request() {
+        paused_runtime().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let accepted = Arc::new(AtomicUsize::new(0));
+            tokio::spawn(serve(listener, accepted.clone()));
+
+            let (socket, mut server) =
+                socket_pair(Duration::from_secs(15), Duration::from_secs(45)).await;
+            // Reading answers the pings, until the server stops at 110s
+            tokio::spawn(async move {
+                let answer = async { while let Some(Ok(_)) = server.next().await {} };
+                let _ = tokio::time::timeout(Duration::from_secs(110), answer).await;
+                std::future::pending::<()>().await;
+                drop(server);
+            });
+
+            let mut websocket = connection_to(&url);
+            websocket.set_keepalive(Duration::ZERO, Duration::ZERO);
+            websocket.socket = Some(socket);
+
+            // Pongs keep the socket open well past the idle timeout
+            tokio::time::sleep(Duration::from_secs(100)).await;
+            assert!(websocket.is_connected().await);
+
+            // The last pong answered the ping at 105s, the one at 165s finds it gone
+            tokio::time::sleep(Duration::from_secs(70)).await;
+            assert!(!websocket.is_connected().await);
+
+            // The reconnect is real network traffic, which the clock must not outrun
+            tokio::time::resume();
+            websocket.ping().await.unwrap();
+            assert!(websocket.is_connected().await);
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+            websocket.close().await;
+        });
+    }
+
+    #[test]
+    fn responses_reach_only_registered_waiters() {
+        paused_runtime().block_on(async {
+            let bus = ResponseBus::new();
+            let metrics = Arc::new(Metrics::for_connection());
+            let received = || metrics.to_json()["bytes_received"].as_u64().unwrap();
+
+            // A response registered for is kept until its waiter gets to it
+            let rx = bus.register("request_id:1", &metrics).await;
+            bus.respond("request_id:1", Value::from(1), 10).await;
+            let answer = bus.wait("request_id:1", rx, Duration::from_secs(5)).await;
+            assert_eq!(answer.unwrap(), Value::from(1));
+            assert_eq!(received(), 10);
+
+            // One that comes before anyone registered for it answers nobody
+            bus.respond("request_id:2", Value::from(2), 10).await;
+            let rx = bus.register("request_id:2", &metrics).await;
+            let err = bus
+                .wait("request_id:2", rx, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code, SQLITE_BUSY_TIMEOUT);
+            assert_eq!(received(), 10);
+        });
+    }
+
+    #[test]
+    fn a_timed_out_wait_cancels_its_registration() {
+        paused_runtime().block_on(async {
+            let bus = ResponseBus::new();
+            let metrics = Arc::new(Metrics::for_connection());
+
+            let rx = bus.register("request_id:1", &metrics).await;
+            let err = bus
+                .wait("request_id:1", rx, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            assert_eq!(err.message, "Response timed out after 5000ms");
+            assert!(bus.waiters.lock().await.pending.is_empty());
+
+            // The late response is dropped rather than counted for a request given up on
+            bus.respond("request_id:1", Value::from(1), 10).await;
+            assert_eq!(metrics.to_json()["bytes_received"], 0);
+
+            // Cancelling what is not registered anymore is harmless
+            bus.cancel("request_id:1").await;
+            let rx = bus.register("request_id:1", &metrics).await;
+            bus.cancel("request_id:1").await;
+            let err = bus
+                .wait("request_id:1", rx, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            assert_eq!(err.message, "Failed to receive response");
+        });
     }
 }
@@ -1,112 +1,244 @@
-use std::{collections::HashMap, ffi::c_int, sync::OnceLock};
+use std::{
+    ffi::c_int,
+    sync::{
+        atomic::{AtomicPtr, AtomicU32, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
 use regex::Regex;
-use tokio::runtime::{self, Runtime};
+use tokio::{
+    runtime::{self, Runtime},
+    sync::oneshot,
+};
 
 use crate::{
-    sqlite::{push_error, SQLite3, SqliteError, Value, SQLITE_ERROR},
-    transport::{QueryResult, RemoteSQLiteResult, RemoteSqliteResponse},
+    protocol::{QueryResult, RemoteSqliteResponse},
+    sqlite::{push_error, SQLite3, SqliteError, SQLITE_MISUSE},
+    status,
+    worker::Worker,
 };
 
-static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+// The runtime and the process it was built in. A forked child inherits the runtime's memory
+// but none of its threads, so it builds one of its own on first use.
+static RUNTIME: AtomicPtr<Runtime> = AtomicPtr::new(std::ptr::null_mut());
+static RUNTIME_PID: AtomicU32 = AtomicU32::new(0);
+static RUNTIME_INIT: Mutex<()> = Mutex::new(());
+// Set with sqlite3_turso_config before the runtime is built, wins over TURSO_RUNTIME
+static RUNTIME_FLAVOR: Mutex<Option<RuntimeFlavor>> = Mutex::new(None);
+// Drives the runtime when it is a current-thread one
+static DRIVER: Mutex<Option<Driver>> = Mutex::new(None);
 
-pub fn get_tokio() -> &'static Runtime {
-    RUNTIME.get_or_init(|| {
-        runtime::Builder::new_multi_thread()
-            .worker_threads(num_cpus::get())
+pub const RUNTIME_KEY: &str = "runtime";
+pub const RUNTIME_ENV: &str = "TURSO_RUNTIME";
+
+// How long sqlite3_shutdown waits for blocking work, e.g. DNS lookups, still on the runtime
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The kind of runtime the library's I/O runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeFlavor {
+    MultiThread(usize), // With this many worker threads
+    CurrentThread,      // On a single thread of its own
+}
+
+impl RuntimeFlavor {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "multi_thread" => Some(RuntimeFlavor::MultiThread(num_cpus::get())),
+            "current_thread" => Some(RuntimeFlavor::CurrentThread),
+            value => {
+                let threads = value.strip_prefix("multi_thread:")?.parse().ok()?;
+                (threads > 0).then_some(RuntimeFlavor::MultiThread(threads))
+            }
+        }
+    }
+
+    // The configured flavor, else the environment's, else one worker thread per CPU
+    fn configured() -> Self {
+        let configured = *RUNTIME_FLAVOR.lock().unwrap_or_else(|e| e.into_inner());
+        configured
+            .or_else(|| {
+                let value = std::env::var(RUNTIME_ENV).ok()?;
+                let flavor = RuntimeFlavor::parse(&value);
+                if flavor.is_none() {
+                    tracing::warn!(%value, "Ignoring invalid {}", RUNTIME_ENV);
+                }
+                flavor
+            })
+            .unwrap_or(RuntimeFlavor::MultiThread(num_cpus::get()))
+    }
+
+    fn build(self) -> Runtime {
+        let mut builder = match self {
+            RuntimeFlavor::MultiThread(threads) => {
+                let mut builder = runtime::Builder::new_multi_thread();
+                builder.worker_threads(threads);
+                builder
+            }
+            RuntimeFlavor::CurrentThread => runtime::Builder::new_current_thread(),
+        };
+        builder
+            .thread_name("turso-runtime")
             .enable_all()
             .build()
             .expect("Failed to create Tokio runtime")
-    })
+    }
 }
 
-// Function to count parameters in the SQL string
-pub fn count_parameters(sql: &str) -> c_int {
-    let re = Regex::new(
-        r"(?x)
-        (?:
-            \?            # anonymous
-            \d*           # or numbered
-          |
-            [:@$]         # named parameters
-            [a-zA-Z_]\w*
+/// Sets the runtime the library builds on first use. Fails once it has been built.
+pub fn configure_runtime(value: &str) -> Result<(), SqliteError> {
+    let flavor = RuntimeFlavor::parse(value).ok_or_else(|| {
+        SqliteError::new(
+            format!(
+                "Invalid value '{}' for '{}': expected multi_thread, multi_thread:<threads> \
+                 or current_thread",
+                value, RUNTIME_KEY
+            ),
+            Some(SQLITE_MISUSE),
         )
-    ",
-    )
-    .unwrap();
+    })?;
 
-    re.find_iter(&sql).count() as c_int
+    let _init = RUNTIME_INIT.lock().unwrap_or_else(|e| e.into_inner());
+    if RUNTIME_PID.load(Ordering::Acquire) == std::process::id() {
+        return Err(SqliteError::new(
+            format!(
+                "'{}' is fixed once the library is in use, set it before the first connection \
+                 opens or after sqlite3_shutdown",
+                RUNTIME_KEY
+            ),
+            Some(SQLITE_MISUSE),
+        ));
+    }
+    *RUNTIME_FLAVOR.lock().unwrap_or_else(|e| e.into_inner()) = Some(flavor);
+    Ok(())
 }
 
-pub fn execute_async_task<F, R>(task: F) -> c_int
+/// A current-thread runtime only runs its I/O, timers and spawned tasks while some thread is
+/// inside its `block_on`. The driver sits there for as long as the runtime lives, so socket
+/// readers and keepalives go on between calls; callers' own `block_on`s poll their futures
+/// on their threads meanwhile.
+struct Driver {
+    stop: oneshot::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Driver {
+    fn start(runtime: &'static Runtime) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("turso-runtime".to_string())
+            .spawn(move || {
+                let _ = runtime.block_on(stopped);
+            })
+            .expect("failed to spawn runtime thread");
+        Self { stop, thread }
+    }
+
+    fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+pub fn get_tokio() -> &'static Runtime {
+    let pid = std::process::id();
+    if RUNTIME_PID.load(Ordering::Acquire) != pid {
+        let _init = RUNTIME_INIT.lock().unwrap_or_else(|e| e.into_inner());
+        if RUNTIME_PID.load(Ordering::Acquire) != pid {
+            let flavor = RuntimeFlavor::configured();
+            let runtime: &'static Runtime = Box::leak(Box::new(flavor.build()));
+            // An inherited runtime is leaked: shutting it down would wait for threads that
+            // only exist in the parent, and its I/O driver is still registered there. So is
+            // the parent's driver, whose thread did not survive the fork either.
+            let driver = (flavor == RuntimeFlavor::CurrentThread).then(|| Driver::start(runtime));
+            std::mem::forget(DRIVER.lock().unwrap_or_else(|e| e.into_inner()).take());
+            *DRIVER.lock().unwrap_or_else(|e| e.into_inner()) = driver;
+            RUNTIME.store(runtime as *const Runtime as *mut Runtime, Ordering::Release);
+            RUNTIME_PID.store(pid, Ordering::Release);
+        }
+    }
+
+    // SAFETY: the runtime is only freed by sqlite3_shutdown, which the caller may not run
+    // while anything else uses the library
+    unsafe { &*RUNTIME.load(Ordering::Acquire) }
+}
+
+/// The runtime, if this process has built one, without building it.
+pub fn running_tokio() -> Option<&'static Runtime> {
+    if RUNTIME_PID.load(Ordering::Acquire) != std::process::id() {
+        return None;
+    }
+    // SAFETY: as in get_tokio
+    unsafe { RUNTIME.load(Ordering::Acquire).as_ref() }
+}
+
+/// Stops the runtime and every task still on it, for `sqlite3_shutdown`. The next call that
+/// needs a runtime builds a new one.
+pub fn shutdown_tokio() {
+    let _init = RUNTIME_INIT.lock().unwrap_or_else(|e| e.into_inner());
+    // Either none was built, or it is a forked parent's, which stays leaked
+    if RUNTIME_PID.load(Ordering::Acquire) != std::process::id() {
+        return;
+    }
+
+    RUNTIME_PID.store(0, Ordering::Release);
+    if let Some(driver) = DRIVER.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        driver.stop();
+    }
+    let runtime = unsafe { Box::from_raw(RUNTIME.swap(std::ptr::null_mut(), Ordering::AcqRel)) };
+    // The caller may itself be running on a runtime, where shutting one down panics
+    let _ = thread::spawn(move || runtime.shutdown_timeout(SHUTDOWN_TIMEOUT)).join();
+}
+
+pub fn execute_async_task<F, R>(worker: &Worker, task: F) -> c_int
 where
     F: std::future::Future<Output = Result<R, SqliteError>>,
     R: Into<c_int>,
 {
-    let runtime = get_tokio();
+    result_code(worker.run(task))
+}
 
-    match runtime.block_on(task) {
+/// The code an FFI call returns for `result`, with the error pushed to the error stack.
+pub fn result_code<R: Into<c_int>>(result: Result<R, SqliteError>) -> c_int {
+    match result {
         Ok(result) => result.into(),
         Err(err) => {
             unsafe { push_error((format!("{}", err), err.code)) };
-            SQLITE_ERROR
+            // As from SQLite, whose calls return primary codes unless extended ones are turned
+            // on: a timeout is SQLITE_BUSY, a failed constraint SQLITE_CONSTRAINT, and the
+            // extended code stays on the error stack for sqlite3_extended_errcode
+            err.code & 0xff
         }
     }
 }
 
-#[inline]
-pub fn sql_is_begin_transaction(sql: &String) -> bool {
-    sql.starts_with("BEGIN")
-}
+/// Matches the shim's own `PRAGMA turso.<name> [= value]` settings, which are handled
+/// locally and never sent to the server.
+pub fn parse_turso_pragma(sql: &str) -> Option<(String, Option<String>)> {
+    let re = Regex::new(
+        r#"(?ix)
+        ^\s*PRAGMA\s+turso\.([a-z_]+)
+        \s*(?:=\s*(?:'([^']*)'|"([^"]*)"|([^\s;]+))|\(\s*(?:'([^']*)'|"([^"]*)"|([^)\s]+))\s*\))?
+        \s*;?\s*$
+    "#,
+    )
+    .unwrap();
 
-#[inline]
-pub fn sql_is_pragma(sql: &String) -> bool {
-    sql.starts_with("PRAGMA")
-}
+    let captures = re.captures(sql)?;
+    let name = captures.get(1)?.as_str().to_ascii_lowercase();
+    let value = (2..=7)
+        .find_map(|i| captures.get(i))
+        .map(|m| m.as_str().to_string());
 
-#[inline]
-pub fn sql_is_rollback(sql: &String) -> bool {
-    sql.starts_with("ROLLBACK")
-}
-
-#[inline]
-pub fn sql_is_commit(sql: &String) -> bool {
-    sql.starts_with("COMMIT")
+    Some((name, value))
 }
 
 #[inline]
 pub fn is_aligned<T>(ptr: *const T) -> bool {
-    !ptr.is_null() && (ptr as usize) % std::mem::align_of::<T>() == 0
-}
-
-pub fn convert_params_to_json(params: &HashMap<i32, Value>) -> Vec<serde_json::Value> {
-    let mut index_value_pairs: Vec<_> = params.iter().collect();
-    // Sort by parameter index
-    index_value_pairs.sort_by_key(|&(k, _)| *k);
-
-    // Map sorted values to JSON
-    index_value_pairs
-        .into_iter()
-        .map(|(_, value)| match value {
-            Value::Integer(i) => serde_json::json!({
-                "type": "integer",
-                "value": *i.to_string()
-            }),
-
-            Value::Real(f) => serde_json::json!({
-                "type": "float",
-                "value": f
-            }),
-            Value::Text(s) => serde_json::json!({
-                "type": "text",
-                "value": s
-            }),
-            Value::Null => serde_json::json!({
-                "type": "null",
-                "value": null
-            }),
-        })
-        .collect()
+    !ptr.is_null() && (ptr as usize).is_multiple_of(std::mem::align_of::<T>())
 }
 
 pub fn get_execution_result<'a>(
@@ -119,29 +251,20 @@ pub fn get_execution_result<'a>(
         baton.replace(new_baton.into());
     }
 
-    let first_execution_result = match result.results.get(0) {
-        Some(inner) => match &inner.response {
-            RemoteSQLiteResult::Error { message, code } => {
-                return Err(SqliteError::new(
-                    format!("Remote SQLite error (code {}): {}", code, message),
-                    Some(SQLITE_ERROR),
-                ));
-            }
-            RemoteSQLiteResult::Execute { result } => Ok(result),
-            RemoteSQLiteResult::Close => Err::<&'a QueryResult, SqliteError>(SqliteError::new(
-                "Remote SQLite closed the connection unexpectedly",
-                None,
-            )),
-        },
-        None => Err::<&'a QueryResult, SqliteError>(SqliteError::new(
-            "No results returned from remote SQLite",
-            None,
-        )),
-    }?;
-
-    if let Some(last_insert_rowid) = &first_execution_result.last_insert_rowid {
-        let mut last_insert_rowid_lock = db.last_insert_rowid.lock().unwrap();
-        *last_insert_rowid_lock = Some(last_insert_rowid.parse::<i64>().unwrap_or(0));
+    let first_execution_result = result.first_result()?;
+
+    db.metrics.record_rows(
+        first_execution_result.rows_read.unwrap_or(0),
+        first_execution_result.rows_written.unwrap_or(0),
+    );
+    if let Some(ms) = first_execution_result.query_duration_ms {
+        db.metrics.record_server_time(ms);
+    }
+
+    if let Some(replication_index) = &first_execution_result.replication_index {
+        if let Ok(index) = replication_index.parse::<u64>() {
+            db.observe_replication_index(index);
+        }
     }
 
     if let Some(rows_written) = &first_execution_result.rows_written {
@@ -151,3 +274,119 @@ pub fn get_execution_result<'a>(
 
     Ok(first_execution_result)
 }
+
+// Every block from `sqlite3_malloc` starts with its size, so `sqlite3_free` can rebuild the
+// layout it was allocated with
+const ALLOCATION_HEADER: usize = 8;
+
+fn allocation_layout(size: usize) -> Option<std::alloc::Layout> {
+    std::alloc::Layout::from_size_align(size.checked_add(ALLOCATION_HEADER)?, ALLOCATION_HEADER)
+        .ok()
+}
+
+/// Allocates memory the caller releases with `sqlite3_free`. Null when `size` is zero or the
+/// allocation fails.
+pub fn allocate(size: usize) -> *mut u8 {
+    let Some(layout) = allocation_layout(size).filter(|_| size > 0) else {
+        return std::ptr::null_mut();
+    };
+
+    unsafe {
+        let block = std::alloc::alloc(layout);
+        if block.is_null() {
+            return block;
+        }
+        (block as *mut u64).write(size as u64);
+        status::MEMORY_USED.add(size as i64);
+        status::MALLOC_COUNT.add(1);
+        status::MALLOC_SIZE.set(size as i64);
+        block.add(ALLOCATION_HEADER)
+    }
+}
+
+/// Size of a block from `allocate`.
+pub unsafe fn allocation_size(ptr: *const u8) -> usize {
+    if ptr.is_null() {
+        return 0;
+    }
+    (ptr.sub(ALLOCATION_HEADER) as *const u64).read() as usize
+}
+
+pub unsafe fn release(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    let size = allocation_size(ptr);
+    if let Some(layout) = allocation_layout(size) {
+        std::alloc::dealloc(ptr.sub(ALLOCATION_HEADER), layout);
+        status::MEMORY_USED.sub(size as i64);
+        status::MALLOC_COUNT.sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn runtime_flavors_parse() {
+        let parse = RuntimeFlavor::parse;
+        assert_eq!(parse("current_thread"), Some(RuntimeFlavor::CurrentThread));
+        assert_eq!(parse("multi_thread:2"), Some(RuntimeFlavor::MultiThread(2)));
+        assert!(matches!(
+            parse("Multi_Thread"),
+            Some(RuntimeFlavor::MultiThread(_))
+        ));
+        assert_eq!(parse("multi_thread:0"), None);
+        assert_eq!(parse("multi_thread:"), None);
+        assert_eq!(parse("4"), None);
+    }
+
+    #[test]
+    fn a_current_thread_runtime_runs_tasks_between_calls() {
+        let runtime: &'static Runtime = Box::leak(Box::new(RuntimeFlavor::CurrentThread.build()));
+        let driver = Driver::start(runtime);
+
+        // Nothing here enters block_on, the driver alone runs the task and its timer
+        let (sender, receiver) = mpsc::channel();
+        runtime.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let _ = sender.send(());
+        });
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(runtime.block_on(async { 7 }), 7);
+
+        driver.stop();
+    }
+
+    #[test]
+    fn turso_pragmas_parse() {
+        let parse = |sql| parse_turso_pragma(sql);
+        assert_eq!(
+            parse("PRAGMA turso.timeout"),
+            Some(("timeout".to_string(), None))
+        );
+        for sql in [
+            "PRAGMA turso.timeout = 250",
+            "pragma TURSO.Timeout=250;",
+            "PRAGMA turso.timeout('250')",
+            "  PRAGMA turso.timeout = '250' ; ",
+            "PRAGMA turso.timeout = \"250\"",
+            "PRAGMA turso.timeout(250)",
+        ] {
+            assert_eq!(
+                parse(sql),
+                Some(("timeout".to_string(), Some("250".to_string()))),
+                "{}",
+                sql
+            );
+        }
+
+        // Anything else goes to the server
+        assert_eq!(parse("PRAGMA main.timeout = 250"), None);
+        assert_eq!(parse("PRAGMA turso.timeout = 250; SELECT 1"), None);
+        assert_eq!(parse("SELECT 'PRAGMA turso.timeout'"), None);
+    }
+}
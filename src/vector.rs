@@ -0,0 +1,80 @@
+//! Vectors as the server's vector search stores them. An `F32_BLOB` value is a BLOB of
+//! little-endian 32-bit floats, so binding one as a BLOB spares building a `vector('[...]')`
+//! literal, and reading one back only has to reinterpret its bytes.
+
+use crate::sqlite::Value;
+
+// The byte that ends a vector BLOB whose length alone does not tell its element type
+const FLOAT32: u8 = 1;
+const FLOAT64: u8 = 2;
+
+/// The BLOB the server stores for `vector`.
+pub fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+/// The elements of a vector value: an `F32_BLOB` or `F64_BLOB`, or the `[1,2,3]` text
+/// `vector_extract` returns. `None` for anything else.
+pub fn from_value(value: &Value) -> Option<Vec<f32>> {
+    match value {
+        Value::Blob(bytes) => from_blob(bytes),
+        Value::Text(text) => serde_json::from_str(text).ok(),
+        _ => None,
+    }
+}
+
+fn from_blob(bytes: &[u8]) -> Option<Vec<f32>> {
+    let (elements, kind) = match bytes.len() % 4 {
+        0 => (bytes, FLOAT32),
+        _ => bytes
+            .split_last()
+            .map(|(kind, elements)| (elements, *kind))?,
+    };
+
+    match kind {
+        FLOAT32 if elements.len() % 4 == 0 => Some(
+            elements
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        FLOAT64 if elements.len() % 8 == 0 => Some(
+            elements
+                .chunks_exact(8)
+                .map(|x| f64::from_le_bytes(x.try_into().unwrap()) as f32)
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_read_back_from_every_form() {
+        let vector = [1.5, -2.0, 3.25];
+        let blob = to_blob(&vector);
+        assert_eq!(blob.len(), 12);
+        assert_eq!(from_value(&Value::Blob(blob.clone())).unwrap(), vector);
+
+        let mut tagged = blob;
+        tagged.push(FLOAT32);
+        assert_eq!(from_value(&Value::Blob(tagged)).unwrap(), vector);
+
+        let mut doubles: Vec<u8> = vector
+            .iter()
+            .flat_map(|x| (*x as f64).to_le_bytes())
+            .collect();
+        doubles.push(FLOAT64);
+        assert_eq!(from_value(&Value::Blob(doubles)).unwrap(), vector);
+
+        let text = Value::Text("[1.5,-2,3.25]".to_string());
+        assert_eq!(from_value(&text).unwrap(), vector);
+
+        assert!(from_value(&Value::Blob(vec![1, 2, 3, 4, 5, 9])).is_none());
+        assert!(from_value(&Value::Text("hello".to_string())).is_none());
+        assert!(from_value(&Value::Integer(1)).is_none());
+    }
+}
@@ -0,0 +1,128 @@
+//! The SQLite version the library reports. Drivers turn features such as RETURNING or STRICT
+//! tables on or off by version, and statements run on the server, so its version is asked for
+//! by the first connection opened and kept for the process. `TURSO_SQLITE_VERSION` pins the
+//! version instead, e.g. to keep a driver on code paths it was tested with.
+
+use std::{
+    ffi::{c_int, CStr, CString},
+    sync::OnceLock,
+};
+
+use crate::{protocol::StatementArgs, sqlite::Value, transport::DatabaseConnection};
+
+pub const VERSION_ENV: &str = "TURSO_SQLITE_VERSION";
+
+// Reported until a server answered
+const FALLBACK_VERSION: &CStr = c"3.37.0";
+const FALLBACK_NUMBER: c_int = 3037000;
+const FALLBACK_SOURCE_ID: &CStr = c"2022-01-06 13:25:4 libsqlite3_turso";
+
+pub struct Version {
+    version: CString,
+    number: c_int,
+    source_id: CString,
+}
+
+impl Version {
+    fn new(version: &str, source_id: &CStr) -> Option<Self> {
+        Some(Self {
+            number: version_number(version)?,
+            version: CString::new(version).ok()?,
+            source_id: source_id.to_owned(),
+        })
+    }
+}
+
+static PINNED: OnceLock<Option<Version>> = OnceLock::new();
+static SERVER: OnceLock<Version> = OnceLock::new();
+
+fn pinned() -> Option<&'static Version> {
+    PINNED
+        .get_or_init(|| {
+            let version = std::env::var(VERSION_ENV).ok()?;
+            let pinned = Version::new(version.trim(), FALLBACK_SOURCE_ID);
+            if pinned.is_none() {
+                tracing::warn!(version, "Ignoring {}, expected X.Y.Z", VERSION_ENV);
+            }
+            pinned
+        })
+        .as_ref()
+}
+
+fn current() -> Option<&'static Version> {
+    pinned().or_else(|| SERVER.get())
+}
+
+pub fn version() -> &'static CStr {
+    current().map_or(FALLBACK_VERSION, |v| v.version.as_c_str())
+}
+
+pub fn number() -> c_int {
+    current().map_or(FALLBACK_NUMBER, |v| v.number)
+}
+
+pub fn source_id() -> &'static CStr {
+    current().map_or(FALLBACK_SOURCE_ID, |v| v.source_id.as_c_str())
+}
+
+/// Asks the server for its version unless it is already known or pinned. A failure is only
+/// logged, the next connection opened asks again.
+pub async fn learn(connection: &mut DatabaseConnection) {
+    if pinned().is_some() || SERVER.get().is_some() {
+        return;
+    }
+
+    let mut request = connection.get_autocommit_request(
+        "SELECT sqlite_version(), sqlite_source_id()",
+        &StatementArgs::default(),
+    );
+    let response = match connection.send(&mut request).await {
+        Ok(response) => response,
+        Err(err) => return tracing::debug!(error = %err, "Failed to read the server's version"),
+    };
+    let row = response
+        .first_result()
+        .ok()
+        .and_then(|result| result.rows.first())
+        .map(|row| row.iter().map(|value| value.decode()).collect::<Vec<_>>());
+
+    let version = match row.as_deref() {
+        Some([Value::Text(version), Value::Text(source_id)]) => CString::new(source_id.as_str())
+            .ok()
+            .and_then(|source_id| Version::new(version, &source_id)),
+        _ => None,
+    };
+    match version {
+        Some(version) => {
+            tracing::debug!(version = ?version.version, "Server version");
+            let _ = SERVER.set(version);
+        }
+        None => tracing::debug!(?row, "Unexpected answer to the version query"),
+    }
+}
+
+// SQLITE_VERSION_NUMBER: 3.45.1 is 3045001
+fn version_number(version: &str) -> Option<c_int> {
+    let mut parts = version.split('.').map(|part| part.parse::<c_int>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() || !(0..1000).contains(&minor) || !(0..1000).contains(&patch) {
+        return None;
+    }
+    Some(major * 1_000_000 + minor * 1000 + patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_numbers_follow_sqlite() {
+        assert_eq!(version_number("3.45.1"), Some(3045001));
+        assert_eq!(version_number("3.37"), Some(3037000));
+        assert_eq!(version_number("3.x.1"), None);
+        assert_eq!(version_number("3.45.1.2"), None);
+        assert_eq!(version_number(""), None);
+    }
+}
@@ -0,0 +1,435 @@
+//! Every connection drives its remote I/O on a thread of its own. The FFI layer hands the
+//! work over a channel and waits for the answer, so the caller's thread never enters the
+//! runtime, and a caller that is itself inside a Tokio runtime does not trip over a nested
+//! `block_on`.
+
+use std::{
+    any::Any,
+    cell::Cell,
+    collections::VecDeque,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle, ThreadId},
+    time::Duration,
+};
+
+use crate::utils::get_tokio;
+
+// How long a caller waits before logging that its call is still running
+const STALL_WARNING: Duration = Duration::from_secs(30);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Where a job goes in the worker's queue: ahead of every job of a lower priority that has
+/// not started yet, behind those of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low, // Background work that may wait for everything else
+    #[default]
+    Normal,
+    High, // Interactive queries sharing the connection with background jobs
+}
+
+impl Priority {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "low" | "-1" => Some(Priority::Low),
+            "normal" | "0" => Some(Priority::Normal),
+            "high" | "1" => Some(Priority::High),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+// Jobs waiting for the worker thread, by priority
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: [VecDeque<(u64, Job)>; 3], // Indexed by priority, each in submission order
+    next_id: u64,
+    closed: bool, // No more jobs come, the thread stops once the queue is empty
+}
+
+impl Queue {
+    fn push(&self, priority: Priority, job: Job) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.next_id += 1;
+        let id = state.next_id;
+        state.jobs[priority as usize].push_back((id, job));
+        self.ready.notify_one();
+        id
+    }
+
+    // The next job, highest priority first. None once closed and empty.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some((_, job)) = state.jobs.iter_mut().rev().find_map(VecDeque::pop_front) {
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    // Takes job `id` back if the thread has not started it
+    fn withdraw(&self, id: u64) -> Option<Job> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.jobs.iter_mut().find_map(|jobs| {
+            let index = jobs.iter().position(|(queued, _)| *queued == id)?;
+            jobs.remove(index).map(|(_, job)| job)
+        })
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.ready.notify_one();
+    }
+}
+
+thread_local! {
+    // Set on a worker thread while it runs a job, to the thread that handed it over
+    static CALLER: Cell<Option<ThreadId>> = const { Cell::new(None) };
+}
+
+/// The application thread the current call came from, even when it runs on a worker.
+pub fn caller_thread() -> ThreadId {
+    CALLER.get().unwrap_or_else(|| thread::current().id())
+}
+
+pub struct Worker {
+    thread: Mutex<Option<WorkerThread>>, // Taken on drop to stop the thread
+}
+
+struct WorkerThread {
+    queue: Arc<Queue>,
+    handle: JoinHandle<()>,
+    pid: u32, // Process the thread runs in
+}
+
+impl WorkerThread {
+    fn spawn() -> Self {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+        });
+        let jobs = queue.clone();
+        let handle = thread::Builder::new()
+            .name("turso-connection".to_string())
+            .spawn(move || {
+                while let Some(job) = jobs.pop() {
+                    job();
+                }
+            })
+            .expect("failed to spawn connection thread");
+
+        Self {
+            queue,
+            handle,
+            pid: std::process::id(),
+        }
+    }
+}
+
+impl Worker {
+    pub fn start() -> Self {
+        Self {
+            thread: Mutex::new(Some(WorkerThread::spawn())),
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        self.thread
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .is_some_and(|thread| thread.handle.thread().id() == thread::current().id())
+    }
+
+    // A forked child inherits the worker but not its thread, so it starts a thread of its own.
+    // The old one's queue and handle are forgotten, they belong to the parent's thread.
+    fn queue(&self) -> Arc<Queue> {
+        let mut thread = self.thread.lock().unwrap_or_else(|e| e.into_inner());
+        let thread = thread.as_mut().expect("connection thread stopped");
+        if thread.pid != std::process::id() {
+            std::mem::forget(std::mem::replace(thread, WorkerThread::spawn()));
+        }
+        thread.queue.clone()
+    }
+
+    /// Runs `task` to completion on the worker and returns its output.
+    pub fn run<F: Future>(&self, task: F) -> F::Output {
+        self.run_at(Priority::Normal, task)
+    }
+
+    /// Like `run`, with the job queued by `priority`.
+    pub fn run_at<F: Future>(&self, priority: Priority, task: F) -> F::Output {
+        let caller = caller_thread();
+
+        // A callback running on the worker that calls back into the library cannot wait
+        // for the worker, it gets a thread of its own for the nested call instead
+        if self.is_current() {
+            return thread::scope(|scope| {
+                let nested = AssertSend(task);
+                let handle = scope.spawn(move || {
+                    CALLER.set(Some(caller));
+                    AssertSend(get_tokio().block_on(nested.into_inner()))
+                });
+                match handle.join() {
+                    Ok(output) => output.into_inner(),
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            });
+        }
+
+        // SAFETY: this function does not return before the job reported back, and the job
+        // never outlives its report, so every borrow is still live while it runs.
+        let (result, _) = unsafe { self.send(caller, priority, task) };
+
+        loop {
+            match result.recv_timeout(STALL_WARNING) {
+                Ok(Ok(output)) => return output.into_inner(),
+                Ok(Err(payload)) => panic::resume_unwind(payload),
+                // The job may still hold borrows of this frame, so it is never abandoned
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    tracing::warn!(
+                        waited_secs = STALL_WARNING.as_secs(),
+                        "Call into the connection thread is still running"
+                    );
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    panic!("connection thread stopped")
+                }
+            }
+        }
+    }
+
+    /// Hands `task` to the worker and returns without waiting for it, for the non-blocking
+    /// step mode. The output is collected through the returned `Pending`.
+    ///
+    /// # Safety
+    ///
+    /// Everything `task` borrows must stay valid until the `Pending` has been taken,
+    /// cancelled or dropped, all of which wait for the job unless it never started.
+    pub unsafe fn submit<F: Future>(&self, priority: Priority, task: F) -> Pending<F::Output> {
+        // Queued behind the running job, a callback's own submission could never be waited for
+        if self.is_current() {
+            let (done, result) = mpsc::channel();
+            let _ = done.send(Ok(AssertSend(self.run(task))));
+            return Pending {
+                result: Some(result),
+                queued: None,
+            };
+        }
+
+        let (result, queued) = self.send(caller_thread(), priority, task);
+        Pending {
+            result: Some(result),
+            queued: Some(queued),
+        }
+    }
+
+    // Queues `task`, which must not borrow anything that goes away before it has reported
+    // back through the returned channel or been withdrawn from the queue.
+    unsafe fn send<F: Future>(
+        &self,
+        caller: ThreadId,
+        priority: Priority,
+        task: F,
+    ) -> (mpsc::Receiver<Report<F::Output>>, Queued) {
+        let (done, result) = mpsc::channel::<Report<F::Output>>();
+        let job = AssertSend(move || {
+            CALLER.set(Some(caller));
+            let output = panic::catch_unwind(AssertUnwindSafe(|| get_tokio().block_on(task)));
+            CALLER.set(None);
+            let _ = done.send(output.map(AssertSend));
+        });
+        let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || (job.into_inner())());
+
+        // The job is only made 'static to cross the channel, the caller keeps its borrows alive
+        let job: Job = std::mem::transmute(job);
+        let queue = self.queue();
+        let id = queue.push(priority, job);
+
+        (result, (queue, id))
+    }
+}
+
+type Report<T> = Result<AssertSend<T>, Box<dyn Any + Send>>;
+
+// The queue a job was pushed to and its id there
+type Queued = (Arc<Queue>, u64);
+
+/// A job submitted with `Worker::submit` that may still be running.
+pub struct Pending<T> {
+    result: Option<mpsc::Receiver<Report<T>>>, // Taken once the job has reported
+    queued: Option<(Arc<Queue>, u64)>,         // Where the job waits for the thread
+}
+
+impl<T> Pending<T> {
+    /// The job's output if it has finished, or the job back if it is still running.
+    pub fn try_take(mut self) -> Result<T, Self> {
+        let result = self.result.as_ref().expect("pending job already taken");
+        match result.try_recv() {
+            Ok(report) => {
+                self.result = None;
+                Ok(unwrap_report(report))
+            }
+            Err(mpsc::TryRecvError::Empty) => Err(self),
+            Err(mpsc::TryRecvError::Disconnected) => panic!("connection thread stopped"),
+        }
+    }
+
+    /// Blocks until the job has finished, keeping its output for `try_take` or `wait`.
+    pub fn settle(&mut self) {
+        let result = self.result.as_ref().expect("pending job already taken");
+        if let Ok(report) = result.recv() {
+            let (done, result) = mpsc::channel();
+            let _ = done.send(report);
+            self.result = Some(result);
+        }
+    }
+
+    /// Takes the job back if it has not started, or waits for it like `wait`. None when the
+    /// job never ran.
+    pub fn cancel(mut self) -> Option<T> {
+        let withdrawn = self
+            .queued
+            .as_ref()
+            .and_then(|(queue, id)| queue.withdraw(*id));
+        match withdrawn {
+            Some(job) => {
+                // Dropped unpolled, so it never touches what it borrows
+                drop(job);
+                self.result = None;
+                None
+            }
+            None => Some(self.wait()),
+        }
+    }
+
+    /// Blocks until the job has finished and returns its output.
+    pub fn wait(mut self) -> T {
+        let result = self.result.take().expect("pending job already taken");
+        match result.recv() {
+            Ok(report) => unwrap_report(report),
+            Err(_) => panic!("connection thread stopped"),
+        }
+    }
+}
+
+impl<T> Drop for Pending<T> {
+    // The job may borrow what its owner is about to free, so it is never abandoned
+    fn drop(&mut self) {
+        if let Some(result) = self.result.take() {
+            let _ = result.recv();
+        }
+    }
+}
+
+fn unwrap_report<T>(report: Report<T>) -> T {
+    match report {
+        Ok(output) => output.into_inner(),
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let thread = self
+            .thread
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        let Some(WorkerThread { queue, handle, pid }) = thread else {
+            return;
+        };
+        if pid != std::process::id() {
+            std::mem::forget((queue, handle));
+            return;
+        }
+
+        // Closing the queue stops the thread once it is empty. A connection closed from one
+        // of its own callbacks cannot wait for itself
+        queue.close();
+        if handle.thread().id() != thread::current().id() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Futures are created on the caller's thread but only ever polled on one other thread,
+// from start to finish, while the caller waits
+struct AssertSend<T>(T);
+
+unsafe impl<T> Send for AssertSend<T> {}
+
+impl<T> AssertSend<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Holds the worker's thread until the returned sender is dropped
+    fn occupy(worker: &Worker) -> (mpsc::Sender<()>, Pending<()>) {
+        let (release, gate) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        let pending = unsafe {
+            worker.submit(Priority::Normal, async move {
+                started.send(()).unwrap();
+                let _ = gate.recv();
+            })
+        };
+        running.recv().unwrap();
+        (release, pending)
+    }
+
+    #[test]
+    fn queued_jobs_run_by_priority_and_can_be_withdrawn() {
+        let worker = Worker::start();
+        let order = Mutex::new(Vec::new());
+        let (release, busy) = occupy(&worker);
+
+        let submit = |priority: Priority| unsafe {
+            let order = &order;
+            worker.submit(
+                priority,
+                async move { order.lock().unwrap().push(priority) },
+            )
+        };
+        let low = submit(Priority::Low);
+        let normal = submit(Priority::Normal);
+        let withdrawn = submit(Priority::High);
+        let high = submit(Priority::High);
+
+        assert!(withdrawn.cancel().is_none());
+        drop(release);
+        busy.wait();
+        for pending in [low, normal, high] {
+            pending.wait();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            [Priority::High, Priority::Normal, Priority::Low]
+        );
+    }
+}
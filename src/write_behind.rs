@@ -0,0 +1,227 @@
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    sync::{Arc, Mutex},
+};
+
+use regex::Regex;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    protocol::{result_error, to_json, PipelineReq, StatementArgs, StreamRequest},
+    sqlite::{SqliteError, SQLITE_ERROR},
+    transport::HttpStrategy,
+    utils::get_tokio,
+};
+
+// Upper bound on statements sent in a single pipeline request
+const MAX_BATCH_SIZE: usize = 128;
+
+pub type AsyncErrorHook = extern "C" fn(
+    user_data: *mut c_void, // User-provided data
+    code: c_int,            // SQLite result code of the failed write
+    sql: *const c_char,     // Statement that failed
+    message: *const c_char, // Error reported by the server
+);
+
+pub struct RegisteredErrorHook {
+    callback: AsyncErrorHook,
+    user_data: *mut c_void,
+}
+
+// The hook is only ever invoked with the pointer the caller handed us
+unsafe impl Send for RegisteredErrorHook {}
+
+pub type SharedErrorHook = Arc<Mutex<Option<RegisteredErrorHook>>>;
+
+pub fn set_error_hook(
+    hook: &SharedErrorHook,
+    callback: Option<AsyncErrorHook>,
+    user_data: *mut c_void,
+) {
+    *hook.lock().unwrap() = callback.map(|callback| RegisteredErrorHook {
+        callback,
+        user_data,
+    });
+}
+
+enum Command {
+    Write {
+        sql: String,
+        args: StatementArgs,
+    },
+    // Acknowledged with the number of writes that failed since the last reported flush
+    Flush {
+        ack: oneshot::Sender<usize>,
+        report: bool, // Reset the failure count, done by sqlite3_turso_flush only
+    },
+}
+
+/// Queue for `PRAGMA turso.async_writes`: DML is handed to a background task which sends it
+/// in pipelined batches over its own HTTP channel, so the caller never waits on a round trip.
+#[derive(Clone)]
+pub struct WriteBehind {
+    sender: mpsc::UnboundedSender<Command>,
+    pid: u32, // Process running the background task
+}
+
+impl WriteBehind {
+    pub fn start(http: HttpStrategy, error_hook: SharedErrorHook) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        get_tokio().spawn(run(http, receiver, error_hook));
+        Self {
+            sender,
+            pid: std::process::id(),
+        }
+    }
+
+    /// Whether the queue belongs to the parent of a forked process. Its task did not survive
+    /// the fork, so nothing sent to it would ever be answered.
+    pub fn is_inherited(&self) -> bool {
+        self.pid != std::process::id()
+    }
+
+    pub fn enqueue(&self, sql: &str, args: StatementArgs) -> Result<(), SqliteError> {
+        self.sender
+            .send(Command::Write {
+                sql: sql.to_string(),
+                args,
+            })
+            .map_err(|_| SqliteError::new("Write-behind queue is closed", Some(SQLITE_ERROR)))
+    }
+
+    /// Waits until everything queued so far has reached the server, failing if any queued
+    /// write failed since the previous call.
+    pub async fn flush(&self) -> Result<(), SqliteError> {
+        match self.wait_drained(true).await {
+            0 => Ok(()),
+            failed => Err(SqliteError::new(
+                format!("{} queued write(s) failed since the last flush", failed),
+                Some(SQLITE_ERROR),
+            )),
+        }
+    }
+
+    /// Waits for the queue to drain so a following statement observes the queued writes.
+    /// Failures stay counted for the next `flush`.
+    pub async fn drain(&self) {
+        self.wait_drained(false).await;
+    }
+
+    async fn wait_drained(&self, report: bool) -> usize {
+        let (ack, done) = oneshot::channel();
+        if self.is_inherited() || self.sender.send(Command::Flush { ack, report }).is_err() {
+            return 0;
+        }
+
+        done.await.unwrap_or(0)
+    }
+}
+
+/// Statements eligible for write-behind: plain DML whose result nobody needs to read.
+pub fn is_fire_and_forget(sql: &str) -> bool {
+    let re = Regex::new(r"(?is)^\s*(INSERT|UPDATE|DELETE|REPLACE)\b").unwrap();
+    let returning = Regex::new(r"(?i)\bRETURNING\b").unwrap();
+    re.is_match(sql) && !returning.is_match(sql)
+}
+
+async fn run(
+    http: HttpStrategy,
+    mut receiver: mpsc::UnboundedReceiver<Command>,
+    error_hook: SharedErrorHook,
+) {
+    let mut failed = 0;
+
+    while let Some(command) = receiver.recv().await {
+        let mut batch = Vec::new();
+        let mut flushes = Vec::new();
+
+        // Take whatever piled up while the previous batch was in flight
+        let mut next = Some(command);
+        while let Some(command) = next.take() {
+            match command {
+                Command::Write { sql, args } => batch.push((sql, args)),
+                Command::Flush { ack, report } => {
+                    flushes.push((ack, report));
+                    break;
+                }
+            }
+            if batch.len() >= MAX_BATCH_SIZE {
+                break;
+            }
+            next = receiver.try_recv().ok();
+        }
+
+        if !batch.is_empty() {
+            failed += send_batch(&http, &batch, &error_hook).await;
+        }
+
+        for (ack, report) in flushes {
+            let _ = ack.send(failed);
+            if report {
+                failed = 0;
+            }
+        }
+    }
+}
+
+/// Sends one batch as a single pipeline and reports each failed statement to the hook.
+/// Returns the number of failures.
+async fn send_batch(
+    http: &HttpStrategy,
+    batch: &[(String, StatementArgs)],
+    error_hook: &SharedErrorHook,
+) -> usize {
+    let requests = batch
+        .iter()
+        .map(|(sql, args)| StreamRequest::execute(args.to_stmt(sql)))
+        .collect();
+    let request = to_json(&PipelineReq::autocommit(requests));
+    tracing::debug!(statements = batch.len(), "Flushing write-behind batch");
+
+    let errors: Vec<Option<SqliteError>> = match http.send_raw(&request).await {
+        Ok(response) => {
+            let results = response.get("results").and_then(|r| r.as_array());
+            record_stats(http, results.map(Vec::as_slice).unwrap_or_default());
+            (0..batch.len())
+                .map(|i| results.and_then(|r| r.get(i)).and_then(result_error))
+                .collect()
+        }
+        // The whole request failed, so did every statement in it
+        Err(err) => (0..batch.len())
+            .map(|_| Some(SqliteError::new(err.to_string(), Some(err.code))))
+            .collect(),
+    };
+
+    let hook = error_hook.lock().unwrap();
+    let mut failed = 0;
+    for ((sql, _), error) in batch.iter().zip(errors) {
+        let Some(error) = error else {
+            continue;
+        };
+        failed += 1;
+        tracing::warn!(error = %error.message, "Queued write failed");
+
+        if let Some(hook) = &*hook {
+            let sql = CString::new(sql.replace('\0', "")).unwrap_or_default();
+            let message = CString::new(error.message.replace('\0', "")).unwrap_or_default();
+            (hook.callback)(hook.user_data, error.code, sql.as_ptr(), message.as_ptr());
+        }
+    }
+
+    failed
+}
+
+// Queued writes are billed like any other, so their stats go into the connection's totals
+fn record_stats(http: &HttpStrategy, results: &[serde_json::Value]) {
+    for result in results {
+        let Some(result) = result.pointer("/response/result") else {
+            continue;
+        };
+        let stat = |name: &str| result.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+        http.metrics()
+            .record_rows(stat("rows_read"), stat("rows_written"));
+        if let Some(ms) = result.get("query_duration_ms").and_then(|v| v.as_f64()) {
+            http.metrics().record_server_time(ms);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! Drives the shim through its C ABI against a real libSQL server and checks that it answers
+//! like SQLite itself. Run with
+//!
+//! ```sh
+//! cargo test --features integration-tests --test integration
+//! ```
+//!
+//! The server is `SQLD_TEST_URL` when set, e.g. `http://127.0.0.1:8080`, otherwise a sqld
+//! container started with docker and removed when the tests exit. Both the shim and the system
+//! SQLite are loaded with libloading, so one set of helpers runs every case on each. The system
+//! library is `libsqlite3.so.0` on Linux and `/usr/lib/libsqlite3.dylib` on macOS; set
+//! `SQLITE_LIB` to use another, and `TURSO_SHIM_LIB` to load the shim from somewhere other
+//! than the target directory.
+
+use std::{
+    env,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString, OsString},
+    process::Command,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use libloading::Library;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ERROR: c_int = 1;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+
+const SQLITE_OPEN_READWRITE: c_int = 0x02;
+const SQLITE_OPEN_CREATE: c_int = 0x04;
+const SQLITE_OPEN_FULLMUTEX: c_int = 0x10000;
+
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_TEXT: c_int = 3;
+const SQLITE_BLOB: c_int = 4;
+
+const SQLD_IMAGE: &str = "ghcr.io/tursodatabase/libsql-server:latest";
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(60);
+
+type UpdateHook = extern "C" fn(*mut c_void, c_int, *const c_char, *const c_char, i64);
+type TransactionHook = extern "C" fn(*mut c_void) -> c_int;
+
+// The functions used from either library, with the signatures of the SQLite C API
+macro_rules! c_api {
+    ($($name:ident: fn($($arg:ty),*) $(-> $ret:ty)?;)*) => {
+        #[allow(non_snake_case)]
+        struct Api {
+            _library: Library,
+            $($name: unsafe extern "C" fn($($arg),*) $(-> $ret)?,)*
+        }
+
+        impl Api {
+            fn load(path: OsString) -> Self {
+                unsafe {
+                    let library = Library::new(&path)
+                        .unwrap_or_else(|e| panic!("Cannot load {:?}: {}", path, e));
+                    $(let $name = *library
+                        .get(concat!(stringify!($name), "\0").as_bytes())
+                        .unwrap_or_else(|e| panic!("{:?} lacks {}: {}", path, stringify!($name), e));)*
+                    Self { _library: library, $($name,)* }
+                }
+            }
+        }
+    };
+}
+
+c_api! {
+    sqlite3_open_v2: fn(*const c_char, *mut *mut c_void, c_int, *const c_char) -> c_int;
+    sqlite3_close_v2: fn(*mut c_void) -> c_int;
+    sqlite3_exec: fn(*mut c_void, *const c_char, *const c_void, *mut c_void, *mut *mut c_char) -> c_int;
+    sqlite3_errmsg: fn(*mut c_void) -> *const c_char;
+    sqlite3_get_autocommit: fn(*mut c_void) -> c_int;
+    sqlite3_changes: fn(*mut c_void) -> c_int;
+    sqlite3_last_insert_rowid: fn(*mut c_void) -> i64;
+    sqlite3_prepare_v3: fn(*mut c_void, *const c_char, c_int, c_uint, *mut *mut c_void, *mut *const c_char) -> c_int;
+    sqlite3_bind_int64: fn(*mut c_void, c_int, i64) -> c_int;
+    sqlite3_bind_double: fn(*mut c_void, c_int, f64) -> c_int;
+    sqlite3_bind_text: fn(*mut c_void, c_int, *const c_char, c_int, *const c_void) -> c_int;
+    sqlite3_bind_null: fn(*mut c_void, c_int) -> c_int;
+    sqlite3_step: fn(*mut c_void) -> c_int;
+    sqlite3_reset: fn(*mut c_void) -> c_int;
+    sqlite3_finalize: fn(*mut c_void) -> c_int;
+    sqlite3_column_count: fn(*mut c_void) -> c_int;
+    sqlite3_column_name: fn(*mut c_void, c_int) -> *const c_char;
+    sqlite3_column_type: fn(*mut c_void, c_int) -> c_int;
+    sqlite3_column_int64: fn(*mut c_void, c_int) -> i64;
+    sqlite3_column_double: fn(*mut c_void, c_int) -> f64;
+    sqlite3_column_text: fn(*mut c_void, c_int) -> *const c_char;
+    sqlite3_column_blob: fn(*mut c_void, c_int) -> *const c_void;
+    sqlite3_column_bytes: fn(*mut c_void, c_int) -> c_int;
+    sqlite3_update_hook: fn(*mut c_void, Option<UpdateHook>, *mut c_void) -> *mut c_void;
+    sqlite3_commit_hook: fn(*mut c_void, Option<TransactionHook>, *mut c_void) -> *mut c_void;
+    sqlite3_rollback_hook: fn(*mut c_void, Option<TransactionHook>, *mut c_void) -> *mut c_void;
+}
+
+fn shim() -> &'static Api {
+    static SHIM: OnceLock<Api> = OnceLock::new();
+    SHIM.get_or_init(|| {
+        env::set_var("TURSO_DB_URL", server_url());
+        let path = env::var_os("TURSO_SHIM_LIB").unwrap_or_else(|| {
+            // Integration tests run from target/<profile>/deps, next to the built cdylib
+            let exe = env::current_exe().unwrap();
+            let target = exe.parent().and_then(|deps| deps.parent()).unwrap();
+            target.join(libloading::library_filename("sqlite3")).into()
+        });
+        Api::load(path)
+    })
+}
+
+fn sqlite() -> &'static Api {
+    static SQLITE: OnceLock<Api> = OnceLock::new();
+    SQLITE.get_or_init(|| {
+        // Not `libsqlite3.so`, which the loader may find in the target directory first
+        let default = if cfg!(target_os = "linux") {
+            "libsqlite3.so.0".into()
+        } else if cfg!(target_os = "macos") {
+            "/usr/lib/libsqlite3.dylib".into()
+        } else {
+            libloading::library_filename("sqlite3")
+        };
+        Api::load(env::var_os("SQLITE_LIB").unwrap_or(default))
+    })
+}
+
+fn server_url() -> String {
+    if let Ok(url) = env::var("SQLD_TEST_URL") {
+        return url;
+    }
+
+    let output = Command::new("docker")
+        .args([
+            "run",
+            "--detach",
+            "--rm",
+            "--publish",
+            "127.0.0.1::8080",
+            SQLD_IMAGE,
+        ])
+        .output()
+        .expect("Set SQLD_TEST_URL or install docker to run the integration tests");
+    assert!(
+        output.status.success(),
+        "Cannot start sqld: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let container = String::from_utf8(output.stdout).unwrap().trim().to_string();
+    *CONTAINER.lock().unwrap() = Some(container.clone());
+    unsafe { atexit(stop_container) };
+
+    let output = Command::new("docker")
+        .args(["port", &container, "8080/tcp"])
+        .output()
+        .unwrap();
+    // One line per address family, e.g. 127.0.0.1:32768
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let address = stdout.lines().next().unwrap().trim();
+    wait_for_server(address);
+    format!("http://{}", address)
+}
+
+static CONTAINER: Mutex<Option<String>> = Mutex::new(None);
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> c_int;
+}
+
+extern "C" fn stop_container() {
+    if let Some(container) = CONTAINER.lock().unwrap().take() {
+        let _ = Command::new("docker").args(["stop", &container]).output();
+    }
+}
+
+fn wait_for_server(address: &str) {
+    let deadline = Instant::now() + SERVER_START_TIMEOUT;
+    while std::net::TcpStream::connect(address).is_err() {
+        assert!(
+            Instant::now() < deadline,
+            "sqld did not start listening on {}",
+            address
+        );
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Param<'a> {
+    Integer(i64),
+    Real(f64),
+    Text(&'a str),
+    Null,
+}
+
+#[derive(Debug, PartialEq)]
+enum Cell {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+    Null,
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Rows(Vec<String>, Vec<Vec<Cell>>),
+    Error, // Messages differ between SQLite and the server, only the failure is compared
+}
+
+struct Db {
+    api: &'static Api,
+    handle: *mut c_void,
+}
+
+impl Db {
+    fn open(api: &'static Api, filename: &str) -> Self {
+        let filename = CString::new(filename).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULLMUTEX;
+        let rc = unsafe {
+            (api.sqlite3_open_v2)(filename.as_ptr(), &mut handle, flags, std::ptr::null())
+        };
+        assert_eq!(rc, SQLITE_OK, "Cannot open {:?}", filename);
+        Self { api, handle }
+    }
+
+    // One connection to the server and one in-memory database, for running a case on both
+    fn pair() -> (Self, Self) {
+        (
+            Self::open(shim(), "integration.db?auth=none"),
+            Self::open(sqlite(), ":memory:"),
+        )
+    }
+
+    fn exec(&self, sql: &str) -> c_int {
+        let sql = CString::new(sql).unwrap();
+        unsafe {
+            (self.api.sqlite3_exec)(
+                self.handle,
+                sql.as_ptr(),
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        }
+    }
+
+    fn exec_ok(&self, sql: &str) {
+        let rc = self.exec(sql);
+        assert_eq!(rc, SQLITE_OK, "{}: {}", sql, self.errmsg());
+    }
+
+    fn errmsg(&self) -> String {
+        let message = unsafe { (self.api.sqlite3_errmsg)(self.handle) };
+        if message.is_null() {
+            return String::new();
+        }
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn autocommit(&self) -> bool {
+        unsafe { (self.api.sqlite3_get_autocommit)(self.handle) != 0 }
+    }
+
+    fn query(&self, sql: &str, params: &[Param]) -> Outcome {
+        let api = self.api;
+        let mut stmt = std::ptr::null_mut();
+        let rc = unsafe {
+            (api.sqlite3_prepare_v3)(
+                self.handle,
+                sql.as_ptr().cast(),
+                sql.len() as c_int,
+                0,
+                &mut stmt,
+                std::ptr::null_mut(),
+            )
+        };
+        if rc != SQLITE_OK {
+            return Outcome::Error;
+        }
+
+        let texts: Vec<CString> = params
+            .iter()
+            .filter_map(|param| match param {
+                Param::Text(text) => Some(CString::new(*text).unwrap()),
+                _ => None,
+            })
+            .collect();
+        let mut texts = texts.iter();
+        for (i, param) in params.iter().enumerate() {
+            let index = i as c_int + 1;
+            let rc = unsafe {
+                match param {
+                    Param::Integer(value) => (api.sqlite3_bind_int64)(stmt, index, *value),
+                    Param::Real(value) => (api.sqlite3_bind_double)(stmt, index, *value),
+                    Param::Text(_) => {
+                        let text = texts.next().unwrap();
+                        let length = text.as_bytes().len() as c_int;
+                        // SQLITE_STATIC, the string outlives the statement
+                        (api.sqlite3_bind_text)(
+                            stmt,
+                            index,
+                            text.as_ptr(),
+                            length,
+                            std::ptr::null(),
+                        )
+                    }
+                    Param::Null => (api.sqlite3_bind_null)(stmt, index),
+                }
+            };
+            assert_eq!(rc, SQLITE_OK, "Cannot bind {:?} to {}", param, sql);
+        }
+
+        let outcome = unsafe { read_rows(api, stmt) };
+        unsafe { (api.sqlite3_finalize)(stmt) };
+        outcome
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        unsafe { (self.api.sqlite3_close_v2)(self.handle) };
+    }
+}
+
+unsafe fn read_rows(api: &Api, stmt: *mut c_void) -> Outcome {
+    let mut rows = Vec::new();
+    loop {
+        match (api.sqlite3_step)(stmt) {
+            SQLITE_ROW => (),
+            SQLITE_DONE => break,
+            _ => return Outcome::Error,
+        }
+
+        let count = (api.sqlite3_column_count)(stmt);
+        let row = (0..count)
+            .map(|i| match (api.sqlite3_column_type)(stmt, i) {
+                SQLITE_INTEGER => Cell::Integer((api.sqlite3_column_int64)(stmt, i)),
+                SQLITE_FLOAT => Cell::Real((api.sqlite3_column_double)(stmt, i)),
+                SQLITE_TEXT => {
+                    let text = CStr::from_ptr((api.sqlite3_column_text)(stmt, i));
+                    Cell::Text(text.to_string_lossy().into_owned())
+                }
+                SQLITE_BLOB => {
+                    let blob = (api.sqlite3_column_blob)(stmt, i).cast::<u8>();
+                    let length = (api.sqlite3_column_bytes)(stmt, i) as usize;
+                    Cell::Blob(std::slice::from_raw_parts(blob, length).to_vec())
+                }
+                _ => Cell::Null,
+            })
+            .collect();
+        rows.push(row);
+    }
+
+    let columns = (0..(api.sqlite3_column_count)(stmt))
+        .map(|i| {
+            let name = (api.sqlite3_column_name)(stmt, i);
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        })
+        .collect();
+    Outcome::Rows(columns, rows)
+}
+
+// Each test owns its tables, as they share one server
+fn create_table(db: &Db, table: &str) {
+    db.exec_ok(&format!("DROP TABLE IF EXISTS {}", table));
+    db.exec_ok(&format!(
+        "CREATE TABLE {} (id INTEGER PRIMARY KEY, name TEXT, score REAL)",
+        table
+    ));
+}
+
+#[test]
+fn statements_match_sqlite() {
+    let cases: &[(&str, &[Param])] = &[
+        ("SELECT 1, 'one', NULL, 1.5, x'00ff'", &[]),
+        ("SELECT 9223372036854775807, -9223372036854775808", &[]),
+        ("SELECT ? + 1", &[Param::Integer(41)]),
+        (
+            "SELECT ?, typeof(?)",
+            &[Param::Real(0.1), Param::Real(-2.5e10)],
+        ),
+        (
+            "SELECT upper(?), length(?)",
+            &[Param::Text("héllo"), Param::Text("日本")],
+        ),
+        ("SELECT ? IS NULL", &[Param::Null]),
+        ("SELECT abs(?) AS magnitude", &[Param::Integer(-7)]),
+        (
+            "INSERT INTO matrix (name, score) VALUES (?, ?), (?, ?)",
+            &[
+                Param::Text("alice"),
+                Param::Real(9.5),
+                Param::Text("bob"),
+                Param::Null,
+            ],
+        ),
+        ("SELECT id, name, score FROM matrix ORDER BY id", &[]),
+        ("SELECT count(*), sum(score), max(name) FROM matrix", &[]),
+        (
+            "SELECT name FROM matrix WHERE score > ?",
+            &[Param::Integer(5)],
+        ),
+        (
+            "UPDATE matrix SET score = score * 2 WHERE name = ?",
+            &[Param::Text("alice")],
+        ),
+        ("SELECT score FROM matrix WHERE name = 'alice'", &[]),
+        ("DELETE FROM matrix WHERE score IS NULL", &[]),
+        ("SELECT * FROM matrix", &[]),
+        ("SELECT * FROM no_such_table", &[]),
+        ("SELEC 1", &[]),
+    ];
+
+    let (remote, local) = Db::pair();
+    create_table(&remote, "matrix");
+    create_table(&local, "matrix");
+
+    for (sql, params) in cases {
+        let expected = local.query(sql, params);
+        let actual = remote.query(sql, params);
+        assert_eq!(actual, expected, "{} with {:?}", sql, params);
+    }
+}
+
+#[test]
+fn changes_and_rowids_match_sqlite() {
+    let (remote, local) = Db::pair();
+    for db in [&remote, &local] {
+        create_table(db, "counted");
+        db.exec_ok("INSERT INTO counted (name) VALUES ('a'), ('b'), ('c')");
+    }
+    let counters = |db: &Db| unsafe {
+        (
+            (db.api.sqlite3_changes)(db.handle),
+            (db.api.sqlite3_last_insert_rowid)(db.handle),
+        )
+    };
+    assert_eq!(counters(&remote), counters(&local));
+
+    for db in [&remote, &local] {
+        db.exec_ok("UPDATE counted SET score = 1 WHERE id < 3");
+    }
+    assert_eq!(counters(&remote), counters(&local));
+}
+
+#[test]
+fn transactions_match_sqlite() {
+    let (remote, local) = Db::pair();
+    for db in [&remote, &local] {
+        create_table(db, "ledger");
+    }
+
+    let steps = [
+        "BEGIN",
+        "INSERT INTO ledger (name) VALUES ('rolled back')",
+        "ROLLBACK",
+        "BEGIN",
+        "INSERT INTO ledger (name) VALUES ('kept')",
+        "UPDATE ledger SET score = 3 WHERE name = 'kept'",
+        "COMMIT",
+        "COMMIT", // Outside a transaction, an error on both
+        "BEGIN",
+        "BEGIN", // Nested, also an error
+        "ROLLBACK",
+    ];
+    for sql in steps {
+        let expected = (local.exec(sql) == SQLITE_OK, local.autocommit());
+        let actual = (remote.exec(sql) == SQLITE_OK, remote.autocommit());
+        assert_eq!(actual, expected, "{}", sql);
+    }
+
+    let sql = "SELECT name, score FROM ledger";
+    assert_eq!(remote.query(sql, &[]), local.query(sql, &[]));
+}
+
+#[test]
+fn statements_are_reusable_after_reset() {
+    let (remote, local) = Db::pair();
+    for db in [&remote, &local] {
+        create_table(db, "reused");
+    }
+
+    let run = |db: &Db| unsafe {
+        let api = db.api;
+        let sql = "INSERT INTO reused (name) VALUES (?) RETURNING id, name";
+        let mut stmt = std::ptr::null_mut();
+        let rc = (api.sqlite3_prepare_v3)(
+            db.handle,
+            sql.as_ptr().cast(),
+            sql.len() as c_int,
+            0,
+            &mut stmt,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(rc, SQLITE_OK, "{}", db.errmsg());
+
+        let mut outcomes = Vec::new();
+        for name in [c"first", c"second", c"third"] {
+            let length = name.to_bytes().len() as c_int;
+            (api.sqlite3_bind_text)(stmt, 1, name.as_ptr(), length, std::ptr::null());
+            outcomes.push(read_rows(api, stmt));
+            assert_eq!((api.sqlite3_reset)(stmt), SQLITE_OK);
+        }
+        (api.sqlite3_finalize)(stmt);
+        outcomes
+    };
+    assert_eq!(run(&remote), run(&local));
+}
+
+extern "C" fn count_commits(counter: *mut c_void) -> c_int {
+    unsafe { *counter.cast::<c_int>() += 1 };
+    0
+}
+
+extern "C" fn ignore_update(_: *mut c_void, _: c_int, _: *const c_char, _: *const c_char, _: i64) {}
+
+#[test]
+fn hooks_can_be_installed_and_removed() {
+    let db = Db::open(shim(), "integration.db?auth=none");
+    create_table(&db, "hooked");
+
+    // The shim accepts the hooks without running them, statements must work regardless
+    let mut commits: c_int = 0;
+    let counter = std::ptr::from_mut(&mut commits).cast();
+    unsafe {
+        (db.api.sqlite3_update_hook)(db.handle, Some(ignore_update), std::ptr::null_mut());
+        (db.api.sqlite3_commit_hook)(db.handle, Some(count_commits), counter);
+        (db.api.sqlite3_rollback_hook)(db.handle, Some(count_commits), counter);
+    }
+    db.exec_ok("BEGIN");
+    db.exec_ok("INSERT INTO hooked (name) VALUES ('x')");
+    db.exec_ok("COMMIT");
+
+    unsafe {
+        (db.api.sqlite3_update_hook)(db.handle, None, std::ptr::null_mut());
+        (db.api.sqlite3_commit_hook)(db.handle, None, std::ptr::null_mut());
+        (db.api.sqlite3_rollback_hook)(db.handle, None, std::ptr::null_mut());
+    }
+    assert_eq!(
+        db.query("SELECT count(*) FROM hooked", &[]),
+        Outcome::Rows(vec!["count(*)".into()], vec![vec![Cell::Integer(1)]])
+    );
+    assert_eq!(db.exec("SELECT * FROM missing"), SQLITE_ERROR);
+}